@@ -2,24 +2,571 @@
 
 use core::fmt::{Display, Formatter, Result as FmtResult};
 
+// Keys 1-6, 17, 22-24, 27-29 and 40 held what is now in LookupParameters and aren't reused
+#[derive(Debug, Copy, Clone)]
 pub enum ProgramParameters {
-    CountryNodeCount = 1,
-    CountryRecordSize = 2,
-    AsnNodeCount = 3,
-    AsnRecordSize = 4,
+    CountryBuildEpoch = 7,
+    AsnBuildEpoch = 8,
+    MalformedAction = 9,
+    LogIpv4Prefix = 10,
+    LogIpv6Prefix = 11,
+    FilterNeighborDiscovery = 12,
+    FilterDhcp = 13,
+    MulticastAction = 14,
+    CountryStatsResetAt = 15,
+    AsnStatsResetAt = 16,
+    Precedence = 18,
+    DryRun = 19,
+    ScopedPolicies = 20,
+    Direction = 21,
+    CityBuildEpoch = 25,
+    CityStatsResetAt = 26,
+    AnonymousBuildEpoch = 30,
+    AnonymousStatsResetAt = 31,
+    ActivePolicies = 32,
+    SynOnly = 33,
+    Egress = 34,
+    TrackConnections = 35,
+    BogonPolicies = 36,
+    BogonStatsResetAt = 37,
+    FeedStatsResetAt = 38,
+    CompoundExcept = 39,
+    VlanFilter = 41,
+    InspectTunnels = 42,
+    FragmentAction = 43,
+    DropEvents = 44,
+    CheckHeaders = 45,
+    InspectGtp = 46,
+    PassServices = 47,
+    LookupParametersSlot = 48,
 }
 
-// Block Marker should be larger than the size of binary tree size
-// For 24bit record sizes, this'll be packed into 3 bits
-// so either we make it different based on record size
-// or since for this projet, I am only working with 24 bit dbs
-// the value is set to 0x00ffffff
-pub const BLOCK_MARKER: u32 = 0x00ffffff;
+impl ProgramParameters {
+    pub fn from_key(key: u8) -> Option<Self> {
+        match key {
+            7 => Some(ProgramParameters::CountryBuildEpoch),
+            8 => Some(ProgramParameters::AsnBuildEpoch),
+            9 => Some(ProgramParameters::MalformedAction),
+            10 => Some(ProgramParameters::LogIpv4Prefix),
+            11 => Some(ProgramParameters::LogIpv6Prefix),
+            12 => Some(ProgramParameters::FilterNeighborDiscovery),
+            13 => Some(ProgramParameters::FilterDhcp),
+            14 => Some(ProgramParameters::MulticastAction),
+            15 => Some(ProgramParameters::CountryStatsResetAt),
+            16 => Some(ProgramParameters::AsnStatsResetAt),
+            18 => Some(ProgramParameters::Precedence),
+            19 => Some(ProgramParameters::DryRun),
+            20 => Some(ProgramParameters::ScopedPolicies),
+            21 => Some(ProgramParameters::Direction),
+            25 => Some(ProgramParameters::CityBuildEpoch),
+            26 => Some(ProgramParameters::CityStatsResetAt),
+            30 => Some(ProgramParameters::AnonymousBuildEpoch),
+            31 => Some(ProgramParameters::AnonymousStatsResetAt),
+            32 => Some(ProgramParameters::ActivePolicies),
+            33 => Some(ProgramParameters::SynOnly),
+            34 => Some(ProgramParameters::Egress),
+            35 => Some(ProgramParameters::TrackConnections),
+            36 => Some(ProgramParameters::BogonPolicies),
+            37 => Some(ProgramParameters::BogonStatsResetAt),
+            38 => Some(ProgramParameters::FeedStatsResetAt),
+            39 => Some(ProgramParameters::CompoundExcept),
+            41 => Some(ProgramParameters::VlanFilter),
+            42 => Some(ProgramParameters::InspectTunnels),
+            43 => Some(ProgramParameters::FragmentAction),
+            44 => Some(ProgramParameters::DropEvents),
+            45 => Some(ProgramParameters::CheckHeaders),
+            46 => Some(ProgramParameters::InspectGtp),
+            47 => Some(ProgramParameters::PassServices),
+            48 => Some(ProgramParameters::LookupParametersSlot),
+            _ => None,
+        }
+    }
+}
+
+// Markers are written over the records of the processed trees. They are above 1 << 28, so they
+// can't be mistaken for a node or a data pointer of a 24 or 28 bit tree. The processed trees
+// have 32 bit records to hold them
+pub const BLOCK_MARKER: u32 = 0xffffffff;
+
+// Records of suspect sources, these are redirected to userspace for inspection
+pub const SUSPECT_MARKER: u32 = 0xfffffffe;
+
+// Records of sources in allow_countries or allow_asn
+pub const ALLOW_MARKER: u32 = 0xfffffffd;
+
+// Records listed with a specific action. Bits 8-10 hold the action, a CountryAction or
+// POLICY_ACTION for the action of the policy, and the low 8 bits have a bit set for every policy
+// that lists the record. Action 7 is taken by the markers above
+pub const ACTION_MARKER: u32 = 0xfffff800;
+
+pub const POLICY_ACTION: u32 = 6;
+
+// Records listed by some policies but not others, with the action of the policy. Records listed
+// by all of them use BLOCK_MARKER
+pub const POLICY_MARKER: u32 = ACTION_MARKER | POLICY_ACTION << 8;
+
+// Records of countries and ASNs in compound rules. The low 8 bits have a bit set for every
+// compound rule that names the record, the rule matches or not depending on the bits of the
+// other tree. Only the top level rules have compound rules
+pub const COMPOUND_MARKER: u32 = ACTION_MARKER | 3 << 8;
+
+pub const MAX_COMPOUND_RULES: usize = 8;
+
+/// Compound rules whose country or ASN is the tree record the walk ended at
+pub fn compound_rules(record: u32) -> u32 {
+    if record & !0xff == COMPOUND_MARKER {
+        record & 0xff
+    } else {
+        0
+    }
+}
+
+// Policy 0 holds the top level rules, the rest are the named policies in the config. The policy
+// of an interface is stored in INTERFACE_POLICIES, interfaces without one use policy 0
+pub const MAX_POLICIES: u32 = 8;
+
+/// Whether the countries or ASNs of `policy` include the tree record the walk ended at. Listed
+/// sources are dropped, or in allow mode the only ones passed
+pub fn is_listed(record: u32, policy: u32) -> bool {
+    let action = (record >> 8) & 7;
+    record == BLOCK_MARKER
+        || (record & !0x7ff == ACTION_MARKER
+            && (action <= CountryAction::Log as u32 || action == POLICY_ACTION)
+            && record & (1 << policy) != 0)
+}
+
+/// What happens to sources from a country in `country_actions`. Only the top level rules,
+/// policy 0, have country actions
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum CountryAction {
+    /// Dropped even when the top level rules are rate limited
+    Drop = 0,
+    /// Throttled with the top level rate limit
+    RateLimit = 1,
+    /// Counted and passed
+    Log = 2,
+}
+
+impl CountryAction {
+    /// The country action `record` holds for `policy`, if any
+    pub fn of_record(record: u32, policy: u32) -> Option<Self> {
+        if policy != 0 || !is_listed(record, policy) || record == BLOCK_MARKER {
+            return None;
+        }
+
+        match (record >> 8) & 7 {
+            0 => Some(CountryAction::Drop),
+            1 => Some(CountryAction::RateLimit),
+            2 => Some(CountryAction::Log),
+            _ => None,
+        }
+    }
+
+    pub fn marker(self, policies: u32) -> u32 {
+        ACTION_MARKER | (self as u32) << 8 | policies
+    }
+}
+
+// The rules of a policy with ports only apply to TCP and UDP packets for one of them. The
+// ScopedPolicies parameter has a bit set for every such policy and PORT_SCOPES holds its ports
+pub fn port_scope_key(policy: u32, protocol: u8, port: u16) -> u32 {
+    (policy << 24) | ((protocol as u32) << 16) | port as u32
+}
+
+// Traffic that skips the rules like DHCP does. TCP and UDP packets are keyed by their
+// destination port, ICMP and ICMPv6 messages by their type
+pub fn pass_service_key(protocol: u8, value: u16) -> u32 {
+    ((protocol as u32) << 16) | value as u32
+}
+
+/// Token bucket of a policy that throttles the sources its rules match instead of dropping them,
+/// stored in RATE_LIMITS at the index of the policy. Buckets are shared by all the sources in a
+/// prefix, policies with `packets_per_second` 0 drop
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub packets_per_second: u32,
+    pub burst: u32,
+    pub ipv4_prefix: u32,
+    pub ipv6_prefix: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for RateLimit {}
+
+/// Where a tree starts in its BLOCKED_* map. Trees with a record size of 0 haven't been loaded
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct TreeShape {
+    pub node_count: u32,
+    pub record_size: u32,
+    /// Node of ::/96, where IPv4 lookups start
+    pub ipv4_start: u32,
+    /// Entry of node 0. The map has room for two trees, a new tree is written to the half the
+    /// current one isn't in and only then is the shape pointed at it
+    pub base: u32,
+}
+
+/// Everything the lookups of a packet need. LOOKUP_PARAMETERS has two slots, LookupParametersSlot
+/// in PARAMETERS says which one is in use. Updates are written to the other slot and only then
+/// is LookupParametersSlot flipped, so a packet never sees the node count of one tree with the
+/// record size of the tree that replaced it
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct LookupParameters {
+    /// Indexed by MaxmindDbType
+    pub trees: [TreeShape; 4],
+    pub mode: u32,
+    pub backend: u32,
+    /// Incremented on every write, so the eBPF program ignores lookups it cached before
+    pub generation: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for LookupParameters {}
+
+// SUSPECT_SOCKETS holds MAX_QUEUES sockets for each of up to MAX_INTERFACES interfaces, at
+// slot * MAX_QUEUES + rx queue. The slot of an interface is stored in SUSPECT_INTERFACES
+pub const MAX_INTERFACES: u32 = 16;
+pub const MAX_QUEUES: u32 = 64;
+
+// Cgroups policies can list, in CGROUP_POLICIES
+pub const MAX_CGROUPS: u32 = 256;
+
+// Verdicts written by userspace into SUSPECT_VERDICTS, keyed by source address. IPv4 sources
+// use the IPv4 mapped IPv6 address
+pub const SUSPECT_PASS: u8 = 1;
+pub const SUSPECT_DROP: u8 = 2;
+
+// Frames redirected to SUSPECT_SOCKETS carry the SUSPECT_VERDICTS key of their source in the XDP
+// metadata in front of them. It's the source the rules were applied to, after VLAN tags and of
+// the inner packet of tunnels, which userspace can't tell from the frame alone
+pub const SUSPECT_KEY_LEN: usize = 16;
+
+/// A dropped packet, queued in the DROP_EVENTS ring buffer when DropEvents is set. IPv4
+/// addresses are IPv4 mapped
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DropEvent {
+    /// Nanoseconds since boot, including time spent suspended
+    pub timestamp: u64,
+    /// The source, or the destination of packets dropped because of it
+    pub addr: [u8; 16],
+    pub ifindex: u32,
+    /// The Counter the packet was counted in
+    pub reason: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for DropEvent {}
+
+/// Indices into the STATS per-CPU array
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Counter {
+    /// Packets dropped because of their country, including suspect sources with a drop verdict
+    CountryDropped = 0,
+    AsnDropped = 1,
+    /// Packets dropped because of their city or subdivision
+    CityDropped = 2,
+    /// Packets from VPNs, proxies, Tor exit nodes or hosting providers
+    AnonymousDropped = 3,
+    /// Packets from reserved and unallocated networks
+    BogonDropped = 4,
+    /// Packets from networks listed in a threat intelligence feed
+    FeedDropped = 5,
+    /// Packets from countries that are only logged
+    CountryLogged = 6,
+    /// Packets from networks in `block_cidrs` and the block lists
+    BlocklistDropped = 7,
+    /// Packets passed because their source is allowed, even if the rules list it
+    Allowed = 8,
+    /// Packets that were looked up and passed
+    Passed = 9,
+    /// Packets that couldn't be parsed, whatever malformed_action does with them
+    Malformed = 10,
+}
+
+pub const COUNTER_COUNT: u32 = 11;
+
+impl Counter {
+    pub fn from_index(index: u32) -> Option<Self> {
+        match index {
+            0 => Some(Counter::CountryDropped),
+            1 => Some(Counter::AsnDropped),
+            2 => Some(Counter::CityDropped),
+            3 => Some(Counter::AnonymousDropped),
+            4 => Some(Counter::BogonDropped),
+            5 => Some(Counter::FeedDropped),
+            6 => Some(Counter::CountryLogged),
+            7 => Some(Counter::BlocklistDropped),
+            8 => Some(Counter::Allowed),
+            9 => Some(Counter::Passed),
+            10 => Some(Counter::Malformed),
+            _ => None,
+        }
+    }
+
+    /// Whether the counter counts dropped packets
+    pub fn is_drop(&self) -> bool {
+        !matches!(
+            self,
+            Counter::CountryLogged | Counter::Allowed | Counter::Passed | Counter::Malformed
+        )
+    }
+
+    /// Name used in logs and as a metric tag
+    pub fn short_name(&self) -> &'static str {
+        match self {
+            Counter::CountryDropped => "country_dropped",
+            Counter::AsnDropped => "asn_dropped",
+            Counter::CityDropped => "city_dropped",
+            Counter::AnonymousDropped => "anonymous_dropped",
+            Counter::BogonDropped => "bogon_dropped",
+            Counter::FeedDropped => "feed_dropped",
+            Counter::CountryLogged => "country_logged",
+            Counter::BlocklistDropped => "blocklist_dropped",
+            Counter::Allowed => "allowed",
+            Counter::Passed => "passed",
+            Counter::Malformed => "malformed",
+        }
+    }
+}
+
+/// What to do with packets whose headers are truncated or otherwise can't be parsed
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum MalformedAction {
+    Pass = 1,
+    Drop = 2,
+    #[default]
+    Abort = 3,
+}
+
+impl MalformedAction {
+    pub fn from_value(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(MalformedAction::Pass),
+            2 => Some(MalformedAction::Drop),
+            3 => Some(MalformedAction::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with packets sent to a multicast or broadcast address
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum MulticastAction {
+    Pass = 1,
+    Drop = 2,
+    /// Look up the source like for unicast packets
+    #[default]
+    Evaluate = 3,
+}
+
+impl MulticastAction {
+    pub fn from_value(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(MulticastAction::Pass),
+            2 => Some(MulticastAction::Drop),
+            3 => Some(MulticastAction::Evaluate),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with fragments other than the first, which have no L4 header
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum FragmentAction {
+    Pass = 1,
+    Drop = 2,
+    /// Look up the source, without the port scopes of policies
+    #[default]
+    Evaluate = 3,
+}
+
+impl FragmentAction {
+    pub fn from_value(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(FragmentAction::Pass),
+            2 => Some(FragmentAction::Drop),
+            3 => Some(FragmentAction::Evaluate),
+            _ => None,
+        }
+    }
+}
 
-#[derive(Copy, Clone)]
+/// Whether the configured countries and ASNs are blocked or the only ones allowed
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Mode {
+    #[default]
+    Block = 1,
+    /// Drop everything that isn't from one of the countries or ASNs
+    Allow = 2,
+}
+
+impl Mode {
+    pub fn from_value(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Mode::Block),
+            2 => Some(Mode::Allow),
+            _ => None,
+        }
+    }
+}
+
+/// How the XDP program finds the record of a source
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum LookupBackend {
+    /// Walks the MMDB search tree copied into the BLOCKED_* arrays, one array lookup per bit
+    #[default]
+    Tree = 1,
+    /// Looks up the marked networks of the tree, loaded into the *_PREFIXES LPM tries, in a
+    /// single longest prefix match
+    Lpm = 2,
+}
+
+impl LookupBackend {
+    pub fn from_value(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(LookupBackend::Tree),
+            2 => Some(LookupBackend::Lpm),
+            _ => None,
+        }
+    }
+}
+
+/// Which list wins for sources that are both allowed and blocked
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Precedence {
+    #[default]
+    Allow = 1,
+    Block = 2,
+}
+
+impl Precedence {
+    pub fn from_value(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Precedence::Allow),
+            2 => Some(Precedence::Block),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to packets the rules of a policy match, stored in POLICY_ACTIONS at the index of
+/// the policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Action {
+    #[default]
+    Drop = 1,
+    /// Answer with a TCP reset or an ICMP administratively prohibited error, so clients fail
+    /// fast instead of retrying until they time out
+    Reject = 2,
+    /// Send the packet out of another interface, like a honeypot or an analysis tap
+    Redirect = 3,
+}
+
+impl Action {
+    pub fn from_value(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Action::Drop),
+            2 => Some(Action::Reject),
+            3 => Some(Action::Redirect),
+            _ => None,
+        }
+    }
+}
+
+/// Which addresses of packets arriving on an interface are looked up
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Direction {
+    #[default]
+    Src = 1,
+    /// The destination, for routers forwarding traffic from their clients
+    Dst = 2,
+    Both = 3,
+}
+
+impl Direction {
+    pub fn from_value(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Direction::Src),
+            2 => Some(Direction::Dst),
+            3 => Some(Direction::Both),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum MaxmindDbType {
     Country,
     Asn,
+    /// Only downloaded and loaded when some rule lists cities or subdivisions
+    City,
+    /// GeoIP2-Anonymous-IP, only downloaded and loaded when some of its flags are blocked
+    Anonymous,
+}
+
+impl MaxmindDbType {
+    /// Name used in the config file and as a metric tag
+    pub fn short_name(&self) -> &'static str {
+        match self {
+            MaxmindDbType::Country => "country",
+            MaxmindDbType::Asn => "asn",
+            MaxmindDbType::City => "city",
+            MaxmindDbType::Anonymous => "anonymous",
+        }
+    }
 }
 
 impl Display for MaxmindDbType {
@@ -27,10 +574,56 @@ impl Display for MaxmindDbType {
         let val = match self {
             MaxmindDbType::Country => "GeoLite2-Country",
             MaxmindDbType::Asn => "GeoLite2-ASN",
+            MaxmindDbType::City => "GeoLite2-City",
+            MaxmindDbType::Anonymous => "GeoIP2-Anonymous-IP",
         };
 
         write!(f, "{val}")
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn listed_records() {
+        assert!(is_listed(BLOCK_MARKER, 0));
+        assert!(is_listed(BLOCK_MARKER, 5));
+        assert!(is_listed(POLICY_MARKER | 0b100, 2));
+        assert!(!is_listed(POLICY_MARKER | 0b100, 0));
+
+        for record in [SUSPECT_MARKER, ALLOW_MARKER, COMPOUND_MARKER | 0xff] {
+            assert!(!is_listed(record, 0), "{:#x}", record);
+        }
+        // Nodes and data pointers of 24 and 28 bit trees, including the old marker range
+        for record in [0, 1000, 0x00fff800, 0x00fffffd, 0x00ffffff, (1 << 28) - 1] {
+            assert!(!is_listed(record, 0), "{:#x}", record);
+        }
+    }
+
+    #[test]
+    fn country_actions_round_trip() {
+        for action in [
+            CountryAction::Drop,
+            CountryAction::RateLimit,
+            CountryAction::Log,
+        ] {
+            let record = action.marker(0b11);
+            assert!(is_listed(record, 0));
+            assert!(is_listed(record, 1));
+            assert_eq!(CountryAction::of_record(record, 0), Some(action));
+            // Only the top level rules have country actions
+            assert_eq!(CountryAction::of_record(record, 1), None);
+        }
+
+        assert_eq!(
+            CountryAction::of_record(CountryAction::Log.marker(0b10), 0),
+            None
+        );
+        assert_eq!(CountryAction::of_record(BLOCK_MARKER, 0), None);
+        assert_eq!(CountryAction::of_record(POLICY_MARKER | 1, 0), None);
+        assert_eq!(CountryAction::of_record(ALLOW_MARKER, 0), None);
+        assert_eq!(CountryAction::of_record(0x00ffffff, 0), None);
+    }
+}
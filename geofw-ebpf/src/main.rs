@@ -1,30 +1,303 @@
 #![no_std]
 #![no_main]
 
+mod reject;
+
 use aya_ebpf::{
-    bindings::xdp_action,
-    macros::{map, xdp},
-    maps::{Array, HashMap},
-    programs::XdpContext,
+    bindings::{xdp_action, TC_ACT_PIPE, TC_ACT_SHOT},
+    helpers::{
+        bpf_ktime_get_boot_ns, bpf_ktime_get_ns, bpf_skb_ancestor_cgroup_id, bpf_xdp_adjust_meta,
+    },
+    macros::{cgroup_skb, classifier, map, xdp},
+    maps::{
+        lpm_trie::Key, Array, DevMap, HashMap, LpmTrie, LruHashMap, LruPerCpuHashMap, PerCpuArray,
+        ProgramArray, RingBuf, XskMap,
+    },
+    programs::{SkBuffContext, TcContext, XdpContext},
+    EbpfContext,
 };
 use aya_log_ebpf::{debug, warn};
-use core::{mem, net::IpAddr};
-use geofw_common::{MaxmindDbType, ProgramParameters, BLOCK_MARKER};
+use core::{
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+use geofw_common::{
+    compound_rules, is_listed, pass_service_key, port_scope_key, Action, Counter, CountryAction,
+    Direction, DropEvent, FragmentAction, LookupBackend, LookupParameters, MalformedAction,
+    MaxmindDbType, Mode, MulticastAction, Precedence, ProgramParameters, RateLimit, TreeShape,
+    ALLOW_MARKER, BLOCK_MARKER, COUNTER_COUNT, MAX_CGROUPS, MAX_INTERFACES, MAX_POLICIES,
+    MAX_QUEUES, SUSPECT_KEY_LEN, SUSPECT_MARKER, SUSPECT_PASS,
+};
 use network_types::{
-    eth::{EthHdr, EtherType},
-    ip::{Ipv4Hdr, Ipv6Hdr},
+    eth::EthHdr,
+    ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
+    tcp::TcpHdr,
+    udp::UdpHdr,
 };
 
 #[xdp]
 pub fn geofw(ctx: XdpContext) -> u32 {
-    match try_geofw(ctx) {
+    let action = match try_geofw(&ctx, None) {
+        Ok(ret) => ret,
+        Err(_) => {
+            count(Counter::Malformed);
+            malformed_action()
+        }
+    };
+
+    // In a dry run packets are still evaluated and counted, but never dropped
+    let action =
+        if is_dry_run() && (action == xdp_action::XDP_DROP || action == xdp_action::XDP_ABORTED) {
+            xdp_action::XDP_PASS
+        } else {
+            action
+        };
+
+    if action == xdp_action::XDP_PASS {
+        // Doesn't return when NEXT_PROGRAM has a program, the packet is passed otherwise
+        let _ = unsafe { NEXT_PROGRAM.tail_call(&ctx, 0) };
+    }
+
+    action
+}
+
+/// Headers pulled into the linear part of the skb before the tc fallback reads them
+const PULL_LEN: u32 = 256;
+
+/// The XDP program as a tc ingress classifier, for drivers and kernels that can't run XDP.
+/// Rejects and redirects are dropped, and suspect sources userspace has no verdict for yet are
+/// passed, since both need the XDP hook
+#[classifier]
+pub fn geofw_ingress(ctx: TcContext) -> i32 {
+    let _ = ctx.pull_data(ctx.len().min(PULL_LEN));
+
+    let skb = unsafe { &*ctx.skb.skb };
+    let offloaded = (skb.vlan_present != 0).then_some(skb.vlan_tci as u16);
+    let action = match try_geofw(&ctx, offloaded) {
         Ok(ret) => ret,
-        Err(_) => xdp_action::XDP_ABORTED,
+        Err(_) => {
+            count(Counter::Malformed);
+            malformed_action()
+        }
+    };
+
+    match action {
+        xdp_action::XDP_DROP | xdp_action::XDP_ABORTED if !is_dry_run() => TC_ACT_SHOT,
+        _ => TC_ACT_PIPE,
+    }
+}
+
+/// Filters traffic leaving the interface by its destination. Packets that can't be parsed and
+/// suspect destinations are let through, the host's own traffic is never inspected
+#[classifier]
+pub fn geofw_egress(ctx: TcContext) -> i32 {
+    let action = try_geofw_egress(&ctx).unwrap_or(TC_ACT_PIPE);
+
+    if is_dry_run() && action == TC_ACT_SHOT {
+        return TC_ACT_PIPE;
+    }
+
+    action
+}
+
+/// Filters traffic for the sockets of the cgroups listed in policies by its source, with the
+/// policy of the deepest listed cgroup they are in. Suspect sources are passed, and rejects and
+/// redirects are dropped
+#[cgroup_skb(ingress)]
+pub fn geofw_cgroup(ctx: SkBuffContext) -> i32 {
+    let pass = try_geofw_cgroup(&ctx).unwrap_or(true);
+
+    (pass || is_dry_run()) as i32
+}
+
+/// The AF_XDP datapath, for systems where the filter can't run in the kernel. Unicast IP packets
+/// are redirected to the socket geofw bound to their rx queue, which applies the rules and
+/// re-injects the passed ones through a TAP device. Frames whose replies must leave through the
+/// interface they arrived on, like ARP, neighbor discovery and DHCP, are passed along with group
+/// and VLAN tagged frames
+#[xdp]
+pub fn geofw_xsk(ctx: XdpContext) -> u32 {
+    if !is_redirected(&ctx).unwrap_or(false) {
+        return xdp_action::XDP_PASS;
+    }
+
+    let ifindex = unsafe { (*ctx.ctx).ingress_ifindex };
+    let queue = unsafe { (*ctx.ctx).rx_queue_index };
+    let Some(&slot) = (unsafe { DATAPATH_INTERFACES.get(&ifindex) }) else {
+        return xdp_action::XDP_PASS;
+    };
+    if queue >= MAX_QUEUES {
+        return xdp_action::XDP_PASS;
+    }
+
+    DATAPATH_SOCKETS
+        .redirect(slot * MAX_QUEUES + queue, xdp_action::XDP_PASS as u64)
+        .unwrap_or(xdp_action::XDP_PASS)
+}
+
+/// Whether the AF_XDP datapath hands the frame to userspace
+fn is_redirected(ctx: &XdpContext) -> Result<bool, ()> {
+    if is_group_frame(ctx)? {
+        return Ok(false);
+    }
+    let ether_type = u16::from_be(unsafe { *ptr_at::<u16>(ctx, 12).ok_or(())? });
+
+    match ether_type {
+        ETH_P_IP => {
+            let ip: *const Ipv4Hdr = ptr_at(ctx, EthHdr::LEN).ok_or(())?;
+            let offset = EthHdr::LEN + unsafe { (*ip).ihl() } as usize * 4;
+            if unsafe { (*ip).proto } == IpProto::Udp {
+                return Ok(!is_dhcp_port(ctx, offset, 68, 67));
+            }
+            Ok(true)
+        }
+        ETH_P_IPV6 => {
+            let ip: *const Ipv6Hdr = ptr_at(ctx, EthHdr::LEN).ok_or(())?;
+            let offset = EthHdr::LEN + Ipv6Hdr::LEN;
+            match unsafe { (*ip).next_hdr } {
+                IpProto::Ipv6Icmp => {
+                    let icmp_type: *const u8 = ptr_at(ctx, offset).ok_or(())?;
+                    Ok(!matches!(unsafe { *icmp_type }, 130..=137 | 143))
+                }
+                IpProto::Udp => Ok(!is_dhcp_port(ctx, offset, 546, 547)),
+                _ => Ok(true),
+            }
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Whether the UDP header at `offset` is between the DHCP `client` and `server` ports, whether
+/// or not FilterDhcp is set
+fn is_dhcp_port<C: Packet>(ctx: &C, offset: usize, client: u16, server: u16) -> bool {
+    let Some(udp) = ptr_at::<UdpHdr>(ctx, offset) else {
+        return false;
+    };
+    let source = u16::from_be(unsafe { (*udp).source });
+    let dest = u16::from_be(unsafe { (*udp).dest });
+
+    (source == client || source == server) && (dest == client || dest == server)
+}
+
+fn is_dry_run() -> bool {
+    is_param_set(ProgramParameters::DryRun)
+}
+
+/// Slot of LOOKUP_PARAMETERS in use, read once per packet
+fn lookup_parameters_slot() -> u32 {
+    unsafe { PARAMETERS.get(&(ProgramParameters::LookupParametersSlot as u8)) }
+        .map_or(0, |&v| v & 1)
+}
+
+fn is_param_set(param: ProgramParameters) -> bool {
+    unsafe { PARAMETERS.get(&(param as u8)) }.is_some_and(|&v| v != 0)
+}
+
+/// Verdict for packets that could not be parsed, as configured by userspace
+fn malformed_action() -> u32 {
+    let action = unsafe { PARAMETERS.get(&(ProgramParameters::MalformedAction as u8)) }
+        .and_then(|&v| MalformedAction::from_value(v))
+        .unwrap_or_default();
+
+    match action {
+        MalformedAction::Pass => xdp_action::XDP_PASS,
+        MalformedAction::Drop => xdp_action::XDP_DROP,
+        MalformedAction::Abort => xdp_action::XDP_ABORTED,
+    }
+}
+
+/// What the XDP program and its tc fallback need from their context. Verdicts are XDP actions
+/// in both
+pub(crate) trait Packet: EbpfContext {
+    fn data(&self) -> usize;
+    fn data_end(&self) -> usize;
+    /// Length of the whole packet, which can be more than the directly accessible data
+    fn len(&self) -> usize;
+    /// Interface the packet arrived on
+    fn ifindex(&self) -> u32;
+    /// Applies a reject, redirect or suspect verdict for a packet from `addr`
+    fn divert(&self, addr: IpAddr, verdict: Verdict) -> u32;
+}
+
+impl Packet for XdpContext {
+    fn data(&self) -> usize {
+        XdpContext::data(self)
+    }
+
+    fn data_end(&self) -> usize {
+        XdpContext::data_end(self)
+    }
+
+    fn len(&self) -> usize {
+        XdpContext::data_end(self) - XdpContext::data(self)
+    }
+
+    fn ifindex(&self) -> u32 {
+        unsafe { (*self.ctx).ingress_ifindex }
+    }
+
+    fn divert(&self, addr: IpAddr, verdict: Verdict) -> u32 {
+        match verdict {
+            Verdict::Pass => xdp_action::XDP_PASS,
+            Verdict::Drop => xdp_action::XDP_DROP,
+            // A dry run must not rewrite or redirect the packet, the drop is turned into a pass
+            Verdict::Reject | Verdict::Redirect(_) if is_dry_run() => xdp_action::XDP_DROP,
+            Verdict::Reject => reject::reject(self, addr),
+            // Policies whose interface is gone fall back to dropping
+            Verdict::Redirect(policy) => REDIRECT_TARGETS
+                .redirect(policy, xdp_action::XDP_DROP as u64)
+                .unwrap_or(xdp_action::XDP_DROP),
+            Verdict::Suspect => {
+                // A dry run must not hand the packet to userspace either, sources without a
+                // verdict yet are passed
+                let action = if is_dry_run() {
+                    suspect_verdict(key_of(addr)).unwrap_or(xdp_action::XDP_PASS)
+                } else {
+                    inspect_suspect(self, key_of(addr))
+                };
+                if action == xdp_action::XDP_DROP {
+                    count(Counter::CountryDropped);
+                }
+                action
+            }
+        }
+    }
+}
+
+impl Packet for TcContext {
+    fn data(&self) -> usize {
+        TcContext::data(self)
+    }
+
+    fn data_end(&self) -> usize {
+        TcContext::data_end(self)
+    }
+
+    fn len(&self) -> usize {
+        TcContext::len(self) as usize
+    }
+
+    fn ifindex(&self) -> u32 {
+        unsafe { (*self.skb.skb).ifindex }
+    }
+
+    fn divert(&self, addr: IpAddr, verdict: Verdict) -> u32 {
+        match verdict {
+            Verdict::Pass => xdp_action::XDP_PASS,
+            Verdict::Drop | Verdict::Reject | Verdict::Redirect(_) => xdp_action::XDP_DROP,
+            Verdict::Suspect => match suspect_verdict(key_of(addr)) {
+                Some(xdp_action::XDP_DROP) => {
+                    count(Counter::CountryDropped);
+                    xdp_action::XDP_DROP
+                }
+                _ => xdp_action::XDP_PASS,
+            },
+        }
     }
 }
 
 #[inline(always)]
-fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Option<*const T> {
+pub(crate) fn ptr_at<T>(ctx: &impl Packet, offset: usize) -> Option<*const T> {
     let start = ctx.data();
     let end = ctx.data_end();
     let len = mem::size_of::<T>();
@@ -36,144 +309,1346 @@ fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Option<*const T> {
     Some((start + offset) as *const T)
 }
 
+// The tree maps hold one node per entry, the left record in the upper 32 bits and the right
+// record in the lower 32 bits, so every level of the walk is a single lookup. Each has room for
+// two trees, the one in use starts at TreeShape::base and updates are written to the other half
+#[map]
+static BLOCKED_ASN: Array<u64> = Array::with_max_entries(1024 * 1024 * 8, 0); // 64MiB
+
+#[map]
+static BLOCKED_COUNTRY: Array<u64> = Array::with_max_entries(1024 * 1024 * 16, 0); // 128MiB
+
+// Userspace resizes this when some rule lists cities or subdivisions, so the map doesn't take
+// up memory otherwise
+#[map]
+static BLOCKED_CITY: Array<u64> = Array::with_max_entries(1, 0);
+
+// Resized like BLOCKED_CITY when some anonymous IP flags are blocked. Every flagged record is
+// marked with BLOCK_MARKER
 #[map]
-static BLOCKED_ASN: Array<u8> = Array::with_max_entries(1024 * 1024 * 20, 0); // 10MiB
+static BLOCKED_ANONYMOUS: Array<u64> = Array::with_max_entries(1, 0);
 
+// The marked networks of each tree with their records, used instead of the BLOCKED_* arrays
+// with the lpm lookup backend. Userspace shrinks the arrays to a single entry in that case
 #[map]
-static BLOCKED_COUNTRY: Array<u8> = Array::with_max_entries(1024 * 1024 * 50, 0);
+static COUNTRY_PREFIXES: LpmTrie<[u8; 16], u32> = LpmTrie::with_max_entries(1024 * 1024, 0);
+
+#[map]
+static ASN_PREFIXES: LpmTrie<[u8; 16], u32> = LpmTrie::with_max_entries(1024 * 1024, 0);
+
+#[map]
+static CITY_PREFIXES: LpmTrie<[u8; 16], u32> = LpmTrie::with_max_entries(1024 * 1024, 0);
+
+#[map]
+static ANONYMOUS_PREFIXES: LpmTrie<[u8; 16], u32> = LpmTrie::with_max_entries(1024 * 1024, 0);
 
 #[map]
 static PARAMETERS: HashMap<u8, u32> = HashMap::with_max_entries(1024, 0);
 
-fn try_geofw(ctx: XdpContext) -> Result<u32, u32> {
-    let eth: *const EthHdr = ptr_at(&ctx, 0).ok_or(xdp_action::XDP_PASS)?;
+// The trees, mode and lookup backend. Userspace writes the slot not in use and then points
+// LookupParametersSlot at it
+#[map]
+static LOOKUP_PARAMETERS: Array<LookupParameters> = Array::with_max_entries(2, 0);
+
+// VLAN IDs the rules apply to when VlanFilter is set, frames on other VLANs and untagged frames
+// are passed
+#[map]
+static VLANS: HashMap<u16, u8> = HashMap::with_max_entries(4096, 0);
+
+// UDP destination ports of VXLAN tunnels, decapsulated when InspectTunnels is set
+#[map]
+static VXLAN_PORTS: HashMap<u16, u8> = HashMap::with_max_entries(64, 0);
+
+// Networks from the block lists. IPv4 networks are stored as IPv4 mapped IPv6 networks
+#[map]
+static BLOCKED_CIDRS: LpmTrie<[u8; 16], u8> = LpmTrie::with_max_entries(1024 * 1024, 0);
+
+// Networks from the threat intelligence feeds, kept apart from the block lists so their drops
+// are counted
+#[map]
+static FEED_CIDRS: LpmTrie<[u8; 16], u8> = LpmTrie::with_max_entries(256 * 1024, 0);
+
+// Networks that are never blocked, checked before every other rule
+#[map]
+static ALLOWED_CIDRS: LpmTrie<[u8; 16], u8> = LpmTrie::with_max_entries(64 * 1024, 0);
+
+// Reserved and unallocated networks, dropped by the policies set in BogonPolicies
+#[map]
+static BOGONS: LpmTrie<[u8; 16], u8> = LpmTrie::with_max_entries(256, 0);
+
+// AF_XDP sockets of the daemon, indexed by interface slot * MAX_QUEUES + rx queue
+#[map]
+static SUSPECT_SOCKETS: XskMap = XskMap::with_max_entries(MAX_INTERFACES * MAX_QUEUES, 0);
+
+// Slot of each interface in SUSPECT_SOCKETS, keyed by ifindex
+#[map]
+static SUSPECT_INTERFACES: HashMap<u32, u32> = HashMap::with_max_entries(MAX_INTERFACES, 0);
 
-    match unsafe { (*eth).ether_type } {
-        EtherType::Ipv4 => filter_ip_packet(ctx),
-        EtherType::Ipv6 => filter_ipv6_packet(ctx),
+// AF_XDP sockets of the AF_XDP datapath, laid out like SUSPECT_SOCKETS
+#[map]
+static DATAPATH_SOCKETS: XskMap = XskMap::with_max_entries(MAX_INTERFACES * MAX_QUEUES, 0);
+
+// Slot of each interface in DATAPATH_SOCKETS, keyed by ifindex
+#[map]
+static DATAPATH_INTERFACES: HashMap<u32, u32> = HashMap::with_max_entries(MAX_INTERFACES, 0);
+
+// Policy of each interface the program is attached to, keyed by ifindex
+#[map]
+static INTERFACE_POLICIES: HashMap<u32, u32> = HashMap::with_max_entries(MAX_INTERFACES, 0);
+
+// Policy of each cgroup listed in policies, keyed by cgroup ID
+#[map]
+static CGROUP_POLICIES: HashMap<u64, u32> = HashMap::with_max_entries(MAX_CGROUPS, 0);
+
+// Ports the rules of scoped policies apply to, keyed by port_scope_key
+#[map]
+static PORT_SCOPES: HashMap<u32, u8> = HashMap::with_max_entries(4096, 0);
+
+// Services from `pass_services` that are passed without being looked up, keyed by
+// pass_service_key
+#[map]
+static PASS_SERVICES: HashMap<u32, u8> = HashMap::with_max_entries(256, 0);
+
+// Rate limits of the policies that throttle instead of dropping, indexed by policy
+#[map]
+static RATE_LIMITS: Array<RateLimit> = Array::with_max_entries(MAX_POLICIES, 0);
+
+// Action of each policy, indexed by policy. Unset entries drop
+#[map]
+static POLICY_ACTIONS: Array<u32> = Array::with_max_entries(MAX_POLICIES, 0);
+
+// Interface the packets of redirecting policies are sent to, indexed by policy
+#[map]
+static REDIRECT_TARGETS: DevMap = DevMap::with_max_entries(MAX_POLICIES, 0);
+
+// Token buckets of rate limited sources, keyed by their prefix
+#[map]
+static RATE_BUCKETS: LruHashMap<[u8; 16], Bucket> = LruHashMap::with_max_entries(65536, 0);
+
+// Records of recently seen sources in every tree, so repeated sources skip the lookups
+#[map]
+static LOOKUP_CACHE: LruPerCpuHashMap<[u8; 16], CachedLookup> =
+    LruPerCpuHashMap::with_max_entries(65536, 0);
+
+// Verdicts for suspect sources that have already been inspected
+#[map]
+static SUSPECT_VERDICTS: LruHashMap<[u8; 16], u8> = LruHashMap::with_max_entries(65536, 0);
+
+// Connections the host opened with the time of their last outgoing packet, written by the
+// egress program
+#[map]
+static CONNECTIONS: LruHashMap<Connection, u64> = LruHashMap::with_max_entries(262144, 0);
+
+// Packet counters, indexed by Counter
+#[map]
+static STATS: PerCpuArray<u64> = PerCpuArray::with_max_entries(COUNTER_COUNT, 0);
+
+// The counter the packet being evaluated on this CPU was last counted in, the reason of its
+// drop event
+#[map]
+static LAST_COUNTER: PerCpuArray<u32> = PerCpuArray::with_max_entries(1, 0);
+
+// The XDP program packets geofw passes are handed to, so geofw can run in front of another
+// program on the same interface
+#[map]
+static NEXT_PROGRAM: ProgramArray = ProgramArray::with_max_entries(1, 0);
+
+// Drops reported to userspace when DropEvents is set. Events are lost while it's full
+#[map]
+static DROP_EVENTS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+/// `offloaded` is the ID of a VLAN tag the NIC already removed from the frame
+fn try_geofw<C: Packet>(ctx: &C, offloaded: Option<u16>) -> Result<u32, ()> {
+    let (ether_type, offset, filtered) = l3_header(
+        |offset| ptr_at::<u16>(ctx, offset).map(|v| unsafe { *v }),
+        offloaded,
+    )
+    .ok_or(())?;
+    if !filtered {
+        return Ok(xdp_action::XDP_PASS);
+    }
+    let (ether_type, offset) = decapsulate(ctx, ether_type, offset).ok_or(())?;
+
+    match ether_type {
+        ETH_P_IP => filter_ip_packet(ctx, offset),
+        ETH_P_IPV6 => filter_ipv6_packet(ctx, offset),
 
         _ => Ok(xdp_action::XDP_PASS),
     }
 }
 
-fn filter_ip_packet(ctx: XdpContext) -> Result<u32, u32> {
-    let ip: *const Ipv4Hdr = ptr_at(&ctx, EthHdr::LEN).ok_or(xdp_action::XDP_PASS)?;
-    let source = unsafe { (*ip).src_addr() };
+pub(crate) const ETH_P_IP: u16 = 0x0800;
+pub(crate) const ETH_P_IPV6: u16 = 0x86dd;
+// 802.1Q VLAN tags and the outer 802.1ad tags of QinQ frames
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_8021AD: u16 = 0x88a8;
+const VLAN_HDR_LEN: usize = 4;
+
+/// EtherType and offset of the L3 header after up to two VLAN tags, and whether the rules
+/// apply to the VLANs of the frame. `load` reads a u16 in network order at an offset of the
+/// frame, `offloaded` is the ID of a tag the NIC already removed from it
+fn l3_header(
+    load: impl Fn(usize) -> Option<u16>,
+    offloaded: Option<u16>,
+) -> Option<(u16, usize, bool)> {
+    let is_filtered = |id: u16| unsafe { VLANS.get(&(id & 0x0fff)) }.is_some();
+
+    let mut filtered =
+        !is_param_set(ProgramParameters::VlanFilter) || offloaded.is_some_and(is_filtered);
+    let mut ether_type = u16::from_be(load(12)?);
+    let mut offset = EthHdr::LEN;
+    for _ in 0..2 {
+        if ether_type != ETH_P_8021Q && ether_type != ETH_P_8021AD {
+            break;
+        }
+        filtered |= is_filtered(u16::from_be(load(offset)?));
+        ether_type = u16::from_be(load(offset + 2)?);
+        offset += VLAN_HDR_LEN;
+    }
+
+    Some((ether_type, offset, filtered))
+}
+
+const IPPROTO_IPIP: u8 = 4;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_IPV6: u8 = 41;
+const IPPROTO_GRE: u8 = 47;
+// Ethernet frames inside GRE
+const ETH_P_TEB: u16 = 0x6558;
+const VXLAN_HDR_LEN: usize = 8;
+
+/// With InspectTunnels set, the EtherType and offset of the packet inside a GRE, VXLAN, IPIP or
+/// 6in4 tunnel, and with InspectGtp set inside a GTP-U tunnel, so the rules apply to its
+/// addresses instead of the tunnel endpoints. Other packets are returned as they are
+fn decapsulate<C: Packet>(ctx: &C, ether_type: u16, offset: usize) -> Option<(u16, usize)> {
+    let tunnels = is_param_set(ProgramParameters::InspectTunnels);
+    let gtp = is_param_set(ProgramParameters::InspectGtp);
+    if !tunnels && !gtp {
+        return Some((ether_type, offset));
+    }
+
+    let (proto, l4) = match ether_type {
+        ETH_P_IP => {
+            let ip: *const Ipv4Hdr = ptr_at(ctx, offset)?;
+            if !is_first_fragment(unsafe { (*ip).frag_off }) {
+                return Some((ether_type, offset));
+            }
+            (
+                unsafe { (*ip).proto } as u8,
+                offset + unsafe { (*ip).ihl() } as usize * 4,
+            )
+        }
+        ETH_P_IPV6 => {
+            let ip: *const Ipv6Hdr = ptr_at(ctx, offset)?;
+            (unsafe { (*ip).next_hdr } as u8, offset + Ipv6Hdr::LEN)
+        }
+        _ => return Some((ether_type, offset)),
+    };
+
+    match proto {
+        IPPROTO_UDP => {
+            let ports: *const [u16; 2] = ptr_at(ctx, l4)?;
+            let port = u16::from_be(unsafe { (*ports)[1] });
+            if gtp && port == GTPU_PORT {
+                return gtp_inner(ctx, l4 + UdpHdr::LEN).or(Some((ether_type, offset)));
+            }
+            if !tunnels || unsafe { VXLAN_PORTS.get(&port) }.is_none() {
+                return Some((ether_type, offset));
+            }
+
+            inner_ethernet(ctx, l4 + UdpHdr::LEN + VXLAN_HDR_LEN)
+        }
+        _ if !tunnels => Some((ether_type, offset)),
+        IPPROTO_IPIP => Some((ETH_P_IP, l4)),
+        IPPROTO_IPV6 => Some((ETH_P_IPV6, l4)),
+        IPPROTO_GRE => {
+            let gre: *const [u16; 2] = ptr_at(ctx, l4)?;
+            let flags = u16::from_be(unsafe { (*gre)[0] });
+            let protocol = u16::from_be(unsafe { (*gre)[1] });
+            // Version 1 is the enhanced GRE of PPTP, which carries PPP
+            if flags & 0b111 != 0 {
+                return Some((ether_type, offset));
+            }
+
+            // The checksum, key and sequence number follow the header when their bits are set
+            let options = (flags >> 15 & 1) + (flags >> 13 & 1) + (flags >> 12 & 1);
+            let inner = l4 + 4 + options as usize * 4;
+            if protocol == ETH_P_TEB {
+                inner_ethernet(ctx, inner)
+            } else {
+                Some((protocol, inner))
+            }
+        }
+        _ => Some((ether_type, offset)),
+    }
+}
+
+const GTPU_PORT: u16 = 2152;
+const GTPU_HDR_LEN: usize = 8;
+// Message type of the G-PDUs that carry subscriber packets
+const GTPU_G_PDU: u8 = 0xff;
+// Extension headers walked before giving up on a GTP-U packet
+const MAX_GTPU_EXT_HEADERS: usize = 4;
+
+/// EtherType and offset of the IP packet in the GTP-U G-PDU at `offset`, whatever its TEID.
+/// None for signalling messages and for headers that aren't GTPv1-U
+fn gtp_inner<C: Packet>(ctx: &C, offset: usize) -> Option<(u16, usize)> {
+    let header: *const [u8; 2] = ptr_at(ctx, offset)?;
+    let flags = unsafe { (*header)[0] };
+    // Version 1 with the protocol type bit set
+    if flags & 0xf0 != 0x30 || unsafe { (*header)[1] } != GTPU_G_PDU {
+        return None;
+    }
+
+    let mut inner = offset + GTPU_HDR_LEN;
+    // The sequence number, N-PDU number and next extension header type follow the header when
+    // any of their bits are set
+    if flags & 0b111 != 0 {
+        let next: *const u8 = ptr_at(ctx, inner + 3)?;
+        let mut next = unsafe { *next };
+        inner += 4;
+        // Extension header types with the bit set, the length is in 4 octet units and their
+        // last octet is the type of the next one
+        if flags & 0b100 != 0 {
+            for _ in 0..MAX_GTPU_EXT_HEADERS {
+                if next == 0 {
+                    break;
+                }
+                let len: *const u8 = ptr_at(ctx, inner)?;
+                let len = unsafe { *len } as usize * 4;
+                if len == 0 {
+                    return None;
+                }
+                let following: *const u8 = ptr_at(ctx, inner + len - 1)?;
+                next = unsafe { *following };
+                inner += len;
+            }
+            if next != 0 {
+                return None;
+            }
+        }
+    }
+
+    let version: *const u8 = ptr_at(ctx, inner)?;
+    match unsafe { *version } >> 4 {
+        4 => Some((ETH_P_IP, inner)),
+        6 => Some((ETH_P_IPV6, inner)),
+        _ => None,
+    }
+}
+
+/// EtherType and offset of the L3 header of the Ethernet frame at `offset`
+fn inner_ethernet<C: Packet>(ctx: &C, offset: usize) -> Option<(u16, usize)> {
+    let ether_type: *const u16 = ptr_at(ctx, offset + 12)?;
+    Some((u16::from_be(unsafe { *ether_type }), offset + EthHdr::LEN))
+}
+
+fn try_geofw_egress(ctx: &TcContext) -> Result<i32, ()> {
+    let eth: EthHdr = ctx.load(0).map_err(|_| ())?;
+    if eth.dst_addr[0] & 1 == 1 {
+        return Ok(TC_ACT_PIPE);
+    }
+
+    let skb = unsafe { &*ctx.skb.skb };
+    let offloaded = (skb.vlan_present != 0).then_some(skb.vlan_tci as u16);
+    let (ether_type, l3, filtered) =
+        l3_header(|offset| ctx.load::<u16>(offset).ok(), offloaded).ok_or(())?;
+    if !filtered {
+        return Ok(TC_ACT_PIPE);
+    }
+
+    let (destination, l4) = match ether_type {
+        ETH_P_IP => {
+            let ip: Ipv4Hdr = ctx.load(l3).map_err(|_| ())?;
+            let destination = ip.dst_addr();
+            if destination.is_multicast() || destination.is_broadcast() {
+                return Ok(TC_ACT_PIPE);
+            }
 
-    let result = should_block(&ctx, MaxmindDbType::Asn, &BLOCKED_ASN, IpAddr::V4(source))
-        || should_block(
-            &ctx,
-            MaxmindDbType::Country,
-            &BLOCKED_COUNTRY,
-            IpAddr::V4(source),
-        );
+            let offset = l3 + ip.ihl() as usize * 4;
+            let l4 = is_first_fragment(ip.frag_off).then_some((ip.proto, offset));
+            (IpAddr::V4(destination), l4)
+        }
+        ETH_P_IPV6 => {
+            let ip: Ipv6Hdr = ctx.load(l3).map_err(|_| ())?;
+            let destination = ip.dst_addr();
+            if destination.is_multicast() {
+                return Ok(TC_ACT_PIPE);
+            }
+
+            (
+                IpAddr::V6(destination),
+                Some((ip.next_hdr, l3 + Ipv6Hdr::LEN)),
+            )
+        }
+
+        _ => return Ok(TC_ACT_PIPE),
+    };
+
+    let ports = l4.and_then(|(proto, offset)| egress_ports(ctx, proto, offset));
+    let service = ports.map(|(proto, _, port)| (proto as u8, u16::from_be(port)));
+
+    let ifindex = skb.ifindex;
+    if is_param_set(ProgramParameters::Egress) {
+        clear_last_counter();
+        // Only packets arriving from a source can be answered or redirected
+        if let Verdict::Drop | Verdict::Reject | Verdict::Redirect(_) =
+            evaluate(ctx, destination, policy_of(ifindex), service)
+        {
+            report_drop(destination, ifindex);
+            return Ok(TC_ACT_SHOT);
+        }
+    }
+
+    if let Some((proto, source_port, port)) = ports {
+        if is_param_set(ProgramParameters::TrackConnections) {
+            track_connection(Connection {
+                remote: key_of(destination),
+                remote_port: port,
+                local_port: source_port,
+                proto: proto as u8,
+                _pad: [0; 3],
+            });
+        }
+    }
+
+    Ok(TC_ACT_PIPE)
+}
 
-    if result {
-        debug!(&ctx, "ipv4 source = {} blocked = {}", source, result as u8);
+/// Deepest cgroups that are checked for a policy, counting from the root
+const MAX_CGROUP_DEPTH: i32 = 16;
 
-        Ok(xdp_action::XDP_DROP)
+/// Policy of the deepest cgroup in CGROUP_POLICIES the socket of the packet is in
+fn cgroup_policy(ctx: &SkBuffContext) -> Option<u32> {
+    let mut policy = None;
+    for level in 1..=MAX_CGROUP_DEPTH {
+        let id = unsafe { bpf_skb_ancestor_cgroup_id(ctx.skb.skb, level) };
+        if id == 0 {
+            break;
+        }
+        if let Some(&p) = unsafe { CGROUP_POLICIES.get(&id) } {
+            policy = Some(p);
+        }
+    }
+
+    policy.filter(|&p| p < MAX_POLICIES)
+}
+
+fn try_geofw_cgroup(ctx: &SkBuffContext) -> Result<bool, ()> {
+    let Some(policy) = cgroup_policy(ctx) else {
+        return Ok(true);
+    };
+
+    // Packets start at the L3 header, skb->protocol is the EtherType in network order
+    let (source, l4) = match u16::from_be(ctx.skb.protocol() as u16) {
+        ETH_P_IP => {
+            let ip: Ipv4Hdr = ctx.load(0).map_err(|_| ())?;
+            let l4 = is_first_fragment(ip.frag_off).then_some((ip.proto, ip.ihl() as usize * 4));
+            (IpAddr::V4(ip.src_addr()), l4)
+        }
+        ETH_P_IPV6 => {
+            let ip: Ipv6Hdr = ctx.load(0).map_err(|_| ())?;
+            (IpAddr::V6(ip.src_addr()), Some((ip.next_hdr, Ipv6Hdr::LEN)))
+        }
+
+        _ => return Ok(true),
+    };
+    if source.is_loopback() {
+        return Ok(true);
+    }
+
+    let mut service = None;
+    if let Some((proto, offset)) = l4 {
+        if proto == IpProto::Tcp && is_param_set(ProgramParameters::SynOnly) {
+            let tcp: TcpHdr = ctx.load(offset).map_err(|_| ())?;
+            if tcp.syn() == 0 || tcp.ack() != 0 {
+                return Ok(true);
+            }
+        }
+        if let IpProto::Tcp | IpProto::Udp = proto {
+            let ports: [u16; 2] = ctx.load(offset).map_err(|_| ())?;
+            service = Some((proto as u8, u16::from_be(ports[1])));
+        }
+    }
+
+    let ifindex = unsafe { (*ctx.skb.skb).ifindex };
+    clear_last_counter();
+    let verdict = if is_bogon(policy, source) {
+        count(Counter::BogonDropped);
+        Verdict::Drop
     } else {
-        //  info!(&ctx, "ipv6 source = {} result = {}", source, result as u8);
-        Ok(xdp_action::XDP_PASS)
+        evaluate(ctx, source, policy, service)
+    };
+    if let Verdict::Pass | Verdict::Suspect = verdict {
+        count(Counter::Passed);
+        return Ok(true);
     }
+
+    report_drop(source, ifindex);
+    Ok(false)
 }
 
-fn filter_ipv6_packet(ctx: XdpContext) -> Result<u32, u32> {
-    let ip: *const Ipv6Hdr = ptr_at(&ctx, EthHdr::LEN).ok_or(xdp_action::XDP_PASS)?;
+/// Protocol, source port and destination port of TCP and UDP packets, ports in network order
+fn egress_ports(ctx: &TcContext, proto: IpProto, offset: usize) -> Option<(IpProto, u16, u16)> {
+    match proto {
+        IpProto::Tcp | IpProto::Udp => {
+            let ports: [u16; 2] = ctx.load(offset).ok()?;
+            Some((proto, ports[0], ports[1]))
+        }
+        _ => None,
+    }
+}
+
+/// Connection the host opened, as its replies see it. Ports are in network order
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Connection {
+    remote: [u8; 16],
+    remote_port: u16,
+    local_port: u16,
+    proto: u8,
+    // Hash keys are compared byte for byte, so the padding has to be zeroed
+    _pad: [u8; 3],
+}
+
+/// Replies are passed for this long after the last packet the host sent on a connection
+const CONNECTION_TIMEOUT: u64 = 300 * 1_000_000_000;
+
+fn track_connection(connection: Connection) {
+    let now = unsafe { bpf_ktime_get_ns() };
+    // Most packets of a busy connection only need a lookup instead of an update
+    let seen = unsafe { CONNECTIONS.get(&connection) }
+        .is_some_and(|&at| now.saturating_sub(at) < 1_000_000_000);
+    if !seen {
+        let _ = CONNECTIONS.insert(&connection, &now, 0);
+    }
+}
+
+/// With TrackConnections set, TCP and UDP packets answering a connection the host opened are
+/// passed without being looked up
+fn is_reply<C: Packet>(ctx: &C, source: IpAddr, proto: IpProto, offset: usize) -> bool {
+    if !matches!(proto, IpProto::Tcp | IpProto::Udp)
+        || !is_param_set(ProgramParameters::TrackConnections)
+    {
+        return false;
+    }
+    let Some(ports) = ptr_at::<[u16; 2]>(ctx, offset) else {
+        return false;
+    };
+
+    let connection = Connection {
+        remote: key_of(source),
+        remote_port: unsafe { (*ports)[0] },
+        local_port: unsafe { (*ports)[1] },
+        proto: proto as u8,
+        _pad: [0; 3],
+    };
+    let now = unsafe { bpf_ktime_get_ns() };
+    unsafe { CONNECTIONS.get(&connection) }
+        .is_some_and(|&at| now.saturating_sub(at) < CONNECTION_TIMEOUT)
+}
+
+fn filter_ip_packet<C: Packet>(ctx: &C, offset: usize) -> Result<u32, ()> {
+    let ip: *const Ipv4Hdr = ptr_at(ctx, offset).ok_or(())?;
     let source = unsafe { (*ip).src_addr() };
 
-    let result = should_block(&ctx, MaxmindDbType::Asn, &BLOCKED_ASN, IpAddr::V6(source))
-        || should_block(
-            &ctx,
-            MaxmindDbType::Country,
-            &BLOCKED_COUNTRY,
-            IpAddr::V6(source),
-        );
+    let udp_offset = offset + unsafe { (*ip).ihl() } as usize * 4;
+    if is_param_set(ProgramParameters::CheckHeaders) {
+        check_ipv4(ctx, unsafe { &*ip }, offset)?;
+        if is_first_fragment(unsafe { (*ip).frag_off }) {
+            check_l4(ctx, unsafe { (*ip).proto }, udp_offset)?;
+        }
+    }
+    if is_first_fragment(unsafe { (*ip).frag_off }) {
+        let proto = unsafe { (*ip).proto };
+        if proto == IpProto::Udp && is_dhcp(ctx, udp_offset, 68, 67) {
+            return Ok(xdp_action::XDP_PASS);
+        }
+        if is_passed_service(ctx, proto, udp_offset) {
+            return Ok(xdp_action::XDP_PASS);
+        }
+    }
 
-    if result {
-        debug!(&ctx, "ipv6 source = {} blocked = {}", source, result as u8);
+    let destination = unsafe { (*ip).dst_addr() };
+    if is_group_frame(ctx)? || destination.is_multicast() || destination.is_broadcast() {
+        if let Some(action) = multicast_action() {
+            return Ok(action);
+        }
+    }
 
-        Ok(xdp_action::XDP_DROP)
+    let service = if is_first_fragment(unsafe { (*ip).frag_off }) {
+        let proto = unsafe { (*ip).proto };
+        if is_established(ctx, proto, udp_offset)
+            || is_reply(ctx, IpAddr::V4(source), proto, udp_offset)
+        {
+            return Ok(xdp_action::XDP_PASS);
+        }
+        service(ctx, proto, udp_offset)
     } else {
-        //   info!(&ctx, "ipv6 source = {} result = {}", source, result as u8);
+        if let Some(action) = fragment_action() {
+            return Ok(action);
+        }
+        None
+    };
+    // Drops aren't logged here, under attack that would flood aya-log. Userspace logs a sample
+    // of the drop events
+    let action = check_addresses(ctx, IpAddr::V4(source), IpAddr::V4(destination), service);
+
+    Ok(action)
+}
+
+fn filter_ipv6_packet<C: Packet>(ctx: &C, offset: usize) -> Result<u32, ()> {
+    let ip: *const Ipv6Hdr = ptr_at(ctx, offset).ok_or(())?;
+    let source = unsafe { (*ip).src_addr() };
 
-        Ok(xdp_action::XDP_PASS)
+    let l3 = offset;
+    let (proto, offset, later_fragment) =
+        ipv6_l4(ctx, unsafe { (*ip).next_hdr }, offset + Ipv6Hdr::LEN).ok_or(())?;
+    if is_param_set(ProgramParameters::CheckHeaders) {
+        check_ipv6(ctx, unsafe { &*ip }, l3)?;
+        if !later_fragment {
+            check_l4(ctx, proto, offset)?;
+        }
+    }
+    if !later_fragment {
+        if proto == IpProto::Ipv6Icmp && is_link_essential(ctx, offset)? {
+            return Ok(xdp_action::XDP_PASS);
+        }
+        if proto == IpProto::Udp && is_dhcp(ctx, offset, 546, 547) {
+            return Ok(xdp_action::XDP_PASS);
+        }
+        if is_passed_service(ctx, proto, offset) {
+            return Ok(xdp_action::XDP_PASS);
+        }
+    }
+
+    let destination = unsafe { (*ip).dst_addr() };
+    if is_group_frame(ctx)? || destination.is_multicast() {
+        if let Some(action) = multicast_action() {
+            return Ok(action);
+        }
     }
+
+    if later_fragment {
+        if let Some(action) = fragment_action() {
+            return Ok(action);
+        }
+        let action = check_addresses(ctx, IpAddr::V6(source), IpAddr::V6(destination), None);
+        return Ok(action);
+    }
+    if is_established(ctx, proto, offset) || is_reply(ctx, IpAddr::V6(source), proto, offset) {
+        return Ok(xdp_action::XDP_PASS);
+    }
+
+    let service = service(ctx, proto, offset);
+    let action = check_addresses(ctx, IpAddr::V6(source), IpAddr::V6(destination), service);
+
+    Ok(action)
 }
 
-pub fn should_block(
-    ctx: &XdpContext,
-    db_type: MaxmindDbType,
-    map: &Array<u8>,
-    addr: IpAddr,
-) -> bool {
-    let record_size = match db_type {
-        MaxmindDbType::Country => unsafe {
-            PARAMETERS.get(&(ProgramParameters::CountryRecordSize as u8))
-        },
-        MaxmindDbType::Asn => unsafe { PARAMETERS.get(&(ProgramParameters::AsnRecordSize as u8)) },
-    };
-    let Some(&record_size) = record_size else {
+/// With CheckHeaders set, IPv4 headers must be at least 20 bytes long, and the total length must
+/// cover the header and fit in the packet. Total lengths of 0 are GRO packets on the tc path
+fn check_ipv4<C: Packet>(ctx: &C, ip: &Ipv4Hdr, offset: usize) -> Result<(), ()> {
+    let header_len = ip.ihl() as usize * 4;
+    let total_len = u16::from_be(ip.tot_len) as usize;
+    if header_len < Ipv4Hdr::LEN {
+        return Err(());
+    }
+    if total_len != 0 && (total_len < header_len || offset + total_len > ctx.len()) {
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// With CheckHeaders set, the payload of IPv6 packets must fit in the packet. Payload lengths of
+/// 0 are jumbograms, or GRO packets on the tc path
+fn check_ipv6<C: Packet>(ctx: &C, ip: &Ipv6Hdr, offset: usize) -> Result<(), ()> {
+    let payload_len = u16::from_be(ip.payload_len) as usize;
+    if payload_len != 0 && offset + Ipv6Hdr::LEN + payload_len > ctx.len() {
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// With CheckHeaders set, TCP and UDP headers must be in the packet, with a TCP data offset of
+/// at least 20 bytes and a UDP length that covers the header
+fn check_l4<C: Packet>(ctx: &C, proto: IpProto, offset: usize) -> Result<(), ()> {
+    match proto {
+        IpProto::Tcp => {
+            let tcp: *const TcpHdr = ptr_at(ctx, offset).ok_or(())?;
+            if (unsafe { (*tcp).doff() } as usize) * 4 < TcpHdr::LEN {
+                return Err(());
+            }
+        }
+        IpProto::Udp => {
+            let udp: *const UdpHdr = ptr_at(ctx, offset).ok_or(())?;
+            if (u16::from_be(unsafe { (*udp).len }) as usize) < UdpHdr::LEN {
+                return Err(());
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Extension headers walked before giving up on a packet, the verifier needs a bound on the loop
+const MAX_EXT_HEADERS: usize = 8;
+
+/// Protocol and offset of the upper layer header of an IPv6 packet after its hop-by-hop,
+/// routing, fragment, destination options and authentication headers, and whether the packet
+/// is a fragment other than the first. Packets with more than MAX_EXT_HEADERS extension headers
+/// are malformed
+fn ipv6_l4<C: Packet>(ctx: &C, next_hdr: IpProto, offset: usize) -> Option<(IpProto, usize, bool)> {
+    let (mut proto, mut offset, mut later_fragment) = (next_hdr, offset, false);
+
+    for _ in 0..MAX_EXT_HEADERS {
+        if !matches!(
+            proto,
+            IpProto::HopOpt
+                | IpProto::Ipv6Route
+                | IpProto::Ipv6Opts
+                | IpProto::Ipv6Frag
+                | IpProto::Ah
+        ) {
+            return Some((proto, offset, later_fragment));
+        }
+
+        // Every extension header starts with the next header
+        let next: *const IpProto = ptr_at(ctx, offset)?;
+        let len: *const u8 = ptr_at(ctx, offset + 1)?;
+        match proto {
+            // Lengths are in 8 octet units, not counting the first 8 octets
+            IpProto::HopOpt | IpProto::Ipv6Route | IpProto::Ipv6Opts => {
+                offset += (unsafe { *len } as usize + 1) * 8;
+            }
+            IpProto::Ipv6Frag => {
+                let frag_off: *const u16 = ptr_at(ctx, offset + 2)?;
+                later_fragment = u16::from_be(unsafe { *frag_off }) & 0xfff8 != 0;
+                offset += 8;
+                // The headers after it are only in the first fragment
+                if later_fragment {
+                    return Some((unsafe { *next }, offset, true));
+                }
+            }
+            // The authentication header length is in 4 octet units, not counting the first 8
+            _ => offset += (unsafe { *len } as usize + 2) * 4,
+        }
+        proto = unsafe { *next };
+    }
+
+    None
+}
+
+/// Verdict for fragments other than the first as configured by userspace, None when they are
+/// evaluated by address alone, since they have no ports
+fn fragment_action() -> Option<u32> {
+    let action = unsafe { PARAMETERS.get(&(ProgramParameters::FragmentAction as u8)) }
+        .and_then(|&v| FragmentAction::from_value(v))
+        .unwrap_or_default();
+
+    match action {
+        FragmentAction::Pass => Some(xdp_action::XDP_PASS),
+        FragmentAction::Drop => Some(xdp_action::XDP_DROP),
+        FragmentAction::Evaluate => None,
+    }
+}
+
+/// Protocol number and destination port of TCP and UDP packets. Both headers start with the
+/// source and destination ports
+fn service<C: Packet>(ctx: &C, proto: IpProto, offset: usize) -> Option<(u8, u16)> {
+    match proto {
+        IpProto::Tcp | IpProto::Udp => {
+            let port: *const u16 = ptr_at(ctx, offset + 2)?;
+            Some((proto as u8, u16::from_be(unsafe { *port })))
+        }
+        _ => None,
+    }
+}
+
+/// With SynOnly set, TCP segments other than the SYN opening a connection are passed without
+/// being looked up, so connections made before their source was blocked aren't cut
+fn is_established<C: Packet>(ctx: &C, proto: IpProto, offset: usize) -> bool {
+    if proto != IpProto::Tcp || !is_param_set(ProgramParameters::SynOnly) {
         return false;
-    };
+    }
+
+    match ptr_at::<TcpHdr>(ctx, offset) {
+        Some(tcp) => unsafe { (*tcp).syn() == 0 || (*tcp).ack() != 0 },
+        None => false,
+    }
+}
+
+/// Only the first fragment of an IPv4 packet has the L4 header, `frag_off` is in network order
+pub(crate) fn is_first_fragment(frag_off: u16) -> bool {
+    u16::from_be(frag_off) & 0x1fff == 0
+}
+
+/// Address truncated to the prefix length userspace allows in logs
+fn masked_ipv4(addr: Ipv4Addr) -> Ipv4Addr {
+    let prefix = unsafe { PARAMETERS.get(&(ProgramParameters::LogIpv4Prefix as u8)) };
+    let mask = u32::MAX
+        .checked_shl(32 - prefix.map_or(32, |&p| p.min(32)))
+        .unwrap_or(0);
+    Ipv4Addr::from_bits(addr.to_bits() & mask)
+}
+
+fn masked_ipv6(addr: Ipv6Addr) -> Ipv6Addr {
+    let prefix = unsafe { PARAMETERS.get(&(ProgramParameters::LogIpv6Prefix as u8)) };
+    let mask = u128::MAX
+        .checked_shl(128 - prefix.map_or(128, |&p| p.min(128)))
+        .unwrap_or(0);
+    Ipv6Addr::from_bits(addr.to_bits() & mask)
+}
+
+/// Router and neighbor discovery and multicast listener messages keep IPv6 working on the link,
+/// so they skip the rules unless userspace asked for them to be filtered
+fn is_link_essential<C: Packet>(ctx: &C, offset: usize) -> Result<bool, ()> {
+    let icmp_type: *const u8 = ptr_at(ctx, offset).ok_or(())?;
+
+    let filter = unsafe { PARAMETERS.get(&(ProgramParameters::FilterNeighborDiscovery as u8)) };
+    if filter.is_some_and(|&v| v != 0) {
+        return Ok(false);
+    }
 
-    let node_count = match db_type {
-        MaxmindDbType::Country => unsafe {
-            PARAMETERS.get(&(ProgramParameters::CountryNodeCount as u8))
+    // 130-132 and 143 are MLD, 133-137 are RS, RA, NS, NA and redirect
+    Ok(matches!(unsafe { *icmp_type }, 130..=137 | 143))
+}
+
+/// Whether the frame is sent to an Ethernet multicast or broadcast address, which also catches
+/// directed broadcasts that can't be told apart from unicast by their IP address
+fn is_group_frame<C: Packet>(ctx: &C) -> Result<bool, ()> {
+    let eth: *const EthHdr = ptr_at(ctx, 0).ok_or(())?;
+    Ok(unsafe { (*eth).dst_addr[0] } & 1 == 1)
+}
+
+/// Verdict for multicast and broadcast packets as configured by userspace, None when they are
+/// evaluated like unicast packets
+fn multicast_action() -> Option<u32> {
+    let action = unsafe { PARAMETERS.get(&(ProgramParameters::MulticastAction as u8)) }
+        .and_then(|&v| MulticastAction::from_value(v))
+        .unwrap_or_default();
+
+    match action {
+        MulticastAction::Pass => Some(xdp_action::XDP_PASS),
+        MulticastAction::Drop => Some(xdp_action::XDP_DROP),
+        MulticastAction::Evaluate => None,
+    }
+}
+
+/// DHCP between clients and servers or relays skips the rules, so a policy can't break address
+/// acquisition, unless userspace asked for it to be filtered. Only the first fragment has the
+/// UDP header, packets too short to hold one aren't DHCP
+fn is_dhcp<C: Packet>(ctx: &C, offset: usize, client: u16, server: u16) -> bool {
+    let filter = unsafe { PARAMETERS.get(&(ProgramParameters::FilterDhcp as u8)) };
+    if filter.is_some_and(|&v| v != 0) {
+        return false;
+    }
+
+    is_dhcp_port(ctx, offset, client, server)
+}
+
+/// Whether the destination port of a TCP or UDP packet or the type of an ICMP or ICMPv6
+/// message is in PASS_SERVICES. PassServices is set when userspace wrote some
+fn is_passed_service<C: Packet>(ctx: &C, proto: IpProto, offset: usize) -> bool {
+    if !is_param_set(ProgramParameters::PassServices) {
+        return false;
+    }
+
+    let value = match proto {
+        IpProto::Tcp | IpProto::Udp => match ptr_at::<u16>(ctx, offset + 2) {
+            Some(port) => u16::from_be(unsafe { *port }),
+            None => return false,
+        },
+        IpProto::Icmp | IpProto::Ipv6Icmp => match ptr_at::<u8>(ctx, offset) {
+            Some(icmp_type) => unsafe { *icmp_type as u16 },
+            None => return false,
         },
-        MaxmindDbType::Asn => unsafe { PARAMETERS.get(&(ProgramParameters::AsnNodeCount as u8)) },
+        _ => return false,
     };
-    let Some(&node_count) = node_count else {
-        return false;
+
+    unsafe { PASS_SERVICES.get(&pass_service_key(proto as u8, value)) }.is_some()
+}
+
+/// Outcome of the rules for an address
+pub(crate) enum Verdict {
+    Pass,
+    Drop,
+    /// Dropped by a policy that answers the source
+    Reject,
+    /// Dropped by a policy that sends the packet to the interface in REDIRECT_TARGETS at its
+    /// index instead
+    Redirect(u32),
+    /// Neither listed nor allowed, but from a country userspace wants to look at more closely
+    Suspect,
+}
+
+/// Checks the source, the destination or both depending on the direction userspace set. Suspect
+/// destinations are passed, only sources are inspected
+fn check_addresses<C: Packet>(
+    ctx: &C,
+    source: IpAddr,
+    destination: IpAddr,
+    service: Option<(u8, u16)>,
+) -> u32 {
+    let direction = unsafe { PARAMETERS.get(&(ProgramParameters::Direction as u8)) }
+        .and_then(|&v| Direction::from_value(v))
+        .unwrap_or_default();
+
+    let ifindex = ctx.ifindex();
+    clear_last_counter();
+    if direction != Direction::Dst {
+        let action = check_source(ctx, source, service);
+        if action != xdp_action::XDP_PASS {
+            report_drop(source, ifindex);
+            return action;
+        }
+    }
+    if direction != Direction::Src {
+        if let Verdict::Drop | Verdict::Reject | Verdict::Redirect(_) =
+            evaluate(ctx, destination, policy_of(ifindex), service)
+        {
+            report_drop(destination, ifindex);
+            return xdp_action::XDP_DROP;
+        }
+    }
+
+    count(Counter::Passed);
+    xdp_action::XDP_PASS
+}
+
+fn check_source<C: Packet>(ctx: &C, addr: IpAddr, service: Option<(u8, u16)>) -> u32 {
+    let policy = policy_of(ctx.ifindex());
+    if is_bogon(policy, addr) {
+        count(Counter::BogonDropped);
+        return xdp_action::XDP_DROP;
+    }
+
+    ctx.divert(addr, evaluate(ctx, addr, policy, service))
+}
+
+fn key_of(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(a) => a.to_ipv6_mapped().octets(),
+        IpAddr::V6(a) => a.octets(),
+    }
+}
+
+/// Applies the rules of `policy` to `addr`, counting the packets it drops. `service` is the
+/// protocol and destination port of TCP and UDP packets
+fn evaluate<C: EbpfContext>(
+    ctx: &C,
+    addr: IpAddr,
+    policy: u32,
+    service: Option<(u8, u16)>,
+) -> Verdict {
+    let key = key_of(addr);
+    if ALLOWED_CIDRS.get(&Key::new(128, key)).is_some() {
+        count(Counter::Allowed);
+        return Verdict::Pass;
+    }
+    if BLOCKED_CIDRS.get(&Key::new(128, key)).is_some() {
+        count(Counter::BlocklistDropped);
+        return Verdict::Drop;
+    }
+    if FEED_CIDRS.get(&Key::new(128, key)).is_some() {
+        count(Counter::FeedDropped);
+        return Verdict::Drop;
+    }
+
+    if !is_active(policy) || !in_scope(policy, service) {
+        return Verdict::Pass;
+    }
+
+    // Nothing can be looked up before userspace writes the parameters
+    let Some(params) = LOOKUP_PARAMETERS.get(lookup_parameters_slot()) else {
+        return Verdict::Pass;
     };
+    let mode = Mode::from_value(params.mode).unwrap_or_default();
+    let [asn, country, city, anonymous] = cached_lookup(ctx, params, &key, addr);
+
+    if mode == Mode::Allow {
+        // Anonymous sources are dropped even from allowed countries
+        if anonymous == BLOCK_MARKER {
+            count(Counter::AnonymousDropped);
+            return Verdict::Drop;
+        }
+        if is_listed(asn, policy) || is_listed(country, policy) || is_listed(city, policy) {
+            return Verdict::Pass;
+        }
+        if country == SUSPECT_MARKER {
+            return Verdict::Suspect;
+        }
+
+        // Sources that aren't allowed are counted as dropped by their country
+        return rule_drop(policy, addr, Counter::CountryDropped);
+    }
+
+    let allowed = asn == ALLOW_MARKER || country == ALLOW_MARKER;
+    if allowed && precedence() == Precedence::Allow {
+        count(Counter::Allowed);
+        return Verdict::Pass;
+    }
+    if anonymous == BLOCK_MARKER {
+        count(Counter::AnonymousDropped);
+        return Verdict::Drop;
+    }
+    if is_listed(asn, policy) {
+        return rule_drop(policy, addr, Counter::AsnDropped);
+    }
+    if is_listed(country, policy) {
+        return match CountryAction::of_record(country, policy) {
+            Some(CountryAction::Drop) => matched(policy, Counter::CountryDropped),
+            Some(CountryAction::Log) => {
+                count(Counter::CountryLogged);
+                match addr {
+                    IpAddr::V4(a) => debug!(ctx, "ipv4 address = {} logged", masked_ipv4(a)),
+                    IpAddr::V6(a) => debug!(ctx, "ipv6 address = {} logged", masked_ipv6(a)),
+                }
+                Verdict::Pass
+            }
+            Some(CountryAction::RateLimit) | None => {
+                rule_drop(policy, addr, Counter::CountryDropped)
+            }
+        };
+    }
+    if is_listed(city, policy) {
+        return rule_drop(policy, addr, Counter::CityDropped);
+    }
+    if policy == 0 && compound_match(country, asn) {
+        return rule_drop(policy, addr, Counter::CountryDropped);
+    }
 
-    let node_size = record_size as usize * 2 / 8;
-    let mut node = 0;
-    let mut i = 128;
-    let mut ip = match addr {
+    if country == SUSPECT_MARKER && !allowed {
+        Verdict::Suspect
+    } else {
+        Verdict::Pass
+    }
+}
+
+/// Whether a compound rule matches. Rules with their bit set in CompoundExcept match sources
+/// from their country that aren't in one of their ASNs, the others sources from their country
+/// that are in one of their ASNs
+fn compound_match(country: u32, asn: u32) -> bool {
+    let except = unsafe { PARAMETERS.get(&(ProgramParameters::CompoundExcept as u8)) }
+        .copied()
+        .unwrap_or(0);
+    let country = compound_rules(country);
+    let asn = compound_rules(asn);
+
+    (country & asn & !except) | (country & !asn & except) != 0
+}
+
+/// Tokens of a rate limited prefix, scaled by a billion so refills from a few nanoseconds aren't
+/// lost
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: u64,
+    updated_at: u64,
+}
+
+const TOKEN: u64 = 1_000_000_000;
+
+/// Drops a packet the rules of `policy` matched, unless the policy is rate limited and the
+/// bucket of the source prefix still has a token
+fn rule_drop(policy: u32, addr: IpAddr, counter: Counter) -> Verdict {
+    let limit = RATE_LIMITS
+        .get(policy)
+        .filter(|l| l.packets_per_second != 0);
+    if let Some(limit) = limit {
+        if take_token(limit, addr) {
+            return Verdict::Pass;
+        }
+    }
+
+    matched(policy, counter)
+}
+
+/// Counts a packet the rules of `policy` matched and applies the action of the policy
+fn matched(policy: u32, counter: Counter) -> Verdict {
+    count(counter);
+    let action = POLICY_ACTIONS
+        .get(policy)
+        .and_then(|&v| Action::from_value(v))
+        .unwrap_or_default();
+    match action {
+        Action::Drop => Verdict::Drop,
+        Action::Reject => Verdict::Reject,
+        Action::Redirect => Verdict::Redirect(policy),
+    }
+}
+
+fn take_token(limit: &RateLimit, addr: IpAddr) -> bool {
+    let key = match addr {
         IpAddr::V4(a) => {
-            i = 32;
-            node = 96;
-            (a.to_bits() as u128) << 96
+            let mask = u32::MAX
+                .checked_shl(32 - limit.ipv4_prefix.min(32))
+                .unwrap_or(0);
+            Ipv4Addr::from_bits(a.to_bits() & mask)
+                .to_ipv6_mapped()
+                .octets()
+        }
+        IpAddr::V6(a) => {
+            let mask = u128::MAX
+                .checked_shl(128 - limit.ipv6_prefix.min(128))
+                .unwrap_or(0);
+            Ipv6Addr::from_bits(a.to_bits() & mask).octets()
         }
-        IpAddr::V6(a) => a.to_bits(),
     };
 
-    while i >= 0 && node < node_count {
-        let left = (ip & (1 << 127)) == 0;
-        ip <<= 1;
+    let now = unsafe { bpf_ktime_get_ns() };
+    let capacity = (limit.burst.max(1) as u64).saturating_mul(TOKEN);
+    let Some(bucket) = RATE_BUCKETS.get_ptr_mut(&key) else {
+        // New prefixes start with a full bucket, minus this packet
+        let bucket = Bucket {
+            tokens: capacity - TOKEN,
+            updated_at: now,
+        };
+        let _ = RATE_BUCKETS.insert(&key, &bucket, 0);
+        return true;
+    };
 
-        let mut slice = [0; 8];
-        for (i, v) in slice.iter_mut().enumerate().take(node_size) {
-            *v = match map.get(node * node_size as u32 + i as u32) {
-                Some(&v) => v,
-                None => {
-                    warn!(
-                        ctx,
-                        "error in reading position = {}",
-                        node * node_size as u32 + i as u32,
-                    );
-                    return false;
-                }
+    // Buckets are updated without locking, concurrent packets on other CPUs can get a few
+    // tokens more or less than they should
+    let bucket = unsafe { &mut *bucket };
+    let elapsed = now.saturating_sub(bucket.updated_at);
+    let refill = elapsed.saturating_mul(limit.packets_per_second as u64);
+    bucket.tokens = bucket.tokens.saturating_add(refill).min(capacity);
+    bucket.updated_at = now;
+
+    if bucket.tokens < TOKEN {
+        return false;
+    }
+    bucket.tokens -= TOKEN;
+    true
+}
+
+/// Policy of the interface, the top level rules for interfaces without one
+fn policy_of(ifindex: u32) -> u32 {
+    unsafe { INTERFACE_POLICIES.get(&ifindex) }
+        .copied()
+        .filter(|&p| p < MAX_POLICIES)
+        .unwrap_or(0)
+}
+
+/// Whether `policy` drops bogons and `addr` is one. Only sources are checked, hosts often have
+/// reserved addresses themselves. The allow lists still win
+fn is_bogon(policy: u32, addr: IpAddr) -> bool {
+    let enabled = unsafe { PARAMETERS.get(&(ProgramParameters::BogonPolicies as u8)) }
+        .is_some_and(|&mask| mask & (1 << policy) != 0);
+    if !enabled {
+        return false;
+    }
+
+    let key = Key::new(128, key_of(addr));
+    ALLOWED_CIDRS.get(&key).is_none() && BOGONS.get(&key).is_some()
+}
+
+/// Whether the schedule of `policy` is in one of its windows. Userspace updates the mask as
+/// windows open and close, every policy is active until it is first written
+fn is_active(policy: u32) -> bool {
+    unsafe { PARAMETERS.get(&(ProgramParameters::ActivePolicies as u8)) }
+        .is_none_or(|&mask| mask & (1 << policy) != 0)
+}
+
+/// Whether the rules of `policy` apply to packets for `service`. Policies without ports apply
+/// to every packet
+fn in_scope(policy: u32, service: Option<(u8, u16)>) -> bool {
+    let scoped = unsafe { PARAMETERS.get(&(ProgramParameters::ScopedPolicies as u8)) }
+        .is_some_and(|&mask| mask & (1 << policy) != 0);
+    if !scoped {
+        return true;
+    }
+
+    service.is_some_and(|(proto, port)| {
+        unsafe { PORT_SCOPES.get(&port_scope_key(policy, proto, port)) }.is_some()
+    })
+}
+
+fn precedence() -> Precedence {
+    unsafe { PARAMETERS.get(&(ProgramParameters::Precedence as u8)) }
+        .and_then(|&v| Precedence::from_value(v))
+        .unwrap_or_default()
+}
+
+fn count(counter: Counter) {
+    if let Some(v) = STATS.get_ptr_mut(counter as u32) {
+        unsafe { *v += 1 };
+    }
+    if let Some(v) = LAST_COUNTER.get_ptr_mut(0) {
+        unsafe { *v = counter as u32 };
+    }
+}
+
+/// Forgets the counter of the previous packet, before evaluating the next one
+fn clear_last_counter() {
+    if let Some(v) = LAST_COUNTER.get_ptr_mut(0) {
+        unsafe { *v = u32::MAX };
+    }
+}
+
+/// Queues a drop event for `addr` when DropEvents is set. Only packets that were counted in a
+/// drop counter are reported, like those the rules matched, not suspect packets sent to
+/// userspace
+fn report_drop(addr: IpAddr, ifindex: u32) {
+    if !is_param_set(ProgramParameters::DropEvents) {
+        return;
+    }
+    let Some(&reason) = LAST_COUNTER.get(0) else {
+        return;
+    };
+    if Counter::from_index(reason).is_none_or(|c| !c.is_drop()) {
+        return;
+    }
+
+    let event = DropEvent {
+        timestamp: unsafe { bpf_ktime_get_boot_ns() },
+        addr: key_of(addr),
+        ifindex,
+        reason,
+    };
+    let _ = DROP_EVENTS.output(&event, 0);
+}
+
+/// Applies the verdict userspace wrote for this source. Sources without one are redirected to
+/// the AF_XDP socket on this interface and rx queue with their key in the XDP metadata, or
+/// passed when there is no socket or the driver doesn't support metadata
+fn inspect_suspect(ctx: &XdpContext, key: [u8; 16]) -> u32 {
+    match suspect_verdict(key) {
+        Some(action) => action,
+        None => {
+            let ifindex = unsafe { (*ctx.ctx).ingress_ifindex };
+            let queue = unsafe { (*ctx.ctx).rx_queue_index };
+            let Some(&slot) = (unsafe { SUSPECT_INTERFACES.get(&ifindex) }) else {
+                return xdp_action::XDP_PASS;
+            };
+            if queue >= MAX_QUEUES {
+                return xdp_action::XDP_PASS;
             }
+
+            if unsafe { bpf_xdp_adjust_meta(ctx.ctx, -(SUSPECT_KEY_LEN as i32)) } != 0 {
+                return xdp_action::XDP_PASS;
+            }
+            let metadata = ctx.metadata();
+            if metadata + SUSPECT_KEY_LEN > ctx.metadata_end() {
+                return xdp_action::XDP_PASS;
+            }
+            unsafe { *(metadata as *mut [u8; 16]) = key };
+
+            SUSPECT_SOCKETS
+                .redirect(slot * MAX_QUEUES + queue, xdp_action::XDP_PASS as u64)
+                .unwrap_or(xdp_action::XDP_PASS)
         }
-        node = node_from_bytes(slice, left, record_size as u16);
-        i -= 1;
+    }
+}
+
+/// The verdict userspace wrote for a suspect source, None when it hasn't looked at it yet
+fn suspect_verdict(key: [u8; 16]) -> Option<u32> {
+    match unsafe { SUSPECT_VERDICTS.get(&key) } {
+        Some(&SUSPECT_PASS) => Some(xdp_action::XDP_PASS),
+        Some(_) => Some(xdp_action::XDP_DROP),
+        None => None,
+    }
+}
+
+/// Records of a source in the ASN, country, city and anonymous trees, from the generation of
+/// LOOKUP_PARAMETERS they were looked up in
+#[derive(Clone, Copy)]
+struct CachedLookup {
+    generation: u32,
+    records: [u32; 4],
+}
+
+/// The records of `addr` in every tree, from LOOKUP_CACHE unless the trees or the parameters
+/// were written since they were cached. Only the lookups are cached, the rules still see every
+/// packet so counters, rate limits and policies apply as before
+fn cached_lookup<C: EbpfContext>(
+    ctx: &C,
+    params: &LookupParameters,
+    key: &[u8; 16],
+    addr: IpAddr,
+) -> [u32; 4] {
+    if let Some(cached) = unsafe { LOOKUP_CACHE.get(key) } {
+        if cached.generation == params.generation {
+            return cached.records;
+        }
+    }
+
+    let records = [
+        lookup(ctx, params, MaxmindDbType::Asn, addr),
+        lookup(ctx, params, MaxmindDbType::Country, addr),
+        // Returns 0 right away unless userspace loaded the city database
+        lookup(ctx, params, MaxmindDbType::City, addr),
+        lookup(ctx, params, MaxmindDbType::Anonymous, addr),
+    ];
+    let cached = CachedLookup {
+        generation: params.generation,
+        records,
+    };
+    let _ = LOOKUP_CACHE.insert(key, &cached, 0);
+
+    records
+}
+
+/// Returns the record of `addr` in the tree of `db_type`, 0 when there is none
+pub fn lookup<C: EbpfContext>(
+    ctx: &C,
+    params: &LookupParameters,
+    db_type: MaxmindDbType,
+    addr: IpAddr,
+) -> u32 {
+    let (map, prefixes) = match db_type {
+        MaxmindDbType::Country => (&BLOCKED_COUNTRY, &COUNTRY_PREFIXES),
+        MaxmindDbType::Asn => (&BLOCKED_ASN, &ASN_PREFIXES),
+        MaxmindDbType::City => (&BLOCKED_CITY, &CITY_PREFIXES),
+        MaxmindDbType::Anonymous => (&BLOCKED_ANONYMOUS, &ANONYMOUS_PREFIXES),
+    };
+
+    if LookupBackend::from_value(params.backend) == Some(LookupBackend::Lpm) {
+        return prefixes
+            .get(&Key::new(128, key_of(addr)))
+            .copied()
+            .unwrap_or(0);
     }
 
-    node == BLOCK_MARKER
+    match params.trees.get(db_type as usize) {
+        Some(tree) => walk_tree(ctx, tree, map, addr),
+        None => 0,
+    }
 }
 
-fn node_from_bytes(n: [u8; 8], left: bool, record_size: u16) -> u32 {
-    match record_size {
-        28 if left => u32::from_be_bytes([(n[3] & 0b1111_0000) >> 4, n[0], n[1], n[2]]),
-        28 => u32::from_be_bytes([n[3] & 0b0000_1111, n[4], n[5], n[6]]),
-        24 if left => u32::from_be_bytes([0, n[0], n[1], n[2]]),
-        24 => u32::from_be_bytes([0, n[3], n[4], n[5]]),
+/// Walks the tree and returns the record the walk ended at
+fn walk_tree<C: EbpfContext>(ctx: &C, tree: &TreeShape, map: &Array<u64>, addr: IpAddr) -> u32 {
+    let node_count = tree.node_count;
+    if tree.record_size == 0 {
+        return 0;
+    }
 
-        // This should never run unless we are using 32bit dbs
-        _ => 0,
+    let (mut node, mut i, mut ip) = match addr {
+        // Skip the 96 levels of ::/96 and start at the root of the IPv4 subtree
+        IpAddr::V4(a) => (tree.ipv4_start, 32, (a.to_bits() as u128) << 96),
+        IpAddr::V6(a) => (0, 128, a.to_bits()),
+    };
+
+    while i > 0 && node < node_count {
+        let left = (ip & (1 << 127)) == 0;
+        ip <<= 1;
+
+        let Some(&records) = map.get(tree.base + node) else {
+            warn!(ctx, "error in reading node = {}", node);
+            return 0;
+        };
+        node = if left {
+            (records >> 32) as u32
+        } else {
+            records as u32
+        };
+        i -= 1;
     }
+
+    node
 }
 
 #[cfg(not(test))]
@@ -0,0 +1,309 @@
+use crate::{is_first_fragment, ptr_at, ETH_P_IP, ETH_P_IPV6};
+use core::{mem, net::IpAddr};
+
+use aya_ebpf::{
+    bindings::xdp_action,
+    helpers::{bpf_xdp_adjust_head, bpf_xdp_adjust_tail},
+    programs::XdpContext,
+};
+use network_types::{
+    eth::{EthHdr, EtherType},
+    ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
+    tcp::TcpHdr,
+};
+
+/// Bytes of the rejected packet quoted in ICMP errors after its IP header
+const QUOTED: usize = 8;
+
+const ICMP_HDR_LEN: usize = 8;
+
+/// Rewrites the packet into a TCP reset or an ICMP administratively prohibited error to its
+/// source and sends it back out. Packets that can't be answered, like ICMP errors, resets,
+/// fragments, IPv4 packets with options, VLAN tagged frames and tunneled packets, whose outer
+/// source isn't `source`, are dropped
+pub fn reject(ctx: &XdpContext, source: IpAddr) -> u32 {
+    let result = match ptr_at::<u16>(ctx, 12).map(|v| u16::from_be(unsafe { *v })) {
+        Some(ETH_P_IP) => reject_ipv4(ctx, source),
+        Some(ETH_P_IPV6) => reject_ipv6(ctx, source),
+        _ => Err(()),
+    };
+
+    result.unwrap_or(xdp_action::XDP_DROP)
+}
+
+fn reject_ipv4(ctx: &XdpContext, source: IpAddr) -> Result<u32, ()> {
+    let ip: *const Ipv4Hdr = ptr_at(ctx, EthHdr::LEN).ok_or(())?;
+    if unsafe { (*ip).ihl() } != 5
+        || !is_first_fragment(unsafe { (*ip).frag_off })
+        || IpAddr::V4(unsafe { (*ip).src_addr() }) != source
+    {
+        return Err(());
+    }
+
+    match unsafe { (*ip).proto } {
+        IpProto::Tcp => tcp_reset_ipv4(ctx),
+        // Errors are never answered with errors
+        IpProto::Icmp => Err(()),
+        _ => unreachable_ipv4(ctx),
+    }
+}
+
+fn reject_ipv6(ctx: &XdpContext, source: IpAddr) -> Result<u32, ()> {
+    let ip: *const Ipv6Hdr = ptr_at(ctx, EthHdr::LEN).ok_or(())?;
+    if IpAddr::V6(unsafe { (*ip).src_addr() }) != source {
+        return Err(());
+    }
+
+    match unsafe { (*ip).next_hdr } {
+        IpProto::Tcp => tcp_reset_ipv6(ctx),
+        IpProto::Ipv6Icmp => Err(()),
+        _ => unreachable_ipv6(ctx),
+    }
+}
+
+fn tcp_reset_ipv4(ctx: &XdpContext) -> Result<u32, ()> {
+    let eth = ptr_at::<EthHdr>(ctx, 0).ok_or(())? as *mut EthHdr;
+    let ip = ptr_at::<Ipv4Hdr>(ctx, EthHdr::LEN).ok_or(())? as *mut Ipv4Hdr;
+    let tcp = ptr_at::<TcpHdr>(ctx, EthHdr::LEN + Ipv4Hdr::LEN).ok_or(())? as *mut TcpHdr;
+
+    unsafe {
+        let payload = (u16::from_be((*ip).tot_len) as u32)
+            .checked_sub(Ipv4Hdr::LEN as u32 + (*tcp).doff() as u32 * 4)
+            .ok_or(())?;
+        make_reset(tcp, payload)?;
+        swap_eth(eth);
+
+        mem::swap(&mut (*ip).src_addr, &mut (*ip).dst_addr);
+        write_ipv4(ip, IpProto::Tcp, (Ipv4Hdr::LEN + TcpHdr::LEN) as u16);
+    }
+
+    let offset = EthHdr::LEN + Ipv4Hdr::LEN;
+    let mut sum = ipv4_pseudo_sum(ctx, IpProto::Tcp, TcpHdr::LEN)?;
+    sum += sum_words(ctx, offset, TcpHdr::LEN)?;
+    unsafe { (*tcp).check = checksum(sum).to_be() };
+    let sum = sum_words(ctx, EthHdr::LEN, Ipv4Hdr::LEN)?;
+    unsafe { (*ip).check = checksum(sum).to_be() };
+
+    truncate(ctx, offset + TcpHdr::LEN)
+}
+
+fn tcp_reset_ipv6(ctx: &XdpContext) -> Result<u32, ()> {
+    let eth = ptr_at::<EthHdr>(ctx, 0).ok_or(())? as *mut EthHdr;
+    let ip = ptr_at::<Ipv6Hdr>(ctx, EthHdr::LEN).ok_or(())? as *mut Ipv6Hdr;
+    let tcp = ptr_at::<TcpHdr>(ctx, EthHdr::LEN + Ipv6Hdr::LEN).ok_or(())? as *mut TcpHdr;
+
+    unsafe {
+        let payload = (u16::from_be((*ip).payload_len) as u32)
+            .checked_sub((*tcp).doff() as u32 * 4)
+            .ok_or(())?;
+        make_reset(tcp, payload)?;
+        swap_eth(eth);
+
+        mem::swap(&mut (*ip).src_addr, &mut (*ip).dst_addr);
+        (*ip).payload_len = (TcpHdr::LEN as u16).to_be();
+        (*ip).hop_limit = 64;
+    }
+
+    let offset = EthHdr::LEN + Ipv6Hdr::LEN;
+    let mut sum = ipv6_pseudo_sum(ctx, IpProto::Tcp, TcpHdr::LEN)?;
+    sum += sum_words(ctx, offset, TcpHdr::LEN)?;
+    unsafe { (*tcp).check = checksum(sum).to_be() };
+
+    truncate(ctx, offset + TcpHdr::LEN)
+}
+
+/// Turns the TCP header into a reset for the segment it held, which carried `payload` bytes of
+/// data. The reset acknowledges the segment unless the segment acknowledged something itself,
+/// as RFC 9293 describes for connections that don't exist
+unsafe fn make_reset(tcp: *mut TcpHdr, payload: u32) -> Result<(), ()> {
+    if (*tcp).rst() != 0 {
+        return Err(());
+    }
+
+    let seq = u32::from_be((*tcp).seq);
+    let ack = u32::from_be((*tcp).ack_seq);
+    let acked = (*tcp).ack() != 0;
+    let len = payload + (*tcp).syn() as u32 + (*tcp).fin() as u32;
+
+    mem::swap(&mut (*tcp).source, &mut (*tcp).dest);
+
+    if acked {
+        (*tcp).seq = ack.to_be();
+        (*tcp).ack_seq = 0;
+    } else {
+        (*tcp).seq = 0;
+        (*tcp).ack_seq = seq.wrapping_add(len).to_be();
+    }
+
+    (*tcp).set_doff(5);
+    (*tcp).set_res1(0);
+    (*tcp).set_fin(0);
+    (*tcp).set_syn(0);
+    (*tcp).set_psh(0);
+    (*tcp).set_urg(0);
+    (*tcp).set_ece(0);
+    (*tcp).set_cwr(0);
+    (*tcp).set_rst(1);
+    (*tcp).set_ack(!acked as u16);
+    (*tcp).window = 0;
+    (*tcp).urg_ptr = 0;
+    (*tcp).check = 0;
+
+    Ok(())
+}
+
+/// Answers with an ICMP destination unreachable, administratively prohibited error quoting the
+/// IP header and the first 8 bytes after it. Room for the new IP and ICMP headers is made in
+/// front of the packet, so the quoted part stays where it is
+fn unreachable_ipv4(ctx: &XdpContext) -> Result<u32, ()> {
+    let quoted = EthHdr::LEN + Ipv4Hdr::LEN + QUOTED;
+    if ctx.data() + quoted > ctx.data_end() {
+        return Err(());
+    }
+
+    let grow = Ipv4Hdr::LEN + ICMP_HDR_LEN;
+    if unsafe { bpf_xdp_adjust_head(ctx.ctx, -(grow as i32)) } != 0 {
+        return Err(());
+    }
+
+    let old_eth: *const EthHdr = ptr_at(ctx, grow).ok_or(())?;
+    let old_ip: *const Ipv4Hdr = ptr_at(ctx, grow + EthHdr::LEN).ok_or(())?;
+    let eth = ptr_at::<EthHdr>(ctx, 0).ok_or(())? as *mut EthHdr;
+    let ip = ptr_at::<Ipv4Hdr>(ctx, EthHdr::LEN).ok_or(())? as *mut Ipv4Hdr;
+    let icmp = ptr_at::<[u8; ICMP_HDR_LEN]>(ctx, EthHdr::LEN + Ipv4Hdr::LEN).ok_or(())?
+        as *mut [u8; ICMP_HDR_LEN];
+
+    unsafe {
+        (*eth).dst_addr = (*old_eth).src_addr;
+        (*eth).src_addr = (*old_eth).dst_addr;
+        (*eth).ether_type = EtherType::Ipv4;
+
+        (*ip).src_addr = (*old_ip).dst_addr;
+        (*ip).dst_addr = (*old_ip).src_addr;
+        (*ip).set_version(4);
+        (*ip).set_ihl(5);
+        write_ipv4(
+            ip,
+            IpProto::Icmp,
+            (Ipv4Hdr::LEN + ICMP_HDR_LEN + Ipv4Hdr::LEN + QUOTED) as u16,
+        );
+
+        // Destination unreachable, communication administratively prohibited
+        *icmp = [3, 13, 0, 0, 0, 0, 0, 0];
+    }
+
+    let offset = EthHdr::LEN + Ipv4Hdr::LEN;
+    let len = ICMP_HDR_LEN + Ipv4Hdr::LEN + QUOTED;
+    let sum = sum_words(ctx, offset, len)?;
+    unsafe { (&mut *icmp)[2..4].copy_from_slice(&checksum(sum).to_be_bytes()) };
+    let sum = sum_words(ctx, EthHdr::LEN, Ipv4Hdr::LEN)?;
+    unsafe { (*ip).check = checksum(sum).to_be() };
+
+    truncate(ctx, offset + len)
+}
+
+/// Same as `unreachable_ipv4` with ICMPv6 destination unreachable, administratively prohibited
+fn unreachable_ipv6(ctx: &XdpContext) -> Result<u32, ()> {
+    let quoted = EthHdr::LEN + Ipv6Hdr::LEN + QUOTED;
+    if ctx.data() + quoted > ctx.data_end() {
+        return Err(());
+    }
+
+    let grow = Ipv6Hdr::LEN + ICMP_HDR_LEN;
+    if unsafe { bpf_xdp_adjust_head(ctx.ctx, -(grow as i32)) } != 0 {
+        return Err(());
+    }
+
+    let old_eth: *const EthHdr = ptr_at(ctx, grow).ok_or(())?;
+    let old_ip: *const Ipv6Hdr = ptr_at(ctx, grow + EthHdr::LEN).ok_or(())?;
+    let eth = ptr_at::<EthHdr>(ctx, 0).ok_or(())? as *mut EthHdr;
+    let ip = ptr_at::<Ipv6Hdr>(ctx, EthHdr::LEN).ok_or(())? as *mut Ipv6Hdr;
+    let icmp = ptr_at::<[u8; ICMP_HDR_LEN]>(ctx, EthHdr::LEN + Ipv6Hdr::LEN).ok_or(())?
+        as *mut [u8; ICMP_HDR_LEN];
+
+    let len = ICMP_HDR_LEN + Ipv6Hdr::LEN + QUOTED;
+    unsafe {
+        (*eth).dst_addr = (*old_eth).src_addr;
+        (*eth).src_addr = (*old_eth).dst_addr;
+        (*eth).ether_type = EtherType::Ipv6;
+
+        (*ip).src_addr = (*old_ip).dst_addr;
+        (*ip).dst_addr = (*old_ip).src_addr;
+        (*ip).set_version(6);
+        (*ip).set_priority(0);
+        (*ip).flow_label = [0; 3];
+        (*ip).payload_len = (len as u16).to_be();
+        (*ip).next_hdr = IpProto::Ipv6Icmp;
+        (*ip).hop_limit = 64;
+
+        // Destination unreachable, communication with destination administratively prohibited
+        *icmp = [1, 1, 0, 0, 0, 0, 0, 0];
+    }
+
+    let offset = EthHdr::LEN + Ipv6Hdr::LEN;
+    let mut sum = ipv6_pseudo_sum(ctx, IpProto::Ipv6Icmp, len)?;
+    sum += sum_words(ctx, offset, len)?;
+    unsafe { (&mut *icmp)[2..4].copy_from_slice(&checksum(sum).to_be_bytes()) };
+
+    truncate(ctx, offset + len)
+}
+
+unsafe fn swap_eth(eth: *mut EthHdr) {
+    mem::swap(&mut (*eth).src_addr, &mut (*eth).dst_addr);
+}
+
+/// Fills in the fixed fields of an IPv4 header without options. The checksum is left at 0
+unsafe fn write_ipv4(ip: *mut Ipv4Hdr, proto: IpProto, total_len: u16) {
+    (*ip).tos = 0;
+    (*ip).tot_len = total_len.to_be();
+    (*ip).id = 0;
+    // Don't fragment
+    (*ip).frag_off = 0x4000u16.to_be();
+    (*ip).ttl = 64;
+    (*ip).proto = proto;
+    (*ip).check = 0;
+}
+
+/// Shrinks the packet to `len` bytes and sends it back out of the interface it came in on
+fn truncate(ctx: &XdpContext, len: usize) -> Result<u32, ()> {
+    let delta = len as i32 - (ctx.data_end() - ctx.data()) as i32;
+    if delta != 0 && unsafe { bpf_xdp_adjust_tail(ctx.ctx, delta) } != 0 {
+        return Err(());
+    }
+
+    Ok(xdp_action::XDP_TX)
+}
+
+/// Sum of the IPv4 pseudo header of the rewritten packet
+fn ipv4_pseudo_sum(ctx: &XdpContext, proto: IpProto, len: usize) -> Result<u64, ()> {
+    // Source and destination addresses are the last 8 bytes of the header
+    let sum = sum_words(ctx, EthHdr::LEN + 12, 8)?;
+    Ok(sum + proto as u64 + len as u64)
+}
+
+/// Sum of the IPv6 pseudo header of the rewritten packet
+fn ipv6_pseudo_sum(ctx: &XdpContext, proto: IpProto, len: usize) -> Result<u64, ()> {
+    // Source and destination addresses are the last 32 bytes of the header
+    let sum = sum_words(ctx, EthHdr::LEN + 8, 32)?;
+    Ok(sum + proto as u64 + len as u64)
+}
+
+/// Sum of the `len` bytes at `offset` as big endian 16 bit words. `len` has to be even
+fn sum_words(ctx: &XdpContext, offset: usize, len: usize) -> Result<u64, ()> {
+    let mut sum = 0;
+    for i in 0..len / 2 {
+        let word: *const [u8; 2] = ptr_at(ctx, offset + i * 2).ok_or(())?;
+        sum += u16::from_be_bytes(unsafe { *word }) as u64;
+    }
+
+    Ok(sum)
+}
+
+/// Internet checksum of a sum of 16 bit words
+fn checksum(mut sum: u64) -> u16 {
+    for _ in 0..4 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
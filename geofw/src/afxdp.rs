@@ -0,0 +1,522 @@
+//! The AF_XDP datapath. geofw_xsk redirects the unicast IP packets of every interface to an
+//! AF_XDP socket on each rx queue, and the top level rules are applied here, with the maps the
+//! XDP program would use. Passed packets are handed back to the kernel through a TAP device.
+
+use crate::{
+    maps::{self, walk_tree, TREE_MAPS},
+    tap::Tap,
+    xsk::{ifindex, rx_queues, XskSocket},
+    Config,
+};
+use aya::{
+    maps::{lpm_trie::Key, Array, HashMap, LpmTrie, MapData, PerCpuArray, PerCpuValues, XskMap},
+    Ebpf,
+};
+use fxhash::FxHashMap;
+use geofw_common::{
+    compound_rules, is_listed, Counter, CountryAction, LookupBackend, LookupParameters,
+    MaxmindDbType, Mode, Precedence, ProgramParameters, ALLOW_MARKER, BLOCK_MARKER, COUNTER_COUNT,
+    MAX_INTERFACES, MAX_QUEUES,
+};
+use log::{info, warn};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::fd::{AsFd, AsRawFd},
+    thread,
+    time::{Duration, Instant},
+};
+
+// How often the parameters are read again and the counters are added to STATS
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sources whose records are cached, the cache is emptied when it grows past this
+const CACHE_SIZE: usize = 64 * 1024;
+
+/// Binds an AF_XDP socket to every rx queue of the interfaces and starts the thread that
+/// filters the packets geofw_xsk redirects to them
+pub fn start(config: &Config, ebpf: &mut Ebpf) -> Result<(), String> {
+    let interfaces = config.interfaces();
+    if interfaces.len() > MAX_INTERFACES as usize {
+        return Err(format!(
+            "the afxdp datapath supports at most {} interfaces",
+            MAX_INTERFACES
+        ));
+    }
+
+    let mut xsk_map = XskMap::try_from(
+        ebpf.map_mut("DATAPATH_SOCKETS")
+            .ok_or("error in getting datapath socket map")?,
+    )
+    .map_err(|e| e.to_string())?;
+
+    let mut sockets = vec![];
+    let mut taps = vec![];
+    let mut slots = vec![];
+    for (slot, interface) in interfaces.iter().enumerate() {
+        let tap = Tap::create(interface)
+            .map_err(|e| format!("error in creating TAP device for {}: {}", interface, e))?;
+        info!(
+            "re-injecting passed traffic of {} through {}",
+            interface,
+            tap.name()
+        );
+        taps.push(tap);
+
+        let slot = slot as u32;
+        let queues = rx_queues(interface)?;
+        if queues > MAX_QUEUES {
+            return Err(format!(
+                "{} has {} rx queues, the afxdp datapath supports at most {}",
+                interface, queues, MAX_QUEUES
+            ));
+        }
+
+        for queue in 0..queues {
+            let socket = XskSocket::bind(interface, queue).map_err(|e| {
+                format!(
+                    "error in binding AF_XDP socket to {} queue {}: {}",
+                    interface, queue, e
+                )
+            })?;
+            xsk_map
+                .set(slot * MAX_QUEUES + queue, socket.as_fd(), 0)
+                .map_err(|e| e.to_string())?;
+            sockets.push((socket, slot as usize));
+        }
+
+        slots.push((ifindex(interface)?, slot));
+    }
+
+    let mut interface_map: HashMap<&mut MapData, u32, u32> = HashMap::try_from(
+        ebpf.map_mut("DATAPATH_INTERFACES")
+            .ok_or("error in getting datapath interface map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    for (ifindex, slot) in slots {
+        interface_map
+            .insert(ifindex, slot, 0)
+            .map_err(|e| e.to_string())?;
+    }
+
+    let filter = Filter::open(ebpf)?;
+    info!(
+        "filtering {} in userspace with {} AF_XDP sockets",
+        interfaces.join(", "),
+        sockets.len()
+    );
+
+    thread::spawn(move || filter_loop(filter, sockets, taps));
+
+    Ok(())
+}
+
+fn filter_loop(mut filter: Filter, mut sockets: Vec<(XskSocket, usize)>, taps: Vec<Tap>) {
+    let mut fds: Vec<libc::pollfd> = sockets
+        .iter()
+        .map(|(s, _)| libc::pollfd {
+            fd: s.as_fd().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let mut refreshed_at = Instant::now();
+    // Frames that couldn't be re-injected since the last refresh, and the last error
+    let mut failed = (0, None);
+
+    loop {
+        let timeout = REFRESH_INTERVAL.saturating_sub(refreshed_at.elapsed());
+        let ret = unsafe {
+            libc::poll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                timeout.as_millis() as libc::c_int,
+            )
+        };
+        if ret < 0 {
+            warn!(
+                "error in polling AF_XDP sockets: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+
+        for (socket, tap) in sockets.iter_mut() {
+            socket.recv(0, |frame, _| {
+                // Frames geofw_xsk shouldn't have redirected are passed like malformed ones
+                let pass = source(frame).is_none_or(|addr| filter.pass(addr));
+                if !pass {
+                    return;
+                }
+                if let Err(e) = taps[*tap].write(frame) {
+                    failed = (failed.0 + 1, Some(e));
+                }
+            });
+        }
+
+        if refreshed_at.elapsed() >= REFRESH_INTERVAL {
+            if let (count, Some(e)) = &failed {
+                warn!("error in re-injecting {} frames: {}", count, e);
+            }
+            failed = (0, None);
+            filter.refresh();
+            refreshed_at = Instant::now();
+        }
+    }
+}
+
+/// Source of an untagged Ethernet frame, the only ones geofw_xsk redirects
+fn source(frame: &[u8]) -> Option<IpAddr> {
+    let ether_type = u16::from_be_bytes(frame.get(12..14)?.try_into().ok()?);
+
+    match ether_type {
+        0x0800 => {
+            let src: [u8; 4] = frame.get(26..30)?.try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(src)))
+        }
+        0x86dd => {
+            let src: [u8; 16] = frame.get(22..38)?.try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(src)))
+        }
+        _ => None,
+    }
+}
+
+/// What the XDP program reads from PARAMETERS and LOOKUP_PARAMETERS, read again every
+/// REFRESH_INTERVAL instead of for every packet
+#[derive(Default)]
+struct Settings {
+    params: LookupParameters,
+    precedence: Precedence,
+    compound_except: u32,
+    dry_run: bool,
+    /// Whether the top level rules are active, as set by their schedule
+    active: bool,
+}
+
+/// The maps of the XDP program the top level rules are evaluated with
+struct Filter {
+    parameters: HashMap<MapData, u8, u32>,
+    lookup_parameters: Array<MapData, LookupParameters>,
+    /// Indexed by MaxmindDbType
+    trees: [Option<Array<MapData, u64>>; 4],
+    prefixes: [Option<LpmTrie<MapData, [u8; 16], u32>>; 4],
+    allowed: LpmTrie<MapData, [u8; 16], u8>,
+    blocked: LpmTrie<MapData, [u8; 16], u8>,
+    feeds: LpmTrie<MapData, [u8; 16], u8>,
+    stats: PerCpuArray<MapData, u64>,
+    settings: Settings,
+    /// Records of recent sources, from the generation of LOOKUP_PARAMETERS in `settings`
+    cache: FxHashMap<IpAddr, [u32; 4]>,
+    /// Packets counted since the last refresh, added to STATS on the first CPU
+    counts: [u64; COUNTER_COUNT as usize],
+}
+
+impl Filter {
+    fn open(ebpf: &Ebpf) -> Result<Self, String> {
+        let map_error = |e: aya::maps::MapError| e.to_string();
+        let mut trees = [None, None, None, None];
+        let mut prefixes = [None, None, None, None];
+        for (name, db_type) in TREE_MAPS {
+            trees[db_type as usize] =
+                Some(Array::try_from(maps::share(ebpf, name)?).map_err(map_error)?);
+            prefixes[db_type as usize] = Some(
+                LpmTrie::try_from(maps::share(ebpf, maps::prefix_map(db_type))?)
+                    .map_err(map_error)?,
+            );
+        }
+
+        let mut filter = Self {
+            parameters: HashMap::try_from(maps::share(ebpf, "PARAMETERS")?).map_err(map_error)?,
+            lookup_parameters: Array::try_from(maps::share(ebpf, "LOOKUP_PARAMETERS")?)
+                .map_err(map_error)?,
+            trees,
+            prefixes,
+            allowed: LpmTrie::try_from(maps::share(ebpf, "ALLOWED_CIDRS")?).map_err(map_error)?,
+            blocked: LpmTrie::try_from(maps::share(ebpf, "BLOCKED_CIDRS")?).map_err(map_error)?,
+            feeds: LpmTrie::try_from(maps::share(ebpf, "FEED_CIDRS")?).map_err(map_error)?,
+            stats: PerCpuArray::try_from(maps::share(ebpf, "STATS")?).map_err(map_error)?,
+            settings: Settings::default(),
+            cache: FxHashMap::default(),
+            counts: [0; COUNTER_COUNT as usize],
+        };
+        filter.refresh();
+
+        Ok(filter)
+    }
+
+    fn parameter(&self, param: ProgramParameters) -> Option<u32> {
+        self.parameters.get(&(param as u8), 0).ok()
+    }
+
+    /// Adds the counts to STATS and reads the parameters again
+    fn refresh(&mut self) {
+        for (index, count) in self.counts.iter_mut().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let updated = self.stats.get(&(index as u32), 0).and_then(|values| {
+                let mut values: Vec<u64> = values.to_vec();
+                values[0] += *count;
+                self.stats
+                    .set(index as u32, PerCpuValues::try_from(values)?, 0)
+            });
+            match updated {
+                Ok(()) => *count = 0,
+                Err(e) => warn!("error in updating counter {}: {}", index, e),
+            }
+        }
+
+        let slot = self
+            .parameter(ProgramParameters::LookupParametersSlot)
+            .map_or(0, |v| v & 1);
+        let params = self.lookup_parameters.get(&slot, 0).unwrap_or_default();
+        if params.generation != self.settings.params.generation {
+            self.cache.clear();
+        }
+        self.settings = Settings {
+            params,
+            precedence: self
+                .parameter(ProgramParameters::Precedence)
+                .and_then(Precedence::from_value)
+                .unwrap_or_default(),
+            compound_except: self
+                .parameter(ProgramParameters::CompoundExcept)
+                .unwrap_or(0),
+            dry_run: self.parameter(ProgramParameters::DryRun).unwrap_or(0) != 0,
+            active: self
+                .parameter(ProgramParameters::ActivePolicies)
+                .is_none_or(|mask| mask & 1 != 0),
+        };
+    }
+
+    /// Applies the top level rules to `addr` and counts the packet, like `evaluate` in the eBPF
+    /// program does with policy 0
+    fn pass(&mut self, addr: IpAddr) -> bool {
+        let (pass, counter) = self.evaluate(addr);
+        self.counts[counter as usize] += 1;
+
+        pass || self.settings.dry_run
+    }
+
+    fn evaluate(&mut self, addr: IpAddr) -> (bool, Counter) {
+        let key = Key::new(128, key_of(addr));
+        if self.allowed.get(&key, 0).is_ok() {
+            return (true, Counter::Allowed);
+        }
+        if self.blocked.get(&key, 0).is_ok() {
+            return (false, Counter::BlocklistDropped);
+        }
+        if self.feeds.get(&key, 0).is_ok() {
+            return (false, Counter::FeedDropped);
+        }
+        if !self.settings.active {
+            return (true, Counter::Passed);
+        }
+
+        let records = match self.cache.get(&addr) {
+            Some(&records) => records,
+            None => {
+                if self.cache.len() >= CACHE_SIZE {
+                    self.cache.clear();
+                }
+                let records = [
+                    self.lookup(MaxmindDbType::Asn, addr),
+                    self.lookup(MaxmindDbType::Country, addr),
+                    self.lookup(MaxmindDbType::City, addr),
+                    self.lookup(MaxmindDbType::Anonymous, addr),
+                ];
+                self.cache.insert(addr, records);
+                records
+            }
+        };
+
+        decide(&self.settings, records)
+    }
+
+    /// Record of `addr` in the tree of `db_type`, 0 when there is none
+    fn lookup(&self, db_type: MaxmindDbType, addr: IpAddr) -> u32 {
+        let params = &self.settings.params;
+        if LookupBackend::from_value(params.backend) == Some(LookupBackend::Lpm) {
+            return self.prefixes[db_type as usize]
+                .as_ref()
+                .and_then(|p| p.get(&Key::new(128, key_of(addr)), 0).ok())
+                .unwrap_or(0);
+        }
+
+        let Some(map) = &self.trees[db_type as usize] else {
+            return 0;
+        };
+        walk_tree(map, &params.trees[db_type as usize], addr).unwrap_or_else(|e| {
+            warn!(
+                "error in looking up {} in the {} tree: {}",
+                addr, db_type, e
+            );
+            0
+        })
+    }
+}
+
+fn key_of(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(a) => a.to_ipv6_mapped().octets(),
+        IpAddr::V6(a) => a.octets(),
+    }
+}
+
+/// Whether a source with these ASN, country, city and anonymous records is passed, and the
+/// counter it's counted in. Suspect sources are passed, there's no inspection on this datapath
+fn decide(settings: &Settings, [asn, country, city, anonymous]: [u32; 4]) -> (bool, Counter) {
+    let mode = Mode::from_value(settings.params.mode).unwrap_or_default();
+    if mode == Mode::Allow {
+        // Anonymous sources are dropped even from allowed countries
+        if anonymous == BLOCK_MARKER {
+            return (false, Counter::AnonymousDropped);
+        }
+        if is_listed(asn, 0) || is_listed(country, 0) || is_listed(city, 0) {
+            return (true, Counter::Passed);
+        }
+        return (false, Counter::CountryDropped);
+    }
+
+    let allowed = asn == ALLOW_MARKER || country == ALLOW_MARKER;
+    if allowed && settings.precedence == Precedence::Allow {
+        return (true, Counter::Allowed);
+    }
+    if anonymous == BLOCK_MARKER {
+        return (false, Counter::AnonymousDropped);
+    }
+    if is_listed(asn, 0) {
+        return (false, Counter::AsnDropped);
+    }
+    if is_listed(country, 0) {
+        return match CountryAction::of_record(country, 0) {
+            Some(CountryAction::Log) => (true, Counter::CountryLogged),
+            _ => (false, Counter::CountryDropped),
+        };
+    }
+    if is_listed(city, 0) {
+        return (false, Counter::CityDropped);
+    }
+    let except = settings.compound_except;
+    let (country, asn) = (compound_rules(country), compound_rules(asn));
+    if (country & asn & !except) | (country & !asn & except) != 0 {
+        return (false, Counter::CountryDropped);
+    }
+
+    (true, Counter::Passed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geofw_common::{ACTION_MARKER, COMPOUND_MARKER};
+
+    fn frame(ether_type: u16, packet: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend(ether_type.to_be_bytes());
+        frame.extend(packet);
+        frame
+    }
+
+    fn settings(mode: Mode, precedence: Precedence) -> Settings {
+        Settings {
+            params: LookupParameters {
+                mode: mode as u32,
+                ..Default::default()
+            },
+            precedence,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sources() {
+        let mut ipv4 = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 6, 0, 0];
+        ipv4.extend([198, 51, 100, 7, 192, 0, 2, 1]);
+        assert_eq!(
+            source(&frame(0x0800, &ipv4)),
+            Some(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7)))
+        );
+
+        let addr: Ipv6Addr = "2001:db8::7".parse().unwrap();
+        let mut ipv6 = vec![0x60, 0, 0, 0, 0, 0, 6, 64];
+        ipv6.extend(addr.octets());
+        ipv6.extend(Ipv6Addr::LOCALHOST.octets());
+        assert_eq!(source(&frame(0x86dd, &ipv6)), Some(IpAddr::V6(addr)));
+
+        // ARP, VLAN tags and truncated headers
+        assert_eq!(source(&frame(0x0806, &ipv4)), None);
+        assert_eq!(source(&frame(0x8100, &ipv4)), None);
+        assert_eq!(source(&frame(0x0800, &ipv4[..14])), None);
+        assert_eq!(source(&[0; 10]), None);
+    }
+
+    #[test]
+    fn block_mode() {
+        let s = settings(Mode::Block, Precedence::Allow);
+        let log = ACTION_MARKER | (CountryAction::Log as u32) << 8 | 1;
+
+        assert_eq!(decide(&s, [0; 4]), (true, Counter::Passed));
+        assert_eq!(
+            decide(&s, [BLOCK_MARKER, 0, 0, 0]),
+            (false, Counter::AsnDropped)
+        );
+        assert_eq!(
+            decide(&s, [0, BLOCK_MARKER, 0, 0]),
+            (false, Counter::CountryDropped)
+        );
+        assert_eq!(
+            decide(&s, [0, 0, BLOCK_MARKER, 0]),
+            (false, Counter::CityDropped)
+        );
+        assert_eq!(
+            decide(&s, [0, 0, 0, BLOCK_MARKER]),
+            (false, Counter::AnonymousDropped)
+        );
+        assert_eq!(decide(&s, [0, log, 0, 0]), (true, Counter::CountryLogged));
+    }
+
+    #[test]
+    fn precedence() {
+        let records = [ALLOW_MARKER, BLOCK_MARKER, 0, BLOCK_MARKER];
+        assert_eq!(
+            decide(&settings(Mode::Block, Precedence::Allow), records),
+            (true, Counter::Allowed)
+        );
+        assert_eq!(
+            decide(&settings(Mode::Block, Precedence::Block), records),
+            (false, Counter::AnonymousDropped)
+        );
+    }
+
+    #[test]
+    fn allow_mode() {
+        let s = settings(Mode::Allow, Precedence::Allow);
+
+        assert_eq!(decide(&s, [0; 4]), (false, Counter::CountryDropped));
+        assert_eq!(decide(&s, [0, BLOCK_MARKER, 0, 0]), (true, Counter::Passed));
+        assert_eq!(decide(&s, [BLOCK_MARKER, 0, 0, 0]), (true, Counter::Passed));
+        assert_eq!(
+            decide(&s, [0, BLOCK_MARKER, 0, BLOCK_MARKER]),
+            (false, Counter::AnonymousDropped)
+        );
+    }
+
+    #[test]
+    fn compound() {
+        let mut s = settings(Mode::Block, Precedence::Allow);
+        let (country, asn) = (COMPOUND_MARKER | 0b11, COMPOUND_MARKER | 0b01);
+
+        // Rule 0 needs both, rule 1 only matches sources outside its ASNs
+        assert_eq!(
+            decide(&s, [asn, country, 0, 0]),
+            (false, Counter::CountryDropped)
+        );
+        assert_eq!(decide(&s, [0, country, 0, 0]), (true, Counter::Passed));
+        s.compound_except = 0b10;
+        assert_eq!(
+            decide(&s, [0, country, 0, 0]),
+            (false, Counter::CountryDropped)
+        );
+        assert!(decide(&s, [COMPOUND_MARKER | 0b10, country, 0, 0]).0);
+    }
+}
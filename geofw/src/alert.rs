@@ -0,0 +1,376 @@
+use crate::{
+    blocklist::Cidr,
+    db_path, drops,
+    maxmind::{Data, MaxmindDb},
+    metrics,
+    privacy::PrivacyConfig,
+    simulate, Config,
+};
+use fxhash::FxHashMap;
+use geofw_common::MaxmindDbType;
+use log::{error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+    sync::mpsc::{self, SyncSender},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// Logs the message and, if configured, posts it to the alert webhook. The payload uses the
+/// `text` field so it can be sent straight to a Slack compatible incoming webhook.
+pub fn alert(config: &Config, message: &str) {
+    error!("{}", message);
+
+    if let Some(url) = &config.alert_webhook {
+        post(url, message);
+    }
+}
+
+/// Posts the message to a Slack compatible incoming webhook
+pub fn post(url: &str, message: &str) {
+    post_json(
+        url,
+        &serde_json::json!({ "text": format!("geofw: {}", message) }),
+    );
+}
+
+fn post_json(url: &str, payload: &serde_json::Value) {
+    if let Err(e) = ureq::post(url)
+        .set("Content-Type", "application/json")
+        .send_string(&payload.to_string())
+    {
+        warn!("error in sending message to webhook: {}", e);
+    }
+}
+
+/// Drops are counted in windows of this length, the thresholds are per window
+pub const DROP_ALERT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Number of prefixes listed in an alert
+const TOP_PREFIXES: usize = 5;
+
+const PAGERDUTY_EVENTS_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+
+/// Alerts on the rate of dropped packets, posted to a webhook when a rule's threshold is
+/// crossed and again when it is resolved. Needs `drop_events`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DropAlertConfig {
+    /// Where alerts are posted, defaults to `alert_webhook`, or to the PagerDuty Events API
+    /// with the pagerduty format
+    #[serde(default)]
+    pub webhook: Option<String>,
+
+    #[serde(default)]
+    pub format: WebhookFormat,
+
+    /// Integration key of the PagerDuty service, needed with the pagerduty format
+    #[serde(default)]
+    pub routing_key: Option<String>,
+
+    pub rules: Vec<DropAlertRule>,
+
+    /// A firing alert is resolved once its drops stay below this fraction of the threshold
+    #[serde(default = "default_clear_ratio")]
+    pub clear_ratio: f64,
+
+    /// Minutes the drops have to stay below `clear_ratio` of the threshold before an alert
+    /// is resolved
+    #[serde(default = "default_clear_after")]
+    pub clear_after: u32,
+}
+
+fn default_clear_ratio() -> f64 {
+    0.5
+}
+
+fn default_clear_after() -> u32 {
+    5
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// `{"text": ...}`, understood by Slack, Mattermost and most chat webhooks
+    #[default]
+    Slack,
+    /// PagerDuty Events API v2, alerts are triggered and resolved with the same dedup key
+    Pagerduty,
+}
+
+/// Fires when the drops from the sources it matches exceed `per_minute`. Without `country`
+/// and `asn` it matches every source
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DropAlertRule {
+    /// ISO code of the country the drops come from
+    #[serde(default)]
+    pub country: Option<String>,
+
+    /// AS the drops come from
+    #[serde(default)]
+    pub asn: Option<u32>,
+
+    pub per_minute: u64,
+}
+
+impl DropAlertRule {
+    fn matches(&self, country: Option<&str>, asn: Option<u32>) -> bool {
+        self.country
+            .as_deref()
+            .is_none_or(|c| country.is_some_and(|country| country.eq_ignore_ascii_case(c)))
+            && self.asn.is_none_or(|a| asn == Some(a))
+    }
+}
+
+impl Display for DropAlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.country, self.asn) {
+            (Some(country), Some(asn)) => write!(f, "{} AS{}", country, asn),
+            (Some(country), None) => write!(f, "{}", country),
+            (None, Some(asn)) => write!(f, "AS{}", asn),
+            (None, None) => write!(f, "all sources"),
+        }
+    }
+}
+
+/// Starts the thread that evaluates `drop_alerts`. It is sent the drops of each source in
+/// every `DROP_ALERT_WINDOW`, None when no alerts are configured
+pub fn start_drop_alerts(config: &Config) -> Option<SyncSender<FxHashMap<IpAddr, u64>>> {
+    let alerts = config.drop_alerts.clone()?;
+    if alerts.rules.is_empty() {
+        return None;
+    }
+    if !config.drop_events {
+        warn!("drop_alerts needs drop_events, no drop alerts are sent");
+        return None;
+    }
+
+    let url = match (alerts.webhook.clone(), alerts.format) {
+        (Some(url), _) => url,
+        (None, WebhookFormat::Pagerduty) => PAGERDUTY_EVENTS_URL.to_string(),
+        (None, WebhookFormat::Slack) => {
+            let Some(url) = config.alert_webhook.clone() else {
+                warn!("drop_alerts has no webhook, set drop_alerts.webhook or alert_webhook");
+                return None;
+            };
+            url
+        }
+    };
+    if alerts.format == WebhookFormat::Pagerduty && alerts.routing_key.is_none() {
+        warn!("drop_alerts.routing_key is needed with the pagerduty format");
+        return None;
+    }
+
+    let mut state = DropAlerts {
+        firing: vec![None; alerts.rules.len()],
+        country: alerts
+            .rules
+            .iter()
+            .any(|r| r.country.is_some())
+            .then(|| CachedDb::new(db_path(config, MaxmindDbType::Country))),
+        asn: alerts
+            .rules
+            .iter()
+            .any(|r| r.asn.is_some())
+            .then(|| CachedDb::new(db_path(config, MaxmindDbType::Asn))),
+        // Prefixes are no more specific than the addresses allowed in logs
+        prefixes: PrivacyConfig {
+            ipv4_prefix: config.privacy.ipv4_prefix.min(24),
+            ipv6_prefix: config.privacy.ipv6_prefix.min(48),
+            hash_key: None,
+        },
+        hostname: metrics::hostname(),
+        url,
+        config: alerts,
+    };
+
+    // A window that arrives while the previous one is evaluated waits, later ones are skipped
+    let (tx, rx) = mpsc::sync_channel(1);
+    thread::spawn(move || {
+        for sources in rx {
+            state.evaluate(sources);
+        }
+    });
+
+    Some(tx)
+}
+
+/// A cached database, opened again when a refresh replaces it
+struct CachedDb {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    db: Option<MaxmindDb>,
+}
+
+impl CachedDb {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            modified: None,
+            db: None,
+        }
+    }
+
+    fn get(&mut self) -> Option<&MaxmindDb> {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified != self.modified {
+            self.modified = modified;
+            self.db = match MaxmindDb::from_file(&self.path.to_string_lossy()) {
+                Ok(db) => Some(db),
+                Err(e) => {
+                    warn!("error in opening {}: {}", self.path.display(), e);
+                    None
+                }
+            };
+        }
+
+        self.db.as_ref()
+    }
+}
+
+/// A rule whose alert is firing
+#[derive(Debug, Clone, Copy)]
+struct Firing {
+    /// Consecutive windows below the clear threshold
+    quiet: u32,
+}
+
+struct DropAlerts {
+    config: DropAlertConfig,
+    url: String,
+    hostname: String,
+    /// Firing state of each rule
+    firing: Vec<Option<Firing>>,
+    country: Option<CachedDb>,
+    asn: Option<CachedDb>,
+    /// Truncates sources to the prefixes listed in alerts
+    prefixes: PrivacyConfig,
+}
+
+impl DropAlerts {
+    fn evaluate(&mut self, sources: FxHashMap<IpAddr, u64>) {
+        let rules = &self.config.rules;
+        let mut totals = vec![0u64; rules.len()];
+        let mut prefixes: Vec<FxHashMap<Cidr, u64>> = vec![FxHashMap::default(); rules.len()];
+
+        let country_db = self.country.as_mut().and_then(CachedDb::get);
+        let asn_db = self.asn.as_mut().and_then(CachedDb::get);
+        for (addr, count) in sources {
+            let country = country_db.and_then(|db| match db.lookup(addr) {
+                Some(Data::Map(data)) => simulate::country_code(&data),
+                _ => None,
+            });
+            let asn = asn_db.and_then(|db| match db.lookup(addr) {
+                Some(Data::Map(data)) => simulate::asn(&data),
+                _ => None,
+            });
+            let prefix = Cidr {
+                addr: self.prefixes.truncate(addr),
+                prefix_len: match addr {
+                    IpAddr::V4(_) => self.prefixes.ipv4_prefix,
+                    IpAddr::V6(_) => self.prefixes.ipv6_prefix,
+                },
+            };
+
+            for (i, rule) in rules.iter().enumerate() {
+                if rule.matches(country.as_deref(), asn) {
+                    totals[i] += count;
+                    *prefixes[i].entry(prefix).or_default() += count;
+                }
+            }
+        }
+
+        for (i, prefixes) in prefixes.into_iter().enumerate() {
+            let rule = &self.config.rules[i];
+            let total = totals[i];
+            match &mut self.firing[i] {
+                None if total > rule.per_minute => {
+                    self.firing[i] = Some(Firing { quiet: 0 });
+                    self.notify(i, total, prefixes, true);
+                }
+                None => (),
+                Some(firing) => {
+                    if (total as f64) < rule.per_minute as f64 * self.config.clear_ratio {
+                        firing.quiet += 1;
+                    } else {
+                        firing.quiet = 0;
+                    }
+                    if firing.quiet >= self.config.clear_after.max(1) {
+                        self.firing[i] = None;
+                        self.notify(i, total, prefixes, false);
+                    }
+                }
+            }
+        }
+    }
+
+    fn notify(&self, index: usize, total: u64, prefixes: FxHashMap<Cidr, u64>, firing: bool) {
+        let rule = &self.config.rules[index];
+        let mut prefixes: Vec<_> = prefixes.into_iter().collect();
+        prefixes.sort_unstable_by_key(|&(_, count)| std::cmp::Reverse(count));
+        prefixes.truncate(TOP_PREFIXES);
+
+        let message = if firing {
+            let top: Vec<String> = prefixes
+                .iter()
+                .map(|(prefix, count)| format!("{} ({})", prefix, drops::abbreviate(*count)))
+                .collect();
+            format!(
+                "{} drops/min from {} exceed {}/min, top prefixes {}",
+                drops::abbreviate(total),
+                rule,
+                rule.per_minute,
+                top.join(", ")
+            )
+        } else {
+            format!(
+                "drops from {} are back below {}/min, {} in the last minute",
+                rule,
+                rule.per_minute,
+                drops::abbreviate(total)
+            )
+        };
+        if firing {
+            warn!("{}", message);
+        } else {
+            info!("{}", message);
+        }
+
+        let details = serde_json::json!({
+            "state": if firing { "firing" } else { "resolved" },
+            "rule": rule.to_string(),
+            "country": rule.country,
+            "asn": rule.asn,
+            "threshold_per_minute": rule.per_minute,
+            "drops_per_minute": total,
+            "prefixes": prefixes
+                .iter()
+                .map(|(prefix, count)| serde_json::json!({ "prefix": prefix, "drops": count }))
+                .collect::<Vec<_>>(),
+            "host": self.hostname,
+        });
+        let payload = match self.config.format {
+            WebhookFormat::Slack => serde_json::json!({
+                "text": format!("geofw on {}: {}", self.hostname, message),
+                "geofw": details,
+            }),
+            WebhookFormat::Pagerduty => serde_json::json!({
+                "routing_key": self.config.routing_key,
+                "event_action": if firing { "trigger" } else { "resolve" },
+                "dedup_key": format!("geofw-{}-drops-{}", self.hostname, index),
+                "payload": {
+                    "summary": message,
+                    "source": self.hostname,
+                    "severity": "warning",
+                    "component": rule.to_string(),
+                    "custom_details": details,
+                },
+            }),
+        };
+
+        post_json(&self.url, &payload);
+    }
+}
@@ -0,0 +1,44 @@
+use crate::maxmind::Data;
+use fxhash::FxHashMap;
+use serde_derive::{Deserialize, Serialize};
+
+/// Flags of the GeoIP2-Anonymous-IP database to block. Sources with any of the enabled flags
+/// are dropped on every interface, in allow mode too
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnonymousIp {
+    /// Any of the other flags
+    pub anonymous: bool,
+    pub anonymous_vpn: bool,
+    pub hosting_provider: bool,
+    pub public_proxy: bool,
+    pub residential_proxy: bool,
+    pub tor_exit_node: bool,
+}
+
+impl AnonymousIp {
+    /// The database fields of the enabled flags
+    fn fields(&self) -> impl Iterator<Item = &'static str> + '_ {
+        [
+            (self.anonymous, "is_anonymous"),
+            (self.anonymous_vpn, "is_anonymous_vpn"),
+            (self.hosting_provider, "is_hosting_provider"),
+            (self.public_proxy, "is_public_proxy"),
+            (self.residential_proxy, "is_residential_proxy"),
+            (self.tor_exit_node, "is_tor_exit_node"),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, field)| enabled.then_some(field))
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.fields().next().is_some()
+    }
+
+    /// The enabled flags set in the record `data` points to
+    pub fn flagged(&self, data: &FxHashMap<&[u8], Data>) -> Vec<&'static str> {
+        self.fields()
+            .filter(|field| matches!(data.get(field.as_bytes()), Some(Data::Boolean(true))))
+            .collect()
+    }
+}
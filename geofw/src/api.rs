@@ -0,0 +1,267 @@
+use crate::{
+    auth::{AuthError, Scope, Tokens},
+    blocklist::Cidr,
+    control::{self, Command, Message, Response},
+    drops::DropFeed,
+    policy::Policy,
+    tls::TlsConfig,
+};
+use log::{debug, info, warn};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// Request bodies longer than this are refused
+const MAX_BODY_LEN: usize = 1024 * 1024;
+
+/// An HTTP API with the operations of the control socket, for managing geofw remotely
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiConfig {
+    /// Address to listen on, like 0.0.0.0:9443
+    pub listen: String,
+
+    /// Serve over TLS. With `tls.ca` clients have to present a certificate signed by it
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Deserialize)]
+struct CidrBody {
+    cidr: Cidr,
+}
+
+/// What a request asks for
+enum Route {
+    Command(Command),
+    /// Stream drop events as lines of JSON until the client disconnects
+    Drops,
+}
+
+/// Starts the API on `config.listen`. Commands are answered by the main loop through `tx`,
+/// like those of the control socket, and authorized with the same tokens
+pub fn serve(
+    config: &ApiConfig,
+    tokens: Tokens,
+    tx: mpsc::Sender<Message>,
+    feed: DropFeed,
+) -> Result<(), String> {
+    let listener = TcpListener::bind(&config.listen)
+        .map_err(|e| format!("error in listening on {}: {}", config.listen, e))?;
+    let tls = config
+        .tls
+        .as_ref()
+        .map(TlsConfig::server_config)
+        .transpose()?;
+    if config.tls.as_ref().is_none_or(|t| t.ca.is_none()) {
+        warn!(
+            "the API on {} doesn't require client certificates, set tls.ca to require them",
+            config.listen
+        );
+    }
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let tokens = tokens.clone();
+            let tx = tx.clone();
+            let feed = feed.clone();
+            let tls = tls.clone();
+            thread::spawn(move || {
+                if let Err(e) = accept(stream, tls, &tokens, &tx, &feed) {
+                    debug!("error in serving API client: {}", e);
+                }
+            });
+        }
+    });
+
+    info!("serving API on {}", config.listen);
+
+    Ok(())
+}
+
+fn accept(
+    stream: TcpStream,
+    tls: Option<Arc<ServerConfig>>,
+    tokens: &Tokens,
+    tx: &mpsc::Sender<Message>,
+    feed: &DropFeed,
+) -> Result<(), String> {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .map_err(|e| e.to_string())?;
+
+    match tls {
+        Some(tls) => {
+            let conn = ServerConnection::new(tls).map_err(|e| e.to_string())?;
+            handle(StreamOwned::new(conn, stream), tokens, tx, feed)
+        }
+        None => handle(stream, tokens, tx, feed),
+    }
+}
+
+fn handle(
+    stream: impl Read + Write,
+    tokens: &Tokens,
+    tx: &mpsc::Sender<Message>,
+    feed: &DropFeed,
+) -> Result<(), String> {
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request).map_err(|e| e.to_string())?;
+    let mut authorization = None;
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        let n = reader.read_line(&mut line).map_err(|e| e.to_string())?;
+        if n == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    if content_length > MAX_BODY_LEN {
+        let mut stream = reader.into_inner();
+        return respond(
+            &mut stream,
+            "413 Payload Too Large",
+            &Response::error("request body is too long"),
+        );
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    let mut stream = reader.into_inner();
+
+    let (method, path) = match request.split_whitespace().collect::<Vec<_>>()[..] {
+        [method, path, _] => (method, path),
+        _ => {
+            return respond(
+                &mut stream,
+                "400 Bad Request",
+                &Response::error("invalid request"),
+            )
+        }
+    };
+    let route = match route(method, path, &body) {
+        Ok(route) => route,
+        Err((status, error)) => return respond(&mut stream, status, &Response::error(error)),
+    };
+
+    let scope = match &route {
+        Route::Command(command) => command.scope(),
+        Route::Drops => Scope::Read,
+    };
+    match tokens.authorize_header(authorization.as_deref(), scope) {
+        Err(AuthError::Unauthenticated) => {
+            return respond(
+                &mut stream,
+                "401 Unauthorized",
+                &Response::error("unauthenticated"),
+            )
+        }
+        Err(AuthError::Forbidden) => {
+            return respond(&mut stream, "403 Forbidden", &Response::error("forbidden"))
+        }
+        Ok(Some(name)) => debug!("API request {} {} token = {}", method, path, name),
+        Ok(None) => (),
+    }
+
+    match route {
+        Route::Command(command) => {
+            let response = control::dispatch(command, tx);
+            let status = if response.ok {
+                "200 OK"
+            } else {
+                "400 Bad Request"
+            };
+            respond(&mut stream, status, &response)
+        }
+        Route::Drops => stream_drops(&mut stream, feed),
+    }
+}
+
+fn route(method: &str, path: &str, body: &[u8]) -> Result<Route, (&'static str, String)> {
+    let json_error = |e: serde_json::Error| ("400 Bad Request", format!("invalid body: {}", e));
+
+    let command = match (method, path) {
+        ("GET", "/v1/status") => Command::Status,
+        ("GET", "/v1/stats") => Command::Stats,
+        ("GET", "/v1/policies") => Command::Policies,
+        ("PUT", "/v1/policies") => Command::SetPolicies {
+            policies: serde_json::from_slice::<Vec<Policy>>(body).map_err(json_error)?,
+        },
+        ("POST", "/v1/reload") => Command::Reload,
+        ("POST", "/v1/refresh") => Command::Refresh,
+        ("POST", "/v1/block") => Command::Block {
+            cidr: serde_json::from_slice::<CidrBody>(body)
+                .map_err(json_error)?
+                .cidr,
+        },
+        ("POST", "/v1/unblock") => Command::Unblock {
+            cidr: serde_json::from_slice::<CidrBody>(body)
+                .map_err(json_error)?
+                .cidr,
+        },
+        ("GET", "/v1/drops") => return Ok(Route::Drops),
+        ("GET", path) if path.starts_with("/v1/lookup/") => {
+            let addr = &path["/v1/lookup/".len()..];
+            Command::Lookup {
+                addr: addr.parse::<IpAddr>().map_err(|e| {
+                    (
+                        "400 Bad Request",
+                        format!("invalid address {}: {}", addr, e),
+                    )
+                })?,
+            }
+        }
+        _ => return Err(("404 Not Found", format!("no route for {} {}", method, path))),
+    };
+
+    Ok(Route::Command(command))
+}
+
+/// Writes every drop event as a line of JSON. The body ends when the client disconnects
+fn stream_drops(stream: &mut impl Write, feed: &DropFeed) -> Result<(), String> {
+    let events = feed.subscribe();
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n",
+        )
+        .and_then(|_| stream.flush())
+        .map_err(|e| e.to_string())?;
+
+    for line in events {
+        stream
+            .write_all(line.as_bytes())
+            .and_then(|_| stream.flush())
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+fn respond(stream: &mut impl Write, status: &str, response: &Response) -> Result<(), String> {
+    let body = serde_json::to_string(response).map_err(|e| e.to_string())?;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .and_then(|_| stream.flush())
+    .map_err(|e| e.to_string())
+}
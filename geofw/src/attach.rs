@@ -0,0 +1,593 @@
+use aya::{
+    maps::{Map, MapData, ProgramArray},
+    programs::{
+        cgroup_skb::CgroupSkbLinkId,
+        links::{FdLink, PinnedLink},
+        loaded_programs,
+        tc::{self, SchedClassifierLinkId},
+        xdp::{XdpLink, XdpLinkId},
+        CgroupAttachMode, CgroupSkb, CgroupSkbAttachType, ProgramInfo, ProgramType,
+        SchedClassifier, TcAttachType, Xdp, XdpFlags,
+    },
+    Ebpf,
+};
+use fxhash::FxHashMap;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{fs::File, io, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum XdpMode {
+    /// Let the kernel pick, native mode if the driver supports it and generic mode otherwise
+    Auto,
+    /// Native mode in the driver
+    Drv,
+    /// Generic mode, slower but works with every driver
+    Skb,
+    /// Offloaded to the NIC, on cards that can run XDP programs like Netronome's
+    Hw,
+}
+
+impl XdpMode {
+    fn flags(&self) -> XdpFlags {
+        match self {
+            XdpMode::Auto => XdpFlags::default(),
+            XdpMode::Drv => XdpFlags::DRV_MODE,
+            XdpMode::Skb => XdpFlags::SKB_MODE,
+            XdpMode::Hw => XdpFlags::HW_MODE,
+        }
+    }
+}
+
+/// Offloaded maps live in the NIC's memory, maps larger than this are unlikely to fit
+const OFFLOAD_MAP_BUDGET: u64 = 64 * 1024 * 1024;
+
+/// Reasons the program can't be offloaded to a NIC. Offloaded programs can only use arrays and
+/// hash maps that fit in the NIC's memory, and they can't tail call, redirect or write to ring
+/// buffers
+pub fn offload_blockers(ebpf: &Ebpf) -> Vec<String> {
+    let mut blockers = vec![];
+    for (name, map) in ebpf.maps() {
+        let data = match map {
+            Map::Array(data) | Map::HashMap(data) => data,
+            Map::LpmTrie(_) => {
+                blockers.push(format!("{} is an LPM trie", name));
+                continue;
+            }
+            Map::LruHashMap(_) | Map::PerCpuLruHashMap(_) => {
+                blockers.push(format!("{} is an LRU hash map", name));
+                continue;
+            }
+            Map::PerCpuArray(_) | Map::PerCpuHashMap(_) => {
+                blockers.push(format!("{} is a per-CPU map", name));
+                continue;
+            }
+            Map::RingBuf(_) => {
+                blockers.push(format!("{} is a ring buffer", name));
+                continue;
+            }
+            Map::ProgramArray(_) => {
+                blockers.push(format!("{} is used for tail calls", name));
+                continue;
+            }
+            Map::DevMap(_) | Map::DevMapHash(_) | Map::CpuMap(_) | Map::XskMap(_) => {
+                blockers.push(format!("{} is used for redirects", name));
+                continue;
+            }
+            _ => {
+                blockers.push(format!("{} has a map type NICs don't support", name));
+                continue;
+            }
+        };
+
+        let Ok(info) = data.info() else {
+            continue;
+        };
+        let size = (info.key_size() + info.value_size()) as u64 * info.max_entries() as u64;
+        if size > OFFLOAD_MAP_BUDGET {
+            blockers.push(format!("{} takes {}MiB", name, size / (1024 * 1024)));
+        }
+    }
+
+    blockers
+}
+
+pub fn default_xdp_mode() -> Vec<XdpMode> {
+    vec![XdpMode::Auto]
+}
+
+/// Where the filter runs on an interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Datapath {
+    /// XDP, falling back to tc on interfaces the XDP program can't be attached to
+    #[default]
+    Auto,
+    Xdp,
+    /// A tc ingress classifier, for drivers and kernels without XDP
+    Tc,
+    /// Packets are redirected to AF_XDP sockets and filtered in userspace, for systems where
+    /// neither of the in-kernel filters can run
+    AfXdp,
+}
+
+/// How the filter is attached to an interface
+pub enum Link {
+    Xdp(XdpLinkId),
+    /// An XDP link pinned in bpffs, it outlives geofw unless it is detached
+    Pinned(PinnedLink),
+    /// An XDP link pinned by the previous instance, its program keeps filtering until this
+    /// instance's maps are filled and `take_over` moves the link to this instance's program
+    Adopted(PinnedLink),
+    Tc(SchedClassifierLinkId),
+    /// The XDP program that redirects packets to the AF_XDP datapath
+    AfXdp(XdpLinkId),
+}
+
+impl Link {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Link::Xdp(_) | Link::Pinned(_) | Link::Adopted(_) => "xdp",
+            Link::Tc(_) => "tc",
+            Link::AfXdp(_) => "afxdp",
+        }
+    }
+}
+
+/// Interfaces the filter is attached to
+pub type Links = FxHashMap<String, Link>;
+
+/// Interfaces the egress program is attached to
+pub type EgressLinks = FxHashMap<String, SchedClassifierLinkId>;
+
+/// cgroups the cgroup program is attached to
+pub type CgroupLinks = FxHashMap<String, CgroupSkbLinkId>;
+
+/// Attaches the filter to `interface` as an XDP program or a tc classifier, or the redirect to
+/// the AF_XDP datapath, depending on `datapath`. XDP links are pinned in `pins` when it is set
+pub fn attach(
+    ebpf: &mut Ebpf,
+    interface: &str,
+    modes: &[XdpMode],
+    datapath: Datapath,
+    pins: Option<&Path>,
+) -> Result<Link, String> {
+    match datapath {
+        Datapath::Tc => return attach_ingress(ebpf, interface).map(Link::Tc),
+        Datapath::AfXdp => return attach_xdp(ebpf, "geofw_xsk", interface, modes).map(Link::AfXdp),
+        Datapath::Auto | Datapath::Xdp => {}
+    }
+
+    match attach_xdp(ebpf, "geofw", interface, modes) {
+        Ok(link) => match pins {
+            Some(dir) => pin_xdp(ebpf, interface, modes, link, dir),
+            None => Ok(Link::Xdp(link)),
+        },
+        Err(e) if datapath == Datapath::Auto => {
+            warn!("{}, falling back to tc", e);
+            attach_ingress(ebpf, interface).map(Link::Tc)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Attaches the XDP program `name` to `interface` with the first of `modes` that works. `hw` is
+/// skipped when the program can't be offloaded
+fn attach_xdp(
+    ebpf: &mut Ebpf,
+    name: &str,
+    interface: &str,
+    modes: &[XdpMode],
+) -> Result<XdpLinkId, String> {
+    let blockers = if modes.contains(&XdpMode::Hw) {
+        offload_blockers(ebpf)
+    } else {
+        vec![]
+    };
+    let program: &mut Xdp = match ebpf.program_mut(name).map(TryInto::try_into) {
+        Some(Ok(p)) => p,
+        _ => return Err("error in getting the XDP program".to_string()),
+    };
+
+    let mut errors = vec![];
+    for mode in modes {
+        if *mode == XdpMode::Hw && !blockers.is_empty() {
+            info!(
+                "not offloading to {}, the program can't be offloaded: {}",
+                interface,
+                blockers.join(", ")
+            );
+            errors.push(format!("{:?}: can't be offloaded", mode));
+            continue;
+        }
+        match program.attach(interface, mode.flags()) {
+            Ok(link) => {
+                info!("attached to {} mode = {:?}", interface, mode);
+                return Ok(link);
+            }
+            Err(e) => {
+                debug!(
+                    "error in attaching to {} mode = {:?}: {}",
+                    interface, mode, e
+                );
+                errors.push(format!("{:?}: {}", mode, e));
+            }
+        }
+    }
+
+    Err(format!(
+        "error in attaching to {} with any of the xdp_mode entries: {}",
+        interface,
+        errors.join(", ")
+    ))
+}
+
+/// Moves `link` out of the program and pins it. Kernels without bpf_link attach XDP programs
+/// over netlink and those can't be pinned, the program is attached again without a pin
+fn pin_xdp(
+    ebpf: &mut Ebpf,
+    interface: &str,
+    modes: &[XdpMode],
+    link: XdpLinkId,
+    dir: &Path,
+) -> Result<Link, String> {
+    let program: &mut Xdp = match ebpf.program_mut("geofw").map(TryInto::try_into) {
+        Some(Ok(p)) => p,
+        _ => return Err("error in getting the XDP program".to_string()),
+    };
+    let link = program
+        .take_link(link)
+        .map_err(|e| format!("error in taking link of {}: {}", interface, e))?;
+
+    match FdLink::try_from(link) {
+        Ok(link) => crate::pin::pin_link(link, dir, interface).map(Link::Pinned),
+        Err(e) => {
+            warn!(
+                "not pinning link of {}, the kernel attached it without bpf_link: {}",
+                interface, e
+            );
+            attach_xdp(ebpf, "geofw", interface, modes).map(Link::Xdp)
+        }
+    }
+}
+
+/// Attaches the tc fallback to the ingress of `interface`
+fn attach_ingress(ebpf: &mut Ebpf, interface: &str) -> Result<SchedClassifierLinkId, String> {
+    let program: &mut SchedClassifier =
+        match ebpf.program_mut("geofw_ingress").map(TryInto::try_into) {
+            Some(Ok(p)) => p,
+            _ => return Err("error in getting the tc ingress program".to_string()),
+        };
+    add_clsact(interface)?;
+
+    let link = program
+        .attach(interface, TcAttachType::Ingress)
+        .map_err(|e| format!("error in attaching tc filter to {}: {}", interface, e))?;
+    info!("attached to {} datapath = tc", interface);
+
+    Ok(link)
+}
+
+fn detach(ebpf: &mut Ebpf, interface: &str, link: Link) {
+    let result = match link {
+        Link::Xdp(link) => match ebpf.program_mut("geofw").map(TryInto::<&mut Xdp>::try_into) {
+            Some(Ok(p)) => p.detach(link).map_err(|e| e.to_string()),
+            _ => Err("error in getting the XDP program".to_string()),
+        },
+        // Dropping the link once it is unpinned detaches the program
+        Link::Pinned(link) | Link::Adopted(link) => {
+            link.unpin().map(drop).map_err(|e| e.to_string())
+        }
+        Link::Tc(link) => match ebpf
+            .program_mut("geofw_ingress")
+            .map(TryInto::<&mut SchedClassifier>::try_into)
+        {
+            Some(Ok(p)) => p.detach(link).map_err(|e| e.to_string()),
+            _ => Err("error in getting the tc ingress program".to_string()),
+        },
+        Link::AfXdp(link) => match ebpf
+            .program_mut("geofw_xsk")
+            .map(TryInto::<&mut Xdp>::try_into)
+        {
+            Some(Ok(p)) => p.detach(link).map_err(|e| e.to_string()),
+            _ => Err("error in getting the AF_XDP redirect program".to_string()),
+        },
+    };
+
+    match result {
+        Ok(()) => info!("detached from {}", interface),
+        Err(e) => warn!("error in detaching from {}: {}", interface, e),
+    }
+}
+
+/// Detaches the filter from the interfaces that are no longer configured, or that it is
+/// attached to with a datapath that's no longer allowed, and attaches it to the new ones
+pub fn reattach(
+    ebpf: &mut Ebpf,
+    links: &mut Links,
+    interfaces: &[String],
+    modes: &[XdpMode],
+    datapath: Datapath,
+    pins: Option<&Path>,
+) {
+    let removed: Vec<String> = links
+        .iter()
+        .filter(|(i, link)| {
+            !interfaces.contains(i)
+                || matches!(
+                    (datapath, link),
+                    (Datapath::Xdp, Link::Tc(_))
+                        | (
+                            Datapath::Tc,
+                            Link::Xdp(_) | Link::Pinned(_) | Link::Adopted(_)
+                        )
+                        | (
+                            Datapath::Auto | Datapath::Xdp | Datapath::Tc,
+                            Link::AfXdp(_)
+                        )
+                        | (
+                            Datapath::AfXdp,
+                            Link::Xdp(_) | Link::Pinned(_) | Link::Adopted(_) | Link::Tc(_)
+                        )
+                )
+        })
+        .map(|(i, _)| i.clone())
+        .collect();
+    for interface in removed {
+        if let Some(link) = links.remove(&interface) {
+            detach(ebpf, &interface, link);
+        }
+    }
+
+    for interface in interfaces {
+        if links.contains_key(interface) {
+            continue;
+        }
+        match attach(ebpf, interface, modes, datapath, pins) {
+            Ok(link) => {
+                links.insert(interface.clone(), link);
+            }
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
+
+/// Moves the adopted links to this instance's program. The kernel replaces the program of a
+/// link atomically, every packet is filtered by either the old or the new program
+pub fn take_over(ebpf: &mut Ebpf, links: &mut Links, modes: &[XdpMode], dir: &Path) {
+    let adopted: Vec<String> = links
+        .iter()
+        .filter(|(_, link)| matches!(link, Link::Adopted(_)))
+        .map(|(i, _)| i.clone())
+        .collect();
+    for interface in adopted {
+        let Some(Link::Adopted(link)) = links.remove(&interface) else {
+            continue;
+        };
+        let link = match replace_program(ebpf, &interface, link, dir) {
+            Ok(link) => {
+                info!("took over {} from the previous instance", interface);
+                link
+            }
+            Err(e) => {
+                // The old link is gone by now, attach the usual way
+                warn!("{}, attaching again", e);
+                match attach(ebpf, &interface, modes, Datapath::Xdp, Some(dir)) {
+                    Ok(link) => link,
+                    Err(e) => {
+                        warn!("{}", e);
+                        continue;
+                    }
+                }
+            }
+        };
+        links.insert(interface, link);
+    }
+}
+
+fn replace_program(
+    ebpf: &mut Ebpf,
+    interface: &str,
+    link: PinnedLink,
+    dir: &Path,
+) -> Result<Link, String> {
+    let program: &mut Xdp = match ebpf.program_mut("geofw").map(TryInto::try_into) {
+        Some(Ok(p)) => p,
+        _ => return Err("error in getting the XDP program".to_string()),
+    };
+
+    // Pinned again below, owned by this instance's program
+    let link = link
+        .unpin()
+        .map_err(|e| format!("error in unpinning link of {}: {}", interface, e))?;
+    let link = XdpLink::try_from(link)
+        .map_err(|e| format!("error in opening link of {}: {}", interface, e))?;
+    let link = program
+        .attach_to_link(link)
+        .map_err(|e| format!("error in replacing program on {}: {}", interface, e))?;
+    let link = program
+        .take_link(link)
+        .map_err(|e| format!("error in taking link of {}: {}", interface, e))?;
+    let link = FdLink::try_from(link)
+        .map_err(|e| format!("error in opening link of {}: {}", interface, e))?;
+
+    crate::pin::pin_link(link, dir, interface).map(Link::Pinned)
+}
+
+/// Drops the pinned links without detaching them, they stay attached after geofw exits for the
+/// next instance to adopt
+pub fn release_pinned(links: &mut Links) {
+    links.retain(|_, link| !matches!(link, Link::Pinned(_) | Link::Adopted(_)));
+}
+
+/// Points NEXT_PROGRAM at `next`, the XDP program that gets the packets geofw passes. `next` is
+/// the path of a pinned program or the name of a loaded one, the newest if there are several
+pub fn write_next_program(next: Option<&str>, ebpf: &mut Ebpf) -> Result<(), String> {
+    let mut map: ProgramArray<&mut MapData> = ProgramArray::try_from(
+        ebpf.map_mut("NEXT_PROGRAM")
+            .ok_or("error in getting next program map")?,
+    )
+    .map_err(|e| e.to_string())?;
+
+    let Some(next) = next else {
+        // Fails when it's already empty
+        let _ = map.clear_index(&0);
+        return Ok(());
+    };
+
+    let program = if next.starts_with('/') {
+        ProgramInfo::from_pin(next)
+            .map_err(|e| format!("error in opening pinned program {}: {}", next, e))?
+    } else {
+        loaded_programs()
+            .filter_map(Result::ok)
+            .filter(|p| p.name_as_str() == Some(next))
+            .max_by_key(|p| p.id())
+            .ok_or(format!("no program named {} is loaded", next))?
+    };
+    if !matches!(program.program_type(), Ok(ProgramType::Xdp)) {
+        return Err(format!("{} is not an XDP program", next));
+    }
+    let fd = program.fd().map_err(|e| e.to_string())?;
+
+    map.set(0, &fd, 0)
+        .map_err(|e| format!("error in chaining to {}: {}", next, e))?;
+    info!("passed packets are handed to {}", next);
+
+    Ok(())
+}
+
+pub fn detach_all(
+    ebpf: &mut Ebpf,
+    links: &mut Links,
+    egress_links: &mut EgressLinks,
+    cgroup_links: &mut CgroupLinks,
+) {
+    reattach(ebpf, links, &[], &[], Datapath::Auto, None);
+    reattach_egress(ebpf, egress_links, &[]);
+    reattach_cgroups(ebpf, cgroup_links, &[]);
+}
+
+/// Adds the clsact qdisc tc programs attach to, if the interface doesn't have one yet
+fn add_clsact(interface: &str) -> Result<(), String> {
+    if let Err(e) = tc::qdisc_add_clsact(interface) {
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(format!(
+                "error in adding clsact qdisc to {}: {}",
+                interface, e
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Attaches the egress program to the clsact qdisc of `interface`
+fn attach_egress(
+    program: &mut SchedClassifier,
+    interface: &str,
+) -> Result<SchedClassifierLinkId, String> {
+    add_clsact(interface)?;
+
+    let link = program
+        .attach(interface, TcAttachType::Egress)
+        .map_err(|e| format!("error in attaching egress filter to {}: {}", interface, e))?;
+    info!("attached egress filter to {}", interface);
+
+    Ok(link)
+}
+
+/// Same as `reattach` for the egress program. The clsact qdisc is left in place on interfaces
+/// it is detached from
+pub fn reattach_egress(ebpf: &mut Ebpf, links: &mut EgressLinks, interfaces: &[String]) {
+    let program: &mut SchedClassifier =
+        match ebpf.program_mut("geofw_egress").map(TryInto::try_into) {
+            Some(Ok(p)) => p,
+            _ => {
+                warn!("error in getting the egress program");
+                return;
+            }
+        };
+
+    let removed: Vec<String> = links
+        .keys()
+        .filter(|i| !interfaces.contains(i))
+        .cloned()
+        .collect();
+    for interface in removed {
+        if let Some(link) = links.remove(&interface) {
+            match program.detach(link) {
+                Ok(()) => info!("detached egress filter from {}", interface),
+                Err(e) => warn!("error in detaching egress filter from {}: {}", interface, e),
+            }
+        }
+    }
+
+    for interface in interfaces {
+        if links.contains_key(interface) {
+            continue;
+        }
+        match attach_egress(program, interface) {
+            Ok(link) => {
+                links.insert(interface.clone(), link);
+            }
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
+
+/// Attaches the cgroup program to the ingress of `cgroup`. Other programs on the cgroup, like
+/// systemd's IP accounting, keep running
+fn attach_cgroup(program: &mut CgroupSkb, cgroup: &str) -> Result<CgroupSkbLinkId, String> {
+    let file =
+        File::open(cgroup).map_err(|e| format!("error in opening cgroup {}: {}", cgroup, e))?;
+
+    let link = program
+        .attach(
+            file,
+            CgroupSkbAttachType::Ingress,
+            CgroupAttachMode::AllowMultiple,
+        )
+        .map_err(|e| format!("error in attaching to cgroup {}: {}", cgroup, e))?;
+    info!("attached to cgroup {}", cgroup);
+
+    Ok(link)
+}
+
+/// Same as `reattach` for the cgroup program
+pub fn reattach_cgroups(ebpf: &mut Ebpf, links: &mut CgroupLinks, cgroups: &[String]) {
+    let program: &mut CgroupSkb = match ebpf.program_mut("geofw_cgroup").map(TryInto::try_into) {
+        Some(Ok(p)) => p,
+        _ => {
+            warn!("error in getting the cgroup program");
+            return;
+        }
+    };
+
+    let removed: Vec<String> = links
+        .keys()
+        .filter(|c| !cgroups.contains(c))
+        .cloned()
+        .collect();
+    for cgroup in removed {
+        if let Some(link) = links.remove(&cgroup) {
+            match program.detach(link) {
+                Ok(()) => info!("detached from cgroup {}", cgroup),
+                Err(e) => warn!("error in detaching from cgroup {}: {}", cgroup, e),
+            }
+        }
+    }
+
+    for cgroup in cgroups {
+        if links.contains_key(cgroup) {
+            continue;
+        }
+        match attach_cgroup(program, cgroup) {
+            Ok(link) => {
+                links.insert(cgroup.clone(), link);
+            }
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use ring::digest;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Read stats, loaded maps and processed trees
+    Read,
+    /// Add and remove rules
+    Rules,
+    /// Turn panic mode on and off
+    Panic,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Shown in logs instead of the token
+    pub name: String,
+    pub token: String,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthError {
+    /// No token or an unknown one
+    Unauthenticated,
+    /// A valid token without the scope
+    Forbidden,
+}
+
+/// Checks tokens presented to the control socket and the HTTP listeners. Without any configured
+/// tokens every request is allowed
+#[derive(Debug, Clone, Default)]
+pub struct Tokens {
+    tokens: Vec<(digest::Digest, ApiToken)>,
+}
+
+impl Tokens {
+    pub fn new(tokens: &[ApiToken]) -> Self {
+        Self {
+            tokens: tokens.iter().map(|t| (hash(&t.token), t.clone())).collect(),
+        }
+    }
+
+    /// Returns the name of the token if it is allowed to use `scope`
+    pub fn authorize(&self, token: Option<&str>, scope: Scope) -> Result<Option<&str>, AuthError> {
+        if self.tokens.is_empty() {
+            return Ok(None);
+        }
+
+        // Comparing digests keeps the time taken independent of how much of the token matches
+        let presented = hash(token.ok_or(AuthError::Unauthenticated)?);
+        let (_, t) = self
+            .tokens
+            .iter()
+            .find(|(h, _)| h.as_ref() == presented.as_ref())
+            .ok_or(AuthError::Unauthenticated)?;
+
+        if t.scopes.contains(&scope) {
+            Ok(Some(&t.name))
+        } else {
+            Err(AuthError::Forbidden)
+        }
+    }
+
+    /// Same as `authorize` with the value of an HTTP Authorization header
+    pub fn authorize_header(
+        &self,
+        header: Option<&str>,
+        scope: Scope,
+    ) -> Result<Option<&str>, AuthError> {
+        self.authorize(header.and_then(|h| h.strip_prefix("Bearer ")), scope)
+    }
+}
+
+fn hash(token: &str) -> digest::Digest {
+    digest::digest(&digest::SHA256, token.as_bytes())
+}
@@ -0,0 +1,315 @@
+use aya::maps::{
+    lpm_trie::{Key, LpmTrie},
+    MapData,
+};
+use fxhash::FxHashSet;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    fs::{self, File},
+    io::Read,
+    net::IpAddr,
+    str::FromStr,
+    time::SystemTime,
+};
+
+/// An address or network, written as `192.0.2.1`, `192.0.2.0/24` or `2001:db8::/32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    /// Key in the LPM tries. IPv4 networks are stored as IPv4 mapped IPv6 networks
+    pub fn key(&self) -> Key<[u8; 16]> {
+        match self.addr {
+            IpAddr::V4(a) => Key::new(self.prefix_len as u32 + 96, a.to_ipv6_mapped().octets()),
+            IpAddr::V6(a) => Key::new(self.prefix_len as u32, a.octets()),
+        }
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(a)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                net.to_bits() == a.to_bits() & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(a)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                net.to_bits() == a.to_bits() & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid address in {}: {}", s, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|&l| l <= max)
+                .ok_or_else(|| format!("invalid prefix length in {}", s))?,
+            None => max,
+        };
+
+        // Clear the host bits so the same network is always stored the same way
+        let addr = match addr {
+            IpAddr::V4(a) => IpAddr::V4(
+                (a.to_bits() & u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)).into(),
+            ),
+            IpAddr::V6(a) => IpAddr::V6(
+                (a.to_bits() & u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)).into(),
+            ),
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(c: Cidr) -> Self {
+        c.to_string()
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Reads a list with one address or network per line. Anything after a # is a comment.
+/// Invalid lines are logged and skipped
+pub fn read_list(path: &str) -> Result<Vec<Cidr>, String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| format!("error in reading {}: {}", path, e))?;
+
+    Ok(parse_list(path, &contents))
+}
+
+/// The networks of a list read from `path`
+fn parse_list(path: &str, contents: &str) -> Vec<Cidr> {
+    let mut cidrs = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.parse() {
+            Ok(cidr) => cidrs.push(cidr),
+            Err(e) => warn!("{}:{}: {}", path, i + 1, e),
+        }
+    }
+
+    cidrs
+}
+
+/// Keeps an LPM trie map like BLOCKED_CIDRS in sync with the configured lists and networks
+pub struct CidrLists {
+    /// Used in logs, e.g. block
+    name: &'static str,
+    map: LpmTrie<MapData, [u8; 16], u8>,
+    paths: Vec<String>,
+    cidrs: Vec<Cidr>,
+    /// Added through the control socket, kept until geofw restarts
+    runtime: FxHashSet<Cidr>,
+    loaded: FxHashSet<Cidr>,
+    // Modification time of every list when it was last read, None until the first load
+    mtimes: Option<Vec<Option<SystemTime>>>,
+}
+
+impl CidrLists {
+    pub fn new(
+        name: &'static str,
+        map: LpmTrie<MapData, [u8; 16], u8>,
+        paths: Vec<String>,
+        cidrs: Vec<Cidr>,
+    ) -> Self {
+        Self {
+            name,
+            map,
+            paths,
+            cidrs,
+            runtime: FxHashSet::default(),
+            loaded: FxHashSet::default(),
+            mtimes: None,
+        }
+    }
+
+    /// Replaces the list files and networks, they are loaded on the next refresh
+    pub fn set_sources(&mut self, paths: Vec<String>, cidrs: Vec<Cidr>) {
+        if paths != self.paths || cidrs != self.cidrs {
+            self.paths = paths;
+            self.cidrs = cidrs;
+            self.mtimes = None;
+        }
+    }
+
+    /// Adds a network on top of the lists and loads it right away
+    pub fn add(&mut self, cidr: Cidr) -> Result<(), String> {
+        if self.runtime.insert(cidr) {
+            self.mtimes = None;
+        }
+        self.refresh()
+    }
+
+    /// Removes a network added with `add`. Networks from the config and the lists stay
+    pub fn remove(&mut self, cidr: Cidr) -> Result<(), String> {
+        if !self.runtime.remove(&cidr) {
+            return Err(format!("{} was not added at runtime", cidr));
+        }
+        self.mtimes = None;
+        self.refresh()
+    }
+
+    /// Reloads the lists if any of them changed since the last call
+    pub fn refresh(&mut self) -> Result<(), String> {
+        let mtimes: Vec<Option<SystemTime>> = self
+            .paths
+            .iter()
+            .map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect();
+        if self.mtimes.as_ref() == Some(&mtimes) {
+            return Ok(());
+        }
+        // A broken list is retried once it changes again, not on every call
+        self.mtimes = Some(mtimes);
+
+        let mut wanted: FxHashSet<Cidr> = self.cidrs.iter().copied().collect();
+        wanted.extend(self.runtime.iter().copied());
+        for path in &self.paths {
+            // On errors, keep what is loaded rather than unblocking everything in the list
+            wanted.extend(read_list(path)?);
+        }
+
+        let mut added = 0;
+        for cidr in wanted.difference(&self.loaded) {
+            self.map
+                .insert(&cidr.key(), 1, 0)
+                .map_err(|e| format!("error in adding {}: {}", cidr, e))?;
+            added += 1;
+        }
+
+        let mut removed = 0;
+        for cidr in self.loaded.difference(&wanted) {
+            if let Err(e) = self.map.remove(&cidr.key()) {
+                warn!("error in removing {}: {}", cidr, e);
+            }
+            removed += 1;
+        }
+
+        info!(
+            "reloaded {} lists entries = {} added = {} removed = {}",
+            self.name,
+            wanted.len(),
+            added,
+            removed
+        );
+
+        self.loaded = wanted;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_addresses_and_networks() {
+        assert_eq!(cidr("192.0.2.1").to_string(), "192.0.2.1/32");
+        assert_eq!(cidr("2001:db8::1").to_string(), "2001:db8::1/128");
+        assert_eq!(cidr(" 192.0.2.0 / 24 ").to_string(), "192.0.2.0/24");
+        // Host bits are cleared
+        assert_eq!(cidr("192.0.2.77/24"), cidr("192.0.2.0/24"));
+        assert_eq!(cidr("2001:db8:1:2::/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+
+        for invalid in [
+            "",
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2.0/",
+            "example.com/8",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let net = cidr("192.0.2.0/24");
+        assert!(net.contains("192.0.2.200".parse().unwrap()));
+        assert!(!net.contains("192.0.3.1".parse().unwrap()));
+        assert!(!net.contains("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(cidr("0.0.0.0/0").contains("203.0.113.1".parse().unwrap()));
+        assert!(cidr("2001:db8::/32").contains("2001:db8:ffff::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn keys_of_ipv4_networks_are_ipv4_mapped() {
+        let key = cidr("192.0.2.0/24").key();
+        assert_eq!(key.prefix_len(), 120);
+        assert_eq!(
+            key.data(),
+            "::ffff:192.0.2.0"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets()
+        );
+        assert_eq!(cidr("2001:db8::/32").key().prefix_len(), 32);
+    }
+
+    #[test]
+    fn lists_skip_comments_and_invalid_lines() {
+        let list =
+            "# blocked\n192.0.2.1\n\n198.51.100.0/24 # scanner\nnot an address\n2001:db8::/32\n";
+
+        assert_eq!(
+            parse_list("list", list),
+            vec![
+                cidr("192.0.2.1"),
+                cidr("198.51.100.0/24"),
+                cidr("2001:db8::/32")
+            ]
+        );
+    }
+}
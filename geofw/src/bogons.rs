@@ -0,0 +1,77 @@
+use crate::{blocklist::Cidr, policy, Config};
+use aya::{
+    maps::{HashMap, LpmTrie, MapData},
+    Ebpf,
+};
+use geofw_common::ProgramParameters;
+
+/// Reserved, special purpose and unallocated networks that never source traffic from the
+/// internet. Multicast and IPv6 link-local networks are left to `multicast_action` and the
+/// neighbor discovery handling, and `::/8` is only partly listed because IPv4 addresses are
+/// stored as IPv4 mapped addresses in it
+const BOGONS: [&str; 33] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "100::/64",
+    "200::/7",
+    "400::/6",
+    "800::/5",
+    "1000::/4",
+    "2001:2::/48",
+    "2001:db8::/32",
+    "3ffe::/16",
+    "4000::/2",
+    "8000::/2",
+    "c000::/3",
+    "e000::/4",
+    "f000::/5",
+    "f800::/6",
+    "fc00::/7",
+    "fe00::/9",
+    "fec0::/10",
+    "3fff::/20",
+];
+
+pub fn bogons() -> Vec<Cidr> {
+    BOGONS
+        .iter()
+        .map(|s| s.parse().expect("error in parsing bogon"))
+        .collect()
+}
+
+/// Fills BOGONS and sets the bits of the policies that drop them in the BogonPolicies parameter
+pub fn write_bogons(config: &Config, ebpf: &mut Ebpf) -> Result<(), String> {
+    let mut map: LpmTrie<&mut MapData, [u8; 16], u8> =
+        LpmTrie::try_from(ebpf.map_mut("BOGONS").ok_or("error in getting bogon map")?)
+            .map_err(|e| e.to_string())?;
+    for cidr in bogons() {
+        map.insert(&cidr.key(), 1, 0)
+            .map_err(|e| format!("error in adding {}: {}", cidr, e))?;
+    }
+
+    let mut parameters: HashMap<&mut MapData, u8, u32> = HashMap::try_from(
+        ebpf.map_mut("PARAMETERS")
+            .ok_or("error in getting parameters map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    parameters
+        .insert(
+            ProgramParameters::BogonPolicies as u8,
+            policy::bogon_policies(config),
+            0,
+        )
+        .map_err(|e| e.to_string())
+}
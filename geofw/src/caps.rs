@@ -0,0 +1,213 @@
+use log::{debug, info};
+use std::{fmt, fs, io};
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    NetAdmin = 12,
+    NetRaw = 13,
+    SysAdmin = 21,
+    SysResource = 24,
+    Perfmon = 38,
+    Bpf = 39,
+}
+
+impl Capability {
+    fn bit(self) -> u64 {
+        1 << self as u32
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Capability::NetAdmin => "cap_net_admin",
+            Capability::NetRaw => "cap_net_raw",
+            Capability::SysAdmin => "cap_sys_admin",
+            Capability::SysResource => "cap_sys_resource",
+            Capability::Perfmon => "cap_perfmon",
+            Capability::Bpf => "cap_bpf",
+        };
+        f.write_str(name)
+    }
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Effective and permitted capabilities of this process
+struct Sets {
+    effective: u64,
+    permitted: u64,
+}
+
+fn get() -> Result<Sets, String> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    let ret = unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) };
+    if ret < 0 {
+        return Err(format!(
+            "error in reading capabilities: {}",
+            io::Error::last_os_error()
+        ));
+    }
+
+    Ok(Sets {
+        effective: data[0].effective as u64 | (data[1].effective as u64) << 32,
+        permitted: data[0].permitted as u64 | (data[1].permitted as u64) << 32,
+    })
+}
+
+/// Highest capability the kernel knows. CAP_BPF and CAP_PERFMON are only known since 5.8
+fn last_cap() -> u32 {
+    fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(Capability::Bpf as u32)
+}
+
+/// Capabilities that loading and attaching the programs needs. Kernels without CAP_BPF need
+/// CAP_SYS_ADMIN instead
+fn required(inspect: bool) -> Vec<Capability> {
+    let mut caps = vec![Capability::NetAdmin];
+    if last_cap() >= Capability::Bpf as u32 {
+        caps.push(Capability::Bpf);
+    } else {
+        caps.push(Capability::SysAdmin);
+    }
+    // AF_XDP sockets of suspect traffic inspection and the afxdp datapath
+    if inspect {
+        caps.push(Capability::NetRaw);
+    }
+    caps
+}
+
+/// Fails with the capabilities to grant when loading and attaching the programs would fail
+/// for lack of them. `inspect` is whether AF_XDP sockets are opened
+pub fn check(inspect: bool) -> Result<(), String> {
+    let sets = get()?;
+    // CAP_SYS_ADMIN implies CAP_BPF
+    let admin = sets.effective & Capability::SysAdmin.bit() != 0;
+    let missing: Vec<Capability> = required(inspect)
+        .into_iter()
+        .filter(|&cap| sets.effective & cap.bit() == 0)
+        .filter(|&cap| !(admin && cap == Capability::Bpf))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let names: Vec<String> = missing.iter().map(Capability::to_string).collect();
+    let mut grant: Vec<String> = required(inspect)
+        .iter()
+        .map(Capability::to_string)
+        .collect();
+    // CAP_PERFMON lets the verifier accept more programs, CAP_SYS_RESOURCE lifts the locked
+    // memory limit on kernels that still account BPF maps against it
+    if last_cap() >= Capability::Perfmon as u32 {
+        grant.push(Capability::Perfmon.to_string());
+    }
+    grant.push(Capability::SysResource.to_string());
+    let exe = std::env::current_exe()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| "geofw".to_string());
+
+    Err(format!(
+        "missing {}. Run geofw as root, grant the capabilities to the binary with \
+         `setcap {}+ep {}`, or set AmbientCapabilities={} in its systemd unit",
+        names.join(", "),
+        grant.join(","),
+        exe,
+        grant
+            .iter()
+            .map(|c| c.to_uppercase())
+            .collect::<Vec<_>>()
+            .join(" ")
+    ))
+}
+
+/// Drops every capability except those reloads need to attach to interfaces and update the
+/// maps. Programs started later, like `maxmind_key_cmd`, can't regain the others either
+pub fn drop_privileges() -> Result<(), String> {
+    let mut keep = vec![Capability::NetAdmin];
+    if last_cap() >= Capability::Bpf as u32 {
+        keep.push(Capability::Bpf);
+    } else {
+        keep.push(Capability::SysAdmin);
+    }
+    let keep_mask = keep.iter().fold(0, |mask, cap| mask | cap.bit());
+
+    unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    };
+    // Needs CAP_SETPCAP, so the bounding set is reduced before it is dropped
+    for cap in 0..=last_cap() {
+        if keep_mask & (1 << cap) != 0 {
+            continue;
+        }
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) } < 0 {
+            debug!(
+                "error in dropping capability {} from the bounding set: {}",
+                cap,
+                io::Error::last_os_error()
+            );
+            break;
+        }
+    }
+
+    let sets = get()?;
+    let kept = sets.permitted & keep_mask;
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapData {
+            effective: kept as u32,
+            permitted: kept as u32,
+            inheritable: 0,
+        },
+        CapData {
+            effective: (kept >> 32) as u32,
+            permitted: (kept >> 32) as u32,
+            inheritable: 0,
+        },
+    ];
+    let ret = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    if ret < 0 {
+        return Err(format!(
+            "error in dropping capabilities: {}",
+            io::Error::last_os_error()
+        ));
+    }
+
+    let names: Vec<String> = keep
+        .iter()
+        .filter(|cap| kept & cap.bit() != 0)
+        .map(Capability::to_string)
+        .collect();
+    info!("dropped capabilities, kept {}", names.join(","));
+
+    Ok(())
+}
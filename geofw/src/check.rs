@@ -0,0 +1,590 @@
+use crate::{
+    attach::XdpMode,
+    feeds::FeedFormat,
+    fleet::FleetRole,
+    migrate::{self, CONFIG_VERSION},
+    output::{print_json, OutputFormat},
+    overrides,
+    policy::{PolicyAction, PortScope, RateLimitConfig},
+    Config, ConfigFormat,
+};
+use fxhash::FxHashSet;
+use geofw_common::{CountryAction, Mode, MAX_COMPOUND_RULES, MAX_POLICIES};
+use serde_derive::Serialize;
+use std::{ffi::CString, fs, path::Path};
+
+/// ISO 3166-1 alpha-2 codes, which the GeoLite2 databases use in `country.iso_code`
+const COUNTRY_CODES: [&str; 249] = [
+    "AD", "AE", "AF", "AG", "AI", "AL", "AM", "AO", "AQ", "AR", "AS", "AT", "AU", "AW", "AX", "AZ",
+    "BA", "BB", "BD", "BE", "BF", "BG", "BH", "BI", "BJ", "BL", "BM", "BN", "BO", "BQ", "BR", "BS",
+    "BT", "BV", "BW", "BY", "BZ", "CA", "CC", "CD", "CF", "CG", "CH", "CI", "CK", "CL", "CM", "CN",
+    "CO", "CR", "CU", "CV", "CW", "CX", "CY", "CZ", "DE", "DJ", "DK", "DM", "DO", "DZ", "EC", "EE",
+    "EG", "EH", "ER", "ES", "ET", "FI", "FJ", "FK", "FM", "FO", "FR", "GA", "GB", "GD", "GE", "GF",
+    "GG", "GH", "GI", "GL", "GM", "GN", "GP", "GQ", "GR", "GS", "GT", "GU", "GW", "GY", "HK", "HM",
+    "HN", "HR", "HT", "HU", "ID", "IE", "IL", "IM", "IN", "IO", "IQ", "IR", "IS", "IT", "JE", "JM",
+    "JO", "JP", "KE", "KG", "KH", "KI", "KM", "KN", "KP", "KR", "KW", "KY", "KZ", "LA", "LB", "LC",
+    "LI", "LK", "LR", "LS", "LT", "LU", "LV", "LY", "MA", "MC", "MD", "ME", "MF", "MG", "MH", "MK",
+    "ML", "MM", "MN", "MO", "MP", "MQ", "MR", "MS", "MT", "MU", "MV", "MW", "MX", "MY", "MZ", "NA",
+    "NC", "NE", "NF", "NG", "NI", "NL", "NO", "NP", "NR", "NU", "NZ", "OM", "PA", "PE", "PF", "PG",
+    "PH", "PK", "PL", "PM", "PN", "PR", "PS", "PT", "PW", "PY", "QA", "RE", "RO", "RS", "RU", "RW",
+    "SA", "SB", "SC", "SD", "SE", "SG", "SH", "SI", "SJ", "SK", "SL", "SM", "SN", "SO", "SR", "SS",
+    "ST", "SV", "SX", "SY", "SZ", "TC", "TD", "TF", "TG", "TH", "TJ", "TK", "TL", "TM", "TN", "TO",
+    "TR", "TT", "TV", "TW", "TZ", "UA", "UG", "UM", "US", "UY", "UZ", "VA", "VC", "VE", "VG", "VI",
+    "VN", "VU", "WF", "WS", "YE", "YT", "ZA", "ZM", "ZW",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Serialize)]
+struct Issue {
+    severity: Severity,
+    field: String,
+    message: String,
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    issues: Vec<Issue>,
+}
+
+impl Report {
+    fn error(&mut self, field: &str, message: String) {
+        self.push(Severity::Error, field, message);
+    }
+
+    fn warning(&mut self, field: &str, message: String) {
+        self.push(Severity::Warning, field, message);
+    }
+
+    fn push(&mut self, severity: Severity, field: &str, message: String) {
+        self.issues.push(Issue {
+            severity,
+            field: field.to_string(),
+            message,
+        });
+    }
+}
+
+/// Validates the config at `path` and prints a report. Returns an error if anything in it would
+/// stop geofw from working as intended
+pub fn check_config(
+    path: &str,
+    format: ConfigFormat,
+    interfaces: &[String],
+    output: OutputFormat,
+) -> Result<(), String> {
+    let mut report = Report::default();
+
+    let parsed = fs::read(path)
+        .map_err(|e| format!("error in reading {}: {}", path, e))
+        .and_then(|contents| migrate::parse(format, &contents));
+    match parsed {
+        Ok(parsed) => {
+            if parsed.version < CONFIG_VERSION {
+                report.warning(
+                    "version",
+                    format!(
+                        "is {}, the file is upgraded to {} when geofw starts",
+                        parsed.version, CONFIG_VERSION
+                    ),
+                );
+            }
+            for field in &parsed.unknown {
+                report.warning(field, "is not a config field and is ignored".to_string());
+            }
+
+            match overrides::apply_env(parsed.config) {
+                Ok(mut config) => {
+                    config.override_interfaces(interfaces);
+                    check(&config, &mut report);
+                }
+                Err(e) => report.error("", e),
+            }
+        }
+        Err(e) => report.error("", e),
+    }
+
+    if output == OutputFormat::Json {
+        print_json(&report.issues)?;
+    } else if report.issues.is_empty() {
+        println!("{} is valid", path);
+    } else {
+        for issue in &report.issues {
+            let severity = match issue.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            if issue.field.is_empty() {
+                println!("{:<8} {}", severity, issue.message);
+            } else {
+                println!("{:<8} {}: {}", severity, issue.field, issue.message);
+            }
+        }
+    }
+
+    let errors = report
+        .issues
+        .iter()
+        .filter(|i| i.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(format!("{} has {} errors", path, errors));
+    }
+
+    Ok(())
+}
+
+fn check(config: &Config, report: &mut Report) {
+    for code in &config.source_countries {
+        check_country(report, "source_countries", code);
+    }
+    if let Some(suspect) = &config.suspect {
+        for code in &suspect.countries {
+            check_country(report, "suspect.countries", code);
+        }
+    }
+
+    for &asn in &config.source_asn {
+        if let Some(problem) = asn_problem(asn) {
+            report.error("source_asn", format!("AS{} {}", asn, problem));
+        }
+    }
+    check_country_actions(config, report);
+    check_compound_rules(config, report);
+    for code in &config.allow_countries {
+        check_country(report, "allow_countries", code);
+    }
+    for &asn in &config.allow_asn {
+        if let Some(problem) = asn_problem(asn) {
+            report.error("allow_asn", format!("AS{} {}", asn, problem));
+        }
+    }
+    for code in &config.source_subdivisions {
+        check_subdivision(report, "source_subdivisions", code);
+    }
+    check_org_patterns(
+        report,
+        "source_asn_org_patterns",
+        &config.source_asn_org_patterns,
+    );
+    check_ports(report, "ports", &config.ports);
+    check_rate_limit(report, "rate_limit", config.rate_limit.as_ref());
+    check_action(report, "action", &config.action);
+    if config.mode == Mode::Allow
+        && !(config.allow_countries.is_empty() && config.allow_asn.is_empty())
+    {
+        report.warning(
+            "allow_countries",
+            "is ignored in allow mode, source_countries and source_asn are the sources allowed"
+                .to_string(),
+        );
+    }
+
+    let field = if config.interfaces.is_empty() {
+        "interface"
+    } else {
+        "interfaces"
+    };
+    if config.interfaces().is_empty() {
+        report.error("interfaces", "is empty".to_string());
+    }
+    if config.xdp_mode.is_empty() {
+        report.error("xdp_mode", "is empty".to_string());
+    }
+    if config.xdp_mode == [XdpMode::Hw] {
+        report.warning(
+            "xdp_mode",
+            "hw is the only mode, interfaces are left unfiltered when the program can't be offloaded"
+                .to_string(),
+        );
+    }
+    if config.inspect_tunnels && config.vxlan_ports.contains(&0) {
+        report.error("vxlan_ports", "0 is not a port".to_string());
+    }
+    if config.pass_services.len() > 256 {
+        report.error(
+            "pass_services",
+            format!(
+                "{} services are listed, at most 256 fit in the map",
+                config.pass_services.len()
+            ),
+        );
+    }
+    for &id in &config.vlans {
+        if !(1..=4094).contains(&id) {
+            report.error(
+                "vlans",
+                format!("{} is not a VLAN ID, they go from 1 to 4094", id),
+            );
+        }
+    }
+    for interface in config.interfaces() {
+        if !Path::new("/sys/class/net").join(&interface).exists() {
+            report.error(field, format!("interface {} does not exist", interface));
+        }
+    }
+
+    check_policies(config, report);
+    check_feeds(config, report);
+    check_db_path(config, report);
+
+    let agent = config
+        .fleet
+        .as_ref()
+        .is_some_and(|f| f.role == FleetRole::Agent);
+    let db = &config.db;
+    if !agent && !db.offline && db.maxmind_key.is_empty() {
+        match (&db.maxmind_key_file, &db.maxmind_key_cmd) {
+            (Some(path), _) if !Path::new(path).is_file() => {
+                report.error("db.maxmind_key_file", format!("{} does not exist", path));
+            }
+            (None, None) => report.error(
+                "db.maxmind_key",
+                "is empty, the databases can't be downloaded".to_string(),
+            ),
+            _ => {}
+        }
+    }
+    if !agent && !db.offline && db.account_id.is_none() {
+        report.warning(
+            "db.account_id",
+            "is not set, the databases are downloaded from the deprecated legacy endpoint"
+                .to_string(),
+        );
+    }
+
+    for path in &config.block_lists {
+        if !Path::new(path).is_file() {
+            report.warning("block_lists", format!("{} does not exist", path));
+        }
+    }
+
+    if config.source_countries.is_empty()
+        && config.country_actions.is_empty()
+        && config.compound_rules.is_empty()
+        && config.source_asn.is_empty()
+        && config.source_asn_org_patterns.is_empty()
+        && config.source_cities.is_empty()
+        && config.source_subdivisions.is_empty()
+        && !config.block_anonymous.enabled()
+    {
+        let message = match config.mode {
+            Mode::Block => "nothing is blocked by geolocation",
+            Mode::Allow => "everything is dropped",
+        };
+        report.warning(
+            "",
+            format!("source_countries and source_asn are empty, {}", message),
+        );
+    }
+}
+
+fn check_policies(config: &Config, report: &mut Report) {
+    if config.policies.len() >= MAX_POLICIES as usize {
+        report.error(
+            "policies",
+            format!("at most {} policies are supported", MAX_POLICIES - 1),
+        );
+    }
+
+    let interfaces = config.interfaces();
+    let mut seen = FxHashSet::default();
+    for policy in &config.policies {
+        let field = format!("policies.{}", policy.name);
+        for code in &policy.source_countries {
+            check_country(report, &format!("{}.source_countries", field), code);
+        }
+        for &asn in &policy.source_asn {
+            if let Some(problem) = asn_problem(asn) {
+                report.error(
+                    &format!("{}.source_asn", field),
+                    format!("AS{} {}", asn, problem),
+                );
+            }
+        }
+        for code in &policy.source_subdivisions {
+            check_subdivision(report, &format!("{}.source_subdivisions", field), code);
+        }
+        check_org_patterns(
+            report,
+            &format!("{}.source_asn_org_patterns", field),
+            &policy.source_asn_org_patterns,
+        );
+        check_ports(report, &format!("{}.ports", field), &policy.ports);
+        check_rate_limit(
+            report,
+            &format!("{}.rate_limit", field),
+            policy.rate_limit.as_ref(),
+        );
+        check_action(report, &format!("{}.action", field), &policy.action);
+
+        for interface in &policy.interfaces {
+            if !interfaces.contains(interface) {
+                report.warning(
+                    &field,
+                    format!(
+                        "{} is not one of the interfaces geofw attaches to",
+                        interface
+                    ),
+                );
+            }
+            if !seen.insert(interface) {
+                report.error(&field, format!("{} already has a policy", interface));
+            }
+        }
+        for cgroup in &policy.cgroups {
+            if !Path::new(cgroup).is_dir() {
+                report.error(&field, format!("{} is not a cgroup directory", cgroup));
+            }
+            if !seen.insert(cgroup) {
+                report.error(&field, format!("{} already has a policy", cgroup));
+            }
+        }
+    }
+}
+
+fn check_country_actions(config: &Config, report: &mut Report) {
+    for (code, &action) in &config.country_actions {
+        check_country(report, "country_actions", code);
+        if config.source_countries.contains(code) {
+            report.error(
+                "country_actions",
+                format!("{} is also in source_countries", code),
+            );
+        }
+        if action == CountryAction::RateLimit && config.rate_limit.is_none() {
+            report.error(
+                "country_actions",
+                format!("{} is rate limited, but rate_limit is not set", code),
+            );
+        }
+    }
+    if config.mode == Mode::Allow && !config.country_actions.is_empty() {
+        report.warning(
+            "country_actions",
+            "only apply in block mode, in allow mode the countries are allowed".to_string(),
+        );
+    }
+}
+
+fn check_compound_rules(config: &Config, report: &mut Report) {
+    if config.compound_rules.len() > MAX_COMPOUND_RULES {
+        report.error(
+            "compound_rules",
+            format!(
+                "at most {} compound rules are supported",
+                MAX_COMPOUND_RULES
+            ),
+        );
+    }
+    if config.mode == Mode::Allow && !config.compound_rules.is_empty() {
+        report.warning("compound_rules", "are ignored in allow mode".to_string());
+    }
+
+    for (i, rule) in config.compound_rules.iter().enumerate() {
+        let field = format!("compound_rules.{}", i);
+        check_country(report, &field, &rule.country);
+        if rule.asn.is_empty() == rule.except_asn.is_empty() {
+            report.error(&field, "needs either asn or except_asn".to_string());
+        }
+        for &asn in rule.asn.iter().chain(&rule.except_asn) {
+            if let Some(problem) = asn_problem(asn) {
+                report.error(&field, format!("AS{} {}", asn, problem));
+            }
+        }
+
+        // Records only hold one marker, listing them somewhere else hides the compound rule
+        let listed = |countries: &FxHashSet<String>, asns: &FxHashSet<u32>| {
+            countries.contains(&rule.country)
+                || rule
+                    .asn
+                    .iter()
+                    .chain(&rule.except_asn)
+                    .any(|a| asns.contains(a))
+        };
+        let shadowed = listed(&config.source_countries, &config.source_asn)
+            || config.country_actions.contains_key(&rule.country)
+            || config
+                .policies
+                .iter()
+                .any(|p| listed(&p.source_countries, &p.source_asn));
+        if shadowed {
+            report.warning(
+                &field,
+                "its country or ASNs are also listed elsewhere, where they take precedence"
+                    .to_string(),
+            );
+        }
+    }
+}
+
+fn check_feeds(config: &Config, report: &mut Report) {
+    let mut seen = FxHashSet::default();
+    for feed in config.feeds() {
+        if !seen.insert(feed.name().to_string()) {
+            report.error("feeds", format!("{} is listed more than once", feed.name()));
+        }
+        if !feed.url.starts_with("http://") && !feed.url.starts_with("https://") {
+            report.error(
+                &format!("feeds.{}", feed.name()),
+                format!("{} is not an HTTP URL", feed.url),
+            );
+        }
+        if let FeedFormat::Json { path } = &feed.format {
+            if path.is_empty() {
+                report.error(
+                    &format!("feeds.{}", feed.name()),
+                    "path is empty".to_string(),
+                );
+            }
+        }
+        if feed.interval < 60 {
+            report.warning(
+                &format!("feeds.{}", feed.name()),
+                "interval is raised to 60 seconds".to_string(),
+            );
+        }
+    }
+}
+
+/// Subdivisions are matched against the country code and the subdivision code joined by a dash
+fn check_subdivision(report: &mut Report, field: &str, code: &str) {
+    match code.split_once('-') {
+        Some((country, subdivision)) if !subdivision.is_empty() => {
+            check_country(report, field, country);
+        }
+        _ => report.error(
+            field,
+            format!("{} is not an ISO 3166-2 code like US-CA", code),
+        ),
+    }
+}
+
+/// An empty pattern is contained in every organization name and would block every ASN
+fn check_org_patterns(report: &mut Report, field: &str, patterns: &[String]) {
+    if patterns.iter().any(|p| p.trim().is_empty()) {
+        report.error(
+            field,
+            "has an empty pattern, which matches every ASN".to_string(),
+        );
+    }
+}
+
+fn check_rate_limit(report: &mut Report, field: &str, limit: Option<&RateLimitConfig>) {
+    let Some(limit) = limit else {
+        return;
+    };
+
+    if limit.packets_per_second == 0 {
+        report.error(
+            field,
+            "packets_per_second is 0, remove rate_limit to drop instead".to_string(),
+        );
+    }
+    if limit.burst == Some(0) {
+        report.error(
+            field,
+            "burst is 0, every packet would be dropped".to_string(),
+        );
+    }
+    if limit.ipv4_prefix > 32 {
+        report.error(
+            field,
+            format!("ipv4_prefix {} is over 32", limit.ipv4_prefix),
+        );
+    }
+    if limit.ipv6_prefix > 128 {
+        report.error(
+            field,
+            format!("ipv6_prefix {} is over 128", limit.ipv6_prefix),
+        );
+    }
+}
+
+fn check_action(report: &mut Report, field: &str, action: &PolicyAction) {
+    if let PolicyAction::Redirect(interface) = action {
+        if !Path::new("/sys/class/net").join(interface).exists() {
+            report.error(field, format!("interface {} does not exist", interface));
+        }
+    }
+}
+
+fn check_ports(report: &mut Report, field: &str, ports: &[PortScope]) {
+    let mut seen = FxHashSet::default();
+    for scope in ports {
+        if scope.port == 0 {
+            report.error(
+                field,
+                format!("{:?} port 0 is not a valid port", scope.protocol),
+            );
+        }
+        if !seen.insert(scope) {
+            report.warning(
+                field,
+                format!(
+                    "{:?}/{} is listed more than once",
+                    scope.protocol, scope.port
+                ),
+            );
+        }
+    }
+}
+
+fn check_country(report: &mut Report, field: &str, code: &str) {
+    if COUNTRY_CODES.contains(&code) {
+        return;
+    }
+
+    let upper = code.to_uppercase();
+    if COUNTRY_CODES.contains(&upper.as_str()) {
+        report.error(
+            field,
+            format!("{} must be upper case, the database uses {}", code, upper),
+        );
+    } else {
+        report.error(
+            field,
+            format!("{} is not an ISO 3166-1 alpha-2 country code", code),
+        );
+    }
+}
+
+/// Why an ASN can never appear in the database, if it can't
+fn asn_problem(asn: u32) -> Option<&'static str> {
+    match asn {
+        0 => Some("is reserved"),
+        23456 => Some("is AS_TRANS, which is never announced"),
+        64496..=64511 | 65536..=65551 => Some("is reserved for documentation"),
+        64512..=65534 | 4200000000..=4294967294 => Some("is reserved for private use"),
+        65535 | 4294967295 => Some("is reserved"),
+        _ => None,
+    }
+}
+
+fn check_db_path(config: &Config, report: &mut Report) {
+    let path = Path::new(&config.db.path);
+
+    // The directory is created on the first download, so its closest existing ancestor has to
+    // be writable
+    let existing = path.ancestors().find(|p| p.exists());
+    let Some(existing) = existing else {
+        report.error("db.path", format!("no parent of {} exists", config.db.path));
+        return;
+    };
+
+    if existing == path && !fs::metadata(path).is_ok_and(|m| m.is_dir()) {
+        report.error("db.path", format!("{} is not a directory", config.db.path));
+        return;
+    }
+
+    let writable = CString::new(existing.as_os_str().as_encoded_bytes())
+        .is_ok_and(|p| unsafe { libc::access(p.as_ptr(), libc::W_OK) } == 0);
+    if !writable {
+        report.error("db.path", format!("{} is not writable", existing.display()));
+    }
+}
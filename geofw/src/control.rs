@@ -0,0 +1,311 @@
+use crate::{
+    auth::{AuthError, Scope, Tokens},
+    blocklist::Cidr,
+    output::{print_json, OutputFormat},
+    policy::Policy,
+};
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::IpAddr,
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    sync::mpsc as std_mpsc,
+    thread,
+};
+use tokio::sync::mpsc;
+
+/// Requests longer than this are refused, they are a single line of JSON
+const MAX_REQUEST_LEN: u64 = 4096;
+
+/// A request to the control socket, one JSON object per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    /// Needed when `api_tokens` has entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// Interfaces, databases and rules in use
+    Status,
+    /// Reload the config like SIGHUP
+    Reload,
+    /// Refresh the databases now instead of at the next refresh interval
+    Refresh,
+    /// Packet counters summed over all CPUs
+    Stats,
+    /// Evaluate an address against the cached databases and the rules
+    Lookup { addr: IpAddr },
+    /// Drop a network until geofw restarts or it is unblocked
+    Block { cidr: Cidr },
+    /// Remove a network added with block
+    Unblock { cidr: Cidr },
+    /// The policies in use
+    Policies,
+    /// Replace the policies until the config is reloaded or geofw restarts
+    SetPolicies { policies: Vec<Policy> },
+}
+
+impl Command {
+    pub fn scope(&self) -> Scope {
+        match self {
+            Command::Status | Command::Stats | Command::Lookup { .. } | Command::Policies => {
+                Scope::Read
+            }
+            Command::Reload
+            | Command::Refresh
+            | Command::Block { .. }
+            | Command::Unblock { .. }
+            | Command::SetPolicies { .. } => Scope::Rules,
+        }
+    }
+}
+
+/// The answer to a request, one JSON object per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub result: serde_json::Value,
+}
+
+impl Response {
+    pub fn ok(result: serde_json::Value) -> Self {
+        Self {
+            ok: true,
+            error: None,
+            result,
+        }
+    }
+
+    pub fn error(error: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: Some(error.into()),
+            result: serde_json::Value::Null,
+        }
+    }
+
+    pub fn from_result<T: serde::Serialize>(result: Result<T, String>) -> Self {
+        match result.and_then(|r| serde_json::to_value(r).map_err(|e| e.to_string())) {
+            Ok(r) => Self::ok(r),
+            Err(e) => Self::error(e),
+        }
+    }
+}
+
+/// A command received on the control socket, answered by the main loop through `reply`
+pub struct Message {
+    pub command: Command,
+    pub reply: std_mpsc::Sender<Response>,
+}
+
+/// Listens on the unix socket at `path` and sends every authorized command to `tx`. The socket
+/// is only accessible to the owner, a stale one left by a previous instance is replaced
+pub fn start(path: &str, tokens: Tokens, tx: mpsc::Sender<Message>) -> Result<(), String> {
+    let _ = fs::remove_file(path);
+    let listener =
+        UnixListener::bind(path).map_err(|e| format!("error in listening on {}: {}", path, e))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("error in setting permissions of {}: {}", path, e))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let tokens = tokens.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve(stream, &tokens, &tx) {
+                    debug!("error in serving control client: {}", e);
+                }
+            });
+        }
+    });
+
+    info!("listening for control requests on {}", path);
+
+    Ok(())
+}
+
+/// Removes the socket on exit
+pub fn stop(path: &str) {
+    if let Err(e) = fs::remove_file(path) {
+        warn!("error in removing control socket {}: {}", path, e);
+    }
+}
+
+fn serve(stream: UnixStream, tokens: &Tokens, tx: &mpsc::Sender<Message>) -> Result<(), String> {
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let mut line = String::new();
+        let n = (&mut reader)
+            .take(MAX_REQUEST_LEN)
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && n as u64 == MAX_REQUEST_LEN {
+            respond(&mut writer, &Response::error("request is too long"))?;
+            return Ok(());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => handle(request, tokens, tx),
+            Err(e) => Response::error(format!("invalid request: {}", e)),
+        };
+        respond(&mut writer, &response)?;
+    }
+}
+
+fn handle(request: Request, tokens: &Tokens, tx: &mpsc::Sender<Message>) -> Response {
+    let name = match tokens.authorize(request.token.as_deref(), request.command.scope()) {
+        Ok(name) => name,
+        Err(AuthError::Unauthenticated) => return Response::error("unauthenticated"),
+        Err(AuthError::Forbidden) => return Response::error("forbidden"),
+    };
+    if let Some(name) = name {
+        debug!("control request {:?} token = {}", request.command, name);
+    }
+
+    dispatch(request.command, tx)
+}
+
+/// Hands `command` to the main loop and waits for its answer
+pub fn dispatch(command: Command, tx: &mpsc::Sender<Message>) -> Response {
+    let (reply, rx) = std_mpsc::channel();
+    let message = Message { command, reply };
+    if tx.blocking_send(message).is_err() {
+        return Response::error("geofw is shutting down");
+    }
+
+    rx.recv()
+        .unwrap_or_else(|_| Response::error("geofw is shutting down"))
+}
+
+/// Sends `command` to the instance listening on `path` and returns its result
+pub fn request(
+    path: &str,
+    token: Option<&str>,
+    command: Command,
+) -> Result<serde_json::Value, String> {
+    let mut stream = UnixStream::connect(path).map_err(|e| {
+        format!(
+            "error in connecting to {}, is geofw running with the control socket enabled: {}",
+            path, e
+        )
+    })?;
+
+    let request = Request {
+        token: token.map(str::to_string),
+        command,
+    };
+    let mut line = serde_json::to_string(&request).map_err(|e| e.to_string())?;
+    line.push('\n');
+    stream
+        .write_all(line.as_bytes())
+        .map_err(|e| format!("error in sending request: {}", e))?;
+
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(|e| format!("error in reading response: {}", e))?;
+    let response: Response =
+        serde_json::from_str(&line).map_err(|e| format!("invalid response: {}", e))?;
+
+    if response.ok {
+        Ok(response.result)
+    } else {
+        Err(response
+            .error
+            .unwrap_or_else(|| "request failed".to_string()))
+    }
+}
+
+/// Sends `command` to the running instance and prints its result
+pub fn run(
+    path: &str,
+    token: Option<&str>,
+    command: Command,
+    output: OutputFormat,
+) -> Result<(), String> {
+    let is_reload = matches!(command, Command::Reload);
+    let result = request(path, token, command)?;
+    if output == OutputFormat::Json {
+        return print_json(&result);
+    }
+
+    if is_reload {
+        println!("reload requested, see the logs of geofw for the outcome");
+        return Ok(());
+    }
+    print_table(&result, "");
+
+    Ok(())
+}
+
+/// Prints objects as `key = value` lines, with nested objects and lists indented
+fn print_table(value: &serde_json::Value, indent: &str) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let width = map.keys().map(String::len).max().unwrap_or(0);
+            for (key, value) in map {
+                match value {
+                    serde_json::Value::Object(_) | serde_json::Value::Array(_) => {
+                        println!("{}{}", indent, key);
+                        print_table(value, &format!("{}  ", indent));
+                    }
+                    _ => println!("{}{:<width$} = {}", indent, key, scalar(value)),
+                }
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                match value {
+                    serde_json::Value::Object(map) => {
+                        let parts: Vec<String> = map
+                            .iter()
+                            .map(|(k, v)| format!("{} = {}", k, scalar(v)))
+                            .collect();
+                        println!("{}{}", indent, parts.join(" "));
+                    }
+                    _ => println!("{}{}", indent, scalar(value)),
+                }
+            }
+        }
+        _ => println!("{}{}", indent, scalar(value)),
+    }
+}
+
+fn scalar(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Null => "-".to_string(),
+        v => v.to_string(),
+    }
+}
+
+fn respond(writer: &mut UnixStream, response: &Response) -> Result<(), String> {
+    let mut line = serde_json::to_string(response).map_err(|e| e.to_string())?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).map_err(|e| e.to_string())
+}
@@ -0,0 +1,124 @@
+use log::warn;
+use std::{
+    fs, io,
+    os::fd::AsRawFd,
+    path::PathBuf,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+/// Write end of the pipe the parent of a daemon waits on, -1 when geofw runs in the foreground
+/// or once it is ready
+static READY_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// Detaches from the terminal and the process that started geofw. That process only exits once
+/// `ready` is called, with 0, or with 1 when geofw exits before that, so init scripts see a
+/// failed startup. Has to run before the tokio runtime starts its threads
+pub fn daemonize() -> Result<(), String> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(format!(
+            "error in creating pipe: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    let [read_fd, write_fd] = fds;
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(format!("error in forking: {}", io::Error::last_os_error())),
+        0 => {}
+        _ => {
+            unsafe { libc::close(write_fd) };
+            let mut byte = 0u8;
+            let n = unsafe { libc::read(read_fd, (&mut byte as *mut u8).cast(), 1) };
+            std::process::exit(if n == 1 { 0 } else { 1 });
+        }
+    }
+    unsafe { libc::close(read_fd) };
+
+    // A new session without a controlling terminal, the second fork makes sure it can't
+    // acquire one again
+    if unsafe { libc::setsid() } < 0 {
+        return Err(format!(
+            "error in creating session: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    match unsafe { libc::fork() } {
+        -1 => return Err(format!("error in forking: {}", io::Error::last_os_error())),
+        0 => {}
+        _ => unsafe { libc::_exit(0) },
+    }
+
+    redirect_stdio()?;
+    READY_FD.store(write_fd, Ordering::SeqCst);
+
+    Ok(())
+}
+
+/// Points stdin, and stdout and stderr when they are a terminal, at /dev/null. Output redirected
+/// to a file by the init script is kept
+fn redirect_stdio() -> Result<(), String> {
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|e| format!("error in opening /dev/null: {}", e))?;
+    let fd = null.as_raw_fd();
+
+    unsafe { libc::dup2(fd, libc::STDIN_FILENO) };
+    for out in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::isatty(out) } == 1 {
+            unsafe { libc::dup2(fd, out) };
+        }
+    }
+
+    Ok(())
+}
+
+/// Lets the process waiting in `daemonize` exit with success. Does nothing in the foreground
+pub fn ready() {
+    let fd = READY_FD.swap(-1, Ordering::SeqCst);
+    if fd < 0 {
+        return;
+    }
+
+    unsafe {
+        libc::write(fd, [1u8].as_ptr().cast(), 1);
+        libc::close(fd);
+    }
+}
+
+/// A file holding the pid of geofw, removed when dropped
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Writes the pid to `path`. Fails when the file holds the pid of a process that's still
+    /// running, a file left by a crashed instance is replaced
+    pub fn create(path: &str) -> Result<Self, String> {
+        if let Ok(existing) = fs::read_to_string(path) {
+            if let Ok(pid) = existing.trim().parse::<libc::pid_t>() {
+                if pid as u32 != std::process::id() && unsafe { libc::kill(pid, 0) } == 0 {
+                    return Err(format!(
+                        "{} holds pid {}, is geofw already running?",
+                        path, pid
+                    ));
+                }
+            }
+        }
+
+        fs::write(path, format!("{}\n", std::process::id()))
+            .map_err(|e| format!("error in writing pidfile {}: {}", path, e))?;
+
+        Ok(Self { path: path.into() })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("error in removing pidfile {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{
+    db_path,
+    maps::{read_parameter, read_parameters},
+    maxmind::MaxmindDb,
+    output::{print_json, OutputFormat},
+    Config,
+};
+use chrono::DateTime;
+use geofw_common::{MaxmindDbType, ProgramParameters};
+use serde_derive::Serialize;
+
+pub fn format_epoch(epoch: u64) -> String {
+    match DateTime::from_timestamp(epoch as i64, 0) {
+        Some(t) => t.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => "invalid".to_string(),
+    }
+}
+
+#[derive(Serialize)]
+struct DbInfo {
+    db: String,
+    path: String,
+    error: Option<String>,
+    metadata: Option<DbMetadata>,
+    /// Build epoch of the database loaded in the kernel, if geofw is running and has loaded it
+    loaded_build_epoch: Option<u64>,
+    running: bool,
+}
+
+#[derive(Serialize)]
+struct DbMetadata {
+    edition: String,
+    build_epoch: u64,
+    ip_version: u16,
+    record_size: u16,
+    node_count: u32,
+    languages: Vec<String>,
+    description: Vec<(String, String)>,
+}
+
+pub fn dbinfo(config: &Config, output: OutputFormat) -> Result<(), String> {
+    // geofw may not be running, in which case nothing is loaded
+    let params = read_parameters().ok();
+
+    let mut infos = vec![];
+    for db_type in config.db_types() {
+        let path = db_path(config, db_type);
+        let param = match db_type {
+            MaxmindDbType::Country => ProgramParameters::CountryBuildEpoch,
+            MaxmindDbType::Asn => ProgramParameters::AsnBuildEpoch,
+            MaxmindDbType::City => ProgramParameters::CityBuildEpoch,
+            MaxmindDbType::Anonymous => ProgramParameters::AnonymousBuildEpoch,
+        };
+
+        let mut info = DbInfo {
+            db: db_type.to_string(),
+            path: path.to_string_lossy().to_string(),
+            error: None,
+            metadata: None,
+            loaded_build_epoch: params
+                .as_deref()
+                .and_then(|p| read_parameter(p, param))
+                .map(|e| e as u64),
+            running: params.is_some(),
+        };
+
+        match MaxmindDb::from_file(&path.to_string_lossy()) {
+            Ok(db) => {
+                let m = db.metadata;
+                info.metadata = Some(DbMetadata {
+                    edition: m.database_type,
+                    build_epoch: m.build_epoch,
+                    ip_version: m.ip_version,
+                    record_size: m.record_size,
+                    node_count: m.node_count,
+                    languages: m.languages,
+                    description: m.description,
+                });
+            }
+            Err(e) => info.error = Some(e),
+        }
+
+        infos.push(info);
+    }
+
+    if output == OutputFormat::Json {
+        return print_json(&infos);
+    }
+
+    for info in infos {
+        println!("{}", info.db);
+        println!("  path         = {}", info.path);
+
+        if let Some(e) = &info.error {
+            println!("  error        = {}", e);
+            continue;
+        }
+        let Some(m) = &info.metadata else {
+            continue;
+        };
+
+        println!("  edition      = {}", m.edition);
+        println!(
+            "  build_epoch  = {} ({})",
+            m.build_epoch,
+            format_epoch(m.build_epoch)
+        );
+        println!("  ip_version   = {}", m.ip_version);
+        println!("  record_size  = {}", m.record_size);
+        println!("  node_count   = {}", m.node_count);
+        println!("  languages    = {}", m.languages.join(", "));
+        for (lang, description) in &m.description {
+            println!("  description  = [{}] {}", lang, description);
+        }
+
+        let loaded = match (info.running, info.loaded_build_epoch) {
+            (false, _) => "no, geofw is not running".to_string(),
+            (true, None) => "no".to_string(),
+            (true, Some(epoch)) if epoch == m.build_epoch as u32 as u64 => "yes".to_string(),
+            (true, Some(epoch)) => {
+                format!("no, kernel has the build from {}", format_epoch(epoch))
+            }
+        };
+        println!("  loaded       = {}", loaded);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,309 @@
+use crate::{alert::DROP_ALERT_WINDOW, events::Event, metrics::Metrics, privacy::PrivacyConfig};
+use aya::maps::{MapData, RingBuf};
+use fxhash::FxHashMap;
+use geofw_common::{Counter, DropEvent};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    mem,
+    net::{IpAddr, Ipv6Addr},
+    os::fd::AsRawFd,
+    sync::{
+        mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Drops are counted in `drops` metrics in batches, one every this often
+const BATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How dropped packets are logged. A sample of the drops of each source is logged as it
+/// arrives, and the busiest sources are summarized every `interval` seconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DropLogConfig {
+    /// Log the first drop of each source in a summary interval and every `sample`th after it.
+    /// 0 logs no single drops
+    #[serde(default = "default_sample")]
+    pub sample: u64,
+
+    /// Sampled drops logged per second at most, the rest are only counted in the summary
+    #[serde(default = "default_max_per_second")]
+    pub max_per_second: u32,
+
+    /// Seconds between summaries
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+
+    /// Sources listed in a summary
+    #[serde(default = "default_top")]
+    pub top: usize,
+}
+
+impl Default for DropLogConfig {
+    fn default() -> Self {
+        Self {
+            sample: default_sample(),
+            max_per_second: default_max_per_second(),
+            interval: default_interval(),
+            top: default_top(),
+        }
+    }
+}
+
+fn default_sample() -> u64 {
+    1000
+}
+
+fn default_max_per_second() -> u32 {
+    5
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_top() -> usize {
+    5
+}
+
+/// Events a subscriber of the feed can fall behind by before it misses some
+const FEED_BACKLOG: usize = 1024;
+
+/// Subscribers to the drop events, like the streams of the management API
+#[derive(Clone, Default)]
+pub struct DropFeed {
+    subscribers: Arc<Mutex<Vec<SyncSender<String>>>>,
+}
+
+impl DropFeed {
+    /// Returns a receiver of every following drop event as a line of JSON
+    pub fn subscribe(&self) -> Receiver<String> {
+        let (tx, rx) = mpsc::sync_channel(FEED_BACKLOG);
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn publish(&self, event: &Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let Ok(mut line) = serde_json::to_string(event) else {
+            return;
+        };
+        line.push('\n');
+
+        // Subscribers that fall behind miss events, those that are gone are removed
+        subscribers
+            .retain(|s| !matches!(s.try_send(line.clone()), Err(TrySendError::Disconnected(_))));
+    }
+}
+
+/// Starts the thread that reads DROP_EVENTS. Every drop is written to the event log and
+/// counted in `drops` metrics tagged with its reason, logged as `log` allows and sent to the
+/// subscribers of `feed`. The drops of each source are sent to `alerts` once per alert window
+pub fn start(
+    ring: RingBuf<MapData>,
+    log: DropLogConfig,
+    privacy: PrivacyConfig,
+    metrics: Arc<Metrics>,
+    events: Option<Sender<Event>>,
+    feed: DropFeed,
+    alerts: Option<SyncSender<FxHashMap<IpAddr, u64>>>,
+) {
+    thread::spawn(move || read_loop(ring, &log, &privacy, &metrics, events, &feed, alerts));
+}
+
+/// Drops since the last summary
+#[derive(Default)]
+struct Summary {
+    reasons: FxHashMap<Counter, u64>,
+    sources: FxHashMap<IpAddr, u64>,
+    /// Sampled drops that weren't logged because of `max_per_second`
+    suppressed: u64,
+}
+
+/// Sampled drops logged in the current second
+struct LineBudget {
+    second: Instant,
+    lines: u32,
+}
+
+impl LineBudget {
+    fn take(&mut self, max: u32) -> bool {
+        if self.second.elapsed() >= Duration::from_secs(1) {
+            self.second = Instant::now();
+            self.lines = 0;
+        }
+        if self.lines >= max {
+            return false;
+        }
+        self.lines += 1;
+
+        true
+    }
+}
+
+fn read_loop(
+    mut ring: RingBuf<MapData>,
+    log: &DropLogConfig,
+    privacy: &PrivacyConfig,
+    metrics: &Metrics,
+    events: Option<Sender<Event>>,
+    feed: &DropFeed,
+    alerts: Option<SyncSender<FxHashMap<IpAddr, u64>>>,
+) {
+    let summary_interval = Duration::from_secs(log.interval.max(1));
+    let mut batch: FxHashMap<Counter, u64> = FxHashMap::default();
+    let mut summary = Summary::default();
+    let mut budget = LineBudget {
+        second: Instant::now(),
+        lines: 0,
+    };
+    let mut flushed_at = Instant::now();
+    let mut summarized_at = Instant::now();
+    // Drops of each source in the current alert window
+    let mut window: FxHashMap<IpAddr, u64> = FxHashMap::default();
+    let mut window_at = Instant::now();
+
+    loop {
+        let mut timeout = BATCH_INTERVAL
+            .saturating_sub(flushed_at.elapsed())
+            .min(summary_interval.saturating_sub(summarized_at.elapsed()));
+        if alerts.is_some() {
+            timeout = timeout.min(DROP_ALERT_WINDOW.saturating_sub(window_at.elapsed()));
+        }
+        let mut fd = libc::pollfd {
+            fd: ring.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) };
+        if ret < 0 {
+            warn!(
+                "error in polling drop events: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+
+        // Boot time stamps are turned into wall clock time with the offset between the clocks
+        let boot_offset = chrono::Utc::now().timestamp_millis() - boot_time_ms();
+        while let Some(item) = ring.next() {
+            if item.len() < mem::size_of::<DropEvent>() {
+                continue;
+            }
+            let event: DropEvent = unsafe { std::ptr::read_unaligned(item.as_ptr().cast()) };
+            let Some(reason) = Counter::from_index(event.reason) else {
+                continue;
+            };
+            let addr = Ipv6Addr::from(event.addr).to_canonical();
+
+            *batch.entry(reason).or_default() += 1;
+            *summary.reasons.entry(reason).or_default() += 1;
+            if alerts.is_some() {
+                *window.entry(addr).or_default() += 1;
+            }
+            let seen = summary.sources.entry(addr).or_default();
+            *seen += 1;
+            if log.sample > 0 && (*seen - 1) % log.sample == 0 {
+                if budget.take(log.max_per_second) {
+                    tracing::info!(
+                        source = %privacy.redact(addr),
+                        reason = reason.short_name(),
+                        ifindex = event.ifindex,
+                        nth = *seen,
+                        "dropped"
+                    );
+                } else {
+                    summary.suppressed += 1;
+                }
+            }
+
+            let event = Event::Drop {
+                source: privacy.redact(addr),
+                reason: reason.short_name(),
+                ifindex: event.ifindex,
+                dropped_at: (event.timestamp / 1_000_000) as i64 + boot_offset,
+            };
+            feed.publish(&event);
+            if let Some(events) = &events {
+                let _ = events.send(event);
+            }
+        }
+
+        if flushed_at.elapsed() >= BATCH_INTERVAL {
+            for (reason, count) in batch.drain() {
+                metrics.count("drops", count, &[("reason", reason.short_name())]);
+            }
+            flushed_at = Instant::now();
+        }
+        if summarized_at.elapsed() >= summary_interval {
+            summarize(&mut summary, log, privacy);
+            summarized_at = Instant::now();
+        }
+        if let (Some(alerts), true) = (&alerts, window_at.elapsed() >= DROP_ALERT_WINDOW) {
+            // Quiet windows are sent too, they resolve firing alerts
+            let _ = alerts.try_send(mem::take(&mut window));
+            window_at = Instant::now();
+        }
+    }
+}
+
+fn summarize(summary: &mut Summary, log: &DropLogConfig, privacy: &PrivacyConfig) {
+    let summary = mem::take(summary);
+    if summary.reasons.is_empty() {
+        return;
+    }
+
+    let total: u64 = summary.reasons.values().sum();
+    let reasons: Vec<String> = summary
+        .reasons
+        .iter()
+        .map(|(reason, count)| format!("{}:{}", reason.short_name(), count))
+        .collect();
+    tracing::info!(
+        total,
+        sources = summary.sources.len(),
+        reasons = %reasons.join(","),
+        suppressed = summary.suppressed,
+        window_secs = log.interval,
+        "drops"
+    );
+
+    let mut sources: Vec<_> = summary.sources.into_iter().collect();
+    sources.sort_unstable_by_key(|&(_, count)| std::cmp::Reverse(count));
+    for (addr, count) in sources.into_iter().take(log.top) {
+        let source = privacy.redact(addr);
+        tracing::info!(
+            source = %source,
+            drops = count,
+            window_secs = log.interval,
+            "{}: {} drops in last {}s",
+            source,
+            abbreviate(count),
+            log.interval
+        );
+    }
+}
+
+/// 15300 as 15.3k, for the summary lines and alerts
+pub fn abbreviate(n: u64) -> String {
+    match n {
+        0..1_000 => n.to_string(),
+        1_000..1_000_000 => format!("{:.1}k", n as f64 / 1e3),
+        _ => format!("{:.1}M", n as f64 / 1e6),
+    }
+}
+
+/// Milliseconds since boot, including time spent suspended, like bpf_ktime_get_boot_ns
+fn boot_time_ms() -> i64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) };
+
+    ts.tv_sec * 1000 + ts.tv_nsec / 1_000_000
+}
@@ -0,0 +1,212 @@
+use crate::state::Verdict;
+use chrono::Utc;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventsConfig {
+    /// Directory the event files are written to
+    pub path: String,
+
+    /// A new file is started once the current one is larger than this many bytes
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+
+    /// The oldest files are deleted while all files together are larger than this many bytes
+    #[serde(default = "default_max_total_size")]
+    pub max_total_size: u64,
+
+    /// Files last written to longer than this many seconds ago are deleted
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+}
+
+fn default_max_file_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_max_total_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_max_age() -> u64 {
+    7 * 86400
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Event {
+    Suspect {
+        source: String,
+        len: usize,
+        verdict: Verdict,
+    },
+    /// A packet the XDP or egress program dropped, `source` is its destination for drops by
+    /// destination
+    Drop {
+        source: String,
+        reason: &'static str,
+        ifindex: u32,
+        /// Milliseconds since the epoch
+        dropped_at: i64,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: i64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Starts the thread that writes events as JSON lines to files in `config.path`
+pub fn start(config: &EventsConfig) -> Result<Sender<Event>, String> {
+    fs::create_dir_all(&config.path)
+        .map_err(|e| format!("error in creating {}: {}", config.path, e))?;
+
+    let (tx, rx) = mpsc::channel();
+    let config = config.clone();
+    thread::spawn(move || write_loop(&config, rx));
+
+    Ok(tx)
+}
+
+struct EventFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl EventFile {
+    fn create(dir: &str) -> Result<Self, String> {
+        let name = format!("events-{}.jsonl", Utc::now().format("%Y%m%dT%H%M%S%.3f"));
+        let path = Path::new(dir).join(name);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("error in creating {}: {}", path.display(), e))?;
+
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            size: 0,
+        })
+    }
+}
+
+fn write_loop(config: &EventsConfig, rx: Receiver<Event>) {
+    let mut current: Option<EventFile> = None;
+    let mut last_prune = Instant::now();
+    prune(config, None);
+
+    loop {
+        let event = match rx.recv_timeout(PRUNE_INTERVAL) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if let Some(event) = event {
+            if current
+                .as_ref()
+                .is_none_or(|f| f.size >= config.max_file_size)
+            {
+                if let Some(mut f) = current.take() {
+                    let _ = f.writer.flush();
+                }
+                match EventFile::create(&config.path) {
+                    Ok(f) => current = Some(f),
+                    Err(e) => {
+                        warn!("{}", e);
+                        continue;
+                    }
+                }
+            }
+
+            let Some(f) = current.as_mut() else {
+                continue;
+            };
+            let record = Record {
+                time: Utc::now().timestamp(),
+                event: &event,
+            };
+            let Ok(mut line) = serde_json::to_string(&record) else {
+                continue;
+            };
+            line.push('\n');
+
+            match f.writer.write_all(line.as_bytes()) {
+                Ok(()) => f.size += line.len() as u64,
+                Err(e) => warn!("error in writing event to {}: {}", f.path.display(), e),
+            }
+        }
+
+        if let Some(f) = current.as_mut() {
+            let _ = f.writer.flush();
+        }
+
+        if last_prune.elapsed() >= PRUNE_INTERVAL {
+            prune(config, current.as_ref().map(|f| f.path.as_path()));
+            last_prune = Instant::now();
+        }
+    }
+}
+
+/// Deletes event files that are too old and then the oldest ones until the total size is under
+/// the limit. The file currently written to is never deleted
+fn prune(config: &EventsConfig, current: Option<&Path>) {
+    let entries = match fs::read_dir(&config.path) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("error in listing {}: {}", config.path, e);
+            return;
+        }
+    };
+
+    let mut files: Vec<(PathBuf, u64, SystemTime)> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            name.starts_with("events-") && name.ends_with(".jsonl")
+        })
+        .filter_map(|e| {
+            let meta = e.metadata().ok()?;
+            Some((e.path(), meta.len(), meta.modified().ok()?))
+        })
+        .collect();
+    // File names start with the time they were created
+    files.sort();
+
+    let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+    let max_age = Duration::from_secs(config.max_age);
+
+    for (path, size, modified) in files {
+        if Some(path.as_path()) == current {
+            continue;
+        }
+
+        let expired = modified.elapsed().is_ok_and(|age| age > max_age);
+        if !expired && total <= config.max_total_size {
+            continue;
+        }
+
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                info!("pruned event file {}", path.display());
+                total -= size;
+            }
+            Err(e) => warn!("error in removing {}: {}", path.display(), e),
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use crate::{blocklist::Cidr, metrics::Metrics};
+use aya::maps::{LpmTrie, MapData};
+use fxhash::{FxHashMap, FxHashSet};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    io::Read,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+/// A blocklist downloaded from `url` every `interval` seconds, like the Spamhaus DROP list or a
+/// FireHOL netset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeedConfig {
+    /// Used in logs and metrics, the URL when empty
+    #[serde(default)]
+    pub name: String,
+
+    pub url: String,
+
+    #[serde(default = "default_feed_interval")]
+    pub interval: u64,
+
+    #[serde(default)]
+    pub format: FeedFormat,
+}
+
+impl FeedConfig {
+    pub fn name(&self) -> &str {
+        if self.name.is_empty() {
+            &self.url
+        } else {
+            &self.name
+        }
+    }
+}
+
+/// How addresses and networks are laid out in a downloaded list
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FeedFormat {
+    /// One per line, followed by anything. Comments start with `#` or `;`
+    #[default]
+    Plain,
+    /// One per row in the `column`th column, counting from 0
+    Csv {
+        column: usize,
+        #[serde(default = "default_csv_delimiter")]
+        delimiter: char,
+        /// Skip the first row
+        #[serde(default)]
+        header: bool,
+    },
+    /// The strings at `path`, a list of object keys and array indexes separated by dots where
+    /// `*` stands for every element of an array or every value of an object
+    Json { path: String },
+}
+
+fn default_csv_delimiter() -> char {
+    ','
+}
+
+fn default_feed_interval() -> u64 {
+    3600
+}
+
+#[derive(Default)]
+struct FeedState {
+    fetched_at: Option<Instant>,
+    cidrs: Vec<Cidr>,
+}
+
+/// Downloads of the feeds that were due, sent back to the main loop by the blocking pool
+pub struct Fetched(Vec<(FeedConfig, Result<Vec<Cidr>, String>)>);
+
+/// Keeps FEED_CIDRS in sync with the configured feeds. Every feed keeps its last good download,
+/// so a feed that can't be fetched doesn't unblock its networks
+pub struct Feeds {
+    map: LpmTrie<MapData, [u8; 16], u8>,
+    feeds: Vec<FeedConfig>,
+    state: FxHashMap<String, FeedState>,
+    loaded: FxHashSet<Cidr>,
+    tx: mpsc::Sender<Fetched>,
+    running: bool,
+    /// Feeds were checked while downloads were running, they are checked again once those are
+    /// done
+    queued: bool,
+}
+
+impl Feeds {
+    pub fn new(
+        map: LpmTrie<MapData, [u8; 16], u8>,
+        feeds: Vec<FeedConfig>,
+        tx: mpsc::Sender<Fetched>,
+    ) -> Self {
+        Self {
+            map,
+            feeds,
+            state: FxHashMap::default(),
+            loaded: FxHashSet::default(),
+            tx,
+            running: false,
+            queued: false,
+        }
+    }
+
+    /// Replaces the feeds. Feeds whose URL or format changed are downloaded again on the next refresh and
+    /// the networks of removed feeds are unblocked
+    pub fn set_feeds(&mut self, feeds: Vec<FeedConfig>) {
+        self.state.retain(|name, _| {
+            let old = self.feeds.iter().find(|f| f.name() == name);
+            let new = feeds.iter().find(|f| f.name() == name);
+            matches!((old, new), (Some(a), Some(b)) if a.url == b.url && a.format == b.format)
+        });
+        self.feeds = feeds;
+    }
+
+    /// Downloads the feeds that are due on the blocking pool, a slow feed would otherwise stall
+    /// signals and control requests. The downloads are written into the map by `apply`
+    pub fn start(&mut self) {
+        if self.running {
+            self.queued = true;
+            return;
+        }
+
+        let due = self.due();
+        if due.is_empty() {
+            return;
+        }
+        self.running = true;
+
+        let tx = self.tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let fetched = due
+                .into_iter()
+                .map(|feed| {
+                    let result = fetch(&feed);
+                    (feed, result)
+                })
+                .collect();
+            // The main loop has exited
+            let _ = tx.blocking_send(Fetched(fetched));
+        });
+    }
+
+    /// Keeps the downloads of `start` and writes the changes into the map. Downloads of feeds
+    /// that were replaced while they ran are dropped
+    pub fn apply(&mut self, fetched: Fetched, metrics: &Metrics) -> Result<(), String> {
+        self.running = false;
+        for (feed, result) in fetched.0 {
+            if self.feeds.contains(&feed) {
+                self.keep(&feed, result, metrics);
+            }
+        }
+
+        if std::mem::take(&mut self.queued) {
+            self.start();
+        }
+
+        self.sync()
+    }
+
+    /// The feeds whose interval has passed since they were last fetched
+    fn due(&mut self) -> Vec<FeedConfig> {
+        let mut due = vec![];
+        for feed in &self.feeds {
+            let state = self.state.entry(feed.name().to_string()).or_default();
+            let interval = Duration::from_secs(feed.interval.max(60));
+            if state.fetched_at.is_some_and(|t| t.elapsed() < interval) {
+                continue;
+            }
+            // Failed downloads are retried on the next interval, not on every call
+            state.fetched_at = Some(Instant::now());
+            due.push(feed.clone());
+        }
+
+        due
+    }
+
+    fn keep(&mut self, feed: &FeedConfig, result: Result<Vec<Cidr>, String>, metrics: &Metrics) {
+        let state = self.state.entry(feed.name().to_string()).or_default();
+        match result {
+            Ok(cidrs) => {
+                info!("fetched feed {} entries = {}", feed.name(), cidrs.len());
+                metrics.gauge("feed.entries", cidrs.len() as f64, &[("feed", feed.name())]);
+                state.cidrs = cidrs;
+            }
+            Err(e) => {
+                warn!("error in fetching feed {}: {}", feed.name(), e);
+                metrics.count("feed.errors", 1, &[("feed", feed.name())]);
+            }
+        }
+    }
+
+    /// Writes the networks of the current feeds into the map and removes those of feeds that
+    /// are gone
+    pub fn sync(&mut self) -> Result<(), String> {
+        let wanted: FxHashSet<Cidr> = self
+            .feeds
+            .iter()
+            .filter_map(|f| self.state.get(f.name()))
+            .flat_map(|s| s.cidrs.iter().copied())
+            .collect();
+        if wanted == self.loaded {
+            return Ok(());
+        }
+
+        for cidr in wanted.difference(&self.loaded) {
+            self.map
+                .insert(&cidr.key(), 1, 0)
+                .map_err(|e| format!("error in adding {}: {}", cidr, e))?;
+        }
+        for cidr in self.loaded.difference(&wanted) {
+            if let Err(e) = self.map.remove(&cidr.key()) {
+                warn!("error in removing {}: {}", cidr, e);
+            }
+        }
+        info!("reloaded feeds entries = {}", wanted.len());
+
+        self.loaded = wanted;
+
+        Ok(())
+    }
+}
+
+fn fetch(feed: &FeedConfig) -> Result<Vec<Cidr>, String> {
+    let resp = ureq::get(&feed.url).call().map_err(|e| e.to_string())?;
+    let mut body = String::new();
+    resp.into_reader()
+        .read_to_string(&mut body)
+        .map_err(|e| format!("error in downloading feed: {}", e))?;
+
+    let words = match &feed.format {
+        FeedFormat::Plain => plain_words(&body),
+        FeedFormat::Csv {
+            column,
+            delimiter,
+            header,
+        } => csv_words(&body, *column, *delimiter, *header),
+        FeedFormat::Json { path } => json_words(&body, path)?,
+    };
+
+    let mut cidrs = vec![];
+    let mut invalid = 0;
+    for word in words {
+        match word.trim().parse() {
+            Ok(cidr) => cidrs.push(cidr),
+            Err(_) => invalid += 1,
+        }
+    }
+    if invalid > 0 {
+        warn!("feed {} has {} invalid entries", feed.name(), invalid);
+    }
+
+    Ok(cidrs)
+}
+
+/// The first word of every line, without comments starting with `#` like in FireHOL netsets or
+/// `;` like in the Spamhaus lists
+fn plain_words(body: &str) -> Vec<String> {
+    body.lines()
+        .filter_map(|line| {
+            let line = line.split(['#', ';']).next().unwrap_or_default();
+            line.split_whitespace().next().map(str::to_string)
+        })
+        .collect()
+}
+
+/// The fields of `column` in every row that isn't empty or a `#` comment. Quotes around fields
+/// are removed, quoted delimiters aren't supported
+fn csv_words(body: &str, column: usize, delimiter: char, header: bool) -> Vec<String> {
+    body.lines()
+        .skip(header as usize)
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split(delimiter).nth(column))
+        .map(|field| field.trim().trim_matches('"').to_string())
+        .collect()
+}
+
+fn json_words(body: &str, path: &str) -> Result<Vec<String>, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(body).map_err(|e| format!("error in parsing feed: {}", e))?;
+
+    let mut values = vec![&value];
+    for segment in path.split('.').filter(|s| !s.is_empty()) {
+        values = values
+            .into_iter()
+            .flat_map(|v| -> Vec<&serde_json::Value> {
+                match (segment, v) {
+                    ("*", serde_json::Value::Array(a)) => a.iter().collect(),
+                    ("*", serde_json::Value::Object(o)) => o.values().collect(),
+                    (_, serde_json::Value::Array(a)) => segment
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|i| a.get(i))
+                        .into_iter()
+                        .collect(),
+                    (_, serde_json::Value::Object(o)) => o.get(segment).into_iter().collect(),
+                    _ => vec![],
+                }
+            })
+            .collect();
+    }
+
+    Ok(values
+        .into_iter()
+        .filter_map(|v| v.as_str().map(str::to_string))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_lists() {
+        let body = "; Spamhaus DROP List\n192.0.2.0/24 ; SBL123\n\n# netset\n198.51.100.0/24\n  2001:db8::/32 extra\n";
+
+        assert_eq!(
+            plain_words(body),
+            vec!["192.0.2.0/24", "198.51.100.0/24", "2001:db8::/32"]
+        );
+    }
+
+    #[test]
+    fn csv_lists() {
+        let body = "id,network,score\n# comment\n1,\"192.0.2.0/24\",10\n\n2, 198.51.100.1 ,5\n3\n";
+
+        assert_eq!(
+            csv_words(body, 1, ',', true),
+            vec!["192.0.2.0/24", "198.51.100.1"]
+        );
+        assert_eq!(csv_words("a;192.0.2.1\n", 1, ';', false), vec!["192.0.2.1"]);
+    }
+
+    #[test]
+    fn json_lists() {
+        let body = r#"{
+            "data": [
+                { "ip": "192.0.2.1", "tags": { "a": "198.51.100.1" } },
+                { "ip": "2001:db8::1", "tags": {} },
+                { "ip": 5 }
+            ]
+        }"#;
+
+        assert_eq!(
+            json_words(body, "data.*.ip").unwrap(),
+            vec!["192.0.2.1", "2001:db8::1"]
+        );
+        assert_eq!(
+            json_words(body, "data.0.tags.*").unwrap(),
+            vec!["198.51.100.1"]
+        );
+        assert_eq!(json_words(body, "data.7.ip").unwrap(), Vec::<String>::new());
+        assert_eq!(
+            json_words(r#"["192.0.2.1"]"#, "").unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            json_words(r#"["192.0.2.1"]"#, "*").unwrap(),
+            vec!["192.0.2.1"]
+        );
+        assert!(json_words("not json", "*").is_err());
+    }
+}
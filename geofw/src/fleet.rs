@@ -0,0 +1,411 @@
+use crate::{
+    auth::{AuthError, Scope, Tokens},
+    maxmind::ProcessedDb,
+    peers::{from_hex, to_hex},
+    tls::TlsConfig,
+    Config, RefreshReport,
+};
+use fxhash::FxHashMap;
+use geofw_common::MaxmindDbType;
+use log::{debug, info, warn};
+use ring::{digest, hmac};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FleetRole {
+    /// Downloads and processes the databases and serves the processed trees to agents
+    Server,
+    /// Loads the processed trees from a server instead of downloading from MaxMind
+    Agent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FleetConfig {
+    pub role: FleetRole,
+
+    /// Server only, address to serve the trees on, e.g. 0.0.0.0:8700
+    #[serde(default)]
+    pub listen: Option<String>,
+
+    /// Agent only, base URL of the server, e.g. http://10.0.0.1:8700
+    #[serde(default)]
+    pub url: Option<String>,
+
+    /// Shared by the server and the agents. Trees are signed with HMAC-SHA256 using it
+    pub secret: String,
+
+    /// Serve the trees over HTTPS on the server. On agents, the client certificate presented to
+    /// the server
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+
+    /// Agent only, API token with the read scope, needed when the server has `api_tokens`
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Describes the tree that follows it in a response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TreeHeader {
+    node_count: u32,
+    record_size: u16,
+    ipv4_start: u32,
+    build_epoch: u64,
+    marked: u32,
+    /// Hash of the rules the tree was processed with
+    rules_hash: String,
+    signature: String,
+}
+
+impl TreeHeader {
+    fn signed_bytes(&self, tree: &[u8]) -> Vec<u8> {
+        let mut out = format!(
+            "{}:{}:{}:{}:{}:{}:",
+            self.node_count,
+            self.record_size,
+            self.ipv4_start,
+            self.build_epoch,
+            self.marked,
+            self.rules_hash
+        )
+        .into_bytes();
+        out.extend_from_slice(tree);
+        out
+    }
+}
+
+/// SHA-256 of every config field that changes how a tree is processed. Agents warn when a
+/// server processed the trees with different rules than their own config has
+pub fn rules_hash(config: &Config) -> String {
+    let mut countries: Vec<&String> = config.source_countries.iter().collect();
+    countries.sort();
+    let mut asns: Vec<&u32> = config.source_asn.iter().collect();
+    asns.sort();
+    let mut country_actions: Vec<_> = config.country_actions.iter().collect();
+    country_actions.sort_by_key(|&(c, _)| c);
+    let compound_rules: Vec<String> = config
+        .compound_rules
+        .iter()
+        .map(|r| {
+            let mut asns: Vec<&u32> = r.asn.iter().collect();
+            asns.sort();
+            let mut except: Vec<&u32> = r.except_asn.iter().collect();
+            except.sort();
+            format!("{}/{:?}/{:?}", r.country, asns, except)
+        })
+        .collect();
+    let mut asn_orgs: Vec<&String> = config.source_asn_org_patterns.iter().collect();
+    asn_orgs.sort();
+    let mut cities: Vec<&u32> = config.source_cities.iter().collect();
+    cities.sort();
+    let mut subdivisions: Vec<&String> = config.source_subdivisions.iter().collect();
+    subdivisions.sort();
+    let mut suspect: Vec<&String> = config
+        .suspect
+        .iter()
+        .flat_map(|s| s.countries.iter())
+        .collect();
+    suspect.sort();
+
+    let policies: Vec<String> = config
+        .policies
+        .iter()
+        .map(|p| {
+            let mut countries: Vec<&String> = p.source_countries.iter().collect();
+            countries.sort();
+            let mut asns: Vec<&u32> = p.source_asn.iter().collect();
+            asns.sort();
+            let mut asn_orgs: Vec<&String> = p.source_asn_org_patterns.iter().collect();
+            asn_orgs.sort();
+            let mut cities: Vec<&u32> = p.source_cities.iter().collect();
+            cities.sort();
+            let mut subdivisions: Vec<&String> = p.source_subdivisions.iter().collect();
+            subdivisions.sort();
+            format!(
+                "{:?}/{:?}/{:?}/{:?}/{:?}",
+                countries, asns, asn_orgs, cities, subdivisions
+            )
+        })
+        .collect();
+
+    let mut allow_countries: Vec<&String> = config.allow_countries.iter().collect();
+    allow_countries.sort();
+    let mut allow_asns: Vec<&u32> = config.allow_asn.iter().collect();
+    allow_asns.sort();
+
+    let rules = format!(
+        "countries={:?};country_actions={:?};compound_rules={:?};asn={:?};asn_orgs={:?};cities={:?};subdivisions={:?};anonymous={:?};skip_anycast={};suspect={:?};policies={:?};mode={:?};allow_countries={:?};allow_asn={:?};precedence={:?}",
+        countries,
+        country_actions,
+        compound_rules,
+        asns,
+        asn_orgs,
+        cities,
+        subdivisions,
+        config.block_anonymous,
+        config.skip_anycast,
+        suspect,
+        policies,
+        config.mode,
+        allow_countries,
+        allow_asns,
+        config.precedence
+    );
+
+    to_hex(digest::digest(&digest::SHA256, rules.as_bytes()).as_ref())
+}
+
+/// A published tree and the header describing it
+type Tree = Arc<(TreeHeader, Vec<u8>)>;
+
+/// Processed trees published by a server, served to agents over HTTP
+#[derive(Clone)]
+pub struct FleetServer {
+    key: hmac::Key,
+    tokens: Tokens,
+    trees: Arc<Mutex<FxHashMap<MaxmindDbType, Tree>>>,
+}
+
+impl FleetServer {
+    pub fn start(config: &FleetConfig, tokens: Tokens) -> Result<Self, String> {
+        let listen = config
+            .listen
+            .as_deref()
+            .ok_or("fleet.listen is required for the server role")?;
+        let listener = TcpListener::bind(listen)
+            .map_err(|e| format!("error in listening on {}: {}", listen, e))?;
+
+        let tls = config
+            .tls
+            .as_ref()
+            .map(TlsConfig::server_config)
+            .transpose()?;
+
+        let server = Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes()),
+            tokens,
+            trees: Arc::default(),
+        };
+
+        let s = server.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let s = s.clone();
+                let tls = tls.clone();
+                thread::spawn(move || {
+                    if let Err(e) = s.serve(stream, tls) {
+                        debug!("error in serving fleet agent: {}", e);
+                    }
+                });
+            }
+        });
+
+        info!("serving processed trees to fleet agents on {}", listen);
+
+        Ok(server)
+    }
+
+    pub fn publish(&self, db_type: MaxmindDbType, db: &ProcessedDb, rules_hash: String) {
+        let mut header = TreeHeader {
+            node_count: db.node_count,
+            record_size: db.record_size,
+            ipv4_start: db.ipv4_start,
+            build_epoch: db.build_epoch,
+            marked: db.marked,
+            rules_hash,
+            signature: String::new(),
+        };
+        header.signature = to_hex(hmac::sign(&self.key, &header.signed_bytes(&db.db)).as_ref());
+
+        if let Ok(mut trees) = self.trees.lock() {
+            trees.insert(db_type, Arc::new((header, db.db.clone())));
+        }
+    }
+
+    fn serve(&self, stream: TcpStream, tls: Option<Arc<ServerConfig>>) -> Result<(), String> {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .map_err(|e| e.to_string())?;
+
+        match tls {
+            Some(tls) => {
+                let conn = ServerConnection::new(tls).map_err(|e| e.to_string())?;
+                self.respond(StreamOwned::new(conn, stream))
+            }
+            None => self.respond(stream),
+        }
+    }
+
+    fn respond(&self, stream: impl Read + Write) -> Result<(), String> {
+        let mut reader = BufReader::new(stream);
+        let mut request = String::new();
+        reader.read_line(&mut request).map_err(|e| e.to_string())?;
+        let mut authorization = None;
+        loop {
+            let mut line = String::new();
+            let n = reader.read_line(&mut line).map_err(|e| e.to_string())?;
+            if n == 0 || line == "\r\n" || line == "\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("authorization") {
+                    authorization = Some(value.trim().to_string());
+                }
+            }
+        }
+        let mut stream = reader.into_inner();
+
+        match self
+            .tokens
+            .authorize_header(authorization.as_deref(), Scope::Read)
+        {
+            Ok(_) => {}
+            Err(AuthError::Unauthenticated) => {
+                return stream
+                    .write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n")
+                    .map_err(|e| e.to_string())
+            }
+            Err(AuthError::Forbidden) => {
+                return stream
+                    .write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")
+                    .map_err(|e| e.to_string())
+            }
+        }
+
+        let tree = match request.split_whitespace().collect::<Vec<_>>()[..] {
+            ["GET", "/v1/trees/country", _] => self.tree(MaxmindDbType::Country),
+            ["GET", "/v1/trees/asn", _] => self.tree(MaxmindDbType::Asn),
+            ["GET", "/v1/trees/city", _] => self.tree(MaxmindDbType::City),
+            ["GET", "/v1/trees/anonymous", _] => self.tree(MaxmindDbType::Anonymous),
+            _ => {
+                return stream
+                    .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                    .map_err(|e| e.to_string())
+            }
+        };
+        let Some(tree) = tree else {
+            return stream
+                .write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n")
+                .map_err(|e| e.to_string());
+        };
+
+        let (header, body) = tree.as_ref();
+        let mut header = serde_json::to_string(header).map_err(|e| e.to_string())?;
+        header.push('\n');
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            header.len() + body.len(),
+            header
+        )
+        .and_then(|_| stream.write_all(body))
+        .and_then(|_| stream.flush())
+        .map_err(|e| e.to_string())
+    }
+
+    fn tree(&self, db_type: MaxmindDbType) -> Option<Tree> {
+        self.trees.lock().ok()?.get(&db_type).cloned()
+    }
+}
+
+/// Downloads a processed tree from the fleet server
+pub fn fetch(
+    config: &Config,
+    fleet: &FleetConfig,
+    db_type: MaxmindDbType,
+    report: &mut RefreshReport,
+) -> Result<ProcessedDb, String> {
+    let base = fleet
+        .url
+        .as_deref()
+        .ok_or("fleet.url is required for the agent role")?;
+    let url = format!(
+        "{}/v1/trees/{}",
+        base.trim_end_matches('/'),
+        db_type.short_name()
+    );
+
+    info!("fetching processed tree from = {}", url);
+
+    let mut agent = ureq::AgentBuilder::new();
+    if let Some(tls) = &fleet.tls {
+        agent = agent.tls_config(tls.client_config()?);
+    }
+
+    let mut request = agent.build().get(&url);
+    if let Some(token) = &fleet.token {
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+
+    let t = Instant::now();
+    let mut body = vec![];
+    request
+        .call()
+        .map_err(|e| format!("error in fetching {}: {}", url, e))?
+        .into_reader()
+        .read_to_end(&mut body)
+        .map_err(|e| format!("error in downloading tree: {}", e))?;
+    report.downloaded_bytes = body.len() as u64;
+    report.download_time = t.elapsed();
+
+    let split = body
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or("malformed response from fleet server")?;
+    let header: TreeHeader = serde_json::from_slice(&body[..split])
+        .map_err(|e| format!("malformed tree header: {}", e))?;
+    let tree = body[split + 1..].to_vec();
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, fleet.secret.as_bytes());
+    let signature = from_hex(&header.signature).ok_or("malformed tree signature")?;
+    hmac::verify(&key, &header.signed_bytes(&tree), &signature)
+        .map_err(|_| "tree signature does not match, check fleet.secret")?;
+
+    // Servers before the markers moved above 1 << 28 send trees with the records of the database
+    if header.record_size != 32 {
+        return Err(format!(
+            "tree has {} bit records, the fleet server runs an older geofw",
+            header.record_size
+        ));
+    }
+    let node_size = header.record_size as usize * 2 / 8;
+    if tree.len() < header.node_count as usize * node_size {
+        return Err(format!(
+            "tree is {} bytes, expected at least {}",
+            tree.len(),
+            header.node_count as usize * node_size
+        ));
+    }
+
+    if header.rules_hash != rules_hash(config) {
+        warn!(
+            "{} was processed by the fleet server with different rules than the local config",
+            db_type
+        );
+    }
+
+    report.build_epoch = header.build_epoch;
+    report.marked = header.marked;
+
+    Ok(ProcessedDb {
+        node_count: header.node_count,
+        record_size: header.record_size,
+        ipv4_start: header.ipv4_start,
+        build_epoch: header.build_epoch,
+        marked: header.marked,
+        db: tree,
+    })
+}
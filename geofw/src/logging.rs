@@ -0,0 +1,52 @@
+use clap::ValueEnum;
+use log::LevelFilter;
+use std::io::IsTerminal;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    /// One line of text per event on stderr
+    #[default]
+    Text,
+    /// One JSON object per event on stdout, with the fields of an event as keys
+    Json,
+    /// Native journald entries, with the fields of an event as journal fields
+    Journald,
+}
+
+/// Installs the subscriber the `log` and `tracing` macros write to. `level` replaces the
+/// filter in RUST_LOG, which defaults to error
+pub fn init(format: LogFormat, level: Option<LevelFilter>) -> Result<(), String> {
+    let filter = match level {
+        Some(level) => EnvFilter::new(level.as_str().to_lowercase()),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error")),
+    };
+
+    // The `log` records of the rest of geofw and aya-log become events with the same fields
+    let result = match format {
+        LogFormat::Text => tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+            .try_init()
+            .map_err(|e| e.to_string()),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_env_filter(filter)
+            .try_init()
+            .map_err(|e| e.to_string()),
+        LogFormat::Journald => {
+            let journald = tracing_journald::layer()
+                .map_err(|e| format!("error in connecting to journald: {}", e))?;
+            tracing_subscriber::registry()
+                .with(filter)
+                .with(journald)
+                .try_init()
+                .map_err(|e| e.to_string())
+        }
+    };
+
+    result.map_err(|e| format!("error in setting up logging: {}", e))
+}
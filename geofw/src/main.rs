@@ -1,75 +1,892 @@
+mod afxdp;
+mod alert;
+mod anonymous;
+mod api;
+mod attach;
+mod auth;
+mod blocklist;
+mod bogons;
+mod caps;
+mod check;
+mod control;
+mod daemon;
+mod dbinfo;
+mod download;
+mod drops;
+mod events;
+mod feeds;
+mod fleet;
+mod logging;
+mod maps;
 mod maxmind;
+mod metrics;
+mod migrate;
+mod output;
+mod overrides;
+mod peers;
+mod pin;
+mod policy;
+mod privacy;
+mod schedule;
+mod simulate;
+mod state;
+mod stats;
+mod summary;
+mod suspect;
+mod systemd;
+mod tap;
+mod testdb;
+mod tls;
+mod verify;
+mod xsk;
 
-use anyhow::Context as _;
+use alert::DropAlertConfig;
+use anonymous::AnonymousIp;
+use api::ApiConfig;
+use attach::{CgroupLinks, Datapath, EgressLinks, Links, XdpMode};
+use auth::{ApiToken, Tokens};
 use aya::{
-    maps::{Array, HashMap, MapData},
-    programs::{Xdp, XdpFlags},
-    Ebpf,
+    maps::{lpm_trie::Key, Array, HashMap, LpmTrie, MapData, PerCpuArray, RingBuf},
+    programs::{CgroupSkb, SchedClassifier, Xdp},
+    Ebpf, EbpfLoader,
 };
+use blocklist::{Cidr, CidrLists};
+use clap::{Parser, Subcommand, ValueEnum};
+use control::Response;
+use download::{RetryConfig, Validators};
+use drops::{DropFeed, DropLogConfig};
+use events::EventsConfig;
+use feeds::{FeedConfig, Feeds};
 use flate2::bufread::GzDecoder;
-use fxhash::FxHashSet;
-use geofw_common::{MaxmindDbType, ProgramParameters};
-use log::{debug, info, warn};
+use fleet::{FleetConfig, FleetRole, FleetServer};
+use fxhash::{FxHashMap, FxHashSet};
+use geofw_common::{
+    pass_service_key, CountryAction, Direction, FragmentAction, LookupBackend, LookupParameters,
+    MalformedAction, MaxmindDbType, Mode, MulticastAction, Precedence, ProgramParameters,
+    TreeShape, ALLOW_MARKER, BLOCK_MARKER, COMPOUND_MARKER, MAX_POLICIES, POLICY_MARKER,
+    SUSPECT_MARKER,
+};
+use log::{debug, info, warn, LevelFilter};
+use logging::LogFormat;
 use maxmind::{Data, ProcessedDb};
+use metrics::{Metrics, PrometheusConfig, PushgatewayConfig, StatsdConfig};
+use output::OutputFormat;
+use peers::PeerSyncConfig;
+use policy::{CompoundRule, Policy, PolicyAction, PortScope, RateLimitConfig};
+use privacy::PrivacyConfig;
+use schedule::TimeWindow;
 use serde_derive::{Deserialize, Serialize};
 use std::{
-    fs::File,
-    io::{BufReader, ErrorKind, Read, Write},
-    path::PathBuf,
-    time::Instant,
+    fs::{self, File},
+    io::{ErrorKind, Read, Write},
+    net::IpAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{mpsc as std_mpsc, Arc},
+    time::{Duration, Instant},
 };
+use summary::{Summary, SummaryConfig};
+use suspect::SuspectConfig;
 use tar::Archive;
-use tokio::{signal, time};
+use tokio::{signal, sync::mpsc, time};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Output format of subcommands
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+
+    /// Path to the config file
+    #[arg(long, global = true, default_value = "./config.json")]
+    config: String,
+
+    /// Format of the config file. Guessed from its extension by default
+    #[arg(long, global = true, value_enum)]
+    config_format: Option<ConfigFormat>,
+
+    /// Attach to this interface instead of the ones in the config. Can be repeated
+    #[arg(long, global = true)]
+    interface: Vec<String>,
+
+    /// off, error, warn, info, debug or trace. Overrides RUST_LOG
+    #[arg(long, global = true)]
+    log_level: Option<LevelFilter>,
+
+    /// Write logs as text, as JSON or to journald with the fields of each event
+    #[arg(long, global = true, value_enum, default_value_t)]
+    log_format: LogFormat,
+
+    /// API token sent to the control socket, needed when the config has `api_tokens`
+    #[arg(long, global = true)]
+    token: Option<String>,
+
+    /// Run in the background. The command returns once geofw is attached and has loaded the
+    /// databases, and fails if it exits before that
+    #[arg(long, global = true)]
+    daemon: bool,
+
+    /// Write the pid to this file while geofw runs
+    #[arg(long, global = true)]
+    pidfile: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Attach to the interfaces and keep the maps up to date, what geofw does without a
+    /// subcommand
+    Run,
+
+    /// Print the interfaces, databases and policies of the running instance
+    Status,
+
+    /// Print the packet counters of the running instance
+    Stats,
+
+    /// Make the running instance reload its config, like SIGHUP
+    Reload,
+
+    /// Make the running instance refresh its databases now
+    Refresh,
+
+    /// Print the policies of the running instance
+    Policies,
+
+    /// Ask the running instance for the verdict on an address
+    Lookup { addr: IpAddr },
+
+    /// Print the decoded contents of a map loaded by a running geofw instance
+    DumpMap {
+        /// BLOCKED_COUNTRY, BLOCKED_ASN, PARAMETERS or STATS
+        name: String,
+
+        /// Only print entries in this range, e.g. 96..128. For tree maps, this is a range of
+        /// node indices
+        #[arg(long, value_parser = maps::parse_range)]
+        range: Option<Range<u32>>,
+    },
+
+    /// Check the trees loaded in the kernel against the cached databases and the configured rules
+    Verify {
+        /// Number of random addresses to check in each database
+        #[arg(long, default_value_t = 10000)]
+        samples: usize,
+    },
+
+    /// Validate the config file and exit non-zero if it has errors
+    CheckConfig,
+
+    /// Print the metadata of the cached databases and whether they are loaded in the kernel
+    Dbinfo,
+
+    /// Evaluate every address in a file against the configured rules and cached databases
+    Simulate {
+        /// File with one address per line
+        #[arg(long)]
+        file: String,
+
+        /// Defaults to csv, or json with --output json
+        #[arg(long, value_enum)]
+        format: Option<simulate::SimulateFormat>,
+    },
+
+    /// Build a small synthetic mmdb file for tests from a JSON description
+    MakeTestDb {
+        /// JSON file describing the networks and their data
+        #[arg(long)]
+        spec: String,
+
+        /// Where to write the mmdb file
+        #[arg(long)]
+        out: String,
+    },
+
+    /// Save or restore the runtime state of a running geofw instance
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+
+    /// Control a running geofw instance
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum CtlCommand {
+    Stats {
+        #[command(subcommand)]
+        command: StatsCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum StatsCommand {
+    /// Zero the packet counters and record when it happened
+    Reset {
+        #[arg(long, value_enum, default_value_t = stats::StatsScope::All)]
+        scope: stats::StatsScope,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum StateCommand {
+    /// Write the runtime state as JSON
+    Export {
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        file: Option<String>,
+    },
+
+    /// Load runtime state written by export
+    Import {
+        #[arg(long)]
+        file: String,
+    },
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
+    /// Version of the config format, older files are upgraded when geofw starts
+    #[serde(default)]
+    pub version: u32,
+
     pub db: Db,
+
+    /// Interfaces the XDP program is attached to
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+
+    /// Used when `interfaces` is empty. Version 1 configs had only this
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub interface: String,
+
+    /// Modes to attach the XDP program in, tried in order until one works
+    #[serde(default = "attach::default_xdp_mode")]
+    pub xdp_mode: Vec<XdpMode>,
+
+    /// Whether the filter runs as an XDP program, a tc classifier, or XDP with tc as the
+    /// fallback
+    #[serde(default)]
+    pub datapath: Datapath,
+
+    /// XDP program that gets the packets geofw passes, the path of a pinned program or the name
+    /// of a loaded one
+    #[serde(default)]
+    pub next_program: Option<String>,
+
+    /// bpffs directory the XDP links and the main maps are pinned in, so filtering continues
+    /// when geofw crashes. Nothing is pinned when empty. Only read at startup
+    #[serde(default = "pin::default_pin_path")]
+    pub pin_path: String,
+
+    /// Leave the pinned links attached on exit, so the next instance adopts them and takes over
+    /// without a moment where packets aren't filtered
+    #[serde(default)]
+    pub keep_pinned: bool,
+
+    /// Keep every capability geofw was started with. By default only those needed to attach to
+    /// interfaces and update the maps are kept once startup is done
+    #[serde(default)]
+    pub keep_capabilities: bool,
+
+    /// How the XDP program looks up sources in the databases. Switching from lpm to tree
+    /// needs a restart, the tree maps are only sized at startup
+    #[serde(default)]
+    pub lookup_backend: LookupBackend,
+
+    /// VLAN IDs the rules apply to, frames on other VLANs and untagged frames are passed. All
+    /// frames are filtered when empty
+    #[serde(default)]
+    pub vlans: Vec<u16>,
+
+    /// Look up the inner packet of GRE, VXLAN, IPIP and 6in4 tunnels instead of the tunnel
+    /// endpoints
+    #[serde(default)]
+    pub inspect_tunnels: bool,
+
+    /// UDP ports of VXLAN tunnels, only used with `inspect_tunnels`
+    #[serde(default = "default_vxlan_ports")]
+    pub vxlan_ports: Vec<u16>,
+
+    /// Look up the subscriber packets carried in GTP-U tunnels on UDP port 2152 instead of the
+    /// tunnel endpoints
+    #[serde(default)]
+    pub inspect_gtp: bool,
+
+    #[serde(alias = "block_countries")]
     pub source_countries: FxHashSet<String>,
+    #[serde(alias = "block_asn")]
     pub source_asn: FxHashSet<u32>,
+
+    /// Countries that are dropped, rate limited or only logged instead of getting the action
+    /// of `source_countries`
+    #[serde(default)]
+    pub country_actions: FxHashMap<String, CountryAction>,
+
+    /// Rules that combine a country with ASNs, like blocking an ASN only in one country
+    #[serde(default)]
+    pub compound_rules: Vec<CompoundRule>,
+
+    /// Sources from these countries are passed even if they are blocked otherwise, unless
+    /// `precedence` is block. Only used in block mode
+    #[serde(default)]
+    pub allow_countries: FxHashSet<String>,
+
+    #[serde(default)]
+    pub allow_asn: FxHashSet<u32>,
+
+    #[serde(default)]
+    pub precedence: Precedence,
+
+    /// Known answers that are checked against every freshly processed database before it is
+    /// written to the kernel
+    #[serde(default)]
+    pub probes: Vec<Probe>,
+
+    /// Never block networks that MaxMind flags as anycast. Their location is meaningless and
+    /// they usually belong to public DNS resolvers and CDNs
+    #[serde(default)]
+    pub skip_anycast: bool,
+
+    /// Alerts are POSTed to this URL as JSON in addition to being logged
+    #[serde(default)]
+    pub alert_webhook: Option<String>,
+
+    #[serde(default)]
+    pub statsd: Option<StatsdConfig>,
+
+    /// Periodically push metrics to a Prometheus Pushgateway
+    #[serde(default)]
+    pub pushgateway: Option<PushgatewayConfig>,
+
+    /// Serve metrics for Prometheus to scrape
+    #[serde(default)]
+    pub prometheus: Option<PrometheusConfig>,
+
+    /// Serve the operations of the control socket over HTTP
+    #[serde(default)]
+    pub api: Option<ApiConfig>,
+
+    /// Files with one address or network per line that are always blocked. They are reloaded
+    /// when they change
+    #[serde(default)]
+    pub block_lists: Vec<String>,
+
+    /// Addresses and networks that are always blocked, like the ones in `block_lists`
+    #[serde(default)]
+    pub block_cidrs: Vec<Cidr>,
+
+    /// Addresses and networks that are never blocked, checked before every other rule
+    #[serde(default)]
+    pub allow_cidrs: Vec<Cidr>,
+
+    /// Drop sources in reserved and unallocated networks, which the databases don't cover
+    #[serde(default)]
+    pub block_bogons: bool,
+
+    /// Blocklists that are downloaded periodically, like the Spamhaus DROP list
+    #[serde(default)]
+    pub feeds: Vec<FeedConfig>,
+
+    /// Lists of any format from internal sources, handled like `feeds` without needing a name
+    #[serde(default)]
+    pub blocklist_urls: Vec<FeedConfig>,
+
+    /// Verdict for packets with truncated or unparseable headers
+    #[serde(default)]
+    pub malformed_action: MalformedAction,
+
+    /// Treat packets whose IP length fields or TCP and UDP headers don't add up as malformed
+    #[serde(default)]
+    pub check_headers: bool,
+
+    /// Redirect traffic from some countries to geofw over AF_XDP for inspection
+    #[serde(default)]
+    pub suspect: Option<SuspectConfig>,
+
+    /// Replicate suspect verdicts with other geofw instances in front of the same service
+    #[serde(default)]
+    pub peer_sync: Option<PeerSyncConfig>,
+
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+
+    /// Write events such as suspect verdicts as JSON lines to disk
+    #[serde(default)]
+    pub events: Option<EventsConfig>,
+
+    /// Share processed trees between a server and edge agents
+    #[serde(default)]
+    pub fleet: Option<FleetConfig>,
+
+    /// Evaluate ICMPv6 neighbor discovery and MLD messages against the rules instead of always
+    /// passing them
+    #[serde(default)]
+    pub filter_neighbor_discovery: bool,
+
+    /// Evaluate DHCP and DHCPv6 messages against the rules instead of always passing them
+    #[serde(default)]
+    pub filter_dhcp: bool,
+
+    /// Other traffic that is always passed, like DHCP and neighbor discovery
+    #[serde(default)]
+    pub pass_services: Vec<PassService>,
+
+    #[serde(default)]
+    pub multicast_action: MulticastAction,
+
+    /// What happens to IPv4 and IPv6 fragments other than the first
+    #[serde(default)]
+    pub fragment_action: FragmentAction,
+
+    /// Evaluate and count packets without dropping any
+    #[serde(default)]
+    pub dry_run: bool,
+
+    /// Also drop traffic sent to blocked destinations, with a TC program on the egress path
+    #[serde(default)]
+    pub egress: bool,
+
+    /// Pass replies to connections the host opened, recorded by the egress program
+    #[serde(default)]
+    pub track_connections: bool,
+
+    /// Whether the source, destination or both addresses of incoming packets are looked up
+    #[serde(default)]
+    pub direction: Direction,
+
+    /// Only drop the TCP SYNs of blocked sources and pass the rest of their TCP segments
+    #[serde(default)]
+    pub syn_only: bool,
+
+    /// Block `source_countries` and `source_asn`, or allow only them and drop everything else
+    #[serde(default)]
+    pub mode: Mode,
+
+    /// Periodically log a summary of drops and loaded databases
+    #[serde(default)]
+    pub summary: Option<SummaryConfig>,
+
+    /// Seconds between reads of the packet counters, which are logged and emitted as metrics
+    #[serde(default = "default_stats_interval")]
+    pub stats_interval: u64,
+
+    /// Report every dropped packet to userspace, where drops are counted, logged as
+    /// `drop_log` allows and written to the event log
+    #[serde(default)]
+    pub drop_events: bool,
+
+    /// How many of the reported drops are logged. Only read at startup
+    #[serde(default)]
+    pub drop_log: DropLogConfig,
+
+    /// Post to a webhook when the drops from a country or AS exceed a rate. Only read at startup
+    #[serde(default)]
+    pub drop_alerts: Option<DropAlertConfig>,
+
+    /// Flags of the GeoIP2-Anonymous-IP database to block, the database is only used when some
+    /// are enabled
+    #[serde(default)]
+    pub block_anonymous: AnonymousIp,
+
+    /// Block every ASN whose organization name contains one of these, ignoring case
+    #[serde(default)]
+    pub source_asn_org_patterns: Vec<String>,
+
+    /// GeoNames IDs of cities to block, looked up in the GeoLite2-City database
+    #[serde(default)]
+    pub source_cities: FxHashSet<u32>,
+
+    /// ISO 3166-2 codes of subdivisions to block, like US-CA
+    #[serde(default)]
+    pub source_subdivisions: FxHashSet<String>,
+
+    /// Only apply `source_countries` and `source_asn` to packets for these ports, every packet
+    /// when empty
+    #[serde(default)]
+    pub ports: Vec<PortScope>,
+
+    /// Only apply `source_countries` and `source_asn` inside these windows of local time, all
+    /// the time when empty
+    #[serde(default)]
+    pub schedule: Vec<TimeWindow>,
+
+    /// Throttle the sources `source_countries` and `source_asn` match instead of dropping them
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+
+    /// What to do with the packets `source_countries` and `source_asn` match
+    #[serde(default)]
+    pub action: PolicyAction,
+
+    /// Rules for specific interfaces instead of `source_countries` and `source_asn`
+    #[serde(default)]
+    pub policies: Vec<Policy>,
+
+    /// Tokens accepted by the control socket and HTTP listeners. Without any, they accept every
+    /// request
+    #[serde(default)]
+    pub api_tokens: Vec<ApiToken>,
+
+    /// Path of the control socket, read at startup. Empty disables it
+    #[serde(default = "default_control_socket")]
+    pub control_socket: String,
+}
+
+impl Config {
+    /// The databases the rules need. The city database is only used when some rule lists
+    /// cities or subdivisions, the anonymous IP one when some of its flags are blocked
+    pub fn db_types(&self) -> Vec<MaxmindDbType> {
+        let mut db_types = vec![MaxmindDbType::Country, MaxmindDbType::Asn];
+        if policy::uses_city(self) {
+            db_types.push(MaxmindDbType::City);
+        }
+        if self.block_anonymous.enabled() {
+            db_types.push(MaxmindDbType::Anonymous);
+        }
+        db_types
+    }
+
+    /// The interfaces the XDP program is attached to
+    pub fn interfaces(&self) -> Vec<String> {
+        if self.interfaces.is_empty() && !self.interface.is_empty() {
+            vec![self.interface.clone()]
+        } else {
+            self.interfaces.clone()
+        }
+    }
+
+    /// The interfaces the egress program is attached to, needed for egress filtering and for
+    /// recording connections
+    pub fn egress_interfaces(&self) -> Vec<String> {
+        if self.egress || self.track_connections {
+            self.interfaces()
+        } else {
+            vec![]
+        }
+    }
+
+    /// The cgroups the cgroup program is attached to, those listed in policies
+    pub fn cgroups(&self) -> Vec<String> {
+        let mut cgroups = vec![];
+        for cgroup in self.policies.iter().flat_map(|p| &p.cgroups) {
+            if !cgroups.contains(cgroup) {
+                cgroups.push(cgroup.clone());
+            }
+        }
+        cgroups
+    }
+
+    /// `feeds` and `blocklist_urls` together
+    pub fn feeds(&self) -> Vec<FeedConfig> {
+        self.feeds
+            .iter()
+            .chain(&self.blocklist_urls)
+            .cloned()
+            .collect()
+    }
+
+    /// Replaces the configured interfaces with the ones given with `--interface`, if any
+    pub fn override_interfaces(&mut self, interfaces: &[String]) {
+        if !interfaces.is_empty() {
+            self.interfaces = interfaces.to_vec();
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            version: migrate::CONFIG_VERSION,
             db: Default::default(),
-            interface: "enp1s0".to_string(),
+            interfaces: vec!["enp1s0".to_string()],
+            interface: String::new(),
+            xdp_mode: attach::default_xdp_mode(),
+            datapath: Datapath::default(),
+            next_program: None,
+            pin_path: pin::default_pin_path(),
+            keep_pinned: false,
+            keep_capabilities: false,
+            lookup_backend: LookupBackend::Tree,
+            vlans: vec![],
+            inspect_tunnels: false,
+            vxlan_ports: default_vxlan_ports(),
+            inspect_gtp: false,
             source_countries: Default::default(),
+            country_actions: Default::default(),
+            compound_rules: vec![],
             source_asn: Default::default(),
+            allow_countries: Default::default(),
+            allow_asn: Default::default(),
+            precedence: Precedence::Allow,
+            probes: Default::default(),
+            skip_anycast: false,
+            alert_webhook: None,
+            statsd: None,
+            pushgateway: None,
+            prometheus: None,
+            api: None,
+            block_lists: vec![],
+            block_cidrs: vec![],
+            allow_cidrs: vec![],
+            block_bogons: false,
+            feeds: vec![],
+            blocklist_urls: vec![],
+            malformed_action: MalformedAction::default(),
+            check_headers: false,
+            suspect: None,
+            peer_sync: None,
+            privacy: PrivacyConfig::default(),
+            events: None,
+            fleet: None,
+            dry_run: false,
+            egress: false,
+            track_connections: false,
+            direction: Direction::default(),
+            syn_only: false,
+            mode: Mode::Block,
+            block_anonymous: AnonymousIp::default(),
+            source_asn_org_patterns: vec![],
+            source_cities: Default::default(),
+            source_subdivisions: Default::default(),
+            ports: vec![],
+            schedule: vec![],
+            rate_limit: None,
+            action: PolicyAction::default(),
+            policies: vec![],
+            api_tokens: vec![],
+            control_socket: default_control_socket(),
+            filter_neighbor_discovery: false,
+            filter_dhcp: false,
+            pass_services: vec![],
+            multicast_action: MulticastAction::default(),
+            fragment_action: FragmentAction::default(),
+            summary: None,
+            stats_interval: default_stats_interval(),
+            drop_events: false,
+            drop_log: DropLogConfig::default(),
+            drop_alerts: None,
+        }
+    }
+}
+
+/// TCP or UDP packets to a port or ICMP or ICMPv6 messages of a type that skip the rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "protocol", rename_all = "lowercase")]
+pub enum PassService {
+    Tcp { port: u16 },
+    Udp { port: u16 },
+    Icmp { r#type: u8 },
+    Icmpv6 { r#type: u8 },
+}
+
+impl PassService {
+    /// Key of the service in PASS_SERVICES
+    pub fn key(&self) -> u32 {
+        match *self {
+            PassService::Tcp { port } => pass_service_key(6, port),
+            PassService::Udp { port } => pass_service_key(17, port),
+            PassService::Icmp { r#type } => pass_service_key(1, r#type as u16),
+            PassService::Icmpv6 { r#type } => pass_service_key(58, r#type as u16),
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Probe {
+    pub addr: IpAddr,
+    pub db: MaxmindDbType,
+    pub blocked: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Db {
+    /// MaxMind account ID. With it the databases are downloaded from the permalinks with
+    /// basic auth, the legacy endpoint that takes only the key is the fallback
+    #[serde(default)]
+    pub account_id: Option<u32>,
+
+    #[serde(default)]
     pub maxmind_key: String,
+
+    /// Read the key from this file when `maxmind_key` is empty
+    #[serde(default)]
+    pub maxmind_key_file: Option<String>,
+
+    /// Run this with `sh -c` and use what it prints as the key when `maxmind_key` and
+    /// `maxmind_key_file` are empty, e.g. `vault kv get -field=key secret/geofw`
+    #[serde(default)]
+    pub maxmind_key_cmd: Option<String>,
+
     pub refresh_interval: i64,
     pub path: String,
+
+    /// Alert when the loaded databases were built longer than this many seconds ago
+    #[serde(default = "default_max_age")]
+    pub max_age: i64,
+
+    /// Refreshes that fall inside one of these windows are postponed until it ends. The first
+    /// load after startup is never deferred
+    #[serde(default)]
+    pub defer_windows: Vec<TimeWindow>,
+
+    /// How failed downloads are retried
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Never download, only load the databases already unpacked in `path`
+    #[serde(default)]
+    pub offline: bool,
+
+    /// Download through this proxy instead of the one in HTTPS_PROXY or ALL_PROXY, e.g.
+    /// `http://proxy:3128` or `socks5://proxy:1080`
+    #[serde(default)]
+    pub proxy: Option<String>,
 }
 
 impl Default for Db {
     fn default() -> Self {
         Self {
+            account_id: None,
             maxmind_key: "".to_string(),
+            maxmind_key_file: None,
+            maxmind_key_cmd: None,
             refresh_interval: 86400,
             path: "/tmp/geofw".to_string(),
+            max_age: default_max_age(),
+            defer_windows: vec![],
+            retry: RetryConfig::default(),
+            offline: false,
+            proxy: None,
+        }
+    }
+}
+
+impl Db {
+    /// The license key from the first of `maxmind_key`, `maxmind_key_file` and `maxmind_key_cmd`
+    /// that is set. The file and command are read again on every call, so keys can be rotated
+    pub fn maxmind_key(&self) -> Result<String, String> {
+        if !self.maxmind_key.is_empty() {
+            return Ok(self.maxmind_key.clone());
+        }
+
+        if let Some(path) = &self.maxmind_key_file {
+            let key = std::fs::read_to_string(path)
+                .map_err(|e| format!("error in reading {}: {}", path, e))?;
+            return Ok(key.trim().to_string());
+        }
+
+        if let Some(cmd) = &self.maxmind_key_cmd {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(cmd)
+                .output()
+                .map_err(|e| format!("error in running maxmind_key_cmd: {}", e))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "maxmind_key_cmd exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            return Ok(String::from_utf8_lossy(&output.stdout).trim().to_string());
+        }
+
+        Err("no MaxMind license key is configured".to_string())
+    }
+}
+
+fn default_stats_interval() -> u64 {
+    60
+}
+
+fn default_control_socket() -> String {
+    "/run/geofw.sock".to_string()
+}
+
+fn default_vxlan_ports() -> Vec<u16> {
+    vec![4789]
+}
+
+fn default_max_age() -> i64 {
+    // GeoLite2 databases are updated twice a week
+    14 * 86400
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Guesses the format from the extension of the config file, falling back to JSON
+    fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    /// Parses and migrates the config, logging fields that were ignored
+    fn parse(&self, contents: &[u8]) -> Result<Config, String> {
+        let parsed = migrate::parse(*self, contents)?;
+        migrate::warn_unknown(&parsed);
+        Ok(parsed.config)
+    }
+
+    fn parse_value(&self, contents: &[u8]) -> Result<serde_json::Value, String> {
+        match self {
+            ConfigFormat::Json => serde_json::from_slice(contents).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => {
+                let contents = std::str::from_utf8(contents).map_err(|e| e.to_string())?;
+                toml::from_str(contents).map_err(|e| e.to_string())
+            }
+            ConfigFormat::Yaml => serde_yaml::from_slice(contents).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn serialize(&self, config: &impl serde::Serialize) -> Result<String, String> {
+        match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(config).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::to_string_pretty(config).map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_yaml::to_string(config).map_err(|e| e.to_string()),
         }
     }
 }
 
-fn read_config(path: &str) -> Result<Config, String> {
+/// Reads the config without creating a default one when the file doesn't exist
+pub fn parse_config(path: &str, format: ConfigFormat) -> Result<Config, String> {
+    let mut contents = vec![];
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .map_err(|e| format!("error in reading {}: {}", path, e))?;
+
+    format.parse(&contents)
+}
+
+fn read_config(path: &str, format: ConfigFormat) -> Result<Config, String> {
     match File::open(path) {
         Ok(mut f) => {
             let mut contents = vec![];
             f.read_to_end(&mut contents).map_err(|e| e.to_string())?;
-            serde_json::from_slice(&contents).map_err(|e| e.to_string())
+            format.parse(&contents)
         }
         Err(e) if e.kind() == ErrorKind::NotFound => {
             let def: Config = Default::default();
             match File::create(path) {
                 Ok(mut f) => {
-                    let json = serde_json::to_string_pretty(&def)
-                        .expect("error in marshalling config to json");
+                    let json = format
+                        .serialize(&def)
+                        .expect("error in marshalling default config");
                     if let Err(e) = f.write_all(json.as_bytes()) {
                         warn!("error in writing default config to disk: {}", e);
                     }
@@ -82,91 +899,371 @@ fn read_config(path: &str) -> Result<Config, String> {
     }
 }
 
-fn fetch_geoip_db(config: &Config, db_type: MaxmindDbType) -> Result<ProcessedDb, String> {
-    let mut unpack_path = PathBuf::new();
-    unpack_path.push(&config.db.path);
-    unpack_path.push(format!("{}.mmdb", db_type));
+fn db_path(config: &Config, db_type: MaxmindDbType) -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(&config.db.path);
+    path.push(format!("{}.mmdb", db_type));
+    path
+}
 
-    let url = format!("https://download.maxmind.com/app/geoip_download?edition_id={}&license_key={}&suffix=tar.gz", db_type, config.db.maxmind_key);
+/// Outcome of each phase of a database refresh
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RefreshReport {
+    pub build_epoch: u64,
+    pub downloaded_bytes: u64,
+    pub download_time: Duration,
+    /// Attempts the download took, 0 when it failed
+    pub download_attempts: u32,
+    pub parse_time: Duration,
+    pub process_time: Duration,
+    pub marked: u32,
+    pub map_write_time: Duration,
+    /// Whether the tree was written with BPF_MAP_UPDATE_BATCH
+    pub batched_write: bool,
+    /// Whether the database was loaded from `db.path` because it couldn't be downloaded
+    pub offline: bool,
+    /// Whether the server answered that the cached database is current
+    pub not_modified: bool,
+}
 
-    info!("path = {:?} fetching db from = {}", unpack_path, url);
+impl RefreshReport {
+    fn emit(&self, metrics: &Metrics, db_type: MaxmindDbType) {
+        let tags = [("db", db_type.short_name())];
 
-    let response = ureq::get(&url).call();
+        metrics.gauge(
+            "update.downloaded_bytes",
+            self.downloaded_bytes as f64,
+            &tags,
+        );
+        metrics.timing("update.download", self.download_time, &tags);
+        metrics.gauge(
+            "update.download_attempts",
+            self.download_attempts as f64,
+            &tags,
+        );
+        metrics.timing("update.parse", self.parse_time, &tags);
+        metrics.timing("update.process", self.process_time, &tags);
+        metrics.gauge("update.marked", self.marked as f64, &tags);
+        metrics.timing("update.map_write", self.map_write_time, &tags);
+    }
+}
 
-    match response {
-        Ok(v) if v.status() != 200 => {
-            warn!("response from maxmind is not 200 = {}", v.status());
-        }
-        Ok(resp) => {
-            let reader = resp.into_reader();
-            let reader = BufReader::new(reader);
-            let tar = GzDecoder::new(reader);
-            let mut archive = Archive::new(tar);
-            let entries = archive
-                .entries()
-                .map_err(|e| format!("error in listing files in the archive: {}", e))?;
+/// Host the databases are downloaded from, matched against NO_PROXY
+const MAXMIND_HOST: &str = "download.maxmind.com";
 
-            let db_entry = entries
-                .into_iter()
-                .filter_map(|e| e.ok())
-                .filter_map(|entry| {
-                    let Ok(path) = entry.path() else {
-                        return None;
-                    };
-                    if path.extension().is_none_or(|x| x != "mmdb") {
-                        return None;
-                    }
-                    Some(entry)
+/// Downloads and processes a database. None when `reuse` is set and the database didn't change
+/// upstream since the cached file was downloaded, the loaded tree is current then
+fn fetch_geoip_db(
+    config: &Config,
+    db_type: MaxmindDbType,
+    report: &mut RefreshReport,
+    reuse: bool,
+) -> Result<Option<ProcessedDb>, String> {
+    let unpack_path = db_path(config, db_type);
+    let validators_path = unpack_path.with_extension("validators");
+    let cached = Validators::read(&validators_path).filter(|_| unpack_path.exists());
+
+    info!("path = {:?} fetching db {}", unpack_path, db_type);
+
+    // The legacy endpoint takes the key in the query string
+    let legacy = |agent: &ureq::Agent, key: &str| {
+        let url = format!(
+            "https://download.maxmind.com/app/geoip_download?edition_id={}",
+            db_type
+        );
+        download::download_verified(
+            agent,
+            &format!("{}&suffix=tar.gz&license_key={}", url, key),
+            &format!("{}&suffix=tar.gz.sha256&license_key={}", url, key),
+            None,
+            cached.as_ref(),
+            &config.db.retry,
+        )
+    };
+
+    // A missing key is handled like a failed download, the cached database is still loaded.
+    // The tarball is only unpacked if it matches the checksum MaxMind publishes next to it
+    let t = Instant::now();
+    let key = if config.db.offline {
+        Err("offline mode".to_string())
+    } else {
+        config.db.maxmind_key()
+    };
+    let response = key.and_then(|key| {
+        let agent = download::agent(config.db.proxy.as_deref(), MAXMIND_HOST)?;
+        match config.db.account_id {
+            Some(account_id) => {
+                let url = format!(
+                    "https://download.maxmind.com/geoip/databases/{}/download",
+                    db_type
+                );
+                download::download_verified(
+                    &agent,
+                    &format!("{}?suffix=tar.gz", url),
+                    &format!("{}?suffix=tar.gz.sha256", url),
+                    Some(&download::basic_auth(&account_id.to_string(), &key)),
+                    cached.as_ref(),
+                    &config.db.retry,
+                )
+                .or_else(|e| {
+                    warn!(
+                        "error in fetching db from the permalink, trying the legacy endpoint: {}",
+                        e
+                    );
+                    legacy(&agent, &key)
                 })
-                .next();
+            }
+            None => legacy(&agent, &key),
+        }
+    });
 
-            let Some(mut db_entry) = db_entry else {
-                return Err("error in finding mmdb file in the tarball".to_string());
-            };
+    let fetched = response.and_then(|download| {
+        report.downloaded_bytes = download.body.len() as u64;
+        report.download_time = t.elapsed();
+        report.download_attempts = download.attempts;
+        if !download.modified {
+            report.not_modified = true;
+            return Ok(());
+        }
 
-            db_entry.unpack(&unpack_path).map_err(|e| e.to_string())?;
+        // Validators of the previous file mustn't outlive it, even if unpacking fails
+        let _ = fs::remove_file(&validators_path);
+        unpack_mmdb(&download.body, &unpack_path)?;
+        if !download.validators.is_empty() {
+            if let Err(e) = download.validators.write(&validators_path) {
+                warn!("{}", e);
+            }
         }
-        Err(e) => {
-            warn!("error in fetching db from maxmind: {}", e);
+        Ok(())
+    });
+
+    if let Err(e) = fetched {
+        report.offline = true;
+        if !unpack_path.exists() {
+            return Err(format!(
+                "{} and there is no cached database at {}",
+                e,
+                unpack_path.display()
+            ));
         }
-    };
+        if !config.db.offline {
+            warn!(
+                "error in fetching db from maxmind, loading the cached one: {}",
+                e
+            );
+        }
+    }
+    if report.not_modified {
+        info!("{} hasn't changed since it was downloaded", db_type);
+        if reuse {
+            return Ok(None);
+        }
+    }
 
+    let t = Instant::now();
     let db = maxmind::MaxmindDb::from_file(&unpack_path.to_string_lossy())?;
+    report.parse_time = t.elapsed();
 
-    match db_type {
-        MaxmindDbType::Country => Ok(db.consume(|data| -> bool {
-            let Some(Data::Map(country)) = data.get("country".as_bytes()) else {
-                return false;
-            };
-            let Some(iso_code) = country.get("iso_code".as_bytes()) else {
-                return false;
-            };
+    let t = Instant::now();
+    let db = db.consume(|data| marker(config, db_type, data));
+    report.process_time = t.elapsed();
+    report.marked = db.marked;
+    report.build_epoch = db.build_epoch;
+
+    Ok(Some(db))
+}
+
+/// Writes the mmdb file in the tarball `body` to `path`
+fn unpack_mmdb(body: &[u8], path: &Path) -> Result<(), String> {
+    let tar = GzDecoder::new(body);
+    let mut archive = Archive::new(tar);
+    let entries = archive
+        .entries()
+        .map_err(|e| format!("error in listing files in the archive: {}", e))?;
 
-            config.source_countries.contains(&iso_code.to_string())
-        })),
-        MaxmindDbType::Asn => Ok(db.consume(|data| -> bool {
-            let Some(Data::U32(asn)) = data.get("autonomous_system_number".as_bytes()) else {
-                return false;
+    let db_entry = entries
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let Ok(path) = entry.path() else {
+                return None;
             };
+            if path.extension().is_none_or(|x| x != "mmdb") {
+                return None;
+            }
+            Some(entry)
+        })
+        .next();
+
+    let Some(mut db_entry) = db_entry else {
+        return Err("error in finding mmdb file in the tarball".to_string());
+    };
+
+    db_entry.unpack(path).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Marker written over records that point to `data`, if any
+fn marker(config: &Config, db_type: MaxmindDbType, data: &FxHashMap<&[u8], Data>) -> Option<u32> {
+    let listed = policy::listed_policies(config, db_type, data);
+    let allowed = config.mode == Mode::Block && policy::is_allowed(config, db_type, data);
+    let compound = policy::compound_rules(config, db_type, data);
+    let country_action = match db_type {
+        MaxmindDbType::Country => policy::country_action(config, data),
+        _ => None,
+    };
+    if allowed && (config.precedence == Precedence::Allow || listed == 0) {
+        Some(ALLOW_MARKER)
+    } else if let Some(action) = country_action.filter(|_| listed & 1 != 0) {
+        Some(action.marker(listed))
+    } else if listed == policy::all_policies(config) {
+        Some(BLOCK_MARKER)
+    } else if listed != 0 {
+        Some(POLICY_MARKER | listed)
+    } else if compound != 0 {
+        Some(COMPOUND_MARKER | compound)
+    } else if db_type == MaxmindDbType::Country && suspect::is_suspect(config, data) {
+        Some(SUSPECT_MARKER)
+    } else {
+        None
+    }
+}
+
+/// Whether the top level countries and ASNs include the record `data` points to
+fn is_listed(config: &Config, db_type: MaxmindDbType, data: &FxHashMap<&[u8], Data>) -> bool {
+    policy::listed_policies(config, db_type, data) & 1 != 0
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
 
-            config.source_asn.contains(asn)
-        })),
+    // Only running geofw is daemonized, not the subcommands
+    let runs = matches!(args.command, None | Some(Command::Run));
+    if runs && args.daemon {
+        daemon::daemonize().map_err(anyhow::Error::msg)?;
     }
+    let _pidfile = match &args.pidfile {
+        Some(path) if runs => Some(daemon::Pidfile::create(path).map_err(anyhow::Error::msg)?),
+        _ => None,
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let result = runtime.block_on(run(args));
+    // A refresh still downloading on the blocking pool isn't waited for
+    runtime.shutdown_background();
+
+    result
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    env_logger::init();
+async fn run(args: Args) -> anyhow::Result<()> {
+    logging::init(args.log_format, args.log_level).map_err(anyhow::Error::msg)?;
+
+    let format = args
+        .config_format
+        .unwrap_or_else(|| ConfigFormat::from_path(&args.config));
+    if let Some(Command::CheckConfig) = args.command {
+        return check::check_config(&args.config, format, &args.interface, args.output)
+            .map_err(anyhow::Error::msg);
+    }
+
+    let mut config = read_config(&args.config, format)
+        .and_then(overrides::apply_env)
+        .expect("error in reading config");
+    config.override_interfaces(&args.interface);
+
+    // Subcommands that talk to the running instance over the control socket
+    let client = |command| {
+        control::run(
+            &config.control_socket,
+            args.token.as_deref(),
+            command,
+            args.output,
+        )
+        .map_err(anyhow::Error::msg)
+    };
+
+    match args.command {
+        Some(Command::DumpMap { name, range }) => {
+            return maps::dump_map(&name, range, args.output).map_err(anyhow::Error::msg);
+        }
+        Some(Command::Verify { samples }) => {
+            return verify::verify(&config, samples, args.output).map_err(anyhow::Error::msg);
+        }
+        Some(Command::CheckConfig) => unreachable!("handled before reading the config"),
+        Some(Command::Dbinfo) => {
+            return dbinfo::dbinfo(&config, args.output).map_err(anyhow::Error::msg);
+        }
+        Some(Command::Simulate { file, format }) => {
+            let format = format.unwrap_or(match args.output {
+                OutputFormat::Table => simulate::SimulateFormat::Csv,
+                OutputFormat::Json => simulate::SimulateFormat::Json,
+            });
+            return simulate::simulate(&config, &file, format).map_err(anyhow::Error::msg);
+        }
+        Some(Command::MakeTestDb { spec, out }) => {
+            return testdb::make_test_db(&spec, &out).map_err(anyhow::Error::msg);
+        }
+        Some(Command::State { command }) => {
+            return match command {
+                StateCommand::Export { file } => state::export(file.as_deref()),
+                StateCommand::Import { file } => state::import(&file),
+            }
+            .map_err(anyhow::Error::msg);
+        }
+        Some(Command::Ctl {
+            command:
+                CtlCommand::Stats {
+                    command: StatsCommand::Reset { scope },
+                },
+        }) => {
+            return stats::reset(scope).map_err(anyhow::Error::msg);
+        }
+        Some(Command::Status) => return client(control::Command::Status),
+        Some(Command::Stats) => return client(control::Command::Stats),
+        Some(Command::Reload) => return client(control::Command::Reload),
+        Some(Command::Refresh) => return client(control::Command::Refresh),
+        Some(Command::Policies) => return client(control::Command::Policies),
+        Some(Command::Lookup { addr }) => return client(control::Command::Lookup { addr }),
+        Some(Command::Run) | None => (),
+    }
 
-    let config = read_config("./config.json").expect("error in reading config");
+    if let Err(e) = migrate::upgrade_file(&args.config, format) {
+        warn!("error in upgrading config: {}", e);
+    }
+    if config.interfaces().is_empty() {
+        anyhow::bail!("no interface to attach to, set interfaces in the config");
+    }
 
+    caps::check(config.suspect.is_some() || config.datapath == Datapath::AfXdp)
+        .map_err(anyhow::Error::msg)?;
     setup();
 
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
     // reach for `Bpf::load_file` instead.
-    let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
+    let city_map_size = if policy::uses_city(&config) {
+        maps::CITY_MAP_SIZE
+    } else {
+        1
+    };
+    let anonymous_map_size = if config.block_anonymous.enabled() {
+        maps::ANONYMOUS_MAP_SIZE
+    } else {
+        1
+    };
+    let mut loader = EbpfLoader::new();
+    loader
+        .set_max_entries("BLOCKED_CITY", city_map_size)
+        .set_max_entries("BLOCKED_ANONYMOUS", anonymous_map_size);
+    if config.lookup_backend == LookupBackend::Lpm {
+        // The trees aren't copied into the kernel, only their marked networks
+        for (map_name, _) in maps::TREE_MAPS {
+            loader.set_max_entries(map_name, 1);
+        }
+    }
+    let mut ebpf = loader.load(aya::include_bytes_aligned!(concat!(
         env!("OUT_DIR"),
         "/geofw"
     )))?;
@@ -175,109 +1272,1302 @@ async fn main() -> anyhow::Result<()> {
         warn!("failed to initialize eBPF logger: {}", e);
     }
 
-    let program: &mut Xdp = ebpf.program_mut("geofw").unwrap().try_into()?;
     let mut interval = time::interval(
         chrono::Duration::seconds(config.db.refresh_interval)
             .to_std()
             .unwrap(),
     );
 
+    let program: &mut Xdp = ebpf.program_mut("geofw").unwrap().try_into()?;
     program.load()?;
-    program.attach(&config.interface, XdpFlags::default())
-        .context("failed to attach the XDP program with default flags - try changing XdpFlags::default() to XdpFlags::SKB_MODE")?;
+    let ingress: &mut SchedClassifier = ebpf.program_mut("geofw_ingress").unwrap().try_into()?;
+    ingress.load()?;
+    let xsk: &mut Xdp = ebpf.program_mut("geofw_xsk").unwrap().try_into()?;
+    xsk.load()?;
 
-    loop {
-        tokio::select! {
-            _ = signal::ctrl_c() => {
-                info!("Exiting...");
-                break;
+    // The tc and afxdp datapaths don't pin links, there's nothing to adopt
+    let adopt = if matches!(config.datapath, Datapath::Tc | Datapath::AfXdp) {
+        vec![]
+    } else {
+        config.interfaces()
+    };
+    let pins = Some(PathBuf::from(&config.pin_path))
+        .filter(|_| !config.pin_path.is_empty())
+        .filter(|dir| match pin::prepare(dir, &adopt) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("not pinning: {}", e);
+                false
             }
-            _ = interval.tick() => {
-                info!("updating DB");
+        });
+    if let Some(dir) = &pins {
+        pin::pin_maps(&ebpf, dir);
+    }
 
-                match update_geoip_map(&config, &mut ebpf, MaxmindDbType::Country, "BLOCKED_COUNTRY") {
-                    Ok(_) => (),
-                    Err(e) => {
-                        warn!("error in updating map {} = {}", MaxmindDbType::Country, e);
+    // Every interface runs the same program and shares its maps
+    let mut links = Links::default();
+    for interface in config.interfaces() {
+        // The previous instance's program keeps filtering until this one's maps are filled
+        if let Some(link) = pins
+            .as_deref()
+            .filter(|_| adopt.contains(&interface))
+            .and_then(|dir| pin::adopt(dir, &interface))
+        {
+            links.insert(interface, attach::Link::Adopted(link));
+            continue;
+        }
+        let link = attach::attach(
+            &mut ebpf,
+            &interface,
+            &config.xdp_mode,
+            config.datapath,
+            pins.as_deref(),
+        )
+        .map_err(anyhow::Error::msg)?;
+        links.insert(interface, link);
+    }
+
+    let egress: &mut SchedClassifier = ebpf.program_mut("geofw_egress").unwrap().try_into()?;
+    egress.load()?;
+    let mut egress_links = EgressLinks::default();
+    attach::reattach_egress(&mut ebpf, &mut egress_links, &config.egress_interfaces());
+
+    let cgroup: &mut CgroupSkb = ebpf.program_mut("geofw_cgroup").unwrap().try_into()?;
+    cgroup.load()?;
+    let mut cgroup_links = CgroupLinks::default();
+    attach::reattach_cgroups(&mut ebpf, &mut cgroup_links, &config.cgroups());
+
+    write_parameters(&config, &mut ebpf);
+    if let Err(e) = policy::write_interface_policies(&config, &mut ebpf) {
+        warn!("error in writing interface policies: {}", e);
+    }
+    if let Err(e) = policy::write_cgroup_policies(&config, &mut ebpf) {
+        warn!("error in writing cgroup policies: {}", e);
+    }
+    if let Err(e) = policy::write_port_scopes(&config, &mut ebpf) {
+        warn!("error in writing port scopes: {}", e);
+    }
+    if let Err(e) = policy::write_rate_limits(&config, &mut ebpf) {
+        warn!("error in writing rate limits: {}", e);
+    }
+    if let Err(e) = policy::write_actions(&config, &mut ebpf) {
+        warn!("error in writing policy actions: {}", e);
+    }
+    if let Err(e) = bogons::write_bogons(&config, &mut ebpf) {
+        warn!("error in writing bogons: {}", e);
+    }
+    if let Err(e) = write_vlans(&config, &mut ebpf) {
+        warn!("error in writing vlans: {}", e);
+    }
+    if let Err(e) = write_vxlan_ports(&config, &mut ebpf) {
+        warn!("error in writing vxlan ports: {}", e);
+    }
+    if let Err(e) = write_pass_services(&config, &mut ebpf) {
+        warn!("error in writing pass services: {}", e);
+    }
+    if let Err(e) = attach::write_next_program(config.next_program.as_deref(), &mut ebpf) {
+        warn!("error in setting the next program: {}", e);
+    }
+
+    let sync = config
+        .peer_sync
+        .as_ref()
+        .and_then(|c| match peers::start(c, &config.privacy) {
+            Ok(tx) => Some(tx),
+            Err(e) => {
+                warn!("error in setting up peer sync: {}", e);
+                None
+            }
+        });
+
+    let events = config.events.as_ref().and_then(|c| match events::start(c) {
+        Ok(tx) => Some(tx),
+        Err(e) => {
+            warn!("error in setting up event log: {}", e);
+            None
+        }
+    });
+
+    let metrics = Arc::new(Metrics::new(config.statsd.as_ref()));
+    let drop_feed = DropFeed::default();
+
+    match ebpf.take_map("DROP_EVENTS").map(RingBuf::try_from) {
+        Some(Ok(ring)) => drops::start(
+            ring,
+            config.drop_log.clone(),
+            config.privacy.clone(),
+            metrics.clone(),
+            events.clone(),
+            drop_feed.clone(),
+            alert::start_drop_alerts(&config),
+        ),
+        Some(Err(e)) => warn!("error in processing drop events map: {}", e),
+        None => warn!("error in getting drop events map"),
+    }
+
+    if config.datapath == Datapath::AfXdp {
+        if config.suspect.is_some() {
+            warn!("suspect traffic isn't inspected on the afxdp datapath, it is passed");
+        }
+        afxdp::start(&config, &mut ebpf).map_err(anyhow::Error::msg)?;
+    } else if let Err(e) = suspect::start(&config, &mut ebpf, sync, events) {
+        warn!(
+            "error in setting up suspect traffic inspection, suspect traffic is passed: {}",
+            e
+        );
+    }
+
+    let tokens = Tokens::new(&config.api_tokens);
+
+    if let Some(prometheus) = &config.prometheus {
+        if let Err(e) = metrics::serve(prometheus, metrics.clone(), tokens.clone()) {
+            warn!("error in starting metrics endpoint: {}", e);
+        }
+    }
+
+    // The sender is kept so the receiver stays open when the socket is disabled
+    let (control_tx, mut control_rx) = mpsc::channel(16);
+    let control_socket = config.control_socket.clone();
+    if !control_socket.is_empty() {
+        if let Err(e) = control::start(&control_socket, tokens.clone(), control_tx.clone()) {
+            warn!("error in starting control socket: {}", e);
+        }
+    }
+    if let Some(api) = &config.api {
+        if let Err(e) = api::serve(api, tokens.clone(), control_tx.clone(), drop_feed) {
+            warn!("error in starting API: {}", e);
+        }
+    }
+
+    let fleet_server = config
+        .fleet
+        .as_ref()
+        .filter(|f| f.role == FleetRole::Server)
+        .and_then(|f| match FleetServer::start(f, tokens.clone()) {
+            Ok(server) => Some(server),
+            Err(e) => {
+                warn!("error in starting fleet server: {}", e);
+                None
+            }
+        });
+
+    let mut block_lists = CidrLists::new(
+        "block",
+        LpmTrie::try_from(
+            ebpf.take_map("BLOCKED_CIDRS")
+                .expect("error in getting blocked cidrs map"),
+        )
+        .expect("error in processing blocked cidrs map"),
+        config.block_lists.clone(),
+        config.block_cidrs.clone(),
+    );
+    let mut block_list_interval = time::interval(Duration::from_secs(5));
+
+    // Feeds are downloaded on the blocking pool and written to the map from the main loop
+    let (feed_tx, mut feed_rx) = mpsc::channel(1);
+    let mut feeds = Feeds::new(
+        LpmTrie::try_from(
+            ebpf.take_map("FEED_CIDRS")
+                .expect("error in getting feed cidrs map"),
+        )
+        .expect("error in processing feed cidrs map"),
+        config.feeds(),
+        feed_tx,
+    );
+    // Feeds have their own intervals, this only checks which ones are due
+    let mut feed_interval = time::interval(Duration::from_secs(60));
+
+    let mut allow_lists = CidrLists::new(
+        "allow",
+        LpmTrie::try_from(
+            ebpf.take_map("ALLOWED_CIDRS")
+                .expect("error in getting allowed cidrs map"),
+        )
+        .expect("error in processing allowed cidrs map"),
+        vec![],
+        config.allow_cidrs.clone(),
+    );
+    if let Err(e) = allow_lists.refresh() {
+        warn!("error in loading allowed networks: {}", e);
+    }
+
+    // Without a Pushgateway this still ticks, but nothing is pushed
+    let mut push_interval = time::interval(Duration::from_secs(
+        config
+            .pushgateway
+            .as_ref()
+            .map_or(60, |p| p.interval.max(1)),
+    ));
+
+    // Without a summary this still ticks, but nothing is reported
+    let summary_period =
+        Duration::from_secs(config.summary.as_ref().map_or(3600, |s| s.interval.max(1)));
+    let mut summary_interval =
+        time::interval_at(time::Instant::now() + summary_period, summary_period);
+    let mut summary = Summary::default();
+
+    let mut stats_interval = time::interval(Duration::from_secs(config.stats_interval.max(1)));
+    let mut stats_exporter = stats::Exporter::default();
+
+    let mut sighup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+    let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+
+    // Without a watchdog this still ticks, but nothing is sent
+    let watchdog = systemd::watchdog_interval();
+    let mut watchdog_interval = time::interval(watchdog.unwrap_or(Duration::from_secs(3600)));
+    // READY=1 is sent once the first update filled the maps
+    let mut ready = false;
+
+    // Schedules are in whole minutes, checking more often keeps the switch close to the minute
+    let mut schedule_interval = time::interval(Duration::from_secs(10));
+    let mut active_policies = None;
+
+    // Build epoch of the databases currently loaded in the kernel
+    let mut loaded: FxHashMap<MaxmindDbType, u64> = FxHashMap::default();
+    // A processed tree is large, the refresh waits until the previous one is written
+    let (refresh_tx, mut refresh_rx) = mpsc::channel(1);
+    let mut refresher = Refresher::new(refresh_tx);
+
+    // Config with the policies set through the control socket, applied by the next SIGHUP
+    let mut pending_config: Option<Config> = None;
+
+    // Everything that needs more than updating maps and attaching has been set up
+    if !config.keep_capabilities {
+        if let Err(e) = caps::drop_privileges() {
+            warn!("{}", e);
+        }
+    }
+
+    loop {
+        tokio::select! {
+            _ = shutdown(&mut sigterm) => {
+                info!("Exiting...");
+                systemd::notify("STOPPING=1");
+                if config.keep_pinned && pins.is_some() {
+                    info!("leaving pinned links attached for the next instance");
+                    attach::release_pinned(&mut links);
+                } else if let Some(dir) = &pins {
+                    pin::unpin_maps(dir);
+                }
+                attach::detach_all(&mut ebpf, &mut links, &mut egress_links, &mut cgroup_links);
+                if !control_socket.is_empty() {
+                    control::stop(&control_socket);
+                }
+                break;
+            }
+            Some(message) = control_rx.recv() => {
+                let response = match message.command {
+                    control::Command::Status => Response::ok(status(&config, &links, &loaded, &refresher.offline, active_policies)),
+                    control::Command::Reload => {
+                        // Handled like a SIGHUP, the outcome is logged
+                        unsafe { libc::kill(libc::getpid(), libc::SIGHUP) };
+                        Response::ok(serde_json::Value::Null)
+                    }
+                    control::Command::Stats => Response::from_result(
+                        match ebpf.map("STATS").map(PerCpuArray::try_from) {
+                            Some(Ok(stats)) => stats::totals(&stats)
+                                .map(|t| t.into_iter().collect::<FxHashMap<_, _>>()),
+                            Some(Err(e)) => Err(format!("error in processing stats map: {}", e)),
+                            None => Err("error in getting stats map".to_string()),
+                        },
+                    ),
+                    control::Command::Lookup { addr } => Response::from_result(
+                        simulate::evaluate(&config, &[&addr.to_string()])
+                            .map(|mut v| v.pop()),
+                    ),
+                    control::Command::Block { cidr } => {
+                        info!("blocking {} from the control socket", cidr);
+                        Response::from_result(block_lists.add(cidr))
+                    }
+                    control::Command::Unblock { cidr } => {
+                        info!("unblocking {} from the control socket", cidr);
+                        Response::from_result(block_lists.remove(cidr))
+                    }
+                    control::Command::Refresh => {
+                        info!("updating DB from the control socket");
+                        refresher.start(&config, &loaded);
+                        // Answered when the refresh is done
+                        refresher.waiting.push(message.reply);
+                        continue;
                     }
+                    control::Command::Policies => Response::from_result(Ok(config.policies.clone())),
+                    control::Command::SetPolicies { policies } => {
+                        if policies.len() >= MAX_POLICIES as usize {
+                            Response::error(format!("at most {} policies are supported", MAX_POLICIES - 1))
+                        } else {
+                            info!("replacing {} policies with {} from the control socket", config.policies.len(), policies.len());
+                            pending_config = Some(Config { policies, ..config.clone() });
+                            unsafe { libc::kill(libc::getpid(), libc::SIGHUP) };
+                            Response::ok(serde_json::Value::Null)
+                        }
+                    }
+                };
+                let _ = message.reply.send(response);
+            }
+            _ = interval.tick() => {
+                let now = chrono::Local::now().time();
+                let window = config.db.defer_windows.iter().find(|w| w.contains(now));
+                if let (Some(window), false) = (window, loaded.is_empty()) {
+                    let remaining = window.remaining(now);
+                    info!("inside defer window {}, postponing DB update by {:?}", window, remaining);
+                    interval.reset_after(remaining);
+                    continue;
                 }
 
-                match update_geoip_map(&config, &mut ebpf, MaxmindDbType::Asn, "BLOCKED_ASN") {
-                    Ok(_) => (),
+                info!("updating DB");
+                refresher.start(&config, &loaded);
+            }
+            Some(refreshed) = refresh_rx.recv() => match refreshed {
+                Refreshed::Done => {
+                    check_staleness(&config, &metrics, &loaded);
+                    take_over(&config, &mut ebpf, &mut links, &loaded, pins.as_deref());
+                    if !ready {
+                        systemd::notify(&format!("READY=1\nSTATUS=filtering on {} interfaces", links.len()));
+                        daemon::ready();
+                        ready = true;
+                    }
+                    refresher.finish(&config, &loaded);
+                }
+                refreshed => refresher.apply(&config, &metrics, &mut ebpf, fleet_server.as_ref(), refreshed, &mut loaded),
+            },
+            _ = watchdog_interval.tick(), if watchdog.is_some() => {
+                systemd::notify("WATCHDOG=1");
+            }
+            _ = sighup.recv() => {
+                let parsed = match pending_config.take() {
+                    Some(c) => {
+                        info!("applying policies set from the control socket");
+                        Ok(c)
+                    }
+                    None => {
+                        info!("reloading config from {}", args.config);
+                        parse_config(&args.config, format).and_then(overrides::apply_env)
+                    }
+                };
+                let mut new_config = match parsed {
+                    Ok(c) => c,
                     Err(e) => {
-                        warn!("error in updating map {} = {}", MaxmindDbType::Asn, e);
+                        warn!("error in reloading config, keeping the current one: {}", e);
+                        continue;
+                    }
+                };
+                new_config.override_interfaces(&args.interface);
+                if (new_config.datapath == Datapath::AfXdp) != (config.datapath == Datapath::AfXdp) {
+                    warn!("switching to or from the afxdp datapath needs a restart, keeping datapath = {:?}", config.datapath);
+                    new_config.datapath = config.datapath;
+                }
+                systemd::reloading();
+                attach::reattach(&mut ebpf, &mut links, &new_config.interfaces(), &new_config.xdp_mode, new_config.datapath, pins.as_deref());
+                attach::reattach_egress(&mut ebpf, &mut egress_links, &new_config.egress_interfaces());
+                attach::reattach_cgroups(&mut ebpf, &mut cgroup_links, &new_config.cgroups());
+                if new_config.db.refresh_interval != config.db.refresh_interval {
+                    let period = Duration::from_secs(new_config.db.refresh_interval.max(1) as u64);
+                    interval = time::interval_at(time::Instant::now() + period, period);
+                }
+                if new_config.stats_interval != config.stats_interval {
+                    stats_interval = time::interval(Duration::from_secs(new_config.stats_interval.max(1)));
+                }
+                config = new_config;
+
+                write_parameters(&config, &mut ebpf);
+                if let Err(e) = policy::write_interface_policies(&config, &mut ebpf) {
+                    warn!("error in writing interface policies: {}", e);
+                }
+                if let Err(e) = policy::write_cgroup_policies(&config, &mut ebpf) {
+                    warn!("error in writing cgroup policies: {}", e);
+                }
+                if let Err(e) = policy::write_port_scopes(&config, &mut ebpf) {
+                    warn!("error in writing port scopes: {}", e);
+                }
+                if let Err(e) = policy::write_rate_limits(&config, &mut ebpf) {
+                    warn!("error in writing rate limits: {}", e);
+                }
+                if let Err(e) = policy::write_actions(&config, &mut ebpf) {
+                    warn!("error in writing policy actions: {}", e);
+                }
+                if let Err(e) = bogons::write_bogons(&config, &mut ebpf) {
+                    warn!("error in writing bogons: {}", e);
+                }
+                if let Err(e) = write_vlans(&config, &mut ebpf) {
+                    warn!("error in writing vlans: {}", e);
+                }
+                if let Err(e) = write_vxlan_ports(&config, &mut ebpf) {
+                    warn!("error in writing vxlan ports: {}", e);
+                }
+                if let Err(e) = write_pass_services(&config, &mut ebpf) {
+                    warn!("error in writing pass services: {}", e);
+                }
+                if let Err(e) = attach::write_next_program(config.next_program.as_deref(), &mut ebpf) {
+                    warn!("error in setting the next program: {}", e);
+                }
+                schedule_interval.reset_immediately();
+                block_lists.set_sources(config.block_lists.clone(), config.block_cidrs.clone());
+                if let Err(e) = block_lists.refresh() {
+                    warn!("error in reloading block lists: {}", e);
+                }
+                allow_lists.set_sources(vec![], config.allow_cidrs.clone());
+                if let Err(e) = allow_lists.refresh() {
+                    warn!("error in reloading allowed networks: {}", e);
+                }
+                feeds.set_feeds(config.feeds());
+                // Removed feeds are unblocked now, new ones once they are downloaded
+                if let Err(e) = feeds.sync() {
+                    warn!("error in reloading feeds: {}", e);
+                }
+                feeds.start();
+                // The databases are reprocessed with the new rules in the background
+                refresher.start(&config, &loaded);
+                systemd::notify("READY=1");
+            }
+            _ = schedule_interval.tick() => {
+                let mask = policy::active_policies(&config, chrono::Local::now().time());
+                if active_policies == Some(mask) {
+                    continue;
+                }
+                match policy::write_active_policies(mask, &mut ebpf) {
+                    Ok(()) => {
+                        info!("active policies = {:#b}", mask);
+                        active_policies = Some(mask);
+                    }
+                    Err(e) => warn!("error in writing active policies: {}", e),
+                }
+            }
+            _ = block_list_interval.tick() => {
+                if let Err(e) = block_lists.refresh() {
+                    warn!("error in reloading block lists: {}", e);
+                }
+            }
+            _ = feed_interval.tick() => {
+                feeds.start();
+            }
+            Some(fetched) = feed_rx.recv() => {
+                if let Err(e) = feeds.apply(fetched, &metrics) {
+                    warn!("error in reloading feeds: {}", e);
+                }
+            }
+            _ = push_interval.tick() => {
+                if let Some(pushgateway) = &config.pushgateway {
+                    if let Err(e) = metrics.push(pushgateway) {
+                        warn!("{}", e);
                     }
                 }
             }
+            _ = summary_interval.tick() => {
+                let Some(summary_config) = &config.summary else {
+                    continue;
+                };
+                match ebpf.map("STATS").map(PerCpuArray::try_from) {
+                    Some(Ok(stats)) => summary.report(summary_config, &stats, &loaded),
+                    Some(Err(e)) => warn!("error in processing stats map: {}", e),
+                    None => warn!("error in getting stats map"),
+                }
+            }
+            _ = stats_interval.tick() => {
+                match ebpf.map("STATS").map(PerCpuArray::try_from) {
+                    Some(Ok(stats)) => stats_exporter.export(&stats, &metrics),
+                    Some(Err(e)) => warn!("error in processing stats map: {}", e),
+                    None => warn!("error in getting stats map"),
+                }
+            }
         }
     }
 
     Ok(())
 }
 
-fn update_geoip_map(
+/// What the refresh pipeline sends to the main loop
+enum Refreshed {
+    /// The outcome of refreshing one database, None when it didn't change and the loaded tree
+    /// is kept
+    Db {
+        db_type: MaxmindDbType,
+        /// Hash of the rules the tree was processed with
+        rules_hash: String,
+        result: Result<Option<FetchedTree>, String>,
+    },
+    /// Every database of the refresh has been sent
+    Done,
+}
+
+/// A tree downloaded and processed on the blocking pool, waiting to be written to its map
+struct FetchedTree {
+    map_name: &'static str,
+    db: ProcessedDb,
+    report: RefreshReport,
+}
+
+/// Runs database refreshes on the blocking pool, downloads and processing take long enough to
+/// stall signals and control requests. The trees are sent back to the main loop, which writes
+/// them to the maps as they arrive
+struct Refresher {
+    tx: mpsc::Sender<Refreshed>,
+    running: bool,
+    /// A refresh was asked for while one was running, it starts once that one is done
+    queued: bool,
+    /// Control requests answered once the refreshes are done
+    waiting: Vec<std_mpsc::Sender<Response>>,
+    /// Databases loaded from db.path because they couldn't be downloaded
+    offline: FxHashSet<MaxmindDbType>,
+    /// Hash of the rules each loaded tree was processed with
+    rules: FxHashMap<MaxmindDbType, String>,
+}
+
+impl Refresher {
+    fn new(tx: mpsc::Sender<Refreshed>) -> Self {
+        Self {
+            tx,
+            running: false,
+            queued: false,
+            waiting: vec![],
+            offline: FxHashSet::default(),
+            rules: FxHashMap::default(),
+        }
+    }
+
+    /// Starts refreshing every database of `config`, or queues a refresh if one is running.
+    /// `loaded` are the builds currently in the maps
+    fn start(&mut self, config: &Config, loaded: &FxHashMap<MaxmindDbType, u64>) {
+        if self.running {
+            info!("a DB update is running, another one follows it");
+            self.queued = true;
+            return;
+        }
+        self.running = true;
+
+        let db_types = config.db_types();
+        let rules_hash = fleet::rules_hash(config);
+        // A tree processed with other rules has to be processed again even if the database
+        // didn't change
+        let reuse: FxHashSet<MaxmindDbType> = db_types
+            .iter()
+            .copied()
+            .filter(|t| loaded.contains_key(t) && self.rules.get(t) == Some(&rules_hash))
+            .collect();
+        let config = config.clone();
+        let tx = self.tx.clone();
+        tokio::task::spawn_blocking(move || {
+            for (map_name, db_type) in maps::TREE_MAPS {
+                if !db_types.contains(&db_type) {
+                    continue;
+                }
+                let result = fetch_tree(&config, db_type, map_name, reuse.contains(&db_type));
+                let refreshed = Refreshed::Db {
+                    db_type,
+                    rules_hash: rules_hash.clone(),
+                    result,
+                };
+                // The main loop has exited
+                if tx.blocking_send(refreshed).is_err() {
+                    return;
+                }
+            }
+            let _ = tx.blocking_send(Refreshed::Done);
+        });
+    }
+
+    /// Called when the running refresh is done. Starts the queued one, or answers the waiting
+    /// control requests when there is none
+    fn finish(&mut self, config: &Config, loaded: &FxHashMap<MaxmindDbType, u64>) {
+        self.running = false;
+        if std::mem::take(&mut self.queued) {
+            self.start(config, loaded);
+            return;
+        }
+
+        for reply in self.waiting.drain(..) {
+            let _ = reply.send(Response::ok(serde_json::Value::Null));
+        }
+    }
+
+    /// Writes a refreshed database to its map, recording the loaded build in `loaded`
+    fn apply(
+        &mut self,
+        config: &Config,
+        metrics: &Metrics,
+        ebpf: &mut Ebpf,
+        fleet_server: Option<&FleetServer>,
+        refreshed: Refreshed,
+        loaded: &mut FxHashMap<MaxmindDbType, u64>,
+    ) {
+        let Refreshed::Db {
+            db_type,
+            rules_hash,
+            result,
+        } = refreshed
+        else {
+            return;
+        };
+        let tags = [("db", db_type.short_name())];
+        let written = result.and_then(|fetched| {
+            fetched
+                .map(|tree| {
+                    write_geoip_map(
+                        config,
+                        metrics,
+                        ebpf,
+                        fleet_server,
+                        db_type,
+                        &rules_hash,
+                        tree,
+                    )
+                })
+                .transpose()
+        });
+
+        match written {
+            Ok(None) => {
+                info!("{} is unchanged, keeping the loaded tree", db_type);
+                metrics.count("update.unchanged", 1, &tags);
+            }
+            Ok(Some(report)) => {
+                loaded.insert(db_type, report.build_epoch);
+                self.rules.insert(db_type, rules_hash);
+                metrics.count("update.success", 1, &tags);
+                metrics.gauge("db.offline", report.offline as u8 as f64, &tags);
+                if report.offline {
+                    warn!(
+                        "running offline on the cached {} database built {}",
+                        db_type,
+                        dbinfo::format_epoch(report.build_epoch)
+                    );
+                    self.offline.insert(db_type);
+                } else {
+                    self.offline.remove(&db_type);
+                }
+            }
+            Err(e) => {
+                warn!("error in updating map {} = {}", db_type, e);
+                metrics.count("update.failure", 1, &tags);
+            }
+        }
+    }
+}
+
+/// Moves the links adopted from the previous instance to this instance's program, once every
+/// database is loaded in its maps. Until then the previous program keeps filtering
+fn take_over(
     config: &Config,
     ebpf: &mut Ebpf,
-    db_type: MaxmindDbType,
-    map_name: &str,
+    links: &mut Links,
+    loaded: &FxHashMap<MaxmindDbType, u64>,
+    pins: Option<&Path>,
+) {
+    let Some(dir) = pins else {
+        return;
+    };
+    if !config.db_types().iter().all(|t| loaded.contains_key(t)) {
+        return;
+    }
+
+    attach::take_over(ebpf, links, &config.xdp_mode, dir);
+}
+
+/// Writes the settings from the config that the XDP program reads from PARAMETERS
+fn write_parameters(config: &Config, ebpf: &mut Ebpf) {
+    if config.dry_run {
+        warn!("dry run, packets are evaluated and counted but none are dropped");
+    }
+
+    let mut params: HashMap<&mut MapData, u8, u32> = HashMap::try_from(
+        ebpf.map_mut("PARAMETERS")
+            .expect("error in getting parameter map"),
+    )
+    .expect("error in processing parameter map");
+    params
+        .insert(
+            ProgramParameters::MalformedAction as u8,
+            config.malformed_action as u32,
+            0,
+        )
+        .expect("error in writing malformed action to map");
+    params
+        .insert(
+            ProgramParameters::CheckHeaders as u8,
+            config.check_headers as u32,
+            0,
+        )
+        .expect("error in writing header checks to map");
+    params
+        .insert(
+            ProgramParameters::LogIpv4Prefix as u8,
+            config.privacy.ipv4_prefix as u32,
+            0,
+        )
+        .expect("error in writing log ipv4 prefix to map");
+    params
+        .insert(
+            ProgramParameters::LogIpv6Prefix as u8,
+            config.privacy.ipv6_prefix as u32,
+            0,
+        )
+        .expect("error in writing log ipv6 prefix to map");
+    params
+        .insert(
+            ProgramParameters::FilterNeighborDiscovery as u8,
+            config.filter_neighbor_discovery as u32,
+            0,
+        )
+        .expect("error in writing neighbor discovery filtering to map");
+    params
+        .insert(
+            ProgramParameters::FilterDhcp as u8,
+            config.filter_dhcp as u32,
+            0,
+        )
+        .expect("error in writing dhcp filtering to map");
+    params
+        .insert(
+            ProgramParameters::MulticastAction as u8,
+            config.multicast_action as u32,
+            0,
+        )
+        .expect("error in writing multicast action to map");
+    params
+        .insert(
+            ProgramParameters::FragmentAction as u8,
+            config.fragment_action as u32,
+            0,
+        )
+        .expect("error in writing fragment action to map");
+    params
+        .insert(
+            ProgramParameters::Precedence as u8,
+            config.precedence as u32,
+            0,
+        )
+        .expect("error in writing precedence to map");
+    params
+        .insert(ProgramParameters::DryRun as u8, config.dry_run as u32, 0)
+        .expect("error in writing dry run to map");
+    params
+        .insert(
+            ProgramParameters::Direction as u8,
+            config.direction as u32,
+            0,
+        )
+        .expect("error in writing direction to map");
+    params
+        .insert(ProgramParameters::SynOnly as u8, config.syn_only as u32, 0)
+        .expect("error in writing syn only to map");
+    params
+        .insert(ProgramParameters::Egress as u8, config.egress as u32, 0)
+        .expect("error in writing egress filtering to map");
+    params
+        .insert(
+            ProgramParameters::TrackConnections as u8,
+            config.track_connections as u32,
+            0,
+        )
+        .expect("error in writing connection tracking to map");
+    params
+        .insert(
+            ProgramParameters::CompoundExcept as u8,
+            policy::compound_except(config),
+            0,
+        )
+        .expect("error in writing compound rules to map");
+    params
+        .insert(
+            ProgramParameters::InspectTunnels as u8,
+            config.inspect_tunnels as u32,
+            0,
+        )
+        .expect("error in writing tunnel inspection to map");
+    params
+        .insert(
+            ProgramParameters::InspectGtp as u8,
+            config.inspect_gtp as u32,
+            0,
+        )
+        .expect("error in writing GTP-U inspection to map");
+    params
+        .insert(
+            ProgramParameters::DropEvents as u8,
+            config.drop_events as u32,
+            0,
+        )
+        .expect("error in writing drop events to map");
+
+    update_lookup_parameters(ebpf, |p| {
+        p.mode = config.mode as u32;
+        p.backend = config.lookup_backend as u32;
+    })
+    .expect("error in writing lookup parameters to map");
+}
+
+/// The slot of LOOKUP_PARAMETERS in use and its contents
+fn lookup_parameters(ebpf: &Ebpf) -> Result<(u32, LookupParameters), String> {
+    let parameters: HashMap<&MapData, u8, u32> = HashMap::try_from(
+        ebpf.map("PARAMETERS")
+            .ok_or("error in getting parameters map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    let slot = parameters
+        .get(&(ProgramParameters::LookupParametersSlot as u8), 0)
+        .map_or(0, |v| v & 1);
+
+    let map: Array<&MapData, LookupParameters> = Array::try_from(
+        ebpf.map("LOOKUP_PARAMETERS")
+            .ok_or("error in getting lookup parameter map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    let params = map.get(&slot, 0).map_err(|e| e.to_string())?;
+
+    Ok((slot, params))
+}
+
+/// Applies `update` to a copy of the LOOKUP_PARAMETERS slot in use, writes it to the other slot
+/// and then points LookupParametersSlot at it. A packet reads the slot once, so it sees either
+/// the old or the new parameters and never a mix of both
+fn update_lookup_parameters(
+    ebpf: &mut Ebpf,
+    update: impl FnOnce(&mut LookupParameters),
 ) -> Result<(), String> {
+    let (slot, mut params) = lookup_parameters(ebpf)?;
+    update(&mut params);
+    params.generation = params.generation.wrapping_add(1);
+
+    let idle = slot ^ 1;
+    let mut map: Array<&mut MapData, LookupParameters> = Array::try_from(
+        ebpf.map_mut("LOOKUP_PARAMETERS")
+            .ok_or("error in getting lookup parameter map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    map.set(idle, params, 0).map_err(|e| e.to_string())?;
+
+    let mut parameters: HashMap<&mut MapData, u8, u32> = HashMap::try_from(
+        ebpf.map_mut("PARAMETERS")
+            .ok_or("error in getting parameters map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    parameters
+        .insert(ProgramParameters::LookupParametersSlot as u8, idle, 0)
+        .map_err(|e| e.to_string())
+}
+
+/// Writes `vlans` into VLANS and sets VlanFilter when the rules only apply to some VLANs
+fn write_vlans(config: &Config, ebpf: &mut Ebpf) -> Result<(), String> {
+    write_u16_set(ebpf, "VLANS", config.vlans.iter().copied().collect())?;
+
+    let mut parameters: HashMap<&mut MapData, u8, u32> = HashMap::try_from(
+        ebpf.map_mut("PARAMETERS")
+            .ok_or("error in getting parameters map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    parameters
+        .insert(
+            ProgramParameters::VlanFilter as u8,
+            !config.vlans.is_empty() as u32,
+            0,
+        )
+        .map_err(|e| e.to_string())
+}
+
+/// Writes `vxlan_ports` into VXLAN_PORTS, the UDP ports whose packets are decapsulated as VXLAN
+fn write_vxlan_ports(config: &Config, ebpf: &mut Ebpf) -> Result<(), String> {
+    write_u16_set(
+        ebpf,
+        "VXLAN_PORTS",
+        config.vxlan_ports.iter().copied().collect(),
+    )
+}
+
+/// Writes `pass_services` into PASS_SERVICES and sets PassServices when there are some
+fn write_pass_services(config: &Config, ebpf: &mut Ebpf) -> Result<(), String> {
+    let wanted: FxHashSet<u32> = config.pass_services.iter().map(PassService::key).collect();
+
+    let mut map: HashMap<&mut MapData, u32, u8> = HashMap::try_from(
+        ebpf.map_mut("PASS_SERVICES")
+            .ok_or("error in getting pass services map")?,
+    )
+    .map_err(|e| e.to_string())?;
+
+    let stale: Vec<u32> = map
+        .keys()
+        .filter_map(|k| k.ok())
+        .filter(|k| !wanted.contains(k))
+        .collect();
+    for k in stale {
+        map.remove(&k).map_err(|e| e.to_string())?;
+    }
+    for &key in &wanted {
+        map.insert(key, 1, 0).map_err(|e| e.to_string())?;
+    }
+
+    let mut parameters: HashMap<&mut MapData, u8, u32> = HashMap::try_from(
+        ebpf.map_mut("PARAMETERS")
+            .ok_or("error in getting parameters map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    parameters
+        .insert(
+            ProgramParameters::PassServices as u8,
+            !wanted.is_empty() as u32,
+            0,
+        )
+        .map_err(|e| e.to_string())
+}
+
+/// Makes the keys of the map `name` equal to `wanted`
+fn write_u16_set(ebpf: &mut Ebpf, name: &str, wanted: FxHashSet<u16>) -> Result<(), String> {
+    let mut map: HashMap<&mut MapData, u16, u8> = HashMap::try_from(
+        ebpf.map_mut(name)
+            .ok_or(format!("error in getting map {}", name))?,
+    )
+    .map_err(|e| e.to_string())?;
+
+    let stale: Vec<u16> = map
+        .keys()
+        .filter_map(|k| k.ok())
+        .filter(|k| !wanted.contains(k))
+        .collect();
+    for k in stale {
+        map.remove(&k).map_err(|e| e.to_string())?;
+    }
+    for id in wanted {
+        map.insert(id, 1, 0).map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Writes the tree to the half of `map_name` the tree in use isn't in, so packets keep walking
+/// a complete tree until the shape is pointed at the new one. Returns the number of nodes, the
+/// entry the tree starts at and whether it was written in batches
+fn write_tree(
+    ebpf: &mut Ebpf,
+    map_name: &str,
+    db_type: MaxmindDbType,
+    result: &ProcessedDb,
+) -> Result<(usize, u32, bool), String> {
+    let current = lookup_parameters(ebpf)?.1.trees[db_type as usize];
+
+    let map = ebpf.map(map_name).expect("error in getting map");
+
+    // The tree maps are sized at startup, depending on whether their databases are used and
+    // on the lookup backend
+    let len = maps::capacity(map).ok_or(format!("error in reading size of {}", map_name))?;
+    let half = len / 2;
+    if result.node_count > half {
+        return Err(format!(
+            "tree with {} nodes doesn't fit in half of map {} with {} entries, restart geofw to resize it",
+            result.node_count, map_name, len
+        ));
+    }
+
+    let base = if current.base == 0 { half } else { 0 };
+    let nodes: Vec<u64> = result.nodes().collect();
+    match maps::write_array_batch(map, base, &nodes) {
+        Ok(()) => return Ok((nodes.len(), base, true)),
+        Err(e) if maps::batch_unsupported(&e) => {
+            debug!(
+                "batch updates aren't supported, writing {} entry by entry: {}",
+                map_name, e
+            );
+        }
+        Err(e) => return Err(format!("error in writing {}: {}", map_name, e)),
+    }
+
+    let mut map: Array<&mut MapData, u64> =
+        Array::try_from(ebpf.map_mut(map_name).expect("error in getting map"))
+            .expect("error in processing map");
+    for (i, node) in nodes.iter().enumerate() {
+        map.set(base + i as u32, node, 0)
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok((nodes.len(), base, false))
+}
+
+/// Loads the marked networks of the processed tree into `map_name` for the lpm lookup backend
+/// and removes the networks that aren't marked anymore. Returns the number of networks
+fn write_prefixes(ebpf: &mut Ebpf, map_name: &str, result: &ProcessedDb) -> Result<usize, String> {
+    let mut map: LpmTrie<&mut MapData, [u8; 16], u32> =
+        LpmTrie::try_from(ebpf.map_mut(map_name).expect("error in getting map"))
+            .expect("error in processing map");
+
+    let prefixes = result.prefixes();
+    let wanted: FxHashSet<(u32, [u8; 16])> = prefixes
+        .iter()
+        .map(|&(network, len, _)| (len as u32, network.to_be_bytes()))
+        .collect();
+    let stale: Vec<_> = map
+        .keys()
+        .filter_map(Result::ok)
+        .filter(|k| !wanted.contains(&(k.prefix_len(), k.data())))
+        .collect();
+
+    for (network, len, record) in prefixes {
+        map.insert(&Key::new(len as u32, network.to_be_bytes()), record, 0)
+            .map_err(|e| format!("error in adding network {:x}/{}: {}", network, len, e))?;
+    }
+    for key in stale {
+        if let Err(e) = map.remove(&key) {
+            warn!("error in removing stale network from {}: {}", map_name, e);
+        }
+    }
+    info!("updated map = {} networks = {}", map_name, wanted.len());
+
+    Ok(wanted.len())
+}
+
+/// Downloads and processes the tree of `db_type`, this blocks for seconds to minutes. None when
+/// `reuse` is set and the database didn't change, the tree in the maps is kept
+fn fetch_tree(
+    config: &Config,
+    db_type: MaxmindDbType,
+    map_name: &'static str,
+    reuse: bool,
+) -> Result<Option<FetchedTree>, String> {
     info!("updating maps db_type = {db_type} map_name = {map_name}");
 
-    let mut map = Array::try_from(ebpf.map_mut(map_name).expect("error in getting map"))
-        .expect("error in processing map");
+    let mut report = RefreshReport::default();
+    let fetched = match &config.fleet {
+        Some(f) if f.role == FleetRole::Agent => {
+            Some(fleet::fetch(config, f, db_type, &mut report)?)
+        }
+        _ => fetch_geoip_db(config, db_type, &mut report, reuse)?,
+    };
+    let Some(db) = fetched else {
+        return Ok(None);
+    };
+    check_probes(config, db_type, &db)?;
 
-    let result = fetch_geoip_db(config, db_type)?;
+    Ok(Some(FetchedTree {
+        map_name,
+        db,
+        report,
+    }))
+}
+
+/// Writes a fetched tree of `db_type` to its map and points the lookups at it
+fn write_geoip_map(
+    config: &Config,
+    metrics: &Metrics,
+    ebpf: &mut Ebpf,
+    fleet_server: Option<&FleetServer>,
+    db_type: MaxmindDbType,
+    rules_hash: &str,
+    tree: FetchedTree,
+) -> Result<RefreshReport, String> {
+    let FetchedTree {
+        map_name,
+        db: result,
+        mut report,
+    } = tree;
 
     let t = Instant::now();
-    for (i, v) in result.db.into_iter().enumerate() {
-        map.set(i as u32, v, 0).map_err(|e| e.to_string())?;
+    let (map_name, entries, base) = match config.lookup_backend {
+        LookupBackend::Tree => {
+            let (entries, base, batched) = write_tree(ebpf, map_name, db_type, &result)?;
+            report.batched_write = batched;
+            (map_name, entries, base)
+        }
+        LookupBackend::Lpm => {
+            let prefix_map = maps::prefix_map(db_type);
+            (prefix_map, write_prefixes(ebpf, prefix_map, &result)?, 0)
+        }
+    };
+    report.map_write_time = t.elapsed();
+
+    metrics.gauge("map.entries", entries as f64, &[("map", map_name)]);
+    // A tree map holds two trees, a tree can fill half of it
+    let capacity = ebpf.map(map_name).and_then(maps::capacity).map(|c| {
+        if config.lookup_backend == LookupBackend::Tree {
+            c / 2
+        } else {
+            c
+        }
+    });
+    if let Some(capacity) = capacity {
+        metrics.gauge("map.capacity", capacity as f64, &[("map", map_name)]);
+        metrics.gauge(
+            "map.fill",
+            entries as f64 / capacity.max(1) as f64,
+            &[("map", map_name)],
+        );
     }
 
-    info!(
-        "updated map = {} record_size = {} node_count = {} est_size = {} time_taken = {:?}",
-        map_name,
-        result.record_size,
-        result.node_count,
-        ((result.record_size as u32 * 2) / 8) * result.node_count,
-        t.elapsed()
+    if let Some(server) = fleet_server {
+        server.publish(db_type, &result, rules_hash.to_string());
+    }
+
+    tracing::info!(
+        map = map_name,
+        record_size = result.record_size,
+        node_count = result.node_count,
+        est_size = 8 * result.node_count as u64,
+        time_taken_ms = report.map_write_time.as_millis() as u64,
+        batched = report.batched_write,
+        entries_per_second =
+            (entries as f64 / report.map_write_time.as_secs_f64().max(1e-9)) as u64,
+        "updated map"
+    );
+    tracing::info!(
+        db_type = %db_type,
+        build_epoch = result.build_epoch,
+        downloaded_bytes = report.downloaded_bytes,
+        download_ms = report.download_time.as_millis() as u64,
+        parse_ms = report.parse_time.as_millis() as u64,
+        process_ms = report.process_time.as_millis() as u64,
+        marked = report.marked,
+        map_write_ms = report.map_write_time.as_millis() as u64,
+        "refresh"
+    );
+
+    report.emit(metrics, db_type);
+    metrics.gauge(
+        "db.node_count",
+        result.node_count as f64,
+        &[("db", db_type.short_name())],
     );
 
+    update_lookup_parameters(ebpf, |p| {
+        p.trees[db_type as usize] = TreeShape {
+            node_count: result.node_count,
+            record_size: result.record_size as u32,
+            ipv4_start: result.ipv4_start,
+            base,
+        };
+    })
+    .expect("error in writing tree shape to map");
+
+    let epoch = match db_type {
+        MaxmindDbType::Country => ProgramParameters::CountryBuildEpoch,
+        MaxmindDbType::Asn => ProgramParameters::AsnBuildEpoch,
+        MaxmindDbType::City => ProgramParameters::CityBuildEpoch,
+        MaxmindDbType::Anonymous => ProgramParameters::AnonymousBuildEpoch,
+    };
     let mut map: HashMap<&mut MapData, u8, u32> = HashMap::try_from(
         ebpf.map_mut("PARAMETERS")
             .expect("error in getting parameter map"),
     )
     .expect("error in processing parameter map");
+    map.insert(epoch as u8, result.build_epoch as u32, 0)
+        .expect("error in writing build epoch to map");
+
+    let tags = [("db", db_type.short_name())];
+    metrics.gauge("db.build_epoch", result.build_epoch as f64, &tags);
+    metrics.gauge(
+        "db.refreshed_at",
+        chrono::Utc::now().timestamp() as f64,
+        &tags,
+    );
+
+    Ok(report)
+}
+
+/// Resolves on the first SIGINT or SIGTERM
+async fn shutdown(sigterm: &mut signal::unix::Signal) {
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+/// What the status command of the control socket reports
+fn status(
+    config: &Config,
+    links: &attach::Links,
+    loaded: &FxHashMap<MaxmindDbType, u64>,
+    offline: &FxHashSet<MaxmindDbType>,
+    active_policies: Option<u32>,
+) -> serde_json::Value {
+    let mut interfaces: Vec<_> = links
+        .iter()
+        .map(|(name, link)| serde_json::json!({ "name": name, "datapath": link.kind() }))
+        .collect();
+    interfaces.sort_by_key(|i| i["name"].as_str().map(str::to_string));
+    let databases: Vec<_> = config
+        .db_types()
+        .into_iter()
+        .map(|db_type| {
+            serde_json::json!({
+                "db": db_type.short_name(),
+                "build_epoch": loaded.get(&db_type),
+                "offline": offline.contains(&db_type),
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "mode": config.mode,
+        "lookup_backend": config.lookup_backend,
+        "interfaces": interfaces,
+        "databases": databases,
+        "active_policies": active_policies,
+    })
+}
+
+fn check_staleness(config: &Config, metrics: &Metrics, loaded: &FxHashMap<MaxmindDbType, u64>) {
+    let now = chrono::Utc::now().timestamp();
 
-    match db_type {
-        MaxmindDbType::Country => {
-            map.insert(
-                ProgramParameters::CountryNodeCount as u8,
-                result.node_count,
-                0,
-            )
-            .expect("error in writing country node count to map");
-            map.insert(
-                ProgramParameters::CountryRecordSize as u8,
-                result.record_size as u32,
-                0,
-            )
-            .expect("error in writing country record size to map");
-        }
-        MaxmindDbType::Asn => {
-            map.insert(ProgramParameters::AsnNodeCount as u8, result.node_count, 0)
-                .expect("error in writing country node count to map");
-            map.insert(
-                ProgramParameters::AsnRecordSize as u8,
-                result.record_size as u32,
-                0,
-            )
-            .expect("error in writing country record size to map");
+    for db_type in config.db_types() {
+        if let Some(&build_epoch) = loaded.get(&db_type) {
+            metrics.gauge(
+                "db.age",
+                (now - build_epoch as i64) as f64,
+                &[("db", db_type.short_name())],
+            );
+        }
+
+        match loaded.get(&db_type) {
+            None => alert::alert(config, &format!("{} has not been loaded", db_type)),
+            Some(&build_epoch) if now - build_epoch as i64 > config.db.max_age => {
+                alert::alert(
+                    config,
+                    &format!(
+                        "{} is stale, loaded build is from {}",
+                        db_type,
+                        dbinfo::format_epoch(build_epoch)
+                    ),
+                );
+            }
+            Some(_) => (),
         }
     }
+}
+
+fn check_probes(config: &Config, db_type: MaxmindDbType, db: &ProcessedDb) -> Result<(), String> {
+    let mut failed = 0;
+
+    for probe in config.probes.iter().filter(|p| p.db == db_type) {
+        let blocked = db.lookup(probe.addr);
+        if blocked != probe.blocked {
+            // Posted to the webhook as well, the new database is rejected
+            alert::alert(
+                config,
+                &format!(
+                    "probe failed db_type = {} addr = {} expected blocked = {} got = {}, keeping the previously loaded data",
+                    db_type, probe.addr, probe.blocked, blocked
+                ),
+            );
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!(
+            "{} probes failed, keeping the previously loaded data",
+            failed
+        ));
+    }
 
     Ok(())
 }
 
+fn is_anycast(data: &FxHashMap<&[u8], Data>) -> bool {
+    let Some(Data::Map(traits)) = data.get("traits".as_bytes()) else {
+        return false;
+    };
+
+    matches!(
+        traits.get("is_anycast".as_bytes()),
+        Some(Data::Boolean(true))
+    )
+}
+
 fn setup() {
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
@@ -0,0 +1,569 @@
+use crate::{
+    output::{print_json, OutputFormat},
+    stats::open_stats,
+};
+use aya::{
+    maps::{loaded_maps, Array, HashMap, Map, MapData},
+    Ebpf,
+};
+use geofw_common::{
+    is_listed, Counter, CountryAction, LookupBackend, LookupParameters, MaxmindDbType,
+    ProgramParameters, TreeShape, ALLOW_MARKER, BLOCK_MARKER, COMPOUND_MARKER, COUNTER_COUNT,
+    POLICY_MARKER, SUSPECT_MARKER,
+};
+use serde_derive::Serialize;
+use std::{
+    io, mem,
+    net::IpAddr,
+    ops::Range,
+    os::fd::{AsFd, AsRawFd},
+};
+
+pub const TREE_MAPS: [(&str, MaxmindDbType); 4] = [
+    ("BLOCKED_COUNTRY", MaxmindDbType::Country),
+    ("BLOCKED_ASN", MaxmindDbType::Asn),
+    ("BLOCKED_CITY", MaxmindDbType::City),
+    ("BLOCKED_ANONYMOUS", MaxmindDbType::Anonymous),
+];
+
+/// LPM trie with the marked networks of the tree of `db_type`, used with the lpm lookup backend
+pub fn prefix_map(db_type: MaxmindDbType) -> &'static str {
+    match db_type {
+        MaxmindDbType::Country => "COUNTRY_PREFIXES",
+        MaxmindDbType::Asn => "ASN_PREFIXES",
+        MaxmindDbType::City => "CITY_PREFIXES",
+        MaxmindDbType::Anonymous => "ANONYMOUS_PREFIXES",
+    }
+}
+
+/// Nodes in BLOCKED_CITY when the city database is used, room for two trees. The GeoLite2-City
+/// tree has about 4.5 million nodes, the map is left with a single entry otherwise
+pub const CITY_MAP_SIZE: u32 = 1024 * 1024 * 16;
+
+/// Nodes in BLOCKED_ANONYMOUS when some anonymous IP flags are blocked, room for two trees
+pub const ANONYMOUS_MAP_SIZE: u32 = 1024 * 1024 * 8;
+
+/// max_entries of an array, hash map or LPM trie
+pub fn capacity(map: &Map) -> Option<u32> {
+    let data = match map {
+        Map::Array(data) | Map::HashMap(data) | Map::LpmTrie(data) => data,
+        _ => return None,
+    };
+
+    data.info().ok().map(|info| info.max_entries())
+}
+
+/// BPF_MAP_UPDATE_BATCH, arrays support it since Linux 5.6
+const BPF_MAP_UPDATE_BATCH: libc::c_long = 26;
+
+/// Entries written by each BPF_MAP_UPDATE_BATCH call
+const BATCH_SIZE: usize = 64 * 1024;
+
+/// The part of `union bpf_attr` the batch commands use
+#[repr(C)]
+#[derive(Default)]
+struct BatchAttr {
+    in_batch: u64,
+    out_batch: u64,
+    keys: u64,
+    values: u64,
+    count: u32,
+    map_fd: u32,
+    elem_flags: u64,
+    flags: u64,
+}
+
+/// Writes `values` to the entries of an array starting at `start`, with a syscall for every
+/// BATCH_SIZE entries instead of one for every entry. aya doesn't wrap the batch commands
+pub fn write_array_batch(map: &Map, start: u32, values: &[u64]) -> io::Result<()> {
+    let Map::Array(data) = map else {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    };
+    let fd = data.fd().as_fd().as_raw_fd();
+
+    for (i, chunk) in values.chunks(BATCH_SIZE).enumerate() {
+        let first = start + (i * BATCH_SIZE) as u32;
+        let keys: Vec<u32> = (first..first + chunk.len() as u32).collect();
+        let mut attr = BatchAttr {
+            keys: keys.as_ptr() as u64,
+            values: chunk.as_ptr() as u64,
+            count: chunk.len() as u32,
+            map_fd: fd as u32,
+            ..Default::default()
+        };
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                BPF_MAP_UPDATE_BATCH,
+                &mut attr as *mut BatchAttr,
+                mem::size_of::<BatchAttr>() as u32,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `write_array_batch` failed because the kernel has no batch commands
+pub fn batch_unsupported(e: &io::Error) -> bool {
+    // ENOTSUPP, which the kernel returns for maps without batch operations
+    const ENOTSUPP: i32 = 524;
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EINVAL | libc::EOPNOTSUPP | ENOTSUPP)
+    )
+}
+
+/// A second handle to the map `name` of `ebpf`, for threads that read or update it while
+/// `ebpf` keeps its own
+pub fn share(ebpf: &Ebpf, name: &str) -> Result<Map, String> {
+    let dup = |data: &MapData| {
+        let fd = data
+            .fd()
+            .as_fd()
+            .try_clone_to_owned()
+            .map_err(|e| format!("error in duplicating map {}: {}", name, e))?;
+        MapData::from_fd(fd).map_err(|e| format!("error in opening map {}: {}", name, e))
+    };
+
+    match ebpf.map(name) {
+        Some(Map::Array(data)) => dup(data).map(Map::Array),
+        Some(Map::HashMap(data)) => dup(data).map(Map::HashMap),
+        Some(Map::LpmTrie(data)) => dup(data).map(Map::LpmTrie),
+        Some(Map::PerCpuArray(data)) => dup(data).map(Map::PerCpuArray),
+        Some(_) => Err(format!("map {} can't be shared", name)),
+        None => Err(format!("error in getting map {}", name)),
+    }
+}
+
+/// Finds a map created by a running geofw instance by its name. If there are multiple
+/// maps with the same name, the most recently created one is returned.
+pub fn open_loaded_map(name: &str) -> Result<MapData, String> {
+    // The kernel only keeps the first 15 bytes of map names
+    let short_name = name.get(..15).unwrap_or(name);
+    let mut found = None;
+
+    for info in loaded_maps() {
+        let info = info.map_err(|e| format!("error in listing loaded maps: {}", e))?;
+        if info.name_as_str() == Some(short_name) {
+            found = Some(info.id()).max(found);
+        }
+    }
+
+    let Some(id) = found else {
+        return Err(format!("map {} is not loaded, is geofw running?", name));
+    };
+
+    MapData::from_id(id).map_err(|e| format!("error in opening map {}: {}", name, e))
+}
+
+pub fn parse_range(s: &str) -> Result<Range<u32>, String> {
+    let Some((start, end)) = s.split_once("..") else {
+        return Err(format!("invalid range {}, expected a..b", s));
+    };
+
+    let start = start.parse().map_err(|e| format!("invalid start: {}", e))?;
+    let end = end.parse().map_err(|e| format!("invalid end: {}", e))?;
+
+    Ok(start..end)
+}
+
+pub fn read_parameters() -> Result<Vec<(u8, u32)>, String> {
+    let map: HashMap<MapData, u8, u32> =
+        HashMap::try_from(Map::HashMap(open_loaded_map("PARAMETERS")?))
+            .map_err(|e| format!("error in processing parameter map: {}", e))?;
+
+    let mut out = vec![];
+    for entry in map.iter() {
+        out.push(entry.map_err(|e| format!("error in reading parameter map: {}", e))?);
+    }
+    out.sort();
+
+    Ok(out)
+}
+
+/// The slot of LOOKUP_PARAMETERS LookupParametersSlot points at
+pub fn read_lookup_parameters() -> Result<LookupParameters, String> {
+    let slot = read_parameter(&read_parameters()?, ProgramParameters::LookupParametersSlot)
+        .map_or(0, |v| v & 1);
+    let map: Array<MapData, LookupParameters> =
+        Array::try_from(Map::Array(open_loaded_map("LOOKUP_PARAMETERS")?))
+            .map_err(|e| format!("error in processing lookup parameter map: {}", e))?;
+
+    map.get(&slot, 0)
+        .map_err(|e| format!("error in reading lookup parameter map: {}", e))
+}
+
+pub fn read_parameter(params: &[(u8, u32)], param: ProgramParameters) -> Option<u32> {
+    params
+        .iter()
+        .find(|(k, _)| *k == param as u8)
+        .map(|&(_, v)| v)
+}
+
+pub fn dump_map(name: &str, range: Option<Range<u32>>, output: OutputFormat) -> Result<(), String> {
+    if name == "PARAMETERS" {
+        return dump_parameters(range, output);
+    }
+    if name == "STATS" {
+        return dump_stats(range, output);
+    }
+    if name == "LOOKUP_PARAMETERS" {
+        return dump_lookup_parameters(output);
+    }
+
+    let Some(&(_, db_type)) = TREE_MAPS.iter().find(|(n, _)| *n == name) else {
+        return Err(format!("unknown map {}", name));
+    };
+
+    dump_tree(name, db_type, range, output)
+}
+
+#[derive(Serialize)]
+struct ParameterEntry {
+    key: u8,
+    name: Option<String>,
+    value: u32,
+}
+
+fn dump_parameters(range: Option<Range<u32>>, output: OutputFormat) -> Result<(), String> {
+    let entries: Vec<ParameterEntry> = read_parameters()?
+        .into_iter()
+        .filter(|(key, _)| range.as_ref().is_none_or(|r| r.contains(&(*key as u32))))
+        .map(|(key, value)| ParameterEntry {
+            key,
+            name: ProgramParameters::from_key(key).map(|p| format!("{:?}", p)),
+            value,
+        })
+        .collect();
+
+    if output == OutputFormat::Json {
+        return print_json(&entries);
+    }
+
+    for e in entries {
+        println!(
+            "{:>3} {:<20} = {}",
+            e.key,
+            e.name.as_deref().unwrap_or("unknown"),
+            e.value
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct TreeEntry {
+    db: &'static str,
+    node_count: u32,
+    record_size: u32,
+    ipv4_start: u32,
+    base: u32,
+}
+
+#[derive(Serialize)]
+struct LookupParametersEntry {
+    trees: Vec<TreeEntry>,
+    mode: u32,
+    backend: u32,
+    generation: u32,
+}
+
+fn dump_lookup_parameters(output: OutputFormat) -> Result<(), String> {
+    let params = read_lookup_parameters()?;
+    let entry = LookupParametersEntry {
+        trees: TREE_MAPS
+            .iter()
+            .map(|&(_, db_type)| {
+                let tree = params.trees[db_type as usize];
+                TreeEntry {
+                    db: db_type.short_name(),
+                    node_count: tree.node_count,
+                    record_size: tree.record_size,
+                    ipv4_start: tree.ipv4_start,
+                    base: tree.base,
+                }
+            })
+            .collect(),
+        mode: params.mode,
+        backend: params.backend,
+        generation: params.generation,
+    };
+
+    if output == OutputFormat::Json {
+        return print_json(&entry);
+    }
+
+    for t in &entry.trees {
+        println!(
+            "{:<10} node_count = {} record_size = {} ipv4_start = {} base = {}",
+            t.db, t.node_count, t.record_size, t.ipv4_start, t.base
+        );
+    }
+    println!("mode = {}", entry.mode);
+    println!("backend = {}", entry.backend);
+    println!("generation = {}", entry.generation);
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CounterEntry {
+    index: u32,
+    name: Option<String>,
+    /// Sum over all CPUs
+    value: u64,
+}
+
+fn dump_stats(range: Option<Range<u32>>, output: OutputFormat) -> Result<(), String> {
+    let stats = open_stats()?;
+
+    let mut entries = vec![];
+    for index in range.unwrap_or(0..COUNTER_COUNT) {
+        let values = stats
+            .get(&index, 0)
+            .map_err(|e| format!("error in reading counter {}: {}", index, e))?;
+        entries.push(CounterEntry {
+            index,
+            name: Counter::from_index(index).map(|c| format!("{:?}", c)),
+            value: values.iter().sum(),
+        });
+    }
+
+    if output == OutputFormat::Json {
+        return print_json(&entries);
+    }
+
+    for e in entries {
+        println!(
+            "{:>3} {:<20} = {}",
+            e.index,
+            e.name.as_deref().unwrap_or("unknown"),
+            e.value
+        );
+    }
+
+    Ok(())
+}
+
+/// A tree map as seen by the kernel, read through the bpf syscall.
+pub struct KernelTree {
+    map: Array<MapData, u64>,
+    pub node_count: u32,
+    pub record_size: u32,
+    pub ipv4_start: u32,
+    /// Entry of node 0, the map holds the previous tree in its other half
+    base: u32,
+}
+
+impl KernelTree {
+    pub fn open(name: &str, db_type: MaxmindDbType) -> Result<Self, String> {
+        let params = read_lookup_parameters()?;
+        if LookupBackend::from_value(params.backend) == Some(LookupBackend::Lpm) {
+            return Err(format!(
+                "{} is loaded into {} with the lpm lookup backend, the tree isn't in the kernel",
+                db_type,
+                prefix_map(db_type)
+            ));
+        }
+        let tree = params.trees[db_type as usize];
+        if tree.record_size == 0 {
+            return Err(format!("{} has not been loaded yet", db_type));
+        }
+
+        let map = Array::try_from(Map::Array(open_loaded_map(name)?))
+            .map_err(|e| format!("error in processing map {}: {}", name, e))?;
+
+        Ok(Self {
+            map,
+            node_count: tree.node_count,
+            record_size: tree.record_size,
+            ipv4_start: tree.ipv4_start,
+            base: tree.base,
+        })
+    }
+
+    pub fn read_node(&self, node: u32) -> Result<(u32, u32), String> {
+        let records = self
+            .map
+            .get(&(self.base + node), 0)
+            .map_err(|e| format!("error in reading node {}: {}", node, e))?;
+
+        Ok(((records >> 32) as u32, records as u32))
+    }
+
+    /// Whether the top level countries or ASNs include `addr`
+    pub fn lookup(&self, addr: IpAddr) -> Result<bool, String> {
+        let tree = TreeShape {
+            node_count: self.node_count,
+            record_size: self.record_size,
+            ipv4_start: self.ipv4_start,
+            base: self.base,
+        };
+
+        walk_tree(&self.map, &tree, addr).map(|record| is_listed(record, 0))
+    }
+}
+
+/// Walks the tree in `map` the same way `walk_tree` in the eBPF program does and returns the
+/// record the walk ended at
+pub fn walk_tree(map: &Array<MapData, u64>, tree: &TreeShape, addr: IpAddr) -> Result<u32, String> {
+    if tree.record_size == 0 {
+        return Ok(0);
+    }
+
+    let (mut node, mut i, mut ip) = match addr {
+        IpAddr::V4(a) => (tree.ipv4_start, 32, (a.to_bits() as u128) << 96),
+        IpAddr::V6(a) => (0, 128, a.to_bits()),
+    };
+
+    while i > 0 && node < tree.node_count {
+        let left = (ip & (1 << 127)) == 0;
+        ip <<= 1;
+
+        let records = map
+            .get(&(tree.base + node), 0)
+            .map_err(|e| format!("error in reading node {}: {}", node, e))?;
+        node = if left {
+            (records >> 32) as u32
+        } else {
+            records as u32
+        };
+        i -= 1;
+    }
+
+    Ok(node)
+}
+
+#[derive(Serialize)]
+struct TreeDump {
+    map: String,
+    db: String,
+    node_count: u32,
+    record_size: u32,
+    nodes: Vec<NodeEntry>,
+}
+
+#[derive(Serialize)]
+struct NodeEntry {
+    node: u32,
+    left: u32,
+    right: u32,
+    left_record: String,
+    right_record: String,
+}
+
+fn dump_tree(
+    name: &str,
+    db_type: MaxmindDbType,
+    range: Option<Range<u32>>,
+    output: OutputFormat,
+) -> Result<(), String> {
+    let tree = KernelTree::open(name, db_type)?;
+    let range = range.unwrap_or(0..tree.node_count);
+
+    if output == OutputFormat::Table {
+        println!(
+            "map = {} db = {} node_count = {} record_size = {}",
+            name, db_type, tree.node_count, tree.record_size
+        );
+    }
+
+    let mut nodes = vec![];
+    for node in range.start..range.end.min(tree.node_count) {
+        let (left, right) = tree.read_node(node)?;
+        let entry = NodeEntry {
+            node,
+            left,
+            right,
+            left_record: describe_record(left, tree.node_count),
+            right_record: describe_record(right, tree.node_count),
+        };
+
+        match output {
+            OutputFormat::Table => println!(
+                "node = {} left = {} right = {}",
+                entry.node, entry.left_record, entry.right_record
+            ),
+            OutputFormat::Json => nodes.push(entry),
+        }
+    }
+
+    if output == OutputFormat::Json {
+        return print_json(&TreeDump {
+            map: name.to_string(),
+            db: db_type.short_name().to_string(),
+            node_count: tree.node_count,
+            record_size: tree.record_size,
+            nodes,
+        });
+    }
+
+    Ok(())
+}
+
+fn describe_record(record: u32, node_count: u32) -> String {
+    if record == BLOCK_MARKER {
+        "BLOCKED".to_string()
+    } else if record == SUSPECT_MARKER {
+        "SUSPECT".to_string()
+    } else if record == ALLOW_MARKER {
+        "ALLOWED".to_string()
+    } else if record & !0xff == POLICY_MARKER {
+        format!("BLOCKED policies={:#010b}", record & 0xff)
+    } else if record & !0xff == COMPOUND_MARKER {
+        format!("COMPOUND rules={:#010b}", record & 0xff)
+    } else if let Some(action) = CountryAction::of_record(record, 0) {
+        format!("{:?} policies={:#010b}", action, record & 0xff)
+    } else if record == node_count {
+        "empty".to_string()
+    } else if record > node_count {
+        format!("data+{}", record - node_count - 16)
+    } else {
+        record.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("96..128"), Ok(96..128));
+        assert_eq!(parse_range("0..0"), Ok(0..0));
+        assert!(parse_range("96").is_err());
+        assert!(parse_range("..128").is_err());
+        assert!(parse_range("a..b").is_err());
+        assert!(parse_range("-1..5").is_err());
+    }
+
+    #[test]
+    fn records() {
+        let node_count = 100;
+
+        assert_eq!(describe_record(BLOCK_MARKER, node_count), "BLOCKED");
+        assert_eq!(describe_record(SUSPECT_MARKER, node_count), "SUSPECT");
+        assert_eq!(describe_record(ALLOW_MARKER, node_count), "ALLOWED");
+        assert_eq!(
+            describe_record(POLICY_MARKER | 0b101, node_count),
+            "BLOCKED policies=0b00000101"
+        );
+        assert_eq!(
+            describe_record(COMPOUND_MARKER | 0b10, node_count),
+            "COMPOUND rules=0b00000010"
+        );
+        assert_eq!(
+            describe_record(CountryAction::RateLimit.marker(1), node_count),
+            "RateLimit policies=0b00000001"
+        );
+        assert_eq!(describe_record(node_count, node_count), "empty");
+        assert_eq!(describe_record(node_count + 16 + 42, node_count), "data+42");
+        assert_eq!(describe_record(7, node_count), "7");
+    }
+}
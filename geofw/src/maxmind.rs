@@ -1,6 +1,6 @@
 use core::str;
 use fxhash::FxHashMap;
-use geofw_common::BLOCK_MARKER;
+use geofw_common::{is_listed, ACTION_MARKER};
 use std::{
     collections::VecDeque,
     fmt::{Debug, Display, Formatter, Result as FmtResult},
@@ -20,9 +20,16 @@ pub struct MaxmindDb {
 
 #[derive(Debug, Default)]
 pub struct Metadata {
-    node_count: u32,
-    record_size: u16,
+    pub node_count: u32,
+    pub record_size: u16,
     pub data_section_start: usize,
+    /// Node at which the IPv4 subtree (::/96) starts
+    pub ipv4_start: u32,
+    pub database_type: String,
+    pub build_epoch: u64,
+    pub ip_version: u16,
+    pub languages: Vec<String>,
+    pub description: Vec<(String, String)>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -43,9 +50,15 @@ pub enum Data<'a> {
     Float(f32),
 }
 
+/// The search tree of a database with the records of listed networks replaced by markers
 pub struct ProcessedDb {
     pub node_count: u32,
+    /// Always 32, markers don't fit in smaller records
     pub record_size: u16,
+    pub ipv4_start: u32,
+    pub build_epoch: u64,
+    /// Number of records that were marked as blocked or suspect
+    pub marked: u32,
     pub db: Vec<u8>,
 }
 
@@ -116,15 +129,68 @@ impl MaxmindDb {
             unreachable!()
         };
 
+        let database_type = match m.get("database_type".as_bytes()) {
+            Some(v @ Data::String(_)) => v.to_string(),
+            _ => String::new(),
+        };
+        let build_epoch = match m.get("build_epoch".as_bytes()) {
+            Some(&Data::U64(v)) => v,
+            _ => 0,
+        };
+        let ip_version = match m.get("ip_version".as_bytes()) {
+            Some(&Data::U16(v)) => v,
+            _ => 6,
+        };
+        let languages = match m.get("languages".as_bytes()) {
+            Some(Data::Array(v)) => v.iter().map(|l| l.to_string()).collect(),
+            _ => vec![],
+        };
+        let mut description: Vec<(String, String)> = match m.get("description".as_bytes()) {
+            Some(Data::Map(v)) => v
+                .iter()
+                .map(|(k, v)| (String::from_utf8_lossy(k).to_string(), v.to_string()))
+                .collect(),
+            _ => vec![],
+        };
+        description.sort();
+
         db.metadata = Metadata {
             data_section_start: ((record_size as usize * 2) / 8) * node_count as usize + 16,
             record_size,
             node_count,
+            ipv4_start: 0,
+            database_type,
+            build_epoch,
+            ip_version,
+            languages,
+            description,
         };
+        db.metadata.ipv4_start = db.find_ipv4_start();
 
         db
     }
 
+    /// Follows the left record for the 96 leading zero bits of an IPv4 address in the IPv6
+    /// tree. The root is the IPv4 subtree of databases that only have IPv4 networks
+    fn find_ipv4_start(&self) -> u32 {
+        if self.metadata.ip_version == 4 {
+            return 0;
+        }
+        let node_size = self.metadata.record_size as usize * 2 / 8;
+        let mut node = 0;
+
+        for _ in 0..96 {
+            if node >= self.metadata.node_count {
+                break;
+            }
+
+            let n = &self.data[node as usize * node_size..(node as usize * node_size) + node_size];
+            node = Self::node_from_bytes(n, true, self.metadata.record_size);
+        }
+
+        node
+    }
+
     fn read_metadata(&self, metadata_start: usize) -> FxHashMap<&[u8], Data> {
         let (Data::Map(map), _) = self.read_data(metadata_start) else {
             unreachable!()
@@ -132,8 +198,10 @@ impl MaxmindDb {
         map
     }
 
-    fn node_from_bytes(n: &[u8], left: bool, record_size: u16) -> u32 {
+    pub fn node_from_bytes(n: &[u8], left: bool, record_size: u16) -> u32 {
         match record_size {
+            32 if left => u32::from_be_bytes([n[0], n[1], n[2], n[3]]),
+            32 => u32::from_be_bytes([n[4], n[5], n[6], n[7]]),
             28 if left => u32::from_be_bytes([(n[3] & 0b1111_0000) >> 4, n[0], n[1], n[2]]),
             28 => u32::from_be_bytes([n[3] & 0b0000_1111, n[4], n[5], n[6]]),
             24 if left => u32::from_be_bytes([0, n[0], n[1], n[2]]),
@@ -142,10 +210,12 @@ impl MaxmindDb {
         }
     }
 
-    fn write_over_node_bytes(n: &mut [u8], left: bool, record_size: u16, val: u32) {
+    pub fn write_over_node_bytes(n: &mut [u8], left: bool, record_size: u16, val: u32) {
         let val = val.to_be_bytes();
 
         match record_size {
+            32 if left => n[0..=3].copy_from_slice(&val),
+            32 => n[4..=7].copy_from_slice(&val),
             28 if left => {
                 n[0..=2].copy_from_slice(&val[1..=3]);
                 n[3] = (n[3] & 0b0000_1111) | (val[0] << 4);
@@ -160,23 +230,12 @@ impl MaxmindDb {
         }
     }
 
-    #[allow(unused)]
     pub fn lookup(&self, addr: IpAddr) -> Option<Data> {
         let node_size = self.metadata.record_size as usize * 2 / 8;
-        let mut node = 0;
-        let mut i = 0i8;
 
-        let mut ip = match addr {
-            IpAddr::V4(a) => {
-                node = 96;
-                i = 31;
-                a.to_bits() as u128
-            }
-            IpAddr::V6(a) => {
-                node = 0;
-                i = 127;
-                a.to_bits()
-            }
+        let (mut node, mut i, ip) = match addr {
+            IpAddr::V4(a) => (self.metadata.ipv4_start, 31i8, a.to_bits() as u128),
+            IpAddr::V6(a) => (0, 127i8, a.to_bits()),
         };
 
         while i >= 0 && node < self.metadata.node_count {
@@ -198,18 +257,26 @@ impl MaxmindDb {
         }
     }
 
-    pub fn consume(
-        mut self,
-        should_block: impl Fn(&FxHashMap<&[u8], Data>) -> bool,
-    ) -> ProcessedDb {
+    pub fn consume(self, marker: impl Fn(&FxHashMap<&[u8], Data>) -> Option<u32>) -> ProcessedDb {
         let mut stack = VecDeque::new();
-        let node_size = self.metadata.record_size as usize * 2 / 8;
+        let record_size = self.metadata.record_size;
+        let node_count = self.metadata.node_count as usize;
+        let node_size = record_size as usize * 2 / 8;
+        let mut marked = 0;
+
+        // Markers don't fit in 24 or 28 bits without overlapping the data pointers, the tree is
+        // copied with 32 bit records and they are written into the copy
+        let mut tree = Vec::with_capacity(node_count * 8);
+        for n in self.data[..node_count * node_size].chunks_exact(node_size) {
+            tree.extend_from_slice(&Self::node_from_bytes(n, true, record_size).to_be_bytes());
+            tree.extend_from_slice(&Self::node_from_bytes(n, false, record_size).to_be_bytes());
+        }
+
+        // ::ffff:0:0/96 and 2002::/16 point at the IPv4 subtree, which is only walked once
+        let mut visited = vec![false; node_count];
         stack.push_back((0, 0, false));
 
         while let Some((node, parent, bit)) = stack.pop_front() {
-            if node == BLOCK_MARKER {
-                continue;
-            }
             if node >= self.metadata.node_count {
                 let ds_offset = node - self.metadata.node_count;
 
@@ -219,36 +286,35 @@ impl MaxmindDb {
                 let Data::Map(data) = data else {
                     unreachable!()
                 };
-                if should_block(&data) {
+                if let Some(marker) = marker(&data) {
                     // Mark the parent of this node as non existent
-                    let node = parent;
-
-                    Self::write_over_node_bytes(
-                        &mut self.data
-                            [node as usize * node_size..(node as usize * node_size) + node_size],
-                        bit,
-                        self.metadata.record_size,
-                        BLOCK_MARKER,
-                    );
+                    let node = parent as usize;
+
+                    Self::write_over_node_bytes(&mut tree[node * 8..node * 8 + 8], bit, 32, marker);
+                    marked += 1;
                 }
 
                 continue;
             }
+            if std::mem::replace(&mut visited[node as usize], true) {
+                continue;
+            }
 
-            let n =
-                &mut self.data[node as usize * node_size..(node as usize * node_size) + node_size];
-            let node_1 = Self::node_from_bytes(n, false, self.metadata.record_size);
-            let node_2 = Self::node_from_bytes(n, true, self.metadata.record_size);
+            let n = &self.data[node as usize * node_size..(node as usize * node_size) + node_size];
+            let node_1 = Self::node_from_bytes(n, false, record_size);
+            let node_2 = Self::node_from_bytes(n, true, record_size);
 
             stack.push_back((node_1, node, false));
             stack.push_back((node_2, node, true));
         }
 
-        // Trim database to only contain the binary tree
         ProcessedDb {
             node_count: self.metadata.node_count,
-            record_size: self.metadata.record_size,
-            db: self.data[..self.metadata.data_section_start].to_vec(),
+            record_size: 32,
+            ipv4_start: self.metadata.ipv4_start,
+            build_epoch: self.metadata.build_epoch,
+            marked,
+            db: tree,
         }
     }
 
@@ -436,25 +502,74 @@ impl MaxmindDb {
 }
 
 impl ProcessedDb {
-    pub fn lookup(&self, addr: IpAddr) -> bool {
+    /// The nodes of the tree the way the tree maps store them, the left record in the upper 32
+    /// bits and the right record in the lower 32 bits
+    pub fn nodes(&self) -> impl Iterator<Item = u64> + '_ {
         let node_size = self.record_size as usize * 2 / 8;
-        let mut node = 0;
 
-        let mut ip = match addr {
-            IpAddr::V4(a) => a.to_bits() as u128,
-            IpAddr::V6(a) => a.to_bits(),
+        self.db
+            .chunks_exact(node_size)
+            .take(self.node_count as usize)
+            .map(|n| {
+                let left = MaxmindDb::node_from_bytes(n, true, self.record_size);
+                let right = MaxmindDb::node_from_bytes(n, false, self.record_size);
+                (left as u64) << 32 | right as u64
+            })
+    }
+
+    pub fn lookup(&self, addr: IpAddr) -> bool {
+        let node_size = self.record_size as usize * 2 / 8;
+        // IPv4 walks start at the root of the IPv4 subtree like in the eBPF program
+        let (mut node, mut i, mut ip) = match addr {
+            IpAddr::V4(a) => (self.ipv4_start, 32, (a.to_bits() as u128) << 96),
+            IpAddr::V6(a) => (0, 128, a.to_bits()),
         };
 
-        let mut i = 0;
-        while i < 128 && node < self.node_count {
+        while i > 0 && node < self.node_count {
             let left = (ip & (1 << 127)) == 0;
             ip <<= 1;
 
             let n = &self.db[node as usize * node_size..(node as usize * node_size) + node_size];
             node = MaxmindDb::node_from_bytes(n, left, self.record_size);
-            i += 1;
+            i -= 1;
+        }
+
+        is_listed(node, 0)
+    }
+
+    /// The networks whose records were marked, as (network, prefix length, record). Networks
+    /// in the IPv4 subtree are returned as IPv4 mapped IPv6 networks like the keys of the LPM
+    /// tries. Aliases of the IPv4 subtree, like ::ffff:0:0/96 and 2002::/16, are skipped
+    pub fn prefixes(&self) -> Vec<(u128, u8, u32)> {
+        let node_size = self.record_size as usize * 2 / 8;
+        let mut prefixes = vec![];
+        let mut stack = vec![(0, 0u8, 0u128)];
+
+        while let Some((node, depth, network)) = stack.pop() {
+            if node >= self.node_count {
+                // Every marker is at or above ACTION_MARKER, anything else is unlisted data
+                if node >= ACTION_MARKER {
+                    let network = if depth >= 96 && network >> 32 == 0 {
+                        network | 0xffff << 32
+                    } else {
+                        network
+                    };
+                    prefixes.push((network, depth, node));
+                }
+                continue;
+            }
+            if depth == 128 || (node == self.ipv4_start && node != 0 && (depth, network) != (96, 0))
+            {
+                continue;
+            }
+
+            let n = &self.db[node as usize * node_size..(node as usize * node_size) + node_size];
+            let left = MaxmindDb::node_from_bytes(n, true, self.record_size);
+            let right = MaxmindDb::node_from_bytes(n, false, self.record_size);
+            stack.push((left, depth + 1, network));
+            stack.push((right, depth + 1, network | 1 << (127 - depth)));
         }
 
-        node == BLOCK_MARKER
+        prefixes
     }
 }
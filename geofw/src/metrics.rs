@@ -0,0 +1,360 @@
+use crate::{
+    auth::{AuthError, Scope, Tokens},
+    tls::TlsConfig,
+};
+use log::{debug, info, warn};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsdConfig {
+    /// host:port of the statsd server
+    pub addr: String,
+
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+
+    /// Send tags using the dogstatsd extension. Plain statsd servers get the tag values
+    /// appended to the metric name instead
+    #[serde(default)]
+    pub dogstatsd: bool,
+}
+
+fn default_prefix() -> String {
+    "geofw".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PushgatewayConfig {
+    /// Base URL of the Pushgateway, e.g. http://pushgateway:9091
+    pub url: String,
+
+    /// Seconds between pushes
+    #[serde(default = "default_push_interval")]
+    pub interval: u64,
+
+    #[serde(default = "default_prefix")]
+    pub job: String,
+
+    /// Value of the instance label, defaults to the hostname
+    #[serde(default)]
+    pub instance: Option<String>,
+}
+
+fn default_push_interval() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrometheusConfig {
+    /// host:port the /metrics endpoint listens on
+    pub listen: String,
+
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+struct Statsd {
+    socket: UdpSocket,
+    config: StatsdConfig,
+}
+
+impl Statsd {
+    fn new(config: &StatsdConfig) -> Result<Self, String> {
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+        socket.connect(&config.addr).map_err(|e| e.to_string())?;
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            socket,
+            config: config.clone(),
+        })
+    }
+
+    fn send(&self, name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) {
+        let mut line = format!("{}.{}", self.config.prefix, name);
+        if !self.config.dogstatsd {
+            for (_, v) in tags {
+                line.push_str(&format!(".{}", v));
+            }
+        }
+
+        line.push_str(&format!(":{}|{}", value, kind));
+
+        if self.config.dogstatsd && !tags.is_empty() {
+            let tags: Vec<String> = tags.iter().map(|(k, v)| format!("{}:{}", k, v)).collect();
+            line.push_str(&format!("|#{}", tags.join(",")));
+        }
+
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            debug!("error in sending metric to statsd: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+type Labels = Vec<(String, String)>;
+
+/// Latest value of every metric, rendered in the Prometheus text format
+#[derive(Default)]
+struct Registry {
+    values: BTreeMap<String, (Kind, BTreeMap<Labels, f64>)>,
+}
+
+impl Registry {
+    fn record(&mut self, name: &str, kind: Kind, value: f64, tags: &[(&str, &str)]) {
+        let name = format!("geofw_{}", name.replace('.', "_"));
+        let labels = tags
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        let (_, series) = self
+            .values
+            .entry(name)
+            .or_insert_with(|| (kind, BTreeMap::new()));
+        let v = series.entry(labels).or_default();
+        match kind {
+            Kind::Counter => *v += value,
+            Kind::Gauge => *v = value,
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        for (name, (kind, series)) in &self.values {
+            let kind = match kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+
+            for (labels, value) in series {
+                let labels: Vec<String> = labels
+                    .iter()
+                    .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('"', "\\\"")))
+                    .collect();
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", name, value);
+                } else {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Entry point for recording metrics. Every metric is forwarded to the configured sinks and
+/// kept in a registry that push and scrape based sinks read from.
+pub struct Metrics {
+    statsd: Option<Statsd>,
+    registry: Mutex<Registry>,
+}
+
+impl Metrics {
+    pub fn new(statsd: Option<&StatsdConfig>) -> Self {
+        let statsd = statsd.and_then(|c| match Statsd::new(c) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                warn!("error in setting up statsd sink for {}: {}", c.addr, e);
+                None
+            }
+        });
+
+        Self {
+            statsd,
+            registry: Mutex::default(),
+        }
+    }
+
+    fn record(&self, name: &str, kind: Kind, value: f64, tags: &[(&str, &str)]) {
+        if let Ok(mut registry) = self.registry.lock() {
+            registry.record(name, kind, value, tags);
+        }
+    }
+
+    pub fn count(&self, name: &str, value: u64, tags: &[(&str, &str)]) {
+        if let Some(statsd) = &self.statsd {
+            statsd.send(name, &value.to_string(), "c", tags);
+        }
+        self.record(
+            &format!("{}.total", name),
+            Kind::Counter,
+            value as f64,
+            tags,
+        );
+    }
+
+    pub fn gauge(&self, name: &str, value: f64, tags: &[(&str, &str)]) {
+        if let Some(statsd) = &self.statsd {
+            statsd.send(name, &value.to_string(), "g", tags);
+        }
+        self.record(name, Kind::Gauge, value, tags);
+    }
+
+    /// Timings are kept in the registry as a gauge with the last value in seconds
+    pub fn timing(&self, name: &str, value: Duration, tags: &[(&str, &str)]) {
+        if let Some(statsd) = &self.statsd {
+            statsd.send(name, &value.as_millis().to_string(), "ms", tags);
+        }
+        self.record(
+            &format!("{}.seconds", name),
+            Kind::Gauge,
+            value.as_secs_f64(),
+            tags,
+        );
+    }
+
+    /// Every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        self.registry.lock().map(|r| r.render()).unwrap_or_default()
+    }
+
+    /// Replaces the metrics of this instance on the Pushgateway with the current values
+    pub fn push(&self, config: &PushgatewayConfig) -> Result<(), String> {
+        let instance = match &config.instance {
+            Some(i) => i.clone(),
+            None => hostname(),
+        };
+        let url = format!(
+            "{}/metrics/job/{}/instance/{}",
+            config.url.trim_end_matches('/'),
+            config.job,
+            instance
+        );
+
+        ureq::put(&url)
+            .set("Content-Type", "text/plain; version=0.0.4")
+            .send_string(&self.render())
+            .map_err(|e| format!("error in pushing metrics to {}: {}", url, e))?;
+
+        Ok(())
+    }
+}
+
+/// Serves the metrics in the Prometheus text format on `GET /metrics`. Requests need a token
+/// with the read scope when `api_tokens` has entries
+pub fn serve(
+    config: &PrometheusConfig,
+    metrics: Arc<Metrics>,
+    tokens: Tokens,
+) -> Result<(), String> {
+    let listener = TcpListener::bind(&config.listen)
+        .map_err(|e| format!("error in listening on {}: {}", config.listen, e))?;
+    let tls = config
+        .tls
+        .as_ref()
+        .map(TlsConfig::server_config)
+        .transpose()?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let metrics = metrics.clone();
+            let tokens = tokens.clone();
+            let tls = tls.clone();
+            thread::spawn(move || {
+                if let Err(e) = scrape(stream, tls, &metrics, &tokens) {
+                    debug!("error in serving metrics: {}", e);
+                }
+            });
+        }
+    });
+
+    info!("serving metrics on {}/metrics", config.listen);
+
+    Ok(())
+}
+
+fn scrape(
+    stream: TcpStream,
+    tls: Option<Arc<ServerConfig>>,
+    metrics: &Metrics,
+    tokens: &Tokens,
+) -> Result<(), String> {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .map_err(|e| e.to_string())?;
+
+    match tls {
+        Some(tls) => {
+            let conn = ServerConnection::new(tls).map_err(|e| e.to_string())?;
+            respond(StreamOwned::new(conn, stream), metrics, tokens)
+        }
+        None => respond(stream, metrics, tokens),
+    }
+}
+
+fn respond(stream: impl Read + Write, metrics: &Metrics, tokens: &Tokens) -> Result<(), String> {
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request).map_err(|e| e.to_string())?;
+    let mut authorization = None;
+    loop {
+        let mut line = String::new();
+        let n = reader.read_line(&mut line).map_err(|e| e.to_string())?;
+        if n == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+    let mut stream = reader.into_inner();
+
+    let status = match tokens.authorize_header(authorization.as_deref(), Scope::Read) {
+        Err(AuthError::Unauthenticated) => "401 Unauthorized",
+        Err(AuthError::Forbidden) => "403 Forbidden",
+        Ok(_) => match request.split_whitespace().collect::<Vec<_>>()[..] {
+            ["GET", "/metrics", _] => "200 OK",
+            _ => "404 Not Found",
+        },
+    };
+    if status != "200 OK" {
+        return stream
+            .write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes())
+            .map_err(|e| e.to_string());
+    }
+
+    let body = metrics.render();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .and_then(|_| stream.flush())
+    .map_err(|e| e.to_string())
+}
+
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "unknown".to_string();
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
@@ -0,0 +1,177 @@
+use crate::{Config, ConfigFormat};
+use log::{info, warn};
+use serde_json::{Map, Value};
+use std::fs;
+
+/// Version of the config written by this geofw. Files without a `version` are version 1
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Upgrades a config from the version at its index + 1 to the next one
+const MIGRATIONS: [fn(&mut Map<String, Value>); 1] = [interface_list];
+
+/// A config and what was noticed while parsing it
+pub struct Parsed {
+    pub config: Config,
+    /// Version of the file before it was migrated
+    pub version: u32,
+    /// Paths of the fields geofw doesn't know about, e.g. because they were misspelled
+    pub unknown: Vec<String>,
+}
+
+pub fn parse(format: ConfigFormat, contents: &[u8]) -> Result<Parsed, String> {
+    let mut value = format.parse_value(contents)?;
+    let version = migrate(&mut value)?;
+
+    let mut unknown = vec![];
+    let config = serde_ignored::deserialize(value, |path| unknown.push(path.to_string()))
+        .map_err(|e| e.to_string())?;
+
+    Ok(Parsed {
+        config,
+        version,
+        unknown,
+    })
+}
+
+/// Runs every migration the config needs to reach CONFIG_VERSION. Returns the version it was at
+fn migrate(value: &mut Value) -> Result<u32, String> {
+    let root = value
+        .as_object_mut()
+        .ok_or("the config has to be a map of fields")?;
+
+    let version = match root.get("version") {
+        None => 1,
+        Some(v) => v
+            .as_u64()
+            .map(|v| v as u32)
+            .ok_or(format!("invalid version {}", v))?,
+    };
+    if version == 0 || version > CONFIG_VERSION {
+        return Err(format!(
+            "config version {} is not supported, this geofw reads versions up to {}",
+            version, CONFIG_VERSION
+        ));
+    }
+
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(root);
+    }
+    root.insert("version".to_string(), Value::from(CONFIG_VERSION));
+
+    Ok(version)
+}
+
+/// Upgrades the config file at `path` in place if it's older than CONFIG_VERSION. The old file
+/// is kept next to it with the version it had appended
+pub fn upgrade_file(path: &str, format: ConfigFormat) -> Result<(), String> {
+    let contents = match fs::read(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("error in reading {}: {}", path, e)),
+    };
+
+    let mut value = format.parse_value(&contents)?;
+    let version = migrate(&mut value)?;
+    if version == CONFIG_VERSION {
+        return Ok(());
+    }
+
+    let backup = format!("{}.v{}", path, version);
+    fs::write(&backup, &contents).map_err(|e| format!("error in writing {}: {}", backup, e))?;
+    let upgraded = format.serialize(&value)?;
+    fs::write(path, upgraded).map_err(|e| format!("error in writing {}: {}", path, e))?;
+
+    info!(
+        "upgraded {} from version {} to {}, the old file is at {}",
+        path, version, CONFIG_VERSION, backup
+    );
+
+    Ok(())
+}
+
+/// Logs the fields of a parsed config that were ignored
+pub fn warn_unknown(parsed: &Parsed) {
+    for path in &parsed.unknown {
+        warn!("unknown config field {} is ignored", path);
+    }
+}
+
+/// Version 2 replaced `interface` with the `interfaces` list
+fn interface_list(root: &mut Map<String, Value>) {
+    let Some(interface) = root.remove("interface") else {
+        return;
+    };
+
+    let empty = root
+        .get("interfaces")
+        .and_then(Value::as_array)
+        .is_none_or(|a| a.is_empty());
+    if empty && interface.as_str().is_some_and(|i| !i.is_empty()) {
+        root.insert("interfaces".to_string(), Value::Array(vec![interface]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn version_1_gets_an_interface_list() {
+        let mut value = json!({ "interface": "eth0", "source_countries": ["CN"] });
+
+        assert_eq!(migrate(&mut value), Ok(1));
+        assert_eq!(
+            value,
+            json!({ "interfaces": ["eth0"], "source_countries": ["CN"], "version": CONFIG_VERSION })
+        );
+    }
+
+    #[test]
+    fn interface_list_wins_over_interface() {
+        let mut root = json!({ "interface": "eth0", "interfaces": ["eth1"] });
+        interface_list(root.as_object_mut().unwrap());
+        assert_eq!(root, json!({ "interfaces": ["eth1"] }));
+
+        let mut root = json!({ "interface": "" });
+        interface_list(root.as_object_mut().unwrap());
+        assert_eq!(root, json!({}));
+    }
+
+    #[test]
+    fn current_version_is_unchanged() {
+        let config = json!({ "version": CONFIG_VERSION, "interface": "eth0" });
+        let mut value = config.clone();
+
+        assert_eq!(migrate(&mut value), Ok(CONFIG_VERSION));
+        assert_eq!(value, config);
+    }
+
+    #[test]
+    fn unsupported_versions() {
+        for version in [json!(0), json!(CONFIG_VERSION + 1), json!("2")] {
+            assert!(
+                migrate(&mut json!({ "version": version })).is_err(),
+                "{}",
+                version
+            );
+        }
+        assert!(migrate(&mut json!([])).is_err());
+    }
+
+    #[test]
+    fn parse_reports_unknown_fields() {
+        let contents = br#"{
+            "db": { "path": "/var/lib/geofw", "refresh_interval": 86400 },
+            "interface": "eth0",
+            "source_countries": ["CN"],
+            "source_asn": [],
+            "source_contries": ["RU"]
+        }"#;
+
+        let parsed = parse(ConfigFormat::Json, contents).unwrap();
+        assert_eq!(parsed.version, 1);
+        assert_eq!(parsed.config.interfaces, vec!["eth0".to_string()]);
+        assert_eq!(parsed.unknown, vec!["source_contries".to_string()]);
+    }
+}
@@ -0,0 +1,18 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// How subcommands print their results
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned text meant for people
+    Table,
+    /// Pretty printed JSON meant for scripts
+    Json,
+}
+
+pub fn print_json<T: Serialize>(value: &T) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(value).map_err(|e| e.to_string())?;
+    println!("{}", json);
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use crate::Config;
+use fxhash::FxHashSet;
+use log::{info, warn};
+use serde_json::{Map, Value};
+use std::env;
+
+const PREFIX: &str = "GEOFW_";
+
+/// Applies `GEOFW_*` environment variables on top of the config read from the file. The name
+/// after the prefix is the path to the field in upper case with `__` between the levels, e.g.
+/// `GEOFW_SOURCE_COUNTRIES` or `GEOFW_DB__REFRESH_INTERVAL`. Fields of `db` can also be set
+/// without the `DB__`, e.g. `GEOFW_MAXMIND_KEY`.
+pub fn apply_env(config: Config) -> Result<Config, String> {
+    let mut vars: Vec<(String, String)> = env::vars()
+        .filter_map(|(k, v)| Some((k.strip_prefix(PREFIX)?.to_string(), v)))
+        .collect();
+    if vars.is_empty() {
+        return Ok(config);
+    }
+    // Deeper paths last, so GEOFW_DB__PATH wins over a GEOFW_DB object
+    vars.sort_by_key(|(k, _)| k.matches("__").count());
+
+    let mut root = serde_json::to_value(&config).map_err(|e| e.to_string())?;
+    let mut started = FxHashSet::default();
+    for (name, value) in vars {
+        let path: Vec<String> = name.split("__").map(|s| s.to_lowercase()).collect();
+
+        let db_path = ["db".to_string(), path[0].clone()];
+        let applied = if set(&mut root, &mut started, &path, &value)? {
+            Some(path.join("."))
+        } else if path.len() == 1 && set(&mut root, &mut started, &db_path, &value)? {
+            Some(db_path.join("."))
+        } else {
+            None
+        };
+
+        match applied {
+            Some(field) => info!("config field {} set from {}{}", field, PREFIX, name),
+            None => warn!("{}{} does not match any config field", PREFIX, name),
+        }
+    }
+
+    serde_json::from_value(root).map_err(|e| format!("invalid environment override: {}", e))
+}
+
+/// Sets the field at `path` if its parent exists. Returns whether the field exists in Config.
+/// `started` holds the sections that were off in the file and have been started by an override
+fn set(
+    root: &mut Value,
+    started: &mut FxHashSet<Vec<String>>,
+    path: &[String],
+    raw: &str,
+) -> Result<bool, String> {
+    let Some((field, parents)) = path.split_last() else {
+        return Ok(false);
+    };
+
+    let mut node = root;
+    for p in parents {
+        // Sections that are off in the file, like `events`, are started from an empty object
+        let Some(obj) = node.as_object_mut() else {
+            return Ok(false);
+        };
+        let Some(child) = obj.get_mut(p) else {
+            return Ok(false);
+        };
+        if child.is_null() {
+            *child = Value::Object(Map::new());
+            started.insert(parents.to_vec());
+        }
+        node = child;
+    }
+
+    let Some(obj) = node.as_object_mut() else {
+        return Ok(false);
+    };
+    // Options that are off serialize as null, but fields of a section that was just started
+    // are missing altogether
+    let current = obj.get(field).cloned();
+    if current.is_none() && !started.contains(parents) {
+        return Ok(false);
+    }
+
+    let value = parse(current.as_ref(), raw)
+        .map_err(|e| format!("error in parsing {}{}: {}", PREFIX, path.join("__"), e))?;
+    obj.insert(field.clone(), value);
+
+    Ok(true)
+}
+
+/// Parses the value like the field it replaces. Lists can be given as JSON or comma separated
+fn parse(current: Option<&Value>, raw: &str) -> Result<Value, String> {
+    match current {
+        Some(Value::String(_)) => Ok(Value::String(raw.to_string())),
+        Some(Value::Array(_)) if !raw.trim_start().starts_with('[') => Ok(Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| serde_json::from_str(s).unwrap_or_else(|_| Value::String(s.to_string())))
+                .collect(),
+        )),
+        Some(Value::Bool(_) | Value::Number(_) | Value::Array(_) | Value::Object(_)) => {
+            serde_json::from_str(raw).map_err(|e| e.to_string())
+        }
+        None | Some(Value::Null) => {
+            Ok(serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string())))
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{
+    privacy::PrivacyConfig,
+    state::{open_verdicts, SuspectVerdict},
+};
+use aya::maps::{HashMap, MapData};
+use log::{debug, info, warn};
+use ring::hmac;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+/// Messages older than this are rejected, so captured messages can't be replayed later
+const MAX_MESSAGE_AGE: i64 = 60;
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerSyncConfig {
+    /// Address to accept updates from peers on, e.g. 0.0.0.0:7946
+    pub listen: String,
+
+    /// Other geofw instances as host:port
+    pub peers: Vec<String>,
+
+    /// Shared by all instances. Every message is authenticated with HMAC-SHA256 using it
+    pub secret: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SyncMessage {
+    sent_at: i64,
+    suspect_verdicts: Vec<SuspectVerdict>,
+}
+
+/// Starts replicating suspect verdicts with the configured peers. Verdicts decided locally have
+/// to be sent into the returned channel.
+pub fn start(
+    config: &PeerSyncConfig,
+    privacy: &PrivacyConfig,
+) -> Result<Sender<SuspectVerdict>, String> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes());
+
+    let listener = TcpListener::bind(&config.listen)
+        .map_err(|e| format!("error in listening on {}: {}", config.listen, e))?;
+    let verdicts = open_verdicts()?;
+
+    let (tx, rx) = mpsc::channel();
+
+    let listen_key = key.clone();
+    let privacy = privacy.clone();
+    thread::spawn(move || receive(listener, listen_key, &privacy, verdicts));
+
+    let peers = config.peers.clone();
+    thread::spawn(move || send(rx, key, peers));
+
+    info!(
+        "syncing with peers = {} listen = {}",
+        config.peers.join(", "),
+        config.listen
+    );
+
+    Ok(tx)
+}
+
+fn send(rx: Receiver<SuspectVerdict>, key: hmac::Key, peers: Vec<String>) {
+    while let Ok(verdict) = rx.recv() {
+        // Batch everything that piled up while the previous batch was being sent
+        let mut suspect_verdicts = vec![verdict];
+        suspect_verdicts.extend(rx.try_iter());
+
+        let message = SyncMessage {
+            sent_at: chrono::Utc::now().timestamp(),
+            suspect_verdicts,
+        };
+        let Ok(body) = serde_json::to_string(&message) else {
+            continue;
+        };
+        let line = format!(
+            "{} {}\n",
+            to_hex(hmac::sign(&key, body.as_bytes()).as_ref()),
+            body
+        );
+
+        for peer in &peers {
+            if let Err(e) = send_to(peer, &line) {
+                warn!("error in sending verdicts to peer {}: {}", peer, e);
+            }
+        }
+    }
+}
+
+fn send_to(peer: &str, line: &str) -> Result<(), String> {
+    let addr = peer
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or("no address found")?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, PEER_TIMEOUT).map_err(|e| e.to_string())?;
+    stream
+        .set_write_timeout(Some(PEER_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    stream.write_all(line.as_bytes()).map_err(|e| e.to_string())
+}
+
+fn receive(
+    listener: TcpListener,
+    key: hmac::Key,
+    privacy: &PrivacyConfig,
+    mut verdicts: HashMap<MapData, [u8; 16], u8>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("error in accepting peer connection: {}", e);
+                continue;
+            }
+        };
+        let peer = stream
+            .peer_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        if let Err(e) = stream.set_read_timeout(Some(PEER_TIMEOUT)) {
+            debug!("error in setting read timeout for {}: {}", peer, e);
+        }
+
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => {
+                    debug!("error in reading from peer {}: {}", peer, e);
+                    break;
+                }
+            };
+
+            match parse_message(&key, &line) {
+                Ok(message) => apply(&mut verdicts, privacy, &peer, message),
+                Err(e) => warn!("rejected message from peer {}: {}", peer, e),
+            }
+        }
+    }
+}
+
+fn parse_message(key: &hmac::Key, line: &str) -> Result<SyncMessage, String> {
+    let (tag, body) = line.split_once(' ').ok_or("malformed message")?;
+    let tag = from_hex(tag).ok_or("malformed signature")?;
+    hmac::verify(key, body.as_bytes(), &tag).map_err(|_| "invalid signature")?;
+
+    let message: SyncMessage = serde_json::from_str(body).map_err(|e| e.to_string())?;
+    let age = chrono::Utc::now().timestamp() - message.sent_at;
+    if age.abs() > MAX_MESSAGE_AGE {
+        return Err(format!("message is {}s old", age));
+    }
+
+    Ok(message)
+}
+
+fn apply(
+    verdicts: &mut HashMap<MapData, [u8; 16], u8>,
+    privacy: &PrivacyConfig,
+    peer: &str,
+    message: SyncMessage,
+) {
+    for v in message.suspect_verdicts {
+        match verdicts.insert(v.key(), v.value(), 0) {
+            Ok(()) => debug!(
+                "peer = {} source = {} verdict = {:?}",
+                peer,
+                privacy.redact(v.addr),
+                v.verdict
+            ),
+            Err(e) => warn!(
+                "error in writing verdict for {}: {}",
+                privacy.redact(v.addr),
+                e
+            ),
+        }
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
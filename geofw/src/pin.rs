@@ -0,0 +1,105 @@
+use aya::{
+    programs::links::{FdLink, PinnedLink},
+    Ebpf,
+};
+use log::{info, warn};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Maps pinned next to the links, the ones worth inspecting with bpftool
+pub const PINNED_MAPS: [&str; 3] = ["BLOCKED_COUNTRY", "BLOCKED_ASN", "PARAMETERS"];
+
+/// Pinned links are named after the interface they are attached to
+const LINK_PREFIX: &str = "link_";
+
+pub fn default_pin_path() -> String {
+    "/sys/fs/bpf/geofw".to_string()
+}
+
+pub fn link_path(dir: &Path, interface: &str) -> PathBuf {
+    dir.join(format!("{}{}", LINK_PREFIX, interface))
+}
+
+/// Creates `dir` and removes the pins a previous instance left there, except the links of
+/// `adopt`. Its other links keep the old program attached to interfaces no longer configured
+pub fn prepare(dir: &Path, adopt: &[String]) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("error in creating {}: {}", dir.display(), e))?;
+
+    let entries =
+        fs::read_dir(dir).map_err(|e| format!("error in reading {}: {}", dir.display(), e))?;
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = entry.path();
+        if let Some(interface) = name.strip_prefix(LINK_PREFIX) {
+            if adopt.iter().any(|i| i == interface) {
+                continue;
+            }
+            // Dropping the link detaches the old program
+            match PinnedLink::from_pin(&path).map(PinnedLink::unpin) {
+                Ok(Ok(_)) => info!("removed stale link {}", path.display()),
+                Ok(Err(e)) => warn!("error in removing stale link {}: {}", path.display(), e),
+                Err(e) => warn!("error in opening stale link {}: {}", path.display(), e),
+            }
+        } else if PINNED_MAPS.contains(&name.as_str()) {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("error in removing stale map {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Opens the link the previous instance pinned for `interface`, if there is one
+pub fn adopt(dir: &Path, interface: &str) -> Option<PinnedLink> {
+    let path = link_path(dir, interface);
+    if !path.exists() {
+        return None;
+    }
+
+    match PinnedLink::from_pin(&path) {
+        Ok(link) => {
+            info!("adopted link {}", path.display());
+            Some(link)
+        }
+        Err(e) => {
+            warn!("error in adopting link {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Pins `PINNED_MAPS` in `dir`, a map that can't be pinned is only logged
+pub fn pin_maps(ebpf: &Ebpf, dir: &Path) {
+    for name in PINNED_MAPS {
+        let Some(map) = ebpf.map(name) else {
+            continue;
+        };
+        let path = dir.join(name);
+        if let Err(e) = map.pin(&path) {
+            warn!("error in pinning {} to {}: {}", name, path.display(), e);
+        }
+    }
+    info!("pinned maps in {}", dir.display());
+}
+
+/// Removes the map pins on exit, the maps are freed once the program is unloaded
+pub fn unpin_maps(dir: &Path) {
+    for name in PINNED_MAPS {
+        let path = dir.join(name);
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("error in unpinning {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Pins `link` so the program stays attached when geofw exits without detaching it
+pub fn pin_link(link: FdLink, dir: &Path, interface: &str) -> Result<PinnedLink, String> {
+    let path = link_path(dir, interface);
+    link.pin(&path)
+        .map_err(|e| format!("error in pinning link to {}: {}", path.display(), e))
+}
@@ -0,0 +1,619 @@
+use crate::{
+    is_anycast,
+    maxmind::Data,
+    schedule::TimeWindow,
+    simulate::{asn, asn_org, city_id, country_code, subdivision_codes},
+    xsk::ifindex,
+    Config,
+};
+use aya::{
+    maps::{Array, DevMap, HashMap, MapData},
+    Ebpf,
+};
+use chrono::NaiveTime;
+use fxhash::{FxHashMap, FxHashSet};
+use geofw_common::{
+    port_scope_key, Action, CountryAction, MaxmindDbType, Mode, ProgramParameters, RateLimit,
+    MAX_COMPOUND_RULES, MAX_POLICIES,
+};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    fs,
+    os::unix::fs::MetadataExt,
+};
+
+/// Rules for a set of interfaces, used there instead of the top level `source_countries` and
+/// `source_asn`. They are blocked or allowed depending on the mode
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Policy {
+    pub name: String,
+
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+
+    /// cgroup v2 directories whose sockets get the rules, and those of the cgroups below them
+    #[serde(default)]
+    pub cgroups: Vec<String>,
+
+    #[serde(default)]
+    pub source_countries: FxHashSet<String>,
+
+    #[serde(default)]
+    pub source_asn: FxHashSet<u32>,
+
+    #[serde(default)]
+    pub source_asn_org_patterns: Vec<String>,
+
+    #[serde(default)]
+    pub source_cities: FxHashSet<u32>,
+
+    #[serde(default)]
+    pub source_subdivisions: FxHashSet<String>,
+
+    /// Only apply the rules to packets for these ports, every packet when empty
+    #[serde(default)]
+    pub ports: Vec<PortScope>,
+
+    /// Only apply the rules inside these windows of local time, all the time when empty
+    #[serde(default)]
+    pub schedule: Vec<TimeWindow>,
+
+    /// Throttle the sources the rules match instead of dropping them
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+
+    /// What to do with the packets the rules match
+    #[serde(default)]
+    pub action: PolicyAction,
+
+    /// Drop sources in reserved and unallocated networks
+    #[serde(default)]
+    pub block_bogons: bool,
+}
+
+/// What to do with the packets the rules of a policy match, written as `drop`, `reject` or
+/// `redirect <interface>`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PolicyAction {
+    #[default]
+    Drop,
+    Reject,
+    /// Send the packets out of this interface
+    Redirect(String),
+}
+
+impl TryFrom<String> for PolicyAction {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let mut words = s.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("drop"), None, _) => Ok(Self::Drop),
+            (Some("reject"), None, _) => Ok(Self::Reject),
+            (Some("redirect"), Some(interface), None) => Ok(Self::Redirect(interface.to_string())),
+            _ => Err(format!(
+                "invalid action {}, expected drop, reject or redirect <interface>",
+                s
+            )),
+        }
+    }
+}
+
+impl From<PolicyAction> for String {
+    fn from(a: PolicyAction) -> Self {
+        a.to_string()
+    }
+}
+
+impl Display for PolicyAction {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::Drop => write!(f, "drop"),
+            Self::Reject => write!(f, "reject"),
+            Self::Redirect(interface) => write!(f, "redirect {}", interface),
+        }
+    }
+}
+
+/// Blocks sources from `country` that are in one of `asn`, or that aren't in any of
+/// `except_asn`. Only one of them is set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompoundRule {
+    pub country: String,
+
+    #[serde(default)]
+    pub asn: FxHashSet<u32>,
+
+    #[serde(default)]
+    pub except_asn: FxHashSet<u32>,
+}
+
+impl CompoundRule {
+    pub fn is_except(&self) -> bool {
+        !self.except_asn.is_empty()
+    }
+
+    pub fn matches(&self, country: Option<&str>, asn: Option<u32>) -> bool {
+        if country != Some(self.country.as_str()) {
+            return false;
+        }
+
+        let listed = asn.is_some_and(|a| self.asn.contains(&a) || self.except_asn.contains(&a));
+        listed != self.is_except()
+    }
+}
+
+/// Packets per second let through from each source prefix that the rules match. Packets over
+/// the budget are dropped
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    pub packets_per_second: u32,
+
+    /// Packets a prefix can send at once after being idle, `packets_per_second` by default
+    #[serde(default)]
+    pub burst: Option<u32>,
+
+    #[serde(default = "default_rate_limit_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+
+    #[serde(default = "default_rate_limit_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+}
+
+fn default_rate_limit_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_rate_limit_ipv6_prefix() -> u8 {
+    48
+}
+
+impl From<&RateLimitConfig> for RateLimit {
+    fn from(c: &RateLimitConfig) -> Self {
+        RateLimit {
+            packets_per_second: c.packets_per_second,
+            burst: c.burst.unwrap_or(c.packets_per_second),
+            ipv4_prefix: c.ipv4_prefix as u32,
+            ipv6_prefix: c.ipv6_prefix as u32,
+        }
+    }
+}
+
+/// A TCP or UDP destination port the rules of a policy are limited to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PortScope {
+    pub protocol: Protocol,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    /// IP protocol number
+    pub fn number(&self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+}
+
+/// The rules of a single policy
+struct Rules<'a> {
+    countries: &'a FxHashSet<String>,
+    asns: &'a FxHashSet<u32>,
+    asn_org_patterns: &'a [String],
+    cities: &'a FxHashSet<u32>,
+    subdivisions: &'a FxHashSet<String>,
+}
+
+/// The rules of each policy, starting with the top level rules as policy 0
+fn rule_sets(config: &Config) -> impl Iterator<Item = Rules<'_>> {
+    std::iter::once(Rules {
+        countries: &config.source_countries,
+        asns: &config.source_asn,
+        asn_org_patterns: &config.source_asn_org_patterns,
+        cities: &config.source_cities,
+        subdivisions: &config.source_subdivisions,
+    })
+    .chain(config.policies.iter().map(|p| Rules {
+        countries: &p.source_countries,
+        asns: &p.source_asn,
+        asn_org_patterns: &p.source_asn_org_patterns,
+        cities: &p.source_cities,
+        subdivisions: &p.source_subdivisions,
+    }))
+    .take(MAX_POLICIES as usize)
+}
+
+/// Whether any policy lists cities or subdivisions, which needs the city database
+pub fn uses_city(config: &Config) -> bool {
+    rule_sets(config).any(|r| !r.cities.is_empty() || !r.subdivisions.is_empty())
+}
+
+/// The ports each policy is limited to, in the same order as `rule_sets`
+fn port_scopes(config: &Config) -> impl Iterator<Item = &[PortScope]> {
+    std::iter::once(config.ports.as_slice())
+        .chain(config.policies.iter().map(|p| p.ports.as_slice()))
+        .take(MAX_POLICIES as usize)
+}
+
+/// The schedule of each policy, in the same order as `rule_sets`
+fn schedules(config: &Config) -> impl Iterator<Item = &[TimeWindow]> {
+    std::iter::once(config.schedule.as_slice())
+        .chain(config.policies.iter().map(|p| p.schedule.as_slice()))
+        .take(MAX_POLICIES as usize)
+}
+
+/// The rate limit of each policy, in the same order as `rule_sets`
+fn rate_limits(config: &Config) -> impl Iterator<Item = Option<&RateLimitConfig>> {
+    std::iter::once(config.rate_limit.as_ref())
+        .chain(config.policies.iter().map(|p| p.rate_limit.as_ref()))
+        .take(MAX_POLICIES as usize)
+}
+
+/// The action of each policy, in the same order as `rule_sets`
+fn actions(config: &Config) -> impl Iterator<Item = &PolicyAction> {
+    std::iter::once(&config.action)
+        .chain(config.policies.iter().map(|p| &p.action))
+        .take(MAX_POLICIES as usize)
+}
+
+/// Mask with a bit set for every policy that drops bogons
+pub fn bogon_policies(config: &Config) -> u32 {
+    std::iter::once(config.block_bogons)
+        .chain(config.policies.iter().map(|p| p.block_bogons))
+        .take(MAX_POLICIES as usize)
+        .enumerate()
+        .filter(|&(_, enabled)| enabled)
+        .fold(0, |mask, (i, _)| mask | 1 << i)
+}
+
+/// Mask with a bit set for every policy whose rules apply at `now`
+pub fn active_policies(config: &Config, now: NaiveTime) -> u32 {
+    let mut mask = 0;
+    for (i, schedule) in schedules(config).enumerate() {
+        if schedule.is_empty() || schedule.iter().any(|w| w.contains(now)) {
+            mask |= 1 << i;
+        }
+    }
+
+    mask
+}
+
+/// Bit mask with every policy set that lists the record `data` points to
+pub fn listed_policies(
+    config: &Config,
+    db_type: MaxmindDbType,
+    data: &FxHashMap<&[u8], Data>,
+) -> u32 {
+    if config.skip_anycast && is_anycast(data) {
+        return 0;
+    }
+
+    let mut mask = 0;
+    for (i, rules) in rule_sets(config).enumerate() {
+        let listed = match db_type {
+            MaxmindDbType::Country => country_code(data).is_some_and(|c| {
+                rules.countries.contains(&c) || (i == 0 && config.country_actions.contains_key(&c))
+            }),
+            MaxmindDbType::Asn => {
+                asn(data).is_some_and(|a| rules.asns.contains(&a))
+                    || asn_org(data).is_some_and(|o| matches_org(rules.asn_org_patterns, &o))
+            }
+            MaxmindDbType::City => {
+                city_id(data).is_some_and(|c| rules.cities.contains(&c))
+                    || subdivision_codes(data)
+                        .iter()
+                        .any(|s| rules.subdivisions.contains(s))
+            }
+            // Anonymous IP flags apply the same way everywhere
+            MaxmindDbType::Anonymous => !config.block_anonymous.flagged(data).is_empty(),
+        };
+        if listed {
+            mask |= 1 << i;
+        }
+    }
+
+    mask
+}
+
+/// Action of the country of the record `data` points to in `country_actions`
+pub fn country_action(config: &Config, data: &FxHashMap<&[u8], Data>) -> Option<CountryAction> {
+    country_code(data).and_then(|c| config.country_actions.get(&c).copied())
+}
+
+/// Bit mask with every compound rule set that names the record `data` points to. Compound rules
+/// only apply in block mode
+pub fn compound_rules(
+    config: &Config,
+    db_type: MaxmindDbType,
+    data: &FxHashMap<&[u8], Data>,
+) -> u32 {
+    if config.mode != Mode::Block {
+        return 0;
+    }
+
+    let rules = config.compound_rules.iter().take(MAX_COMPOUND_RULES);
+    let mut mask = 0;
+    for (i, rule) in rules.enumerate() {
+        let named = match db_type {
+            MaxmindDbType::Country => country_code(data).is_some_and(|c| c == rule.country),
+            MaxmindDbType::Asn => {
+                asn(data).is_some_and(|a| rule.asn.contains(&a) || rule.except_asn.contains(&a))
+            }
+            MaxmindDbType::City | MaxmindDbType::Anonymous => false,
+        };
+        if named {
+            mask |= 1 << i;
+        }
+    }
+
+    mask
+}
+
+/// Mask with a bit set for every compound rule with `except_asn`
+pub fn compound_except(config: &Config) -> u32 {
+    config
+        .compound_rules
+        .iter()
+        .take(MAX_COMPOUND_RULES)
+        .enumerate()
+        .filter(|(_, rule)| rule.is_except())
+        .fold(0, |mask, (i, _)| mask | 1 << i)
+}
+
+/// Whether the organization name contains one of `patterns`, ignoring case
+fn matches_org(patterns: &[String], org: &str) -> bool {
+    let org = org.to_lowercase();
+    patterns.iter().any(|p| org.contains(&p.to_lowercase()))
+}
+
+/// Whether `allow_countries` or `allow_asn` include the record `data` points to
+pub fn is_allowed(config: &Config, db_type: MaxmindDbType, data: &FxHashMap<&[u8], Data>) -> bool {
+    match db_type {
+        MaxmindDbType::Country => {
+            country_code(data).is_some_and(|c| config.allow_countries.contains(&c))
+        }
+        MaxmindDbType::Asn => asn(data).is_some_and(|a| config.allow_asn.contains(&a)),
+        MaxmindDbType::City | MaxmindDbType::Anonymous => false,
+    }
+}
+
+/// Mask with a bit set for every policy in use
+pub fn all_policies(config: &Config) -> u32 {
+    let count = rule_sets(config).count() as u32;
+    (1 << count) - 1
+}
+
+/// Writes the policy of every interface it is bound to into INTERFACE_POLICIES, removing
+/// interfaces that no longer have one
+pub fn write_interface_policies(config: &Config, ebpf: &mut Ebpf) -> Result<(), String> {
+    if config.policies.len() >= MAX_POLICIES as usize {
+        warn!(
+            "only {} policies are supported, ignoring the rest",
+            MAX_POLICIES - 1
+        );
+    }
+
+    let interfaces = config.interfaces();
+    let mut wanted: FxHashMap<u32, u32> = FxHashMap::default();
+    for (i, policy) in config
+        .policies
+        .iter()
+        .take(MAX_POLICIES as usize - 1)
+        .enumerate()
+    {
+        for interface in &policy.interfaces {
+            if !interfaces.contains(interface) {
+                warn!(
+                    "policy {} lists {}, which geofw is not attached to",
+                    policy.name, interface
+                );
+                continue;
+            }
+            wanted.insert(ifindex(interface)?, i as u32 + 1);
+        }
+    }
+
+    let mut map: HashMap<&mut MapData, u32, u32> = HashMap::try_from(
+        ebpf.map_mut("INTERFACE_POLICIES")
+            .ok_or("error in getting interface policy map")?,
+    )
+    .map_err(|e| e.to_string())?;
+
+    let stale: Vec<u32> = map
+        .keys()
+        .filter_map(|k| k.ok())
+        .filter(|k| !wanted.contains_key(k))
+        .collect();
+    for k in stale {
+        map.remove(&k).map_err(|e| e.to_string())?;
+    }
+    for (ifindex, policy) in wanted {
+        map.insert(ifindex, policy, 0).map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Writes the policy of every cgroup policies list into CGROUP_POLICIES, keyed by the cgroup ID,
+/// which is the inode number of its directory
+pub fn write_cgroup_policies(config: &Config, ebpf: &mut Ebpf) -> Result<(), String> {
+    let mut wanted: FxHashMap<u64, u32> = FxHashMap::default();
+    for (i, policy) in config
+        .policies
+        .iter()
+        .take(MAX_POLICIES as usize - 1)
+        .enumerate()
+    {
+        for cgroup in &policy.cgroups {
+            match fs::metadata(cgroup) {
+                Ok(m) => {
+                    wanted.insert(m.ino(), i as u32 + 1);
+                }
+                Err(e) => warn!("error in reading cgroup {}: {}", cgroup, e),
+            }
+        }
+    }
+
+    let mut map: HashMap<&mut MapData, u64, u32> = HashMap::try_from(
+        ebpf.map_mut("CGROUP_POLICIES")
+            .ok_or("error in getting cgroup policy map")?,
+    )
+    .map_err(|e| e.to_string())?;
+
+    let stale: Vec<u64> = map
+        .keys()
+        .filter_map(|k| k.ok())
+        .filter(|k| !wanted.contains_key(k))
+        .collect();
+    for k in stale {
+        map.remove(&k).map_err(|e| e.to_string())?;
+    }
+    for (id, policy) in wanted {
+        map.insert(id, policy, 0).map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Writes the ports of every policy limited to some into PORT_SCOPES and sets their bits in the
+/// ScopedPolicies parameter
+pub fn write_port_scopes(config: &Config, ebpf: &mut Ebpf) -> Result<(), String> {
+    let mut scoped = 0;
+    let mut wanted = FxHashSet::default();
+    for (i, ports) in port_scopes(config).enumerate() {
+        if ports.is_empty() {
+            continue;
+        }
+        scoped |= 1 << i;
+        for scope in ports {
+            wanted.insert(port_scope_key(
+                i as u32,
+                scope.protocol.number(),
+                scope.port,
+            ));
+        }
+    }
+
+    let mut map: HashMap<&mut MapData, u32, u8> = HashMap::try_from(
+        ebpf.map_mut("PORT_SCOPES")
+            .ok_or("error in getting port scope map")?,
+    )
+    .map_err(|e| e.to_string())?;
+
+    let stale: Vec<u32> = map
+        .keys()
+        .filter_map(|k| k.ok())
+        .filter(|k| !wanted.contains(k))
+        .collect();
+    for k in stale {
+        map.remove(&k).map_err(|e| e.to_string())?;
+    }
+    for key in wanted {
+        map.insert(key, 1, 0).map_err(|e| e.to_string())?;
+    }
+
+    let mut parameters: HashMap<&mut MapData, u8, u32> = HashMap::try_from(
+        ebpf.map_mut("PARAMETERS")
+            .ok_or("error in getting parameters map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    parameters
+        .insert(ProgramParameters::ScopedPolicies as u8, scoped, 0)
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// Writes the mask of policies whose schedule is active into the ActivePolicies parameter
+pub fn write_active_policies(mask: u32, ebpf: &mut Ebpf) -> Result<(), String> {
+    let mut parameters: HashMap<&mut MapData, u8, u32> = HashMap::try_from(
+        ebpf.map_mut("PARAMETERS")
+            .ok_or("error in getting parameters map")?,
+    )
+    .map_err(|e| e.to_string())?;
+
+    parameters
+        .insert(ProgramParameters::ActivePolicies as u8, mask, 0)
+        .map_err(|e| e.to_string())
+}
+
+/// Writes the rate limit of every policy into RATE_LIMITS, policies without one are zeroed so
+/// they drop
+pub fn write_rate_limits(config: &Config, ebpf: &mut Ebpf) -> Result<(), String> {
+    let mut limits = vec![RateLimit::default(); MAX_POLICIES as usize];
+    for (i, limit) in rate_limits(config).enumerate() {
+        if let Some(limit) = limit {
+            limits[i] = limit.into();
+        }
+    }
+
+    let mut map: Array<&mut MapData, RateLimit> = Array::try_from(
+        ebpf.map_mut("RATE_LIMITS")
+            .ok_or("error in getting rate limit map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    for (i, limit) in limits.into_iter().enumerate() {
+        map.set(i as u32, limit, 0).map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Writes the action of every policy into POLICY_ACTIONS and the interfaces redirecting
+/// policies send to into REDIRECT_TARGETS. Policies redirecting to an interface that doesn't
+/// exist drop, unused entries drop too
+pub fn write_actions(config: &Config, ebpf: &mut Ebpf) -> Result<(), String> {
+    let mut actions = vec![Action::Drop; MAX_POLICIES as usize];
+    let mut targets = vec![];
+    for (i, action) in self::actions(config).enumerate() {
+        actions[i] = match action {
+            PolicyAction::Drop => Action::Drop,
+            PolicyAction::Reject => Action::Reject,
+            PolicyAction::Redirect(interface) => match ifindex(interface) {
+                Ok(index) => {
+                    targets.push((i as u32, index));
+                    Action::Redirect
+                }
+                Err(e) => {
+                    warn!(
+                        "error in resolving redirect target: {}, dropping instead",
+                        e
+                    );
+                    Action::Drop
+                }
+            },
+        };
+    }
+
+    let mut map: DevMap<&mut MapData> = DevMap::try_from(
+        ebpf.map_mut("REDIRECT_TARGETS")
+            .ok_or("error in getting redirect target map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    for (i, index) in targets {
+        map.set(i, index, None, 0).map_err(|e| e.to_string())?;
+    }
+
+    let mut map: Array<&mut MapData, u32> = Array::try_from(
+        ebpf.map_mut("POLICY_ACTIONS")
+            .ok_or("error in getting policy action map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    for (i, action) in actions.into_iter().enumerate() {
+        map.set(i as u32, action as u32, 0)
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
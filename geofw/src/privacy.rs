@@ -0,0 +1,129 @@
+use ring::hmac;
+use serde_derive::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// How source addresses are written to logs and events. Enforcement always uses the full address
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrivacyConfig {
+    /// Leading bits of IPv4 addresses that are kept, the rest are zeroed
+    #[serde(default = "default_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+
+    /// Leading bits of IPv6 addresses that are kept, the rest are zeroed
+    #[serde(default = "default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+
+    /// Replace the truncated address with its HMAC-SHA256 under this key. The XDP program's
+    /// debug logs can only be truncated
+    #[serde(default)]
+    pub hash_key: Option<String>,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        Self {
+            ipv4_prefix: default_ipv4_prefix(),
+            ipv6_prefix: default_ipv6_prefix(),
+            hash_key: None,
+        }
+    }
+}
+
+fn default_ipv4_prefix() -> u8 {
+    32
+}
+
+fn default_ipv6_prefix() -> u8 {
+    128
+}
+
+impl PrivacyConfig {
+    pub fn truncate(&self, addr: IpAddr) -> IpAddr {
+        match addr {
+            IpAddr::V4(a) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.ipv4_prefix.min(32) as u32)
+                    .unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from_bits(a.to_bits() & mask))
+            }
+            IpAddr::V6(a) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.ipv6_prefix.min(128) as u32)
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from_bits(a.to_bits() & mask))
+            }
+        }
+    }
+
+    /// The address as it should appear in logs and events
+    pub fn redact(&self, addr: IpAddr) -> String {
+        let addr = self.truncate(addr);
+
+        match &self.hash_key {
+            Some(key) => {
+                let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+                let tag = hmac::sign(&key, addr.to_string().as_bytes());
+                tag.as_ref()[..8]
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect()
+            }
+            None => addr.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(ipv4_prefix: u8, ipv6_prefix: u8, hash_key: Option<&str>) -> PrivacyConfig {
+        PrivacyConfig {
+            ipv4_prefix,
+            ipv6_prefix,
+            hash_key: hash_key.map(str::to_string),
+        }
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn defaults_keep_the_address() {
+        let privacy = PrivacyConfig::default();
+
+        assert_eq!(privacy.redact(addr("192.0.2.77")), "192.0.2.77");
+        assert_eq!(privacy.redact(addr("2001:db8::77")), "2001:db8::77");
+    }
+
+    #[test]
+    fn truncation() {
+        let privacy = config(24, 48, None);
+        assert_eq!(privacy.redact(addr("192.0.2.77")), "192.0.2.0");
+        assert_eq!(privacy.redact(addr("2001:db8:1:2::77")), "2001:db8:1::");
+
+        let privacy = PrivacyConfig {
+            ipv4_prefix: 0,
+            ..Default::default()
+        };
+        assert_eq!(privacy.redact(addr("192.0.2.77")), "0.0.0.0");
+        // Prefixes past the length of the address keep all of it
+        assert_eq!(
+            config(40, 200, None).truncate(addr("192.0.2.77")),
+            addr("192.0.2.77")
+        );
+    }
+
+    #[test]
+    fn hashes_are_keyed_and_stable_within_the_prefix() {
+        let hashed = config(24, 128, Some("key"));
+        let a = hashed.redact(addr("192.0.2.1"));
+
+        assert_eq!(a.len(), 16);
+        assert!(a.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(a, hashed.redact(addr("192.0.2.200")));
+        assert_ne!(a, hashed.redact(addr("192.0.3.1")));
+        assert_ne!(a, config(24, 128, Some("other")).redact(addr("192.0.2.1")));
+    }
+}
@@ -0,0 +1,122 @@
+use chrono::{NaiveTime, TimeDelta};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    time::Duration,
+};
+
+/// A daily window of local time written as `HH:MM-HH:MM`. Windows where the end is before the
+/// start wrap around midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, t: NaiveTime) -> bool {
+        if self.start <= self.end {
+            t >= self.start && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+
+    /// Time left from `t` until the window ends
+    pub fn remaining(&self, t: NaiveTime) -> Duration {
+        let mut d = self.end - t;
+        if d <= TimeDelta::zero() {
+            d += TimeDelta::days(1);
+        }
+
+        d.to_std().unwrap_or_default()
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let Some((start, end)) = s.split_once('-') else {
+            return Err(format!("invalid time window {}, expected HH:MM-HH:MM", s));
+        };
+
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .map_err(|e| format!("invalid time {} in window {}: {}", t, s, e))
+        };
+
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(w: TimeWindow) -> Self {
+        w.to_string()
+    }
+}
+
+impl Display for TimeWindow {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(s: &str) -> TimeWindow {
+        TimeWindow::try_from(s.to_string()).unwrap()
+    }
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(window("09:00-17:30").to_string(), "09:00-17:30");
+        assert_eq!(window(" 22:00 - 02:00 ").to_string(), "22:00-02:00");
+
+        for invalid in ["09:00", "9-17", "25:00-26:00", "09:00-17:60"] {
+            assert!(
+                TimeWindow::try_from(invalid.to_string()).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn windows_within_a_day() {
+        let w = window("09:00-17:00");
+
+        assert!(w.contains(time("09:00")));
+        assert!(w.contains(time("16:59")));
+        assert!(!w.contains(time("17:00")));
+        assert!(!w.contains(time("08:59")));
+        assert_eq!(w.remaining(time("16:00")), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn windows_around_midnight() {
+        let w = window("22:00-02:00");
+
+        assert!(w.contains(time("23:00")));
+        assert!(w.contains(time("01:59")));
+        assert!(!w.contains(time("02:00")));
+        assert!(!w.contains(time("12:00")));
+        assert_eq!(w.remaining(time("23:00")), Duration::from_secs(3 * 3600));
+        assert_eq!(w.remaining(time("01:00")), Duration::from_secs(3600));
+    }
+}
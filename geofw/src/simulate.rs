@@ -0,0 +1,258 @@
+use crate::{
+    blocklist::read_list,
+    bogons, db_path, is_listed,
+    maxmind::{Data, MaxmindDb},
+    policy, Config,
+};
+use clap::ValueEnum;
+use fxhash::FxHashMap;
+use geofw_common::{CountryAction, MaxmindDbType, Mode, Precedence, MAX_COMPOUND_RULES};
+use serde_derive::Serialize;
+use std::{fs::File, io::Read, net::IpAddr};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SimulateFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Verdict {
+    pub address: String,
+    pub blocked: bool,
+    pub reason: String,
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// Evaluates every address listed in `path` against the cached databases and the configured
+/// rules. The file has one address per line, empty lines and lines starting with # are skipped.
+pub fn simulate(config: &Config, path: &str, format: SimulateFormat) -> Result<(), String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| format!("error in reading {}: {}", path, e))?;
+
+    let lines: Vec<&str> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect();
+    let verdicts = evaluate(config, &lines)?;
+
+    match format {
+        SimulateFormat::Csv => {
+            println!("address,blocked,reason,country,asn");
+            for v in verdicts {
+                println!(
+                    "{},{},\"{}\",{},{}",
+                    v.address,
+                    v.blocked,
+                    v.reason,
+                    v.country.unwrap_or_default(),
+                    v.asn.map(|a| a.to_string()).unwrap_or_default()
+                );
+            }
+        }
+        SimulateFormat::Json => {
+            let json = serde_json::to_string_pretty(&verdicts).map_err(|e| e.to_string())?;
+            println!("{}", json);
+        }
+    }
+
+    Ok(())
+}
+
+/// Evaluates every address in `lines` against the cached databases and the configured rules.
+/// Lines that aren't addresses get a verdict with the reason `invalid address`
+pub fn evaluate(config: &Config, lines: &[&str]) -> Result<Vec<Verdict>, String> {
+    let mut dbs = vec![];
+    for db_type in config.db_types() {
+        let db = MaxmindDb::from_file(&db_path(config, db_type).to_string_lossy())?;
+        dbs.push((db_type, db));
+    }
+
+    let mut cidrs = config.block_cidrs.clone();
+    for path in &config.block_lists {
+        cidrs.extend(read_list(path)?);
+    }
+
+    // Bogons are only dropped on interfaces whose policy blocks them, this reports them for all
+    let bogons = if policy::bogon_policies(config) != 0 {
+        bogons::bogons()
+    } else {
+        vec![]
+    };
+
+    let mut verdicts = vec![];
+    for &line in lines {
+        let Ok(addr) = line.parse::<IpAddr>() else {
+            verdicts.push(Verdict {
+                address: line.to_string(),
+                blocked: false,
+                reason: "invalid address".to_string(),
+                country: None,
+                asn: None,
+            });
+            continue;
+        };
+
+        let mut verdict = Verdict {
+            address: addr.to_string(),
+            blocked: false,
+            reason: String::new(),
+            country: None,
+            asn: None,
+        };
+        let mut reasons = vec![];
+        let mut listed = vec![];
+        let mut allowed = vec![];
+        let mut anonymous = vec![];
+
+        if let Some(cidr) = config.allow_cidrs.iter().find(|c| c.contains(addr)) {
+            verdict.reason = format!("allow_cidrs {}", cidr);
+            verdicts.push(verdict);
+            continue;
+        }
+
+        if let Some(cidr) = cidrs.iter().find(|c| c.contains(addr)) {
+            verdict.blocked = true;
+            reasons.push(format!("block list {}", cidr));
+        }
+        if let Some(cidr) = bogons.iter().find(|c| c.contains(addr)) {
+            verdict.blocked = true;
+            reasons.push(format!("bogon {}", cidr));
+        }
+
+        for (db_type, db) in &dbs {
+            let Some(Data::Map(data)) = db.lookup(addr) else {
+                continue;
+            };
+
+            match db_type {
+                MaxmindDbType::Country => verdict.country = country_code(&data),
+                MaxmindDbType::Asn => verdict.asn = asn(&data),
+                MaxmindDbType::City | MaxmindDbType::Anonymous => {}
+            }
+
+            let reason = match db_type {
+                MaxmindDbType::Country => {
+                    format!("country {}", verdict.country.as_deref().unwrap_or("?"))
+                }
+                MaxmindDbType::Asn => format!("asn {}", verdict.asn.unwrap_or_default()),
+                MaxmindDbType::City => {
+                    let mut reason = format!("city {}", city_id(&data).unwrap_or_default());
+                    for code in subdivision_codes(&data) {
+                        reason.push_str(&format!(" {}", code));
+                    }
+                    reason
+                }
+                MaxmindDbType::Anonymous => {
+                    let flags = config.block_anonymous.flagged(&data);
+                    if !flags.is_empty() {
+                        anonymous.push(format!("anonymous {}", flags.join(" ")));
+                    }
+                    continue;
+                }
+            };
+            let logged = *db_type == MaxmindDbType::Country
+                && config.mode == Mode::Block
+                && policy::country_action(config, &data) == Some(CountryAction::Log);
+            if logged {
+                reasons.push(format!("logged {}", reason));
+            } else if is_listed(config, *db_type, &data) {
+                listed.push(reason.clone());
+            }
+            if policy::is_allowed(config, *db_type, &data) {
+                allowed.push(format!("allowed {}", reason));
+            }
+        }
+
+        if config.mode == Mode::Block {
+            let rules = config.compound_rules.iter().take(MAX_COMPOUND_RULES);
+            for (i, rule) in rules.enumerate() {
+                if rule.matches(verdict.country.as_deref(), verdict.asn) {
+                    listed.push(format!("compound rule {}", i));
+                }
+            }
+        }
+
+        match config.mode {
+            Mode::Allow if !anonymous.is_empty() => {
+                verdict.blocked = true;
+                reasons.extend(anonymous);
+            }
+            Mode::Block if !allowed.is_empty() && config.precedence == Precedence::Allow => {
+                reasons.extend(allowed);
+            }
+            Mode::Block if !anonymous.is_empty() => {
+                verdict.blocked = true;
+                reasons.extend(anonymous);
+            }
+            Mode::Block if !listed.is_empty() => {
+                verdict.blocked = true;
+                reasons.extend(listed);
+            }
+            Mode::Allow if listed.is_empty() => {
+                verdict.blocked = true;
+                reasons.push("not allowed".to_string());
+            }
+            _ => {}
+        }
+
+        verdict.reason = reasons.join(", ");
+        verdicts.push(verdict);
+    }
+
+    Ok(verdicts)
+}
+
+pub fn country_code(data: &FxHashMap<&[u8], Data>) -> Option<String> {
+    let Some(Data::Map(country)) = data.get("country".as_bytes()) else {
+        return None;
+    };
+
+    country.get("iso_code".as_bytes()).map(|c| c.to_string())
+}
+
+/// GeoNames ID of the city in a GeoLite2-City record
+pub fn city_id(data: &FxHashMap<&[u8], Data>) -> Option<u32> {
+    let Some(Data::Map(city)) = data.get("city".as_bytes()) else {
+        return None;
+    };
+
+    match city.get("geoname_id".as_bytes()) {
+        Some(&Data::U32(id)) => Some(id),
+        _ => None,
+    }
+}
+
+/// ISO 3166-2 codes of the subdivisions in a GeoLite2-City record, like US-CA
+pub fn subdivision_codes(data: &FxHashMap<&[u8], Data>) -> Vec<String> {
+    let (Some(country), Some(Data::Array(subdivisions))) =
+        (country_code(data), data.get("subdivisions".as_bytes()))
+    else {
+        return vec![];
+    };
+
+    subdivisions
+        .iter()
+        .filter_map(|s| match s {
+            Data::Map(s) => s.get("iso_code".as_bytes()),
+            _ => None,
+        })
+        .map(|code| format!("{}-{}", country, code))
+        .collect()
+}
+
+pub fn asn(data: &FxHashMap<&[u8], Data>) -> Option<u32> {
+    match data.get("autonomous_system_number".as_bytes()) {
+        Some(&Data::U32(asn)) => Some(asn),
+        _ => None,
+    }
+}
+
+pub fn asn_org(data: &FxHashMap<&[u8], Data>) -> Option<String> {
+    data.get("autonomous_system_organization".as_bytes())
+        .map(|o| o.to_string())
+}
@@ -0,0 +1,133 @@
+use crate::maps::open_loaded_map;
+use aya::maps::{HashMap, Map, MapData};
+use geofw_common::{SUSPECT_DROP, SUSPECT_PASS};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    net::{IpAddr, Ipv6Addr},
+};
+
+const STATE_VERSION: u32 = 1;
+
+/// Operational state of a running instance that is not derived from the config or the
+/// databases
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    pub version: u32,
+    pub exported_at: i64,
+
+    #[serde(default)]
+    pub suspect_verdicts: Vec<SuspectVerdict>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Pass,
+    Drop,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SuspectVerdict {
+    pub addr: IpAddr,
+    pub verdict: Verdict,
+}
+
+impl SuspectVerdict {
+    pub fn from_entry(key: [u8; 16], value: u8) -> Self {
+        let addr = Ipv6Addr::from(key);
+
+        Self {
+            addr: match addr.to_ipv4_mapped() {
+                Some(a) => IpAddr::V4(a),
+                None => IpAddr::V6(addr),
+            },
+            verdict: if value == SUSPECT_PASS {
+                Verdict::Pass
+            } else {
+                Verdict::Drop
+            },
+        }
+    }
+
+    /// Key in SUSPECT_VERDICTS
+    pub fn key(&self) -> [u8; 16] {
+        match self.addr {
+            IpAddr::V4(a) => a.to_ipv6_mapped().octets(),
+            IpAddr::V6(a) => a.octets(),
+        }
+    }
+
+    /// Value in SUSPECT_VERDICTS
+    pub fn value(&self) -> u8 {
+        match self.verdict {
+            Verdict::Pass => SUSPECT_PASS,
+            Verdict::Drop => SUSPECT_DROP,
+        }
+    }
+}
+
+pub fn open_verdicts() -> Result<HashMap<MapData, [u8; 16], u8>, String> {
+    HashMap::try_from(Map::LruHashMap(open_loaded_map("SUSPECT_VERDICTS")?))
+        .map_err(|e| format!("error in processing suspect verdict map: {}", e))
+}
+
+/// Writes the state of the running instance as JSON to `path`, or stdout without one
+pub fn export(path: Option<&str>) -> Result<(), String> {
+    let verdicts = open_verdicts()?;
+
+    let mut state = State {
+        version: STATE_VERSION,
+        exported_at: chrono::Utc::now().timestamp(),
+        suspect_verdicts: vec![],
+    };
+
+    for entry in verdicts.iter() {
+        let (key, value) =
+            entry.map_err(|e| format!("error in reading suspect verdict map: {}", e))?;
+        state
+            .suspect_verdicts
+            .push(SuspectVerdict::from_entry(key, value));
+    }
+
+    let json = serde_json::to_string_pretty(&state).map_err(|e| e.to_string())?;
+    match path {
+        Some(path) => File::create(path)
+            .and_then(|mut f| f.write_all(json.as_bytes()))
+            .map_err(|e| format!("error in writing {}: {}", path, e)),
+        None => {
+            println!("{}", json);
+            Ok(())
+        }
+    }
+}
+
+/// Loads a file written by `export` into the running instance. Entries already present are
+/// overwritten
+pub fn import(path: &str) -> Result<(), String> {
+    let mut contents = vec![];
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .map_err(|e| format!("error in reading {}: {}", path, e))?;
+
+    let state: State = serde_json::from_slice(&contents)
+        .map_err(|e| format!("error in parsing {}: {}", path, e))?;
+    if state.version > STATE_VERSION {
+        return Err(format!(
+            "{} has state version {}, this geofw only understands up to {}",
+            path, state.version, STATE_VERSION
+        ));
+    }
+
+    let mut verdicts = open_verdicts()?;
+    for v in &state.suspect_verdicts {
+        verdicts
+            .insert(v.key(), v.value(), 0)
+            .map_err(|e| format!("error in writing verdict for {}: {}", v.addr, e))?;
+    }
+
+    println!("imported {} suspect verdicts", state.suspect_verdicts.len());
+
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use crate::maps::open_loaded_map;
+use crate::metrics::Metrics;
+use aya::{
+    maps::{HashMap, Map, MapData, PerCpuArray, PerCpuValues},
+    util::nr_cpus,
+};
+use clap::ValueEnum;
+use geofw_common::{Counter, ProgramParameters, COUNTER_COUNT};
+use log::{info, warn};
+
+const COUNTERS: [Counter; COUNTER_COUNT as usize] = [
+    Counter::CountryDropped,
+    Counter::AsnDropped,
+    Counter::CityDropped,
+    Counter::AnonymousDropped,
+    Counter::BogonDropped,
+    Counter::FeedDropped,
+    Counter::CountryLogged,
+    Counter::BlocklistDropped,
+    Counter::Allowed,
+    Counter::Passed,
+    Counter::Malformed,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum StatsScope {
+    Country,
+    Asn,
+    City,
+    Anonymous,
+    Bogon,
+    Feed,
+    All,
+}
+
+impl StatsScope {
+    fn counters(&self) -> &'static [Counter] {
+        match self {
+            StatsScope::Country => &[Counter::CountryDropped, Counter::CountryLogged],
+            StatsScope::Asn => &[Counter::AsnDropped],
+            StatsScope::City => &[Counter::CityDropped],
+            StatsScope::Anonymous => &[Counter::AnonymousDropped],
+            StatsScope::Bogon => &[Counter::BogonDropped],
+            StatsScope::Feed => &[Counter::FeedDropped],
+            StatsScope::All => &COUNTERS,
+        }
+    }
+
+    /// Parameters that record when the counters were last reset
+    fn reset_params(&self) -> &'static [ProgramParameters] {
+        match self {
+            StatsScope::Country => &[ProgramParameters::CountryStatsResetAt],
+            StatsScope::Asn => &[ProgramParameters::AsnStatsResetAt],
+            StatsScope::City => &[ProgramParameters::CityStatsResetAt],
+            StatsScope::Anonymous => &[ProgramParameters::AnonymousStatsResetAt],
+            StatsScope::Bogon => &[ProgramParameters::BogonStatsResetAt],
+            StatsScope::Feed => &[ProgramParameters::FeedStatsResetAt],
+            StatsScope::All => &[
+                ProgramParameters::CountryStatsResetAt,
+                ProgramParameters::AsnStatsResetAt,
+                ProgramParameters::CityStatsResetAt,
+                ProgramParameters::AnonymousStatsResetAt,
+                ProgramParameters::BogonStatsResetAt,
+                ProgramParameters::FeedStatsResetAt,
+            ],
+        }
+    }
+}
+
+pub fn open_stats() -> Result<PerCpuArray<MapData, u64>, String> {
+    PerCpuArray::try_from(Map::PerCpuArray(open_loaded_map("STATS")?))
+        .map_err(|e| format!("error in processing stats map: {}", e))
+}
+
+/// Zeroes the counters of a running geofw instance in `scope` and records when it happened.
+/// Every counter is replaced on all CPUs with a single update
+pub fn reset(scope: StatsScope) -> Result<(), String> {
+    let mut stats = open_stats()?;
+    let mut params: HashMap<MapData, u8, u32> =
+        HashMap::try_from(Map::HashMap(open_loaded_map("PARAMETERS")?))
+            .map_err(|e| format!("error in processing parameter map: {}", e))?;
+
+    let cpus = nr_cpus().map_err(|(msg, e)| format!("{}: {}", msg, e))?;
+    for &counter in scope.counters() {
+        let zero = PerCpuValues::try_from(vec![0u64; cpus]).map_err(|e| e.to_string())?;
+        stats
+            .set(counter as u32, zero, 0)
+            .map_err(|e| format!("error in resetting {:?}: {}", counter, e))?;
+    }
+
+    let now = chrono::Utc::now().timestamp() as u32;
+    for &param in scope.reset_params() {
+        params
+            .insert(param as u8, now, 0)
+            .map_err(|e| format!("error in recording reset time: {}", e))?;
+    }
+
+    info!("reset counters scope = {:?} at = {}", scope, now);
+
+    Ok(())
+}
+
+/// Every counter summed over all CPUs, by its short name
+pub fn totals(stats: &PerCpuArray<&MapData, u64>) -> Result<Vec<(&'static str, u64)>, String> {
+    COUNTERS
+        .iter()
+        .map(|&counter| {
+            let values = stats
+                .get(&(counter as u32), 0)
+                .map_err(|e| format!("error in reading counter {:?}: {}", counter, e))?;
+            Ok((counter.short_name(), values.iter().sum()))
+        })
+        .collect()
+}
+
+/// Remembers the counters at the last export, so only intervals with traffic are logged
+#[derive(Debug, Default)]
+pub struct Exporter {
+    last: [u64; COUNTER_COUNT as usize],
+}
+
+impl Exporter {
+    /// Sums every counter over all CPUs, emits the totals as `stats.packets` gauges tagged with
+    /// the counter and logs how much each grew since the last export
+    pub fn export(&mut self, stats: &PerCpuArray<&MapData, u64>, metrics: &Metrics) {
+        let mut parts = vec![];
+        let mut changed = false;
+        for counter in COUNTERS {
+            let total: u64 = match stats.get(&(counter as u32), 0) {
+                Ok(values) => values.iter().sum(),
+                Err(e) => {
+                    warn!("error in reading counter {:?}: {}", counter, e);
+                    continue;
+                }
+            };
+            metrics.gauge(
+                "stats.packets",
+                total as f64,
+                &[("counter", counter.short_name())],
+            );
+
+            let last = std::mem::replace(&mut self.last[counter as usize], total);
+            // The counters went backwards, so they were reset in between
+            let delta = total.checked_sub(last).unwrap_or(total);
+            changed |= delta != 0;
+            parts.push(format!("{} = {}", counter.short_name(), delta));
+        }
+
+        if changed {
+            info!("stats {}", parts.join(" "));
+        }
+    }
+}
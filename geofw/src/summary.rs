@@ -0,0 +1,84 @@
+use crate::{alert, dbinfo::format_epoch};
+use aya::maps::{MapData, PerCpuArray};
+use fxhash::FxHashMap;
+use geofw_common::{Counter, MaxmindDbType};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SummaryConfig {
+    /// Seconds between summaries, e.g. 3600 for hourly or 86400 for daily
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+
+    /// Also post every summary to this Slack compatible webhook
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+fn default_interval() -> u64 {
+    3600
+}
+
+/// Remembers the counters at the last summary, so each summary covers its own interval
+#[derive(Debug, Default)]
+pub struct Summary {
+    last: FxHashMap<MaxmindDbType, u64>,
+}
+
+impl Summary {
+    pub fn report(
+        &mut self,
+        config: &SummaryConfig,
+        stats: &PerCpuArray<&MapData, u64>,
+        loaded: &FxHashMap<MaxmindDbType, u64>,
+    ) {
+        let mut parts = vec![];
+
+        for (db_type, counter) in [
+            (MaxmindDbType::Country, Counter::CountryDropped),
+            (MaxmindDbType::Asn, Counter::AsnDropped),
+            (MaxmindDbType::City, Counter::CityDropped),
+            (MaxmindDbType::Anonymous, Counter::AnonymousDropped),
+        ] {
+            // The city and anonymous IP databases are only loaded when some rule needs them
+            let optional = matches!(db_type, MaxmindDbType::City | MaxmindDbType::Anonymous);
+            if optional && !loaded.contains_key(&db_type) {
+                continue;
+            }
+
+            let total: u64 = match stats.get(&(counter as u32), 0) {
+                Ok(values) => values.iter().sum(),
+                Err(e) => {
+                    warn!("error in reading counter {:?}: {}", counter, e);
+                    continue;
+                }
+            };
+            let last = self.last.insert(db_type, total).unwrap_or(0);
+            // The counters went backwards, so they were reset in between
+            let dropped = total.checked_sub(last).unwrap_or(total);
+
+            let build = loaded
+                .get(&db_type)
+                .map_or("not loaded".to_string(), |&e| format_epoch(e));
+            parts.push(format!(
+                "{} dropped = {} build = {}",
+                db_type.short_name(),
+                dropped,
+                build
+            ));
+        }
+
+        let message = format!(
+            "summary of the last {:?}: {}",
+            Duration::from_secs(config.interval),
+            parts.join(", ")
+        );
+        info!("{}", message);
+
+        if let Some(url) = &config.webhook {
+            alert::post(url, &message);
+        }
+    }
+}
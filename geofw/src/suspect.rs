@@ -0,0 +1,568 @@
+use crate::{
+    events::Event,
+    is_anycast,
+    maxmind::Data,
+    privacy::PrivacyConfig,
+    simulate::country_code,
+    state::{SuspectVerdict, Verdict},
+    tap::Tap,
+    xsk::{ifindex, rx_queues, XskSocket},
+    Config,
+};
+use aya::{
+    maps::{HashMap, MapData, XskMap},
+    Ebpf,
+};
+use fxhash::{FxHashMap, FxHashSet};
+use geofw_common::{MAX_INTERFACES, MAX_QUEUES, SUSPECT_KEY_LEN, SUSPECT_PASS};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    io,
+    io::Write,
+    net::{IpAddr, Ipv6Addr},
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    process::{Command, Stdio},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Inspect commands running at the same time, later sources wait for one of them to exit
+const MAX_INSPECTIONS: usize = 16;
+
+// Frames of a source kept while it is inspected, later ones are dropped
+const MAX_PENDING_FRAMES: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SuspectConfig {
+    /// Traffic from these countries is redirected to geofw instead of being passed or dropped
+    pub countries: FxHashSet<String>,
+
+    /// Run with `sh -c` for the first packet from every suspect source. The raw frame is written
+    /// to stdin and the source address is in GEOFW_SOURCE. Exiting with 0 passes the source,
+    /// anything else drops it. Without a command, sources are only logged and then passed
+    #[serde(default)]
+    pub inspect_command: Option<String>,
+
+    /// Seconds the inspect command may run before it is killed and the source is passed
+    #[serde(default = "default_inspect_timeout")]
+    pub inspect_timeout: u64,
+}
+
+fn default_inspect_timeout() -> u64 {
+    5
+}
+
+pub fn is_suspect(config: &Config, data: &FxHashMap<&[u8], Data>) -> bool {
+    let Some(suspect) = &config.suspect else {
+        return false;
+    };
+    if config.skip_anycast && is_anycast(data) {
+        return false;
+    }
+
+    country_code(data).is_some_and(|c| suspect.countries.contains(&c))
+}
+
+/// Binds an AF_XDP socket to every rx queue of the interface and starts a thread that inspects
+/// the redirected packets and writes a verdict for their source into SUSPECT_VERDICTS. Passed
+/// packets are handed back to the kernel through a TAP device for each interface. Verdicts are
+/// also sent to `sync` to replicate them to peers, and recorded in `events`.
+pub fn start(
+    config: &Config,
+    ebpf: &mut Ebpf,
+    sync: Option<Sender<SuspectVerdict>>,
+    events: Option<Sender<Event>>,
+) -> Result<(), String> {
+    let Some(suspect) = config.suspect.clone() else {
+        return Ok(());
+    };
+
+    let interfaces = config.interfaces();
+    if interfaces.len() > MAX_INTERFACES as usize {
+        return Err(format!(
+            "inspection supports at most {} interfaces",
+            MAX_INTERFACES
+        ));
+    }
+
+    let mut sockets = vec![];
+    let mut xsk_map = XskMap::try_from(
+        ebpf.map_mut("SUSPECT_SOCKETS")
+            .ok_or("error in getting suspect socket map")?,
+    )
+    .map_err(|e| e.to_string())?;
+
+    let mut slots = vec![];
+    let mut taps = vec![];
+    for (slot, interface) in interfaces.iter().enumerate() {
+        let tap = Tap::create(interface)
+            .map_err(|e| format!("error in creating TAP device for {}: {}", interface, e))?;
+        info!(
+            "re-injecting passed suspect traffic of {} through {}",
+            interface,
+            tap.name()
+        );
+        taps.push(tap);
+
+        let slot = slot as u32;
+        let queues = rx_queues(interface)?;
+        if queues > MAX_QUEUES {
+            return Err(format!(
+                "{} has {} rx queues, inspection supports at most {}",
+                interface, queues, MAX_QUEUES
+            ));
+        }
+
+        for queue in 0..queues {
+            let socket = XskSocket::bind(interface, queue).map_err(|e| {
+                format!(
+                    "error in binding AF_XDP socket to {} queue {}: {}",
+                    interface, queue, e
+                )
+            })?;
+            xsk_map
+                .set(slot * MAX_QUEUES + queue, socket.as_fd(), 0)
+                .map_err(|e| e.to_string())?;
+            sockets.push((socket, slot as usize));
+        }
+
+        slots.push((ifindex(interface)?, slot));
+    }
+
+    let mut interface_map: HashMap<&mut MapData, u32, u32> = HashMap::try_from(
+        ebpf.map_mut("SUSPECT_INTERFACES")
+            .ok_or("error in getting suspect interface map")?,
+    )
+    .map_err(|e| e.to_string())?;
+    for (ifindex, slot) in slots {
+        interface_map
+            .insert(ifindex, slot, 0)
+            .map_err(|e| e.to_string())?;
+    }
+
+    let verdicts: HashMap<MapData, [u8; 16], u8> = HashMap::try_from(
+        ebpf.take_map("SUSPECT_VERDICTS")
+            .ok_or("error in getting suspect verdict map")?,
+    )
+    .map_err(|e| e.to_string())?;
+
+    info!(
+        "inspecting suspect traffic on {} with {} AF_XDP sockets",
+        interfaces.join(", "),
+        sockets.len()
+    );
+
+    let privacy = config.privacy.clone();
+    thread::spawn(move || inspect_loop(&suspect, &privacy, sockets, taps, verdicts, sync, events));
+
+    Ok(())
+}
+
+fn inspect_loop(
+    config: &SuspectConfig,
+    privacy: &PrivacyConfig,
+    mut sockets: Vec<(XskSocket, usize)>,
+    taps: Vec<Tap>,
+    verdicts: HashMap<MapData, [u8; 16], u8>,
+    sync: Option<Sender<SuspectVerdict>>,
+    events: Option<Sender<Event>>,
+) {
+    let wake = match eventfd() {
+        Ok(fd) => Arc::new(fd),
+        Err(e) => {
+            warn!(
+                "error in creating eventfd, suspect traffic is passed: {}",
+                e
+            );
+            return;
+        }
+    };
+    let (done_tx, done_rx) = mpsc::channel();
+    let mut inspector = Inspector {
+        config,
+        privacy,
+        taps,
+        verdicts,
+        sync,
+        events,
+        pending: FxHashMap::default(),
+        waiting: VecDeque::new(),
+        running: 0,
+        done: done_tx,
+        wake: wake.clone(),
+    };
+
+    let mut fds: Vec<libc::pollfd> = sockets
+        .iter()
+        .map(|(s, _)| s.as_fd().as_raw_fd())
+        .chain([wake.as_raw_fd()])
+        .map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+
+    loop {
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ret < 0 {
+            warn!(
+                "error in polling AF_XDP sockets: {}",
+                std::io::Error::last_os_error()
+            );
+            continue;
+        }
+
+        for (socket, tap) in sockets.iter_mut() {
+            socket.recv(SUSPECT_KEY_LEN, |frame, metadata| {
+                if let Some(source) = source(metadata) {
+                    inspector.frame(*tap, source, frame);
+                }
+            });
+        }
+
+        let mut count = 0u64;
+        unsafe {
+            libc::read(
+                wake.as_raw_fd(),
+                &mut count as *mut u64 as *mut libc::c_void,
+                8,
+            )
+        };
+        inspector.finish(&done_rx);
+    }
+}
+
+/// Frames of a source waiting for its verdict
+struct Pending {
+    tap: usize,
+    frames: Vec<Vec<u8>>,
+}
+
+/// Tracks the sources being inspected. Commands run on their own threads and send their verdict
+/// to `done`, so the frames of other sources keep flowing while one is inspected
+struct Inspector<'a> {
+    config: &'a SuspectConfig,
+    privacy: &'a PrivacyConfig,
+    taps: Vec<Tap>,
+    verdicts: HashMap<MapData, [u8; 16], u8>,
+    sync: Option<Sender<SuspectVerdict>>,
+    events: Option<Sender<Event>>,
+    pending: FxHashMap<IpAddr, Pending>,
+    // Sources whose command hasn't started, as MAX_INSPECTIONS are running
+    waiting: VecDeque<IpAddr>,
+    running: usize,
+    done: Sender<(IpAddr, bool)>,
+    wake: Arc<OwnedFd>,
+}
+
+impl Inspector<'_> {
+    /// Handles a frame that arrived on the interface of `tap`
+    fn frame(&mut self, tap: usize, source: IpAddr, frame: &[u8]) {
+        let key = SuspectVerdict {
+            addr: source,
+            verdict: Verdict::Pass,
+        }
+        .key();
+
+        // Packets that were already queued when the verdict was written
+        if let Ok(verdict) = self.verdicts.get(&key, 0) {
+            if verdict == SUSPECT_PASS {
+                self.reinject(tap, source, frame);
+            }
+            return;
+        }
+
+        if let Some(pending) = self.pending.get_mut(&source) {
+            if pending.frames.len() < MAX_PENDING_FRAMES {
+                pending.frames.push(frame.to_vec());
+            }
+            return;
+        }
+
+        self.pending.insert(
+            source,
+            Pending {
+                tap,
+                frames: vec![frame.to_vec()],
+            },
+        );
+        if self.config.inspect_command.is_none() {
+            self.verdict(source, true);
+        } else if self.running < MAX_INSPECTIONS {
+            self.start(source);
+        } else {
+            self.waiting.push_back(source);
+        }
+    }
+
+    /// Applies the verdicts of the commands that exited and starts the waiting ones
+    fn finish(&mut self, done: &Receiver<(IpAddr, bool)>) {
+        while let Ok((source, pass)) = done.try_recv() {
+            self.running -= 1;
+            self.verdict(source, pass);
+        }
+
+        while self.running < MAX_INSPECTIONS {
+            let Some(source) = self.waiting.pop_front() else {
+                break;
+            };
+            self.start(source);
+        }
+    }
+
+    fn start(&mut self, source: IpAddr) {
+        let (Some(cmd), Some(pending)) = (&self.config.inspect_command, self.pending.get(&source))
+        else {
+            return;
+        };
+        let cmd = cmd.clone();
+        let frame = pending.frames[0].clone();
+        let timeout = Duration::from_secs(self.config.inspect_timeout);
+        let done = self.done.clone();
+        let wake = self.wake.clone();
+
+        self.running += 1;
+        thread::spawn(move || {
+            let pass = inspect(&cmd, timeout, source, &frame);
+            let _ = done.send((source, pass));
+            let one = 1u64;
+            unsafe {
+                libc::write(
+                    wake.as_raw_fd(),
+                    &one as *const u64 as *const libc::c_void,
+                    8,
+                )
+            };
+        });
+    }
+
+    /// Writes the verdict for `source` and re-injects or drops the frames kept for it
+    fn verdict(&mut self, source: IpAddr, pass: bool) {
+        let Some(pending) = self.pending.remove(&source) else {
+            return;
+        };
+        let verdict = SuspectVerdict {
+            addr: source,
+            verdict: if pass { Verdict::Pass } else { Verdict::Drop },
+        };
+        let len = pending.frames[0].len();
+        info!(
+            "suspect source = {} len = {} verdict = {:?}",
+            self.privacy.redact(source),
+            len,
+            verdict.verdict
+        );
+
+        if let Err(e) = self.verdicts.insert(verdict.key(), verdict.value(), 0) {
+            warn!(
+                "error in writing verdict for {}: {}",
+                self.privacy.redact(source),
+                e
+            );
+        }
+        if let Some(events) = &self.events {
+            let _ = events.send(Event::Suspect {
+                source: self.privacy.redact(source),
+                len,
+                verdict: verdict.verdict,
+            });
+        }
+        if let Some(sync) = &self.sync {
+            // The peer sync threads only stop with the process
+            let _ = sync.send(verdict);
+        }
+
+        if pass {
+            for frame in &pending.frames {
+                self.reinject(pending.tap, source, frame);
+            }
+        }
+    }
+
+    fn reinject(&self, tap: usize, source: IpAddr, frame: &[u8]) {
+        if let Err(e) = self.taps[tap].write(frame) {
+            warn!(
+                "error in re-injecting frame from {} through {}: {}",
+                self.privacy.redact(source),
+                self.taps[tap].name(),
+                e
+            );
+        }
+    }
+}
+
+/// Runs `cmd` with the frame on stdin, killing it after `timeout`. Sources are passed when the
+/// command can't be run or times out, as without a command
+fn inspect(cmd: &str, timeout: Duration, source: IpAddr, frame: &[u8]) -> bool {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("GEOFW_SOURCE", source.to_string())
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("error in running inspect command: {}", e);
+            return true;
+        }
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command may not read stdin at all
+        let _ = stdin.write_all(frame);
+    }
+
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return status.success(),
+            Ok(None) if started.elapsed() >= timeout => {
+                warn!(
+                    "inspect command ran for more than {}s, killing it",
+                    timeout.as_secs()
+                );
+                let _ = child.kill();
+                let _ = child.wait();
+                return true;
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => {
+                warn!("error in waiting for inspect command: {}", e);
+                return true;
+            }
+        }
+    }
+}
+
+fn eventfd() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Source the XDP program looked the frame up with, from the key it wrote into the metadata. The
+/// frame itself can have VLAN tags or be a tunnel whose outer source isn't the one inspected
+fn source(metadata: &[u8]) -> Option<IpAddr> {
+    let key: [u8; SUSPECT_KEY_LEN] = metadata.try_into().ok()?;
+
+    Some(Ipv6Addr::from(key).to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xsk::metadata;
+    use std::net::Ipv4Addr;
+
+    // Where the kernel puts frames in a chunk, XDP_PACKET_HEADROOM
+    const HEADROOM: usize = 256;
+
+    fn ipv4(source: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, protocol, 0, 0];
+        packet.extend(source.octets());
+        packet.extend([192, 0, 2, 1]);
+        packet.extend(payload);
+        packet
+    }
+
+    fn ethernet(tags: &[u16], ether_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        for &tag in tags {
+            frame.extend(0x8100u16.to_be_bytes());
+            frame.extend(tag.to_be_bytes());
+        }
+        frame.extend(ether_type.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    /// A chunk as the kernel fills it, with `key` in the metadata in front of `frame`
+    fn chunk(key: [u8; SUSPECT_KEY_LEN], frame: &[u8]) -> Vec<u8> {
+        let mut chunk = vec![0xaa; HEADROOM - SUSPECT_KEY_LEN];
+        chunk.extend(key);
+        chunk.extend(frame);
+        chunk
+    }
+
+    fn source_of(chunk: &[u8]) -> Option<IpAddr> {
+        source(metadata(chunk, HEADROOM, SUSPECT_KEY_LEN))
+    }
+
+    #[test]
+    fn vlan_tagged_frames() {
+        let inspected = Ipv4Addr::new(198, 51, 100, 7);
+        let frame = ethernet(&[10, 20], 0x0800, &ipv4(inspected, 6, &[]));
+
+        let chunk = chunk(inspected.to_ipv6_mapped().octets(), &frame);
+        assert_eq!(source_of(&chunk), Some(IpAddr::V4(inspected)));
+    }
+
+    #[test]
+    fn tunnelled_frames_use_the_inner_source() {
+        let outer = Ipv4Addr::new(192, 0, 2, 99);
+        let inner = Ipv4Addr::new(203, 0, 113, 9);
+        // GRE without options, carrying IPv4
+        let mut gre = vec![0, 0, 0x08, 0x00];
+        gre.extend(ipv4(inner, 17, &[]));
+        let frame = ethernet(&[], 0x0800, &ipv4(outer, 47, &gre));
+
+        let chunk = chunk(inner.to_ipv6_mapped().octets(), &frame);
+        assert_eq!(source_of(&chunk), Some(IpAddr::V4(inner)));
+    }
+
+    #[test]
+    fn ipv6_sources() {
+        let inspected: Ipv6Addr = "2001:db8::7".parse().unwrap();
+        let frame = ethernet(&[10], 0x86dd, &[0; 40]);
+
+        let chunk = chunk(inspected.octets(), &frame);
+        assert_eq!(source_of(&chunk), Some(IpAddr::V6(inspected)));
+    }
+
+    #[test]
+    fn inspect_command_verdicts() {
+        let source = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+        let timeout = Duration::from_secs(5);
+
+        assert!(inspect(
+            "test \"$GEOFW_SOURCE\" = 198.51.100.7",
+            timeout,
+            source,
+            &[]
+        ));
+        assert!(!inspect("exit 3", timeout, source, &[]));
+        assert!(inspect(
+            "test \"$(wc -c)\" -eq 4",
+            timeout,
+            source,
+            &[1, 2, 3, 4]
+        ));
+    }
+
+    #[test]
+    fn inspect_command_timeout() {
+        let source = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+        let started = Instant::now();
+
+        assert!(inspect("sleep 10", Duration::from_millis(100), source, &[]));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn frames_without_room_for_metadata() {
+        let frame = ethernet(&[], 0x0800, &ipv4(Ipv4Addr::new(198, 51, 100, 7), 6, &[]));
+
+        assert_eq!(source(metadata(&frame, 8, SUSPECT_KEY_LEN)), None);
+    }
+}
@@ -0,0 +1,55 @@
+use log::{debug, warn};
+use std::{
+    env,
+    os::{linux::net::SocketAddrExt, unix::net::SocketAddr, unix::net::UnixDatagram},
+    time::Duration,
+};
+
+/// Sends a state like `READY=1` to the service manager. Does nothing when geofw isn't started
+/// by systemd with `Type=notify`
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.to_string_lossy();
+
+    // Names starting with @ are in the abstract namespace
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(path.as_ref()),
+    };
+    let result = addr.and_then(|addr| {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+
+    match result {
+        Ok(_) => debug!("notified service manager {}", state.replace('\n', " ")),
+        Err(e) => warn!("error in notifying service manager at {}: {}", path, e),
+    }
+}
+
+/// Marks the start of a reload, it ends with the next READY=1
+pub fn reloading() {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    let usec = ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000;
+
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", usec));
+}
+
+/// How often the watchdog has to be notified, half of `WatchdogSec=` so a slow tick isn't
+/// mistaken for a hang. None when the watchdog isn't enabled for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+
+    Some(Duration::from_micros(usec / 2).max(Duration::from_millis(100)))
+}
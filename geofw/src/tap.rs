@@ -0,0 +1,112 @@
+//! TAP devices that hand frames taken off an interface over AF_XDP back to the kernel. Frames
+//! written to the device are received by the host as if they had arrived on it.
+
+use log::warn;
+use std::{
+    ffi::CString,
+    fs, io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+pub struct Tap {
+    name: String,
+    fd: OwnedFd,
+}
+
+impl Tap {
+    /// Creates a TAP device for `interface`, with its MAC address and MTU so the frames are
+    /// accepted as they are, and brings it up. Replies to the re-injected packets leave through
+    /// `interface`, so reverse path filtering is loosened on the device.
+    pub fn create(interface: &str) -> io::Result<Self> {
+        let name = tap_name(interface);
+
+        let path = CString::new("/dev/net/tun").map_err(|e| io::Error::other(e.to_string()))?;
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut req = ifreq(&name);
+        req.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short;
+        ioctl(fd.as_raw_fd(), libc::TUNSETIFF as libc::c_ulong, &mut req)?;
+
+        // The address, MTU and flags are set through any socket
+        let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = unsafe { OwnedFd::from_raw_fd(sock) };
+        let sock = sock.as_raw_fd();
+
+        let mut source = ifreq(interface);
+        let mut tap = ifreq(&name);
+        ioctl(sock, libc::SIOCGIFHWADDR, &mut source)?;
+        tap.ifr_ifru.ifru_hwaddr = unsafe { source.ifr_ifru.ifru_hwaddr };
+        ioctl(sock, libc::SIOCSIFHWADDR, &mut tap)?;
+
+        ioctl(sock, libc::SIOCGIFMTU, &mut source)?;
+        tap.ifr_ifru.ifru_mtu = unsafe { source.ifr_ifru.ifru_mtu };
+        ioctl(sock, libc::SIOCSIFMTU, &mut tap)?;
+
+        ioctl(sock, libc::SIOCGIFFLAGS, &mut tap)?;
+        unsafe { tap.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+        ioctl(sock, libc::SIOCSIFFLAGS, &mut tap)?;
+
+        let rp_filter = format!("/proc/sys/net/ipv4/conf/{}/rp_filter", name);
+        if let Err(e) = fs::write(&rp_filter, "2") {
+            warn!("error in writing {}: {}", rp_filter, e);
+        }
+
+        Ok(Self { name, fd })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Hands `frame` to the kernel
+    pub fn write(&self, frame: &[u8]) -> io::Result<()> {
+        let ret = unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                frame.as_ptr() as *const libc::c_void,
+                frame.len(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+/// `gfw-` and the name of the interface, cut to the 15 bytes the kernel allows
+fn tap_name(interface: &str) -> String {
+    let mut name = format!("gfw-{}", interface);
+    while name.len() >= libc::IFNAMSIZ {
+        name.pop();
+    }
+    name
+}
+
+fn ifreq(name: &str) -> libc::ifreq {
+    let mut req: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, &src) in req
+        .ifr_name
+        .iter_mut()
+        .zip(name.as_bytes().iter().take(libc::IFNAMSIZ - 1))
+    {
+        *dst = src as libc::c_char;
+    }
+    req
+}
+
+fn ioctl(fd: libc::c_int, request: libc::c_ulong, req: &mut libc::ifreq) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, request as _, req as *mut libc::ifreq) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,521 @@
+use crate::{blocklist::Cidr, maxmind::MaxmindDb};
+use fxhash::FxHashMap;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::{Read, Write},
+    net::IpAddr,
+};
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// Describes a synthetic database, see the README for an example
+#[derive(Debug, Deserialize)]
+struct Spec {
+    #[serde(default = "default_database_type")]
+    database_type: String,
+
+    #[serde(default = "default_record_size")]
+    record_size: u16,
+
+    /// 6 stores IPv4 networks in ::/96 like the GeoLite2 databases, 4 only holds IPv4 networks
+    #[serde(default = "default_ip_version")]
+    ip_version: u16,
+
+    /// Defaults to the current time
+    #[serde(default)]
+    build_epoch: Option<u64>,
+
+    #[serde(default)]
+    languages: Vec<String>,
+
+    /// Point ::ffff:0:0/96 and 2002::/16 at the IPv4 subtree like the GeoLite2 databases do.
+    /// Networks in those ranges are replaced. Only used with ip_version 6
+    #[serde(default = "default_ipv4_aliases")]
+    ipv4_aliases: bool,
+
+    networks: Vec<Network>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Network {
+    network: Cidr,
+    /// Must be a JSON object. Non-negative integers are stored as uint32 if they fit and uint64
+    /// otherwise, negative ones as int32
+    data: Value,
+}
+
+fn default_database_type() -> String {
+    "GeoLite2-Country".to_string()
+}
+
+fn default_record_size() -> u16 {
+    24
+}
+
+fn default_ip_version() -> u16 {
+    6
+}
+
+fn default_ipv4_aliases() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Record {
+    Empty,
+    Node(u32),
+    Data(u32),
+}
+
+/// Builds the mmdb file described by the spec at `spec_path` and writes it to `out_path`
+pub fn make_test_db(spec_path: &str, out_path: &str) -> Result<(), String> {
+    let mut contents = vec![];
+    File::open(spec_path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .map_err(|e| format!("error in reading {}: {}", spec_path, e))?;
+    let spec: Spec = serde_json::from_slice(&contents)
+        .map_err(|e| format!("error in parsing {}: {}", spec_path, e))?;
+
+    let (out, node_count) = build(&spec)?;
+
+    File::create(out_path)
+        .and_then(|mut f| f.write_all(&out))
+        .map_err(|e| format!("error in writing {}: {}", out_path, e))?;
+
+    println!(
+        "wrote {} networks = {} node_count = {} record_size = {}",
+        out_path,
+        spec.networks.len(),
+        node_count,
+        spec.record_size
+    );
+
+    Ok(())
+}
+
+/// Encodes the database described by `spec`, returns it with its node count
+fn build(spec: &Spec) -> Result<(Vec<u8>, u32), String> {
+    if ![24, 28].contains(&spec.record_size) {
+        return Err(format!(
+            "record size {} is not supported, use 24 or 28",
+            spec.record_size
+        ));
+    }
+    if ![4, 6].contains(&spec.ip_version) {
+        return Err(format!("invalid ip_version {}", spec.ip_version));
+    }
+
+    // Broader networks first, so more specific ones split them instead of being overwritten
+    let mut networks: Vec<&Network> = spec.networks.iter().collect();
+    networks.sort_by_key(|n| n.network.prefix_len);
+
+    let mut data_section = vec![];
+    let mut offsets: FxHashMap<Vec<u8>, u32> = FxHashMap::default();
+    let mut nodes: Vec<[Record; 2]> = vec![[Record::Empty, Record::Empty]];
+
+    for n in networks {
+        if !n.data.is_object() {
+            return Err(format!("data of {} must be an object", n.network));
+        }
+
+        let mut encoded = vec![];
+        encode(&n.data, &mut encoded)?;
+        let offset = *offsets.entry(encoded).or_insert_with_key(|encoded| {
+            let offset = data_section.len() as u32;
+            data_section.extend_from_slice(encoded);
+            offset
+        });
+
+        let (bits, depth, prefix_len) = match (n.network.addr, spec.ip_version) {
+            (IpAddr::V4(a), 4) => (a.to_bits() as u128, 32, n.network.prefix_len as u32),
+            (IpAddr::V4(a), _) => (a.to_bits() as u128, 128, n.network.prefix_len as u32 + 96),
+            (IpAddr::V6(a), 6) => (a.to_bits(), 128, n.network.prefix_len as u32),
+            (IpAddr::V6(_), _) => {
+                return Err(format!("{} can't be stored in an IPv4 database", n.network))
+            }
+        };
+        if prefix_len == 0 {
+            return Err(format!("{} covers the whole tree", n.network));
+        }
+
+        insert(&mut nodes, bits, depth, prefix_len, Record::Data(offset));
+    }
+
+    if spec.ip_version == 6 && spec.ipv4_aliases {
+        let ipv4_start = subtree(&mut nodes, 0, 128, 96);
+        for (bits, prefix_len) in [(0xffff << 32, 96), (0x2002 << 112, 16)] {
+            insert(&mut nodes, bits, 128, prefix_len, Record::Node(ipv4_start));
+        }
+    }
+
+    let node_count = nodes.len() as u32;
+    let node_size = spec.record_size as usize * 2 / 8;
+    let record = |r: Record| match r {
+        Record::Empty => node_count,
+        Record::Node(n) => n,
+        Record::Data(offset) => node_count + 16 + offset,
+    };
+
+    let mut out = vec![0; nodes.len() * node_size];
+    for (i, [left, right]) in nodes.iter().enumerate() {
+        let n = &mut out[i * node_size..(i + 1) * node_size];
+        MaxmindDb::write_over_node_bytes(n, true, spec.record_size, record(*left));
+        MaxmindDb::write_over_node_bytes(n, false, spec.record_size, record(*right));
+    }
+    out.extend_from_slice(&[0; 16]);
+    out.extend_from_slice(&data_section);
+
+    out.extend_from_slice(METADATA_MARKER);
+    let build_epoch = spec
+        .build_epoch
+        .unwrap_or_else(|| chrono::Utc::now().timestamp() as u64);
+    control(&mut out, 7, 9);
+    string(&mut out, "binary_format_major_version");
+    uint(&mut out, 5, 2);
+    string(&mut out, "binary_format_minor_version");
+    uint(&mut out, 5, 0);
+    string(&mut out, "build_epoch");
+    uint(&mut out, 9, build_epoch as u128);
+    string(&mut out, "database_type");
+    string(&mut out, &spec.database_type);
+    string(&mut out, "description");
+    control(&mut out, 7, 1);
+    string(&mut out, "en");
+    string(&mut out, "geofw test database");
+    string(&mut out, "ip_version");
+    uint(&mut out, 5, spec.ip_version as u128);
+    string(&mut out, "languages");
+    control(&mut out, 11, spec.languages.len());
+    for l in &spec.languages {
+        string(&mut out, l);
+    }
+    string(&mut out, "node_count");
+    uint(&mut out, 6, node_count as u128);
+    string(&mut out, "record_size");
+    uint(&mut out, 5, spec.record_size as u128);
+
+    Ok((out, node_count))
+}
+
+/// Points the first `prefix_len` of `depth` bits of `bits` at `record`
+fn insert(nodes: &mut Vec<[Record; 2]>, bits: u128, depth: u32, prefix_len: u32, record: Record) {
+    let node = subtree(nodes, bits, depth, prefix_len - 1);
+    let bit = ((bits >> (depth - prefix_len)) & 1) as usize;
+    nodes[node as usize][bit] = record;
+}
+
+/// The node reached after the first `prefix_len` of `depth` bits of `bits`, creating the nodes
+/// along the way
+fn subtree(nodes: &mut Vec<[Record; 2]>, bits: u128, depth: u32, prefix_len: u32) -> u32 {
+    let mut node = 0;
+
+    for i in 0..prefix_len {
+        let bit = ((bits >> (depth - 1 - i)) & 1) as usize;
+        match nodes[node][bit] {
+            Record::Node(n) => node = n as usize,
+            // Both halves of a broader network keep its data
+            other => {
+                let n = nodes.len();
+                nodes.push([other, other]);
+                nodes[node][bit] = Record::Node(n as u32);
+                node = n;
+            }
+        }
+    }
+
+    node as u32
+}
+
+/// Writes the control byte, extended type and size of a field
+fn control(out: &mut Vec<u8>, data_type: u8, size: usize) {
+    let (type_bits, extended) = if data_type <= 7 {
+        (data_type << 5, None)
+    } else {
+        (0, Some(data_type - 7))
+    };
+
+    let (size_bits, extra) = if size < 29 {
+        (size as u8, vec![])
+    } else if size < 285 {
+        (29, vec![(size - 29) as u8])
+    } else if size < 65821 {
+        (30, ((size - 285) as u16).to_be_bytes().to_vec())
+    } else {
+        (31, ((size - 65821) as u32).to_be_bytes()[1..].to_vec())
+    };
+
+    out.push(type_bits | size_bits);
+    out.extend(extended);
+    out.extend(extra);
+}
+
+fn string(out: &mut Vec<u8>, s: &str) {
+    control(out, 2, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Writes an unsigned integer with as few bytes as possible
+fn uint(out: &mut Vec<u8>, data_type: u8, v: u128) {
+    let bytes = v.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+
+    control(out, data_type, bytes.len() - skip);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+fn encode(value: &Value, out: &mut Vec<u8>) -> Result<(), String> {
+    match value {
+        Value::Null => return Err("null is not supported".to_string()),
+        Value::Bool(b) => control(out, 14, *b as usize),
+        Value::String(s) => string(out, s),
+        Value::Number(n) => {
+            if let Some(v) = n.as_u64() {
+                uint(out, if v <= u32::MAX as u64 { 6 } else { 9 }, v as u128);
+            } else if let Some(v) = n.as_i64() {
+                let v = i32::try_from(v).map_err(|_| format!("{} does not fit in int32", v))?;
+                control(out, 8, 4);
+                out.extend_from_slice(&v.to_be_bytes());
+            } else {
+                control(out, 3, 8);
+                out.extend_from_slice(&n.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        }
+        Value::Array(values) => {
+            control(out, 11, values.len());
+            for v in values {
+                encode(v, out)?;
+            }
+        }
+        Value::Object(map) => {
+            control(out, 7, map.len());
+            for (k, v) in map {
+                string(out, k);
+                encode(v, out)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        maxmind::{Data, ProcessedDb},
+        Config,
+    };
+    use geofw_common::{CountryAction, MaxmindDbType, ALLOW_MARKER};
+
+    fn country_db(record_size: u16) -> MaxmindDb {
+        let spec: Spec = serde_json::from_value(serde_json::json!({
+            "record_size": record_size,
+            "networks": [
+                { "network": "1.0.0.0/8", "data": { "country": { "iso_code": "CN" } } },
+                { "network": "1.2.0.0/16", "data": { "country": { "iso_code": "AU" } } },
+                { "network": "2001:db8::/32", "data": { "country": { "iso_code": "CN" } } },
+            ]
+        }))
+        .unwrap();
+        let (out, _) = build(&spec).unwrap();
+
+        MaxmindDb::new(&out)
+    }
+
+    fn process(db: MaxmindDb, config: &Config) -> ProcessedDb {
+        db.consume(|data| crate::marker(config, MaxmindDbType::Country, data))
+    }
+
+    fn blocking(countries: &[&str]) -> Config {
+        Config {
+            source_countries: countries.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ipv4_aliases_point_at_the_ipv4_subtree() {
+        let db = country_db(24);
+        let ipv4_start = db.metadata.ipv4_start;
+        assert_ne!(ipv4_start, 0);
+
+        let node_size = 6;
+        let record = |node: u32, left| {
+            let n = &db.data[node as usize * node_size..(node as usize + 1) * node_size];
+            MaxmindDb::node_from_bytes(n, left, 24)
+        };
+        let walk = |bits: u128, prefix_len: u32| {
+            (0..prefix_len).fold(0, |node, i| record(node, (bits >> (127 - i)) & 1 == 0))
+        };
+        assert_eq!(walk(0xffff << 32, 96), ipv4_start);
+        assert_eq!(walk(0x2002 << 112, 16), ipv4_start);
+
+        let spec: Spec = serde_json::from_value(serde_json::json!({
+            "ipv4_aliases": false,
+            "networks": [{ "network": "1.0.0.0/8", "data": { "country": { "iso_code": "CN" } } }]
+        }))
+        .unwrap();
+        let db = MaxmindDb::new(&build(&spec).unwrap().0);
+        assert_eq!(
+            db.lookup("::ffff:1.1.1.1".parse().unwrap()),
+            None,
+            "aliases were written without ipv4_aliases"
+        );
+    }
+
+    #[test]
+    fn lookups_follow_the_aliases() {
+        let db = country_db(24);
+
+        for addr in ["1.1.1.1", "::ffff:1.1.1.1", "2002:101:101::1"] {
+            let Some(Data::Map(data)) = db.lookup(addr.parse().unwrap()) else {
+                panic!("{} is not in the database", addr);
+            };
+            let Some(Data::Map(country)) = data.get("country".as_bytes()) else {
+                panic!("{} has no country", addr);
+            };
+            assert_eq!(
+                country.get("iso_code".as_bytes()),
+                Some(&Data::String(b"CN")),
+                "{}",
+                addr
+            );
+        }
+        assert_eq!(db.lookup("2.0.0.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn processed_tree_matches_the_rules() {
+        for record_size in [24, 28] {
+            let processed = process(country_db(record_size), &blocking(&["CN"]));
+
+            for (addr, listed) in [
+                ("1.1.1.1", true),
+                ("1.2.3.4", false),
+                ("2.0.0.1", false),
+                ("2001:db8::1", true),
+                ("2001:db9::1", false),
+                ("::ffff:1.1.1.1", true),
+                ("::ffff:1.2.3.4", false),
+                ("2002:101:101::1", true),
+                ("2002:102:304::1", false),
+            ] {
+                assert_eq!(
+                    processed.lookup(addr.parse().unwrap()),
+                    listed,
+                    "{} with record size {}",
+                    addr,
+                    record_size
+                );
+            }
+        }
+    }
+
+    /// The record the walk of the kernel ends at for `addr`
+    fn record_of(processed: &ProcessedDb, addr: &str) -> u32 {
+        let nodes: Vec<u64> = processed.nodes().collect();
+        let (mut node, mut i, mut ip) = match addr.parse().unwrap() {
+            IpAddr::V4(a) => (processed.ipv4_start, 32, (a.to_bits() as u128) << 96),
+            IpAddr::V6(a) => (0, 128, a.to_bits()),
+        };
+
+        while i > 0 && node < processed.node_count {
+            let records = nodes[node as usize];
+            node = if ip & (1 << 127) == 0 {
+                (records >> 32) as u32
+            } else {
+                records as u32
+            };
+            ip <<= 1;
+            i -= 1;
+        }
+
+        node
+    }
+
+    #[test]
+    fn aliases_are_processed_once() {
+        let mut allowing = blocking(&["CN"]);
+        allowing.allow_countries = ["AU".to_string()].into_iter().collect();
+        let mut logging = blocking(&["CN"]);
+        logging.country_actions = [("CN".to_string(), CountryAction::Log)]
+            .into_iter()
+            .collect();
+
+        // 1.0.0.0/8 is split into 8 records around 1.2.0.0/16, every network is marked once and
+        // not again through the aliases
+        for (config, addr, record, marked) in [
+            (&allowing, "1.2.3.4", ALLOW_MARKER, 10),
+            (&logging, "1.1.1.1", CountryAction::Log.marker(1), 9),
+        ] {
+            let processed = process(country_db(24), config);
+
+            for addr in [addr.to_string(), format!("::ffff:{}", addr)] {
+                assert_eq!(record_of(&processed, &addr), record, "{}", addr);
+            }
+            assert_eq!(processed.marked, marked);
+        }
+    }
+
+    #[test]
+    fn data_pointers_of_28_bit_trees_are_not_markers() {
+        // Data of 2.0.0.0/8 follows the padding, which is sized so its pointer is 0xffffff, where
+        // BLOCK_MARKER used to be
+        let spec = |padding: usize| -> Spec {
+            serde_json::from_value(serde_json::json!({
+                "record_size": 28,
+                "networks": [
+                    { "network": "1.0.0.0/8", "data": { "padding": "x".repeat(padding) } },
+                    { "network": "2.0.0.0/8", "data": { "country": { "iso_code": "DE" } } },
+                ]
+            }))
+            .unwrap()
+        };
+        let pointer = |db: &MaxmindDb| {
+            let processed = process(MaxmindDb::new(&db.data), &blocking(&[]));
+            record_of(&processed, "2.0.0.1")
+        };
+        let db = MaxmindDb::new(&build(&spec(1 << 17)).unwrap().0);
+        let padding = (1 << 17) + (0xffffff - pointer(&db)) as usize;
+        let db = MaxmindDb::new(&build(&spec(padding)).unwrap().0);
+        assert_eq!(pointer(&db), 0xffffff);
+
+        let processed = process(db, &blocking(&["CN"]));
+        assert_eq!(processed.record_size, 32);
+        assert!(!processed.lookup("2.0.0.1".parse().unwrap()));
+        assert_eq!(processed.marked, 0);
+        assert_eq!(processed.prefixes(), vec![]);
+    }
+
+    #[test]
+    fn prefixes_skip_the_aliases() {
+        let processed = process(country_db(24), &blocking(&["CN"]));
+        let mut prefixes: Vec<_> = processed
+            .prefixes()
+            .into_iter()
+            .map(|(network, prefix_len, _)| (network, prefix_len))
+            .collect();
+        prefixes.sort();
+
+        // 1.0.0.0/8 without 1.2.0.0/16
+        let mut expected = vec![(0x2001_0db8 << 96, 32)];
+        for (i, prefix_len) in [
+            (0, 15),
+            (3, 16),
+            (4, 14),
+            (8, 13),
+            (16, 12),
+            (32, 11),
+            (64, 10),
+            (128, 9),
+        ] {
+            let network: u128 = 0xffff_0100_0000 | (i << 16);
+            expected.push((network, 96 + prefix_len));
+        }
+        expected.sort();
+
+        assert_eq!(prefixes, expected);
+    }
+}
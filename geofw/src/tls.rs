@@ -0,0 +1,102 @@
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{fs::File, io::BufReader, sync::Arc};
+
+/// Certificates for an HTTP listener, or for a client connecting to one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain
+    pub cert: String,
+
+    /// PEM file with the private key of the certificate
+    pub key: String,
+
+    /// PEM file with the CA certificates. On a listener, clients have to present a certificate
+    /// signed by one of them. On a client, the server's certificate has to be signed by one of
+    /// them instead of a public CA
+    #[serde(default)]
+    pub ca: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, String> {
+        let provider = Arc::new(default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+
+        let builder = match &self.ca {
+            Some(ca) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots(ca)?), provider)
+                        .build()
+                        .map_err(|e| format!("error in setting up client verification: {}", e))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder
+            .with_single_cert(certs(&self.cert)?, private_key(&self.key)?)
+            .map_err(|e| format!("error in loading {}: {}", self.cert, e))?;
+
+        Ok(Arc::new(config))
+    }
+
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>, String> {
+        let roots = match &self.ca {
+            Some(ca) => roots(ca)?,
+            None => RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+        };
+
+        let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs(&self.cert)?, private_key(&self.key)?)
+            .map_err(|e| format!("error in loading {}: {}", self.cert, e))?;
+
+        Ok(Arc::new(config))
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("error in opening {}: {}", path, e))
+}
+
+fn certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("error in reading certificates from {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", path));
+    }
+
+    Ok(certs)
+}
+
+fn private_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| format!("error in reading private key from {}: {}", path, e))?
+        .ok_or_else(|| format!("no private key found in {}", path))
+}
+
+fn roots(path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| format!("error in adding CA from {}: {}", path, e))?;
+    }
+
+    Ok(roots)
+}
@@ -0,0 +1,164 @@
+use crate::{
+    db_path,
+    maps::{KernelTree, TREE_MAPS},
+    marker,
+    maxmind::{Data, MaxmindDb},
+    output::{print_json, OutputFormat},
+    Config,
+};
+use geofw_common::is_listed;
+use serde_derive::Serialize;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Small xorshift generator, good enough to pick sample addresses.
+struct Sampler(u64);
+
+impl Sampler {
+    fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0x2545_f491_4f6c_dd1d);
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn addr(&mut self) -> IpAddr {
+        // Mostly IPv4, with some addresses from the global unicast IPv6 range
+        if !self.next().is_multiple_of(4) {
+            IpAddr::V4(Ipv4Addr::from_bits(self.next() as u32))
+        } else {
+            let bits = ((self.next() as u128) << 64) | self.next() as u128;
+            IpAddr::V6(Ipv6Addr::from_bits((bits >> 3) | (1 << 125)))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DbReport {
+    db: String,
+    checked: usize,
+    /// Set when the shape of the kernel tree doesn't match the database on disk, in which case
+    /// no addresses are checked
+    shape_mismatch: Option<String>,
+    mismatches: Vec<Mismatch>,
+}
+
+#[derive(Serialize)]
+struct Mismatch {
+    addr: IpAddr,
+    kernel: bool,
+    userspace: bool,
+    rules: bool,
+}
+
+/// Compares the tree loaded in the kernel against the cached database on disk for a random
+/// sample of addresses. Every address is checked three ways: walking the kernel map, walking the
+/// processed tree in userspace and evaluating the rules against the raw record.
+pub fn verify(config: &Config, samples: usize, output: OutputFormat) -> Result<(), String> {
+    let mut sampler = Sampler::new();
+    let mut reports = vec![];
+
+    let db_types = config.db_types();
+    for (map_name, db_type) in TREE_MAPS {
+        if !db_types.contains(&db_type) {
+            continue;
+        }
+
+        let path = db_path(config, db_type);
+        let path = path.to_string_lossy();
+
+        let kernel = KernelTree::open(map_name, db_type)?;
+        let raw = MaxmindDb::from_file(&path)?;
+        let processed = MaxmindDb::from_file(&path)?.consume(|data| marker(config, db_type, data));
+
+        let mut report = DbReport {
+            db: db_type.to_string(),
+            checked: 0,
+            shape_mismatch: None,
+            mismatches: vec![],
+        };
+
+        if kernel.node_count != processed.node_count
+            || kernel.record_size != processed.record_size as u32
+        {
+            report.shape_mismatch = Some(format!(
+                "kernel node_count = {} record_size = {}, on disk node_count = {} record_size = {}",
+                kernel.node_count, kernel.record_size, processed.node_count, processed.record_size
+            ));
+            reports.push(report);
+            continue;
+        }
+
+        for _ in 0..samples {
+            let addr = sampler.addr();
+
+            let in_kernel = kernel.lookup(addr)?;
+            let in_userspace = processed.lookup(addr);
+            let by_rules = match raw.lookup(addr) {
+                Some(Data::Map(data)) => {
+                    marker(config, db_type, &data).is_some_and(|m| is_listed(m, 0))
+                }
+                _ => false,
+            };
+
+            if in_kernel != in_userspace || in_userspace != by_rules {
+                report.mismatches.push(Mismatch {
+                    addr,
+                    kernel: in_kernel,
+                    userspace: in_userspace,
+                    rules: by_rules,
+                });
+            }
+        }
+        report.checked = samples;
+        reports.push(report);
+    }
+
+    match output {
+        OutputFormat::Json => print_json(&reports)?,
+        OutputFormat::Table => {
+            for r in &reports {
+                if let Some(shape) = &r.shape_mismatch {
+                    println!("{}: {}", r.db, shape);
+                    continue;
+                }
+
+                for m in &r.mismatches {
+                    println!(
+                        "{}: addr = {} kernel = {} userspace = {} rules = {}",
+                        r.db, m.addr, m.kernel, m.userspace, m.rules
+                    );
+                }
+                println!(
+                    "{}: checked {} addresses, {} mismatches",
+                    r.db,
+                    r.checked,
+                    r.mismatches.len()
+                );
+            }
+        }
+    }
+
+    let mismatches: usize = reports
+        .iter()
+        .map(|r| r.mismatches.len() + r.shape_mismatch.is_some() as usize)
+        .sum();
+    if mismatches > 0 {
+        return Err(format!(
+            "found {} mismatches, the loaded maps may be corrupt or the config has changed since they were loaded",
+            mismatches
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,312 @@
+//! Minimal AF_XDP socket that only receives. Frames handed to the callback in `recv` are
+//! returned to the fill ring right after, so the kernel can reuse them.
+
+use std::{
+    ffi::CString,
+    fs, io, mem,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+const FRAME_SIZE: u32 = 4096;
+const RING_SIZE: u32 = 2048;
+// Every frame is either owned by the kernel through the fill ring or waiting in the rx ring, so
+// the fill ring never overflows when frames are handed back.
+const FRAME_COUNT: u32 = RING_SIZE;
+
+struct Ring {
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    desc: *mut u8,
+    mask: u32,
+    map: *mut libc::c_void,
+    map_len: usize,
+}
+
+impl Ring {
+    /// Maps the ring at `pgoff` on the socket. `elem` is the size of one descriptor
+    unsafe fn map(
+        fd: BorrowedFd,
+        offsets: &libc::xdp_ring_offset,
+        elem: usize,
+        pgoff: libc::off_t,
+    ) -> io::Result<Self> {
+        let map_len = offsets.desc as usize + RING_SIZE as usize * elem;
+        let map = libc::mmap(
+            ptr::null_mut(),
+            map_len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            fd.as_raw_fd(),
+            pgoff,
+        );
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let base = map as *mut u8;
+        Ok(Self {
+            producer: base.add(offsets.producer as usize) as *const AtomicU32,
+            consumer: base.add(offsets.consumer as usize) as *const AtomicU32,
+            desc: base.add(offsets.desc as usize),
+            mask: RING_SIZE - 1,
+            map,
+            map_len,
+        })
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map, self.map_len) };
+    }
+}
+
+pub struct XskSocket {
+    // Rings and UMEM are unmapped before the socket is closed
+    fill: Ring,
+    _completion: Ring,
+    rx: Ring,
+    umem: *mut u8,
+    umem_len: usize,
+    fd: OwnedFd,
+}
+
+// The rings and UMEM are only ever touched through &mut self
+unsafe impl Send for XskSocket {}
+
+/// Index of the interface, as seen in `ingress_ifindex` by the XDP program
+pub fn ifindex(interface: &str) -> Result<u32, String> {
+    let name = CString::new(interface).map_err(|e| e.to_string())?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(format!("interface {} does not exist", interface)),
+        i => Ok(i),
+    }
+}
+
+/// Number of rx queues on the interface. Each queue needs its own AF_XDP socket
+pub fn rx_queues(interface: &str) -> Result<u32, String> {
+    let path = format!("/sys/class/net/{}/queues", interface);
+    let entries = fs::read_dir(&path).map_err(|e| format!("error in reading {}: {}", path, e))?;
+
+    let count = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with("rx-"))
+        .count();
+
+    Ok(count.max(1) as u32)
+}
+
+impl XskSocket {
+    /// Creates a socket bound to `queue` on `interface`. The socket only receives packets once
+    /// it has been inserted into an XSKMAP that the XDP program redirects to.
+    pub fn bind(interface: &str, queue: u32) -> io::Result<Self> {
+        let name = CString::new(interface).map_err(|e| io::Error::other(e.to_string()))?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let umem_len = (FRAME_COUNT * FRAME_SIZE) as usize;
+        let umem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                umem_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if umem == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let umem = umem as *mut u8;
+
+        let setup = || -> io::Result<(Ring, Ring, Ring)> {
+            let reg = libc::xdp_umem_reg {
+                addr: umem as u64,
+                len: umem_len as u64,
+                chunk_size: FRAME_SIZE,
+                headroom: 0,
+                flags: 0,
+                tx_metadata_len: 0,
+            };
+            setsockopt(fd.as_fd(), libc::XDP_UMEM_REG, &reg)?;
+            setsockopt(fd.as_fd(), libc::XDP_UMEM_FILL_RING, &RING_SIZE)?;
+            setsockopt(fd.as_fd(), libc::XDP_UMEM_COMPLETION_RING, &RING_SIZE)?;
+            setsockopt(fd.as_fd(), libc::XDP_RX_RING, &RING_SIZE)?;
+
+            let mut offsets: libc::xdp_mmap_offsets = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::xdp_mmap_offsets>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    fd.as_raw_fd(),
+                    libc::SOL_XDP,
+                    libc::XDP_MMAP_OFFSETS,
+                    &mut offsets as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let (fill, completion, rx) = unsafe {
+                (
+                    Ring::map(
+                        fd.as_fd(),
+                        &offsets.fr,
+                        mem::size_of::<u64>(),
+                        libc::XDP_UMEM_PGOFF_FILL_RING as libc::off_t,
+                    )?,
+                    Ring::map(
+                        fd.as_fd(),
+                        &offsets.cr,
+                        mem::size_of::<u64>(),
+                        libc::XDP_UMEM_PGOFF_COMPLETION_RING as libc::off_t,
+                    )?,
+                    Ring::map(
+                        fd.as_fd(),
+                        &offsets.rx,
+                        mem::size_of::<libc::xdp_desc>(),
+                        libc::XDP_PGOFF_RX_RING,
+                    )?,
+                )
+            };
+
+            // Hand every frame to the kernel
+            for i in 0..FRAME_COUNT {
+                unsafe { *(fill.desc as *mut u64).add(i as usize) = (i * FRAME_SIZE) as u64 };
+            }
+            fill.producer().store(FRAME_COUNT, Ordering::Release);
+
+            let addr = libc::sockaddr_xdp {
+                sxdp_family: libc::AF_XDP as u16,
+                sxdp_flags: 0,
+                sxdp_ifindex: ifindex,
+                sxdp_queue_id: queue,
+                sxdp_shared_umem_fd: 0,
+            };
+            let ret = unsafe {
+                libc::bind(
+                    fd.as_raw_fd(),
+                    &addr as *const _ as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_xdp>() as libc::socklen_t,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok((fill, completion, rx))
+        };
+
+        let (fill, completion, rx) = setup().inspect_err(|_| unsafe {
+            libc::munmap(umem as *mut libc::c_void, umem_len);
+        })?;
+
+        Ok(Self {
+            fill,
+            _completion: completion,
+            rx,
+            umem,
+            umem_len,
+            fd,
+        })
+    }
+
+    /// Calls `f` with every frame waiting in the rx ring and the `metadata_len` bytes of XDP
+    /// metadata in front of it, and returns the number of frames seen
+    pub fn recv(&mut self, metadata_len: usize, mut f: impl FnMut(&[u8], &[u8])) -> usize {
+        let producer = self.rx.producer().load(Ordering::Acquire);
+        let consumer = self.rx.consumer().load(Ordering::Relaxed);
+        let n = producer.wrapping_sub(consumer);
+        if n == 0 {
+            return 0;
+        }
+
+        let fill_producer = self.fill.producer().load(Ordering::Relaxed);
+        for i in 0..n {
+            let desc = unsafe {
+                *(self.rx.desc as *const libc::xdp_desc)
+                    .add((consumer.wrapping_add(i) & self.rx.mask) as usize)
+            };
+
+            let start = desc.addr & !(FRAME_SIZE as u64 - 1);
+            let chunk = unsafe {
+                std::slice::from_raw_parts(self.umem.add(start as usize), FRAME_SIZE as usize)
+            };
+            let offset = (desc.addr - start) as usize;
+            let frame = chunk
+                .get(offset..offset + desc.len as usize)
+                .unwrap_or_default();
+            f(frame, metadata(chunk, offset, metadata_len));
+
+            let slot = fill_producer.wrapping_add(i) & self.fill.mask;
+            unsafe { *(self.fill.desc as *mut u64).add(slot as usize) = start };
+        }
+
+        self.rx
+            .consumer()
+            .store(consumer.wrapping_add(n), Ordering::Release);
+        self.fill
+            .producer()
+            .store(fill_producer.wrapping_add(n), Ordering::Release);
+
+        n as usize
+    }
+}
+
+impl AsFd for XskSocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl Drop for XskSocket {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.umem as *mut libc::c_void, self.umem_len) };
+    }
+}
+
+/// The `len` bytes in front of the frame at `offset` in its chunk, where the XDP program put its
+/// metadata. Empty when the frame starts less than `len` bytes into the chunk
+pub fn metadata(chunk: &[u8], offset: usize, len: usize) -> &[u8] {
+    offset
+        .checked_sub(len)
+        .and_then(|start| chunk.get(start..offset))
+        .unwrap_or_default()
+}
+
+fn setsockopt<T>(fd: BorrowedFd, name: libc::c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_XDP,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}